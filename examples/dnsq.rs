@@ -0,0 +1,487 @@
+//! A dig-like client for trying the server by hand:
+//!
+//! ```text
+//! cargo run --example dnsq -- @127.0.0.1 -p 2053 hernan.rs AAAA +tcp +dnssec +norecurse
+//! ```
+//!
+//! `+short` prints the answer data alone, `+json` one object per response and
+//! `+trace` follows referrals down from the root servers. `-f FILE` reads one
+//! query per line, written like the command line, on top of its options.
+use anyhow::{Context, Result};
+use dns_starter_rust::{
+    encoding::json_string,
+    message::{
+        data::{absolute, Data},
+        domain::{in_zone, Domain, Record},
+        edns::Edns,
+        header::{
+            Authenticity, Authoritative, Checking, OpCode, PacketId, QueryMode, Recursion,
+            Truncation,
+        },
+        route::Route,
+        Header, Message,
+    },
+    socket::{tcp::DnsStream, DnsClient, DnsSocket, RetryPolicy},
+};
+use std::{
+    env, fs,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+/// Referrals followed by `+trace` before giving up.
+const MAX_REFERRALS: usize = 16;
+
+#[derive(Clone, Debug)]
+struct Options {
+    /// Server from `/etc/resolv.conf` when unset.
+    server: Option<String>,
+    port: u16,
+    tcp: bool,
+    dnssec: bool,
+    recurse: bool,
+    short: bool,
+    json: bool,
+    trace: bool,
+    timeout: Duration,
+    batch: Option<String>,
+    questions: Vec<(String, Record)>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            server: None,
+            port: 53,
+            tcp: false,
+            dnssec: false,
+            recurse: true,
+            short: false,
+            json: false,
+            trace: false,
+            timeout: Duration::from_secs(5),
+            batch: None,
+            questions: vec![],
+        }
+    }
+}
+
+/// Reads `args` on top of `options`. A type right after a name applies to it;
+/// a name alone asks for its A records.
+fn parse(args: &[String], mut options: Options) -> Result<Options> {
+    let mut args = args.iter();
+    // Whether the last name was given a type already.
+    let mut typed = true;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-p" => {
+                let port = args.next().context("Missing port")?;
+                options.port = port.parse().with_context(|| format!("Bad port: {port}"))?
+            }
+            "-f" => options.batch = Some(args.next().context("Missing batch file")?.clone()),
+            "+tcp" | "+vc" => options.tcp = true,
+            "+notcp" | "+novc" => options.tcp = false,
+            "+dnssec" => options.dnssec = true,
+            "+nodnssec" => options.dnssec = false,
+            "+recurse" => options.recurse = true,
+            "+norecurse" => options.recurse = false,
+            "+short" => options.short = true,
+            "+noshort" => options.short = false,
+            "+json" => options.json = true,
+            "+nojson" => options.json = false,
+            "+trace" => options.trace = true,
+            "+notrace" => options.trace = false,
+            a if a.starts_with("+timeout=") => {
+                let secs = a["+timeout=".len()..]
+                    .parse()
+                    .with_context(|| format!("Bad timeout: {a}"))?;
+                options.timeout = Duration::from_secs(secs)
+            }
+            a if a.starts_with('+') => anyhow::bail!("Unknown option: {a}"),
+            a if a.starts_with('@') => options.server = Some(a[1..].to_string()),
+            a => match (a.parse::<Record>(), options.questions.last_mut()) {
+                (Ok(record), Some((_, asked))) if !typed => {
+                    *asked = record;
+                    typed = true
+                }
+                _ => {
+                    options.questions.push((a.to_string(), Record::AA));
+                    typed = false
+                }
+            },
+        }
+    }
+    Ok(options)
+}
+
+/// The first nameserver of `/etc/resolv.conf`, or the loopback address.
+fn system_server() -> String {
+    fs::read_to_string("/etc/resolv.conf")
+        .ok()
+        .and_then(|conf| {
+            conf.lines().find_map(|l| {
+                let mut words = l.split_whitespace();
+                (words.next() == Some("nameserver"))
+                    .then(|| words.next().map(str::to_string))
+                    .flatten()
+            })
+        })
+        .unwrap_or_else(|| "127.0.0.1".to_string())
+}
+
+/// `host:port`, bracketing IPv6 addresses.
+fn address(host: &str, port: u16) -> String {
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(ip)) => format!("[{ip}]:{port}"),
+        _ => format!("{host}:{port}"),
+    }
+}
+
+#[derive(Debug)]
+struct Exchange {
+    server: String,
+    tcp: bool,
+    time: Duration,
+    response: Message,
+}
+
+/// Asks `server` about `name`, over TCP when told to or when the UDP answer
+/// comes back truncated.
+fn ask(
+    options: &Options,
+    server: &str,
+    name: &str,
+    record: Record,
+    recurse: bool,
+) -> Result<Exchange> {
+    let mut header = Header::query(PacketId::random());
+    if recurse {
+        header.rd = Recursion::Enabled;
+    }
+    let mut query = Message::new(header);
+    query.set_questions(vec![Domain::new(name.trim_end_matches('.'), record)])?;
+    query.set_edns(Some(Edns::new(options.dnssec)))?;
+    let policy = RetryPolicy {
+        deadline: options.timeout,
+        ..RetryPolicy::default()
+    };
+
+    let started = Instant::now();
+    let mut tcp = options.tcp;
+    if !tcp {
+        let response = DnsSocket::<DnsClient>::connect(server)?
+            .with_policy(policy)
+            .query(&query)
+            .with_context(|| format!("No answer from {server}"))?;
+        if response.header().tc == Truncation::Complete {
+            return Ok(Exchange {
+                server: server.to_string(),
+                tcp,
+                time: started.elapsed(),
+                response,
+            });
+        }
+        if !options.short && !options.json {
+            println!(";; Truncated, retrying in TCP mode.");
+        }
+        tcp = true;
+    }
+    let response = DnsStream::connect(server, policy)?
+        .query(&query)
+        .with_context(|| format!("No answer from {server}"))?;
+    Ok(Exchange {
+        server: server.to_string(),
+        tcp,
+        time: started.elapsed(),
+        response,
+    })
+}
+
+fn op_code_name(code: OpCode) -> String {
+    match code.0 {
+        0 => "QUERY".to_string(),
+        4 => "NOTIFY".to_string(),
+        5 => "UPDATE".to_string(),
+        n => n.to_string(),
+    }
+}
+
+fn flags(h: &Header) -> Vec<&'static str> {
+    [
+        ("qr", h.qr == QueryMode::Response),
+        ("aa", h.aa == Authoritative::Owned),
+        ("tc", h.tc == Truncation::Truncated),
+        ("rd", h.rd == Recursion::Enabled),
+        ("ra", h.ra == Recursion::Enabled),
+        ("ad", h.ad == Authenticity::Authentic),
+        ("cd", h.cd == Checking::Disabled),
+    ]
+    .into_iter()
+    .filter_map(|(name, set)| set.then_some(name))
+    .collect()
+}
+
+fn question_line(d: &Domain) -> String {
+    format!(";{}\t\t{}\t{}", absolute(&d.name), d.class, d.record)
+}
+
+/// The response the way dig shows it.
+fn full(e: &Exchange) -> String {
+    let res = &e.response;
+    let h = res.header();
+    let mut out = format!(
+        ";; ->>HEADER<<- opcode: {}, status: {}, id: {}\n\
+         ;; flags: {}; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}\n",
+        op_code_name(h.op_code),
+        h.r_code.r_code_name(),
+        h.id.0,
+        flags(h).join(" "),
+        h.qd_count,
+        h.an_count,
+        h.ns_count,
+        h.ar_count,
+    );
+    if let Some(edns) = res.edns() {
+        let flags = if edns.dnssec_ok { " do" } else { "" };
+        out += &format!(
+            "\n;; OPT PSEUDOSECTION:\n; EDNS: version: {}, flags:{flags}; udp: {}\n",
+            edns.version, edns.udp_payload
+        );
+    }
+    out += "\n;; QUESTION SECTION:\n";
+    for d in res.questions() {
+        out += &question_line(d);
+        out.push('\n');
+    }
+    for (title, routes) in [
+        ("ANSWER", res.answers()),
+        ("AUTHORITY", res.authorities()),
+        ("ADDITIONAL", res.additionals()),
+    ] {
+        if routes.is_empty() {
+            continue;
+        }
+        out += &format!("\n;; {title} SECTION:\n");
+        for r in routes {
+            out += &format!("{r}\n");
+        }
+    }
+    let transport = if e.tcp { "TCP" } else { "UDP" };
+    out += &format!(
+        "\n;; Query time: {} msec\n;; SERVER: {} ({transport})\n;; MSG SIZE  rcvd: {}\n",
+        e.time.as_millis(),
+        e.server,
+        res.flush().len()
+    );
+    out
+}
+
+/// The answer data alone, one record per line.
+fn short(e: &Exchange) -> String {
+    e.response
+        .answers()
+        .iter()
+        .map(|r| format!("{}\n", r.rdata()))
+        .collect()
+}
+
+fn json_routes(routes: &[Route]) -> String {
+    let routes: Vec<_> = routes
+        .iter()
+        .map(|r| {
+            let d = r.domain();
+            format!(
+                "{{\"name\":{},\"ttl\":{},\"class\":{},\"type\":{},\"data\":{}}}",
+                json_string(&absolute(&d.name)),
+                r.ttl(),
+                json_string(&d.class.to_string()),
+                json_string(&d.record.to_string()),
+                json_string(&r.rdata()),
+            )
+        })
+        .collect();
+    format!("[{}]", routes.join(","))
+}
+
+/// The response as one JSON object.
+fn json(e: &Exchange) -> String {
+    let res = &e.response;
+    let h = res.header();
+    let flags: Vec<_> = flags(h).into_iter().map(json_string).collect();
+    let questions: Vec<_> = res
+        .questions()
+        .iter()
+        .map(|d| {
+            format!(
+                "{{\"name\":{},\"class\":{},\"type\":{}}}",
+                json_string(&absolute(&d.name)),
+                json_string(&d.class.to_string()),
+                json_string(&d.record.to_string()),
+            )
+        })
+        .collect();
+    let edns = res.edns().map_or("null".to_string(), |edns| {
+        format!(
+            "{{\"version\":{},\"udp\":{},\"do\":{}}}",
+            edns.version, edns.udp_payload, edns.dnssec_ok
+        )
+    });
+    format!(
+        "{{\"server\":{},\"transport\":{},\"time_ms\":{},\"size\":{},\"id\":{},\
+         \"opcode\":{},\"status\":{},\"flags\":[{}],\"question\":[{}],\"answer\":{},\
+         \"authority\":{},\"additional\":{},\"edns\":{edns}}}",
+        json_string(&e.server),
+        json_string(if e.tcp { "tcp" } else { "udp" }),
+        e.time.as_millis(),
+        res.flush().len(),
+        h.id.0,
+        json_string(&op_code_name(h.op_code)),
+        json_string(h.r_code.r_code_name()),
+        flags.join(","),
+        questions.join(","),
+        json_routes(res.answers()),
+        json_routes(res.authorities()),
+        json_routes(res.additionals()),
+    )
+}
+
+fn show(options: &Options, e: &Exchange) {
+    match (options.json, options.short) {
+        (true, _) => println!("{}", json(e)),
+        (false, true) => print!("{}", short(e)),
+        (false, false) => println!("{}", full(e)),
+    }
+}
+
+/// The IPv4 addresses of `host`, asking `resolver` unless it is one already.
+fn addresses(options: &Options, resolver: &str, host: &str) -> Result<Vec<String>> {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![ip.to_string()]);
+    }
+    let e = ask(options, resolver, host, Record::AA, true)?;
+    Ok(e.response
+        .answers()
+        .iter()
+        .filter_map(|r| match r.data() {
+            Data::Ipv4(ip) => Some(ip.to_string()),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Walks down from the root servers `resolver` knows of, asking each zone's
+/// servers in turn, the way `dig +trace` does. Only IPv4 glue is followed.
+fn trace(options: &Options, resolver: &str, name: &str, record: Record) -> Result<()> {
+    let roots = ask(options, resolver, "", Record::NS, true)?;
+    show(options, &roots);
+    let mut zone = String::new();
+    let mut referral = roots;
+    let mut routes = referral.response.answers().clone();
+
+    for _ in 0..MAX_REFERRALS {
+        let servers: Vec<String> = routes
+            .iter()
+            .filter_map(|r| match r.data() {
+                Data::Name(ns) if r.domain().record == Record::NS => Some(ns.clone()),
+                _ => None,
+            })
+            .collect();
+        let glue: Vec<String> = referral
+            .response
+            .additionals()
+            .iter()
+            .filter(|r| {
+                let owner = r.domain().name.trim_end_matches('.');
+                servers
+                    .iter()
+                    .any(|ns| ns.trim_end_matches('.').eq_ignore_ascii_case(owner))
+            })
+            .filter_map(|r| match r.data() {
+                Data::Ipv4(ip) => Some(ip.to_string()),
+                _ => None,
+            })
+            .collect();
+        let addrs = match glue.is_empty() {
+            false => glue,
+            true => match servers.first() {
+                Some(ns) => addresses(options, resolver, ns)?,
+                None => vec![],
+            },
+        };
+
+        let mut answered = None;
+        for addr in &addrs {
+            match ask(options, &address(addr, 53), name, record, false) {
+                Ok(e) => {
+                    answered = Some(e);
+                    break;
+                }
+                Err(e) => eprintln!(";; {e:#}"),
+            }
+        }
+        let e = answered.with_context(|| format!("No server of {} answered", absolute(&zone)))?;
+        show(options, &e);
+
+        let next = e
+            .response
+            .authorities()
+            .iter()
+            .find(|r| r.domain().record == Record::NS)
+            .map(|r| r.domain().name.clone());
+        match next {
+            Some(cut) if e.response.answers().is_empty() => {
+                anyhow::ensure!(
+                    in_zone(name, &cut) && !in_zone(&zone, &cut),
+                    "Bad referral from {} to {}",
+                    absolute(&zone),
+                    absolute(&cut)
+                );
+                zone = cut;
+                routes = e.response.authorities().clone();
+                referral = e;
+            }
+            _ => return Ok(()),
+        }
+    }
+    anyhow::bail!("Gave up after {MAX_REFERRALS} referrals")
+}
+
+fn run(options: &Options) -> Result<()> {
+    let server = options.server.clone().unwrap_or_else(system_server);
+    let server = address(&server, options.port);
+    for (name, record) in &options.questions {
+        if options.trace {
+            trace(options, &server, name, *record)?;
+            continue;
+        }
+        let e = ask(options, &server, name, *record, options.recurse)?;
+        show(options, &e);
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut options = parse(&args, Options::default())?;
+    let Some(path) = options.batch.take() else {
+        if options.questions.is_empty() {
+            options.questions.push((String::new(), Record::NS));
+        }
+        return run(&options);
+    };
+
+    run(&options)?;
+    let batch = fs::read_to_string(&path).with_context(|| format!("Could not read {path}"))?;
+    options.questions.clear();
+    for line in batch.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+            continue;
+        }
+        let args: Vec<String> = line.split_whitespace().map(str::to_string).collect();
+        let run = parse(&args, options.clone()).and_then(|o| run(&o));
+        if let Err(e) = run {
+            eprintln!(";; {line}: {e:#}");
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,107 @@
+use crate::{
+    cidr::Cidr,
+    message::{header::OpCode, Message},
+};
+use anyhow::{Context, Result};
+use std::{net::IpAddr, str::FromStr};
+
+/// Who may do something: clients from some networks, or signing with some
+/// TSIG key. Empty lists allow nobody.
+#[derive(Clone, Debug, Default)]
+pub struct Acl {
+    nets: Vec<Cidr>,
+    keys: Vec<String>,
+}
+
+impl Acl {
+    /// Allows a network (`10.0.0.0/8`, `::1`) or a key (`key:name`).
+    pub fn allow(&mut self, spec: &str) -> Result<()> {
+        match spec.strip_prefix("key:") {
+            Some(name) => {
+                anyhow::ensure!(!name.is_empty(), "Missing key name in {spec}");
+                self.keys
+                    .push(name.trim_end_matches('.').to_ascii_lowercase());
+            }
+            None => {
+                let net = spec
+                    .parse()
+                    .with_context(|| format!("Invalid network: {spec}"))?;
+                self.nets.push(net);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `client`, whose message was signed with `key` if any, is allowed.
+    pub fn allows(&self, client: IpAddr, key: Option<&str>) -> bool {
+        let key = key.map(|k| k.trim_end_matches('.').to_ascii_lowercase());
+        self.nets.iter().any(|n| n.contains(client)) || key.is_some_and(|k| self.keys.contains(&k))
+    }
+}
+
+/// What happens to messages an ACL turns away.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Denial {
+    #[default]
+    Refuse,
+    /// No response at all, as if nobody listened.
+    Drop,
+}
+
+impl Denial {
+    /// The response turning `query` away, if any.
+    pub fn response(self, query: &Message) -> Option<Message> {
+        match self {
+            Self::Refuse => {
+                let mut res = Message::new_response(query);
+                res.set_r_code(OpCode::refused());
+                Some(res)
+            }
+            Self::Drop => None,
+        }
+    }
+}
+
+impl FromStr for Denial {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "refuse" => Ok(Self::Refuse),
+            "drop" => Ok(Self::Drop),
+            _ => anyhow::bail!("Not a denial: {s}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{header::PacketId, Header};
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_acl() {
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3));
+        let mut acl = Acl::default();
+        assert!(!acl.allows(ip, Some("transfer.key")));
+
+        acl.allow("10.0.0.0/8").unwrap();
+        acl.allow("key:Transfer.Key.").unwrap();
+        assert!(acl.allows(ip, None));
+        assert!(acl.allows(IpAddr::V4(Ipv4Addr::LOCALHOST), Some("transfer.key")));
+        assert!(!acl.allows(IpAddr::V4(Ipv4Addr::LOCALHOST), Some("other.key")));
+
+        assert!(acl.allow("key:").is_err());
+        assert!(acl.allow("10.0.0.0/33").is_err());
+    }
+
+    #[test]
+    fn test_denial() {
+        let query = Message::new(Header::query(PacketId(3)));
+        let res = Denial::Refuse.response(&query).unwrap();
+        assert_eq!(res.header().r_code, OpCode::refused());
+        assert!(Denial::Drop.response(&query).is_none());
+        assert_eq!("drop".parse::<Denial>().unwrap(), Denial::Drop);
+        assert!("ignore".parse::<Denial>().is_err());
+    }
+}
@@ -0,0 +1,376 @@
+use crate::message::{
+    data::Data,
+    domain::{Domain, Record},
+    header::OpCode,
+    route::Route,
+    Message,
+};
+use anyhow::{Context, Result};
+use std::{
+    collections::HashMap,
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+const BLOCK_TTL: u32 = 60;
+
+/// Names hosts files map to themselves, never worth blocking.
+const HOSTS_BUILTINS: [&str; 5] = [
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+];
+
+/// Names indexed label by label from the root, so a lookup costs one step per
+/// label no matter how many names are stored.
+#[derive(Debug, Default)]
+pub struct NameTrie {
+    children: HashMap<String, NameTrie>,
+    exact: bool,
+    subtree: bool,
+}
+
+impl NameTrie {
+    /// Adds `name`; with `subtree` every name below it matches as well.
+    pub fn insert(&mut self, name: &str, subtree: bool) {
+        let node = name
+            .trim_end_matches('.')
+            .rsplit('.')
+            .filter(|l| !l.is_empty())
+            .fold(self, |node, label| {
+                node.children.entry(label.to_ascii_lowercase()).or_default()
+            });
+        node.exact = true;
+        node.subtree |= subtree;
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        let mut node = self;
+        for label in name.trim_end_matches('.').rsplit('.') {
+            if node.subtree {
+                return true;
+            }
+            match node.children.get(&label.to_ascii_lowercase()) {
+                Some(n) => node = n,
+                None => return false,
+            }
+        }
+        node.exact || node.subtree
+    }
+}
+
+/// How blocked names are answered.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    NxDomain,
+    Refused,
+    /// 0.0.0.0 for A questions and :: for AAAA ones.
+    Null,
+    Address(IpAddr),
+}
+
+impl FromStr for Action {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "nxdomain" => Ok(Self::NxDomain),
+            "refused" => Ok(Self::Refused),
+            "null" => Ok(Self::Null),
+            ip => ip
+                .parse()
+                .map(Self::Address)
+                .with_context(|| format!("Not a block action: {s}")),
+        }
+    }
+}
+
+impl Action {
+    fn data(&self, record: Record) -> Option<Data> {
+        let ip = match self {
+            Self::Null => match record {
+                Record::AA => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                _ => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            },
+            Self::Address(ip) => *ip,
+            _ => return None,
+        };
+        match (record, ip) {
+            (Record::AA, IpAddr::V4(ip)) => Some(Data::Ipv4(ip)),
+            (Record::AAAA, IpAddr::V6(ip)) => Some(Data::Ipv6(ip)),
+            _ => None,
+        }
+    }
+
+    /// Answers `query` on behalf of a blocked name.
+    pub fn answer(&self, query: &Message) -> Result<Message> {
+        let mut msg = Message::new_response(query);
+        match self {
+            Self::NxDomain => msg.set_r_code(OpCode::name_error()),
+            Self::Refused => msg.set_r_code(OpCode::refused()),
+            _ => {
+                let answers = query
+                    .questions()
+                    .iter()
+                    .filter_map(|q| {
+                        let data = self.data(q.record)?;
+                        Some(Route::new(q.clone(), BLOCK_TTL, data))
+                    })
+                    .collect();
+                msg.set_answers(answers)?;
+            }
+        }
+        Ok(msg)
+    }
+}
+
+/// Names blocked by one list, with the names it exempts and its hit count.
+#[derive(Debug)]
+pub struct BlockList {
+    pub name: String,
+    pub action: Action,
+    blocked: NameTrie,
+    allowed: NameTrie,
+    /// Shared with the list this one replaced on reload.
+    hits: Arc<AtomicU64>,
+}
+
+fn is_domain(name: &str) -> bool {
+    !name.is_empty()
+        && name.contains('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+impl BlockList {
+    /// Reads hosts files (`0.0.0.0 name`, blocking the exact names), plain
+    /// domain lists and adblock `||domain^` rules (both blocking whole
+    /// subtrees). Adblock `@@||domain^` exceptions are honoured.
+    pub fn parse(name: &str, text: &str, action: Action) -> Self {
+        let mut list = Self {
+            name: name.to_string(),
+            action,
+            blocked: NameTrie::default(),
+            allowed: NameTrie::default(),
+            hits: Arc::default(),
+        };
+
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() || line.starts_with('!') || line.starts_with('[') {
+                continue;
+            }
+
+            let adblock = |rule: &str| {
+                let (domain, rest) = rule.split_once('^')?;
+                (rest.is_empty() && is_domain(domain)).then(|| domain.to_string())
+            };
+            if let Some(rule) = line.strip_prefix("@@||") {
+                if let Some(domain) = adblock(rule) {
+                    list.allowed.insert(&domain, true);
+                }
+                continue;
+            }
+            if let Some(rule) = line.strip_prefix("||") {
+                if let Some(domain) = adblock(rule) {
+                    list.blocked.insert(&domain, true);
+                }
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            let first = fields.next().unwrap_or_default();
+            if first.parse::<IpAddr>().is_ok() {
+                fields
+                    .filter(|n| !HOSTS_BUILTINS.contains(&n.to_ascii_lowercase().as_str()))
+                    .filter(|n| is_domain(n))
+                    .for_each(|n| list.blocked.insert(n, false));
+            } else if is_domain(first) {
+                list.blocked.insert(first, true);
+            }
+        }
+        list
+    }
+
+    pub fn load(path: &Path, action: Action) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Could not read blocklist {}", path.display()))?;
+        Ok(Self::parse(&path.display().to_string(), &text, action))
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    fn blocks(&self, name: &str) -> bool {
+        self.blocked.matches(name) && !self.allowed.matches(name)
+    }
+}
+
+/// Every blocklist, checked in the order they were given.
+#[derive(Debug, Default)]
+pub struct Blocker {
+    lists: Vec<BlockList>,
+    allowed: NameTrie,
+}
+
+impl Blocker {
+    pub fn push(&mut self, list: BlockList) {
+        self.lists.push(list);
+    }
+
+    /// Exempts from every list the names of an allowlist, written in any of
+    /// the blocklist formats.
+    pub fn allow(&mut self, text: &str) {
+        let allow = BlockList::parse("", text, Action::Refused);
+        self.allowed = merge(std::mem::take(&mut self.allowed), allow.blocked);
+    }
+
+    /// Keeps counting the hits of lists that `previous` also had, read from
+    /// the same file.
+    pub fn carry_hits(&mut self, previous: &Blocker) {
+        for list in self.lists.iter_mut() {
+            if let Some(old) = previous.lists.iter().find(|l| l.name == list.name) {
+                list.hits = old.hits.clone();
+            }
+        }
+    }
+
+    /// The first list blocking `q`, which gets the hit counted.
+    pub fn check(&self, q: &Domain) -> Option<&BlockList> {
+        if self.allowed.matches(&q.name) {
+            return None;
+        }
+        let list = self.lists.iter().find(|l| l.blocks(&q.name))?;
+        list.hits.fetch_add(1, Ordering::Relaxed);
+        Some(list)
+    }
+}
+
+fn merge(mut into: NameTrie, from: NameTrie) -> NameTrie {
+    into.exact |= from.exact;
+    into.subtree |= from.subtree;
+    for (label, child) in from.children {
+        let node = into.children.remove(&label).unwrap_or_default();
+        into.children.insert(label, merge(node, child));
+    }
+    into
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{header::PacketId, Header};
+
+    const LIST: &str = r#"
+[Adblock Plus 2.0]
+! adblock comment
+# hosts comment
+0.0.0.0 localhost
+0.0.0.0 ads.example.com tracker.example.com # trailing
+127.0.0.1 exact.example.org
+malware.test
+||adnetwork.net^
+||cdn.adnetwork.net^$third-party
+@@||good.adnetwork.net^
+"#;
+
+    #[test]
+    fn test_trie() {
+        let mut t = NameTrie::default();
+        t.insert("Example.com.", false);
+        t.insert("ads.net", true);
+
+        assert!(t.matches("example.com"));
+        assert!(!t.matches("www.example.com"));
+        assert!(!t.matches("com"));
+        assert!(t.matches("ads.net"));
+        assert!(t.matches("x.y.ADS.net"));
+        assert!(!t.matches("notads.net"));
+    }
+
+    #[test]
+    fn test_parse_list() {
+        let list = BlockList::parse("test", LIST, Action::NxDomain);
+
+        assert!(list.blocks("ads.example.com"));
+        assert!(list.blocks("tracker.example.com"));
+        assert!(!list.blocks("sub.ads.example.com"));
+        assert!(list.blocks("exact.example.org"));
+        assert!(list.blocks("www.malware.test"));
+        assert!(list.blocks("x.adnetwork.net"));
+        assert!(!list.blocks("good.adnetwork.net"));
+        assert!(!list.blocks("localhost"));
+        assert!(!list.blocks("example.com"));
+    }
+
+    #[test]
+    fn test_blocker_check() {
+        let mut blocker = Blocker::default();
+        blocker.push(BlockList::parse("first", "a.test\n", Action::Refused));
+        blocker.push(BlockList::parse("second", "a.test\nb.test\n", Action::Null));
+        blocker.allow("ok.b.test\n||fine.a.test^\n");
+
+        let check = |n| blocker.check(&Domain::new_aa(n)).map(|l| l.name.clone());
+        assert_eq!(check("a.test").as_deref(), Some("first"));
+        assert_eq!(check("x.b.test").as_deref(), Some("second"));
+        assert_eq!(check("ok.b.test"), None);
+        assert_eq!(check("fine.a.test"), None);
+        assert_eq!(check("c.test"), None);
+
+        assert_eq!(blocker.lists[0].hits(), 1);
+        assert_eq!(blocker.lists[1].hits(), 1);
+
+        let mut reloaded = Blocker::default();
+        reloaded.push(BlockList::parse(
+            "second",
+            "b.test
+",
+            Action::Null,
+        ));
+        reloaded.push(BlockList::parse(
+            "third",
+            "c.test
+",
+            Action::Null,
+        ));
+        reloaded.carry_hits(&blocker);
+        assert!(reloaded.check(&Domain::new_aa("b.test")).is_some());
+        assert_eq!(blocker.lists[1].hits(), 2);
+        assert_eq!(reloaded.lists[1].hits(), 0);
+    }
+
+    #[test]
+    fn test_action_answer() {
+        let mut query = Message::new(Header::query(PacketId(1)));
+        query
+            .set_questions(vec![Domain::new("ads.test", Record::AAAA)])
+            .unwrap();
+
+        let res = Action::Null.answer(&query).unwrap();
+        assert_eq!(res.answers()[0].data(), &Data::Ipv6(Ipv6Addr::UNSPECIFIED));
+
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let res = Action::Address(ip).answer(&query).unwrap();
+        assert!(res.answers().is_empty());
+        assert_eq!(res.header().r_code, OpCode::no_error());
+
+        let res = Action::NxDomain.answer(&query).unwrap();
+        assert_eq!(res.header().r_code, OpCode::name_error());
+
+        assert_eq!("REFUSED".parse::<Action>().unwrap(), Action::Refused);
+        assert_eq!(
+            "::1".parse::<Action>().unwrap(),
+            Action::Address(IpAddr::V6(Ipv6Addr::LOCALHOST))
+        );
+        assert!("drop".parse::<Action>().is_err());
+    }
+}
@@ -0,0 +1,298 @@
+use crate::message::{
+    data::Data,
+    domain::{Domain, Record},
+    header::{Checking, OpCode, Truncation},
+    Message,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Longest a response is kept, whatever its TTLs say.
+const MAX_TTL: u32 = 86400;
+
+/// What a response answers: the question, and whether DNSSEC records were
+/// asked for and checking disabled, as either changes what comes back.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct Key {
+    question: Domain,
+    dnssec_ok: bool,
+    checking_disabled: bool,
+}
+
+impl Key {
+    fn of(query: &Message) -> Option<Self> {
+        let q = query.questions().first()?;
+        Some(Self {
+            question: Domain {
+                name: q.name.to_ascii_lowercase(),
+                ..q.clone()
+            },
+            dnssec_ok: query.edns().is_some_and(|e| e.dnssec_ok),
+            checking_disabled: query.header().cd == Checking::Disabled,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    res: Message,
+    stored: Instant,
+    expires: Instant,
+    /// Tells apart entries expiring at the same instant in `Entries::expiry`.
+    id: u64,
+}
+
+/// Responses by what they answer, and the same ordered by expiry so the
+/// first to go is found without a scan.
+#[derive(Debug, Default)]
+struct Entries {
+    by_key: HashMap<Key, Entry>,
+    expiry: BTreeMap<(Instant, u64), Key>,
+    next_id: u64,
+}
+
+impl Entries {
+    fn remove(&mut self, key: &Key) {
+        if let Some(entry) = self.by_key.remove(key) {
+            self.expiry.remove(&(entry.expires, entry.id));
+        }
+    }
+
+    /// Drops the response expiring first, `false` when there is none.
+    fn pop_first(&mut self) -> bool {
+        match self.expiry.pop_first() {
+            Some((_, key)) => self.by_key.remove(&key).is_some(),
+            None => false,
+        }
+    }
+
+    /// The instant the first response expires.
+    fn first_expiry(&self) -> Option<Instant> {
+        self.expiry.keys().next().map(|&(expires, _)| expires)
+    }
+}
+
+/// How long `res` may be kept: its lowest TTL when it has answers, and the
+/// SOA's negative TTL when it denies the name or type (RFC 2308 section 5).
+/// `None` for failures, truncated responses, and denials without an SOA.
+fn lifetime(res: &Message) -> Option<u32> {
+    let code = res.header().r_code;
+    if res.header().tc == Truncation::Truncated
+        || (code != OpCode::no_error() && code != OpCode::name_error())
+    {
+        return None;
+    }
+    let ttl = match res.answers().is_empty() || code == OpCode::name_error() {
+        true => res.authorities().iter().find_map(|r| match r.data() {
+            Data::Soa(soa) if r.domain().record == Record::SOA => Some(r.ttl().min(soa.minimum)),
+            _ => None,
+        })?,
+        false => res
+            .answers()
+            .iter()
+            .chain(res.authorities())
+            .chain(res.additionals())
+            .map(|r| r.ttl())
+            .min()?,
+    };
+    (ttl > 0).then_some(ttl.min(MAX_TTL))
+}
+
+/// Upstream responses, kept until their TTLs run out.
+#[derive(Debug)]
+pub struct Cache {
+    /// Most responses kept at once.
+    size: usize,
+    entries: Mutex<Entries>,
+    /// Responses dropped before expiring to make room for others.
+    evictions: AtomicU64,
+}
+
+impl Cache {
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            entries: Default::default(),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    /// The response kept for `query` at `now`, with TTLs counted down by the
+    /// time it spent here.
+    pub fn get(&self, query: &Message, now: Instant) -> Option<Message> {
+        let key = Key::of(query)?;
+        let mut entries = self.entries.lock().expect("Cache lock poisoned");
+        let entry = entries.by_key.get(&key)?;
+        if now >= entry.expires {
+            entries.remove(&key);
+            return None;
+        }
+        let elapsed = now.saturating_duration_since(entry.stored).as_secs() as u32;
+        let mut res = entry.res.clone();
+        res.records_mut()
+            .for_each(|r| r.set_ttl(r.ttl().saturating_sub(elapsed)));
+        Some(res)
+    }
+
+    /// Keeps `res` to `query` from `now`, if it may be kept. When full, expired
+    /// responses make room, or else the one expiring first.
+    pub fn insert(&self, query: &Message, res: &Message, now: Instant) {
+        let (Some(key), Some(ttl)) = (Key::of(query), lifetime(res)) else {
+            return;
+        };
+        if self.size == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().expect("Cache lock poisoned");
+        entries.remove(&key);
+        while entries.by_key.len() >= self.size && entries.first_expiry() <= Some(now) {
+            entries.pop_first();
+        }
+        if entries.by_key.len() >= self.size && entries.pop_first() {
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+        let entry = Entry {
+            res: res.clone(),
+            stored: now,
+            expires: now + Duration::from_secs(ttl as u64),
+            id: entries.next_id,
+        };
+        entries.next_id += 1;
+        entries
+            .expiry
+            .insert((entry.expires, entry.id), key.clone());
+        entries.by_key.insert(key, entry);
+    }
+
+    /// Responses kept, some of which may have expired.
+    pub fn len(&self) -> usize {
+        self.entries
+            .lock()
+            .expect("Cache lock poisoned")
+            .by_key
+            .len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+
+    /// Forgets every response, as when the upstreams they came from change.
+    pub fn clear(&self) {
+        *self.entries.lock().expect("Cache lock poisoned") = Entries::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{data::Soa, edns::Edns, header::PacketId, route::Route, Header};
+    use std::net::Ipv4Addr;
+
+    fn query(name: &str) -> Message {
+        let mut query = Message::new(Header::query(PacketId(1)));
+        query.set_questions(vec![Domain::new_aa(name)]).unwrap();
+        query
+    }
+
+    fn answer(query: &Message, ttl: u32) -> Message {
+        let mut res = Message::new_response(query);
+        let a = Route::new(
+            query.questions()[0].clone(),
+            ttl,
+            Data::Ipv4(Ipv4Addr::LOCALHOST),
+        );
+        res.set_answers(vec![a]).unwrap();
+        res
+    }
+
+    #[test]
+    fn test_get_counts_down() {
+        let cache = Cache::new(10);
+        let now = Instant::now();
+        let q = query("hernan.rs");
+        cache.insert(&q, &answer(&q, 60), now);
+
+        let res = cache.get(&query("HERNAN.rs"), now + Duration::from_secs(20));
+        assert_eq!(res.unwrap().answers()[0].ttl(), 40);
+        assert!(cache.get(&q, now + Duration::from_secs(60)).is_none());
+        assert!(cache.is_empty());
+
+        let mut dnssec = q.clone();
+        dnssec.set_edns(Some(Edns::new(true))).unwrap();
+        cache.insert(&q, &answer(&q, 60), now);
+        assert!(cache.get(&dnssec, now).is_none());
+    }
+
+    #[test]
+    fn test_lifetime() {
+        let q = query("hernan.rs");
+        assert_eq!(lifetime(&answer(&q, 300)), Some(300));
+        assert_eq!(lifetime(&answer(&q, 0)), None);
+        assert_eq!(lifetime(&answer(&q, 1 << 30)), Some(MAX_TTL));
+
+        let mut res = Message::new_response(&q);
+        res.set_r_code(OpCode::name_error());
+        assert_eq!(lifetime(&res), None);
+        let soa = Soa {
+            mname: "ns.hernan.rs".to_string(),
+            rname: "admin.hernan.rs".to_string(),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 30,
+        };
+        let soa = Route::new(Domain::new("hernan.rs", Record::SOA), 3600, Data::Soa(soa));
+        res.set_authorities(vec![soa]).unwrap();
+        assert_eq!(lifetime(&res), Some(30));
+
+        res.set_r_code(OpCode::server_failure());
+        assert_eq!(lifetime(&res), None);
+    }
+
+    #[test]
+    fn test_insert_makes_room() {
+        let cache = Cache::new(2);
+        let now = Instant::now();
+        for (name, ttl) in [
+            ("a.hernan.rs", 30),
+            ("b.hernan.rs", 10),
+            ("c.hernan.rs", 20),
+        ] {
+            let q = query(name);
+            cache.insert(&q, &answer(&q, ttl), now);
+        }
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.evictions(), 1);
+        assert!(cache.get(&query("b.hernan.rs"), now).is_none());
+        assert!(cache.get(&query("c.hernan.rs"), now).is_some());
+
+        // Expired responses make room first, and storing one again moves it.
+        let later = now + Duration::from_secs(25);
+        for name in ["d.hernan.rs", "a.hernan.rs"] {
+            let q = query(name);
+            cache.insert(&q, &answer(&q, 30), later);
+        }
+        assert_eq!(cache.evictions(), 1);
+        let q = query("e.hernan.rs");
+        cache.insert(&q, &answer(&q, 30), later);
+        assert_eq!(cache.evictions(), 2);
+        assert!(cache.get(&query("d.hernan.rs"), later).is_none());
+        assert!(cache.get(&query("a.hernan.rs"), later).is_some());
+        assert_eq!(cache.entries.lock().unwrap().expiry.len(), 2);
+
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+}
@@ -0,0 +1,133 @@
+use anyhow::{Context, Result};
+use std::{fmt, net::IpAddr, str::FromStr};
+
+/// An IPv4 or IPv6 network, written `address/prefix`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+fn bits(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u32::from(ip) as u128,
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+fn width(ip: IpAddr) -> u8 {
+    match ip {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+impl Cidr {
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Self> {
+        anyhow::ensure!(prefix <= width(addr), "Prefix too long: {addr}/{prefix}");
+        Ok(Self { addr, prefix })
+    }
+
+    /// The network of `prefix` bits holding `addr`.
+    pub fn network(addr: IpAddr, prefix: u8) -> Self {
+        let prefix = prefix.min(width(addr));
+        let shift = (width(addr) - prefix) as u32;
+        let masked = bits(addr) & u128::MAX.checked_shl(shift).unwrap_or(0);
+        let addr = match addr {
+            IpAddr::V4(_) => IpAddr::V4((masked as u32).into()),
+            IpAddr::V6(_) => IpAddr::V6(masked.into()),
+        };
+        Self { addr, prefix }
+    }
+
+    /// The network of a single address.
+    pub fn host(addr: IpAddr) -> Self {
+        Self {
+            addr,
+            prefix: width(addr),
+        }
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        if self.addr.is_ipv4() != ip.is_ipv4() {
+            return false;
+        }
+        let shift = (width(ip) - self.prefix) as u32;
+        let mask = u128::MAX.checked_shl(shift).unwrap_or(0);
+        bits(self.addr) & mask == bits(ip) & mask
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let context = || format!("Not a network: {s}");
+        let addr: IpAddr = addr.parse().with_context(context)?;
+        match prefix {
+            Some(p) => Self::new(addr, p.parse().with_context(context)?),
+            None => Ok(Self::host(addr)),
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_contains() {
+        let net: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains("10.1.200.3".parse().unwrap()));
+        assert!(!net.contains("10.2.0.1".parse().unwrap()));
+        assert!(!net.contains("::1".parse().unwrap()));
+
+        let all: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains("192.168.1.1".parse().unwrap()));
+
+        let net: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(net.contains("2001:db8:1::1".parse().unwrap()));
+        assert!(!net.contains("2001:db9::1".parse().unwrap()));
+
+        let host: Cidr = "::1".parse().unwrap();
+        assert_eq!(host.prefix(), 128);
+        assert!(host.contains("::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_network() {
+        let ip = "10.1.2.3".parse().unwrap();
+        assert_eq!(Cidr::network(ip, 24).to_string(), "10.1.2.0/24");
+        assert_eq!(Cidr::network(ip, 40).to_string(), "10.1.2.3/32");
+        assert_eq!(Cidr::network(ip, 0).to_string(), "0.0.0.0/0");
+        let ip = "2001:db8:aa:bb::1".parse().unwrap();
+        assert_eq!(Cidr::network(ip, 56).to_string(), "2001:db8:aa::/56");
+        assert_eq!(
+            Cidr::network(ip, 56),
+            Cidr::network("2001:db8:aa:ff::".parse().unwrap(), 56)
+        );
+    }
+
+    #[test]
+    fn test_from_str() {
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert_eq!(
+            "10.0.0.0/8".parse::<Cidr>().unwrap().to_string(),
+            "10.0.0.0/8"
+        );
+    }
+}
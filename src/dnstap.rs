@@ -0,0 +1,443 @@
+use anyhow::{Context, Result};
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    net::{IpAddr, SocketAddr},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Messages waiting to be written; more are dropped rather than slowing
+/// serving down.
+const BUFFER: usize = 4096;
+/// Content type of dnstap Frame Streams.
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+/// Least time between attempts to reconnect to the collector.
+const RECONNECT: Duration = Duration::from_secs(1);
+
+/// Frame Streams control frame types.
+const ACCEPT: u32 = 1;
+const START: u32 = 2;
+const STOP: u32 = 3;
+const READY: u32 = 4;
+const FINISH: u32 = 5;
+const FIELD_CONTENT_TYPE: u32 = 1;
+
+/// Type of a dnstap message.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    ClientQuery = 5,
+    ClientResponse = 6,
+    ForwarderQuery = 7,
+    ForwarderResponse = 8,
+}
+
+/// Transport a message went over.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    Udp = 1,
+    Tcp = 2,
+    Doh = 4,
+}
+
+/// A message seen by the server.
+#[derive(Clone, Debug)]
+pub struct Event {
+    pub kind: Kind,
+    pub protocol: Protocol,
+    /// Client of client messages, upstream of forwarder ones.
+    pub peer: Option<SocketAddr>,
+    pub wire: Vec<u8>,
+    pub at: SystemTime,
+}
+
+fn varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+fn field_varint(buf: &mut Vec<u8>, field: u64, v: u64) {
+    varint(buf, field << 3);
+    varint(buf, v);
+}
+
+fn field_bytes(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    varint(buf, field << 3 | 2);
+    varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn field_fixed32(buf: &mut Vec<u8>, field: u64, v: u32) {
+    varint(buf, field << 3 | 5);
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+impl Event {
+    /// This event as a `dnstap.Dnstap` protobuf message.
+    pub fn encode(&self) -> Vec<u8> {
+        let query = matches!(self.kind, Kind::ClientQuery | Kind::ForwarderQuery);
+        let client = matches!(self.kind, Kind::ClientQuery | Kind::ClientResponse);
+        let at = self.at.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+        let mut msg = vec![];
+        field_varint(&mut msg, 1, self.kind as u64);
+        if let Some(peer) = self.peer {
+            let (family, addr) = match peer.ip() {
+                IpAddr::V4(ip) => (1, ip.octets().to_vec()),
+                IpAddr::V6(ip) => (2, ip.octets().to_vec()),
+            };
+            field_varint(&mut msg, 2, family);
+            field_varint(&mut msg, 3, self.protocol as u64);
+            let (addr_field, port_field) = match client {
+                true => (4, 6),
+                false => (5, 7),
+            };
+            field_bytes(&mut msg, addr_field, &addr);
+            field_varint(&mut msg, port_field, peer.port() as u64);
+        } else {
+            field_varint(&mut msg, 3, self.protocol as u64);
+        }
+        let (sec, nsec, wire) = match query {
+            true => (8, 9, 10),
+            false => (12, 13, 14),
+        };
+        field_varint(&mut msg, sec, at.as_secs());
+        field_fixed32(&mut msg, nsec, at.subsec_nanos());
+        field_bytes(&mut msg, wire, &self.wire);
+
+        let mut dnstap = vec![];
+        let version = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+        field_bytes(&mut dnstap, 2, version.as_bytes());
+        field_bytes(&mut dnstap, 14, &msg);
+        field_varint(&mut dnstap, 15, 1);
+        dnstap
+    }
+}
+
+/// A Frame Streams data frame.
+fn data_frame(payload: &[u8]) -> Vec<u8> {
+    [&(payload.len() as u32).to_be_bytes()[..], payload].concat()
+}
+
+/// A Frame Streams control frame, with the dnstap content type unless it
+/// is STOP or FINISH.
+fn control_frame(kind: u32) -> Vec<u8> {
+    let mut frame = kind.to_be_bytes().to_vec();
+    if kind != STOP && kind != FINISH {
+        frame.extend_from_slice(&FIELD_CONTENT_TYPE.to_be_bytes());
+        frame.extend_from_slice(&(CONTENT_TYPE.len() as u32).to_be_bytes());
+        frame.extend_from_slice(CONTENT_TYPE);
+    }
+    [&[0; 4][..], &(frame.len() as u32).to_be_bytes(), &frame].concat()
+}
+
+/// Reads a control frame, returning its type.
+fn read_control(r: &mut impl Read) -> io::Result<u32> {
+    let mut word = [0; 4];
+    r.read_exact(&mut word)?;
+    if word != [0; 4] {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Expected a control frame",
+        ));
+    }
+    r.read_exact(&mut word)?;
+    let mut frame = vec![0; u32::from_be_bytes(word) as usize];
+    r.read_exact(&mut frame)?;
+    match frame.get(..4) {
+        Some(kind) => Ok(u32::from_be_bytes([kind[0], kind[1], kind[2], kind[3]])),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Control frame too short",
+        )),
+    }
+}
+
+/// Where dnstap messages go.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Output {
+    /// Written to a file, replacing it, as a unidirectional stream.
+    File(PathBuf),
+    /// Sent to a collector listening on a Unix socket, as a bidirectional
+    /// stream.
+    Unix(PathBuf),
+}
+
+impl FromStr for Output {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            Some(("file", path)) if !path.is_empty() => Ok(Self::File(path.into())),
+            Some(("unix", path)) if !path.is_empty() => Ok(Self::Unix(path.into())),
+            _ => anyhow::bail!("Invalid dnstap output, expected file:PATH or unix:PATH: {s}"),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Writer {
+    File(File),
+    Unix(UnixStream),
+}
+
+impl Output {
+    /// Opens the stream, handshaking with the collector if any.
+    fn open(&self) -> Result<Writer> {
+        match self {
+            Self::File(path) => {
+                let mut file = File::create(path)
+                    .with_context(|| format!("Could not open dnstap file {}", path.display()))?;
+                file.write_all(&control_frame(START))?;
+                Ok(Writer::File(file))
+            }
+            Self::Unix(path) => {
+                let mut stream = UnixStream::connect(path).with_context(|| {
+                    format!("Could not connect to dnstap socket {}", path.display())
+                })?;
+                stream.set_read_timeout(Some(RECONNECT))?;
+                stream.write_all(&control_frame(READY))?;
+                let kind = read_control(&mut stream)?;
+                anyhow::ensure!(kind == ACCEPT, "dnstap collector answered {kind}");
+                stream.write_all(&control_frame(START))?;
+                Ok(Writer::Unix(stream))
+            }
+        }
+    }
+}
+
+impl Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Self::File(file) => file.write_all(buf),
+            Self::Unix(stream) => stream.write_all(buf),
+        }
+    }
+
+    /// Ends the stream, waiting for the collector to acknowledge it.
+    fn finish(mut self) -> io::Result<()> {
+        self.write(&control_frame(STOP))?;
+        if let Self::Unix(stream) = &mut self {
+            read_control(stream)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes `events` to `output` until every sender is gone, reconnecting
+/// when the stream breaks.
+fn run(output: Output, events: Receiver<Event>, dropped: &AtomicU64) {
+    let mut writer = None;
+    let mut next_attempt = Instant::now();
+    for event in events {
+        if writer.is_none() && Instant::now() >= next_attempt {
+            next_attempt = Instant::now() + RECONNECT;
+            match output.open() {
+                Ok(w) => writer = Some(w),
+                Err(e) => println!("dnstap output unavailable: {e}"),
+            }
+        }
+        let Some(w) = writer.as_mut() else {
+            dropped.fetch_add(1, Ordering::Relaxed);
+            continue;
+        };
+        if let Err(e) = w.write(&data_frame(&event.encode())) {
+            println!("dnstap output failed: {e}");
+            writer = None;
+        }
+        let lost = dropped.swap(0, Ordering::Relaxed);
+        if lost > 0 {
+            println!("dnstap dropped {lost} messages");
+        }
+    }
+    if let Some(w) = writer {
+        if let Err(e) = w.finish() {
+            println!("Could not close dnstap output: {e}");
+        }
+    }
+}
+
+/// Sends dnstap messages to a writer thread, never waiting for it.
+#[derive(Debug)]
+pub struct Dnstap {
+    /// Gone once closed.
+    events: Mutex<Option<SyncSender<Event>>>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Dnstap {
+    pub fn new(output: Output) -> Self {
+        let (events, receiver) = mpsc::sync_channel(BUFFER);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let lost = dropped.clone();
+        let writer = thread::spawn(move || run(output, receiver, &lost));
+        Self {
+            events: Mutex::new(Some(events)),
+            writer: Mutex::new(Some(writer)),
+            dropped,
+        }
+    }
+
+    /// Queues `event`, dropping it when the writer falls behind.
+    pub fn log(&self, event: Event) {
+        let events = self.events.lock().expect("dnstap lock poisoned");
+        let Some(events) = events.as_ref() else {
+            return;
+        };
+        match events.try_send(event) {
+            Ok(()) | Err(TrySendError::Disconnected(_)) => {}
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Writes out what is queued and ends the stream; later messages are
+    /// dropped.
+    pub fn close(&self) {
+        drop(self.events.lock().expect("dnstap lock poisoned").take());
+        if let Some(writer) = self.writer.lock().expect("dnstap lock poisoned").take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, os::unix::net::UnixListener};
+
+    fn event() -> Event {
+        Event {
+            kind: Kind::ClientQuery,
+            protocol: Protocol::Udp,
+            peer: Some("10.0.0.1:5353".parse().unwrap()),
+            wire: vec![0xab; 3],
+            at: UNIX_EPOCH + Duration::new(300, 7),
+        }
+    }
+
+    /// Data frames of a stream, after checking that it starts with a
+    /// control frame.
+    fn frames(mut r: impl Read) -> Vec<Vec<u8>> {
+        assert_eq!(read_control(&mut r).unwrap(), START);
+        let mut frames = vec![];
+        loop {
+            let mut len = [0; 4];
+            r.read_exact(&mut len).unwrap();
+            let len = u32::from_be_bytes(len) as usize;
+            if len == 0 {
+                break;
+            }
+            let mut frame = vec![0; len];
+            r.read_exact(&mut frame).unwrap();
+            frames.push(frame);
+        }
+        frames
+    }
+
+    #[test]
+    fn test_varint() {
+        let mut buf = vec![];
+        varint(&mut buf, 300);
+        assert_eq!(buf, [0xac, 0x02]);
+    }
+
+    #[test]
+    fn test_encode() {
+        let buf = event().encode();
+        let msg = [
+            &[
+                0x08, 5, 0x10, 1, 0x18, 1, 0x22, 4, 10, 0, 0, 1, 0x30, 0xe9, 0x29,
+            ][..],
+            &[
+                0x40, 0xac, 0x02, 0x4d, 7, 0, 0, 0, 0x52, 3, 0xab, 0xab, 0xab,
+            ],
+        ]
+        .concat();
+        let tail = [&[0x72, msg.len() as u8][..], &msg, &[0x78, 1]].concat();
+        assert!(buf.ends_with(&tail));
+        assert_eq!(buf[0], 0x12);
+
+        let res = Event {
+            kind: Kind::ForwarderResponse,
+            peer: None,
+            ..event()
+        };
+        let msg = [
+            &[0x08, 8, 0x18, 1, 0x60, 0xac, 0x02, 0x6d, 7, 0, 0, 0][..],
+            &[0x72, 3, 0xab, 0xab, 0xab],
+        ]
+        .concat();
+        assert!(res
+            .encode()
+            .ends_with(&[&[0x72, 17][..], &msg, &[0x78, 1]].concat()));
+    }
+
+    #[test]
+    fn test_output() {
+        assert_eq!(
+            "file:/tmp/x".parse::<Output>().unwrap(),
+            Output::File("/tmp/x".into())
+        );
+        assert!("tcp:/tmp/x".parse::<Output>().is_err());
+        assert!("unix:".parse::<Output>().is_err());
+    }
+
+    #[test]
+    fn test_file() {
+        let path = std::env::temp_dir().join(format!("dnstap-{}.fstrm", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let (events, receiver) = mpsc::sync_channel(4);
+        events.send(event()).unwrap();
+        events.send(event()).unwrap();
+        drop(events);
+        run(Output::File(path.clone()), receiver, &AtomicU64::new(0));
+
+        let buf = fs::read(&path).unwrap();
+        assert_eq!(frames(&buf[..]), [event().encode(), event().encode()]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_unix() {
+        let path = std::env::temp_dir().join(format!("dnstap-{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let collector = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            assert_eq!(read_control(&mut stream).unwrap(), READY);
+            stream.write_all(&control_frame(ACCEPT)).unwrap();
+            let frames = frames(&mut stream);
+            let mut rest = [0; 8];
+            stream.read_exact(&mut rest).unwrap();
+            stream.write_all(&control_frame(FINISH)).unwrap();
+            frames
+        });
+
+        let dnstap = Dnstap::new(Output::Unix(path.clone()));
+        dnstap.log(Event {
+            kind: Kind::ClientResponse,
+            protocol: Protocol::Tcp,
+            peer: None,
+            wire: vec![1, 2],
+            at: SystemTime::now(),
+        });
+        dnstap.close();
+        let frames = collector.join().unwrap();
+        assert_eq!(frames.len(), 1);
+        assert!(frames[0].windows(4).any(|w| w == [0x72, 2, 1, 2]));
+        fs::remove_file(&path).unwrap();
+    }
+}
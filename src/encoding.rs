@@ -0,0 +1,155 @@
+use anyhow::{Context, Result};
+use std::fmt::Write;
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const BASE64URL: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+const BASE32HEX: &[u8; 32] = b"0123456789ABCDEFGHIJKLMNOPQRSTUV";
+
+/// Decodes `s` written with `alphabet`, `bits` per character. Trailing
+/// padding is ignored, as are leftover bits too few to make a byte.
+fn decode(s: &str, alphabet: &[u8], bits: u32) -> Result<Vec<u8>> {
+    let s = s.trim_end_matches('=');
+    let mut out = Vec::with_capacity(s.len() * bits as usize / 8);
+    let (mut acc, mut held) = (0u32, 0);
+    for c in s.bytes() {
+        let v = alphabet
+            .iter()
+            .position(|a| *a == c)
+            .with_context(|| format!("Invalid character: {}", c as char))?;
+        acc = (acc << bits) | v as u32;
+        held += bits;
+        if held >= 8 {
+            held -= 8;
+            out.push((acc >> held) as u8);
+        }
+    }
+    anyhow::ensure!(held < bits, "Truncated data: {s}");
+    Ok(out)
+}
+
+/// Decodes base64 (RFC 4648 section 4), as DNSKEY and RRSIG records carry
+/// keys and signatures.
+pub fn decode_base64(s: &str) -> Result<Vec<u8>> {
+    decode(s, BASE64, 6)
+}
+
+/// Decodes unpadded base64url (RFC 4648 section 5), as DoH GET requests carry
+/// their query. Trailing padding is tolerated.
+pub fn decode_base64url(s: &str) -> Result<Vec<u8>> {
+    decode(s, BASE64URL, 6)
+}
+
+/// Decodes base32hex (RFC 4648 section 7), as NSEC3 records carry hashed names.
+pub fn decode_base32hex(s: &str) -> Result<Vec<u8>> {
+    decode(&s.to_ascii_uppercase(), BASE32HEX, 5)
+}
+
+/// Encodes `bytes` with `alphabet`, `bits` per character and no padding.
+fn encode(bytes: &[u8], alphabet: &[u8], bits: u32) -> String {
+    let mut out = String::with_capacity(bytes.len() * 8 / bits as usize + 1);
+    let (mut acc, mut held) = (0u32, 0);
+    for b in bytes {
+        acc = (acc << 8) | *b as u32;
+        held += 8;
+        while held >= bits {
+            held -= bits;
+            out.push(alphabet[(acc >> held) as usize & ((1 << bits) - 1)] as char);
+        }
+    }
+    if held > 0 {
+        out.push(alphabet[(acc << (bits - held)) as usize & ((1 << bits) - 1)] as char);
+    }
+    out
+}
+
+/// Encodes base32hex without padding, lowercase as NSEC3 owner names are.
+pub fn encode_base32hex(bytes: &[u8]) -> String {
+    encode(bytes, BASE32HEX, 5).to_ascii_lowercase()
+}
+
+/// Encodes padded base64, as DNSKEY records are written.
+pub fn encode_base64(bytes: &[u8]) -> String {
+    let out = encode(bytes, BASE64, 6);
+    let padding = (4 - out.len() % 4) % 4;
+    out + &"=".repeat(padding)
+}
+
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut out, b| {
+        let _ = write!(out, "{b:02X}");
+        out
+    })
+}
+
+pub fn decode_hex(s: &str) -> Result<Vec<u8>> {
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair)?;
+            anyhow::ensure!(pair.len() == 2, "Odd number of hex digits: {s}");
+            u8::from_str_radix(pair, 16).context("Invalid hex digit")
+        })
+        .collect()
+}
+
+/// `s` as a JSON string.
+pub fn json_string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string("a\"b\\c\n"), "\"a\\\"b\\\\c\\u000a\"");
+    }
+
+    #[test]
+    fn test_decode_base64() {
+        assert_eq!(decode_base64("aGk=").unwrap(), b"hi");
+        assert_eq!(decode_base64("+/8=").unwrap(), vec![0xfb, 0xff]);
+        assert!(decode_base64("-_8").is_err());
+
+        assert_eq!(decode_base64url("aGk").unwrap(), b"hi");
+        assert_eq!(decode_base64url("-_8").unwrap(), vec![0xfb, 0xff]);
+        assert!(decode_base64url("a").is_err());
+        assert!(decode_base64url("a+b/").is_err());
+    }
+
+    #[test]
+    fn test_decode_base32hex() {
+        assert_eq!(decode_base32hex("CPNMUOG").unwrap(), b"foob");
+        assert_eq!(decode_base32hex("cpnmuog=").unwrap(), b"foob");
+        assert!(decode_base32hex("W").is_err());
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode_base32hex(b"foob"), "cpnmuog");
+        assert_eq!(encode_base32hex(b""), "");
+        assert_eq!(encode_base64(b"hi"), "aGk=");
+        assert_eq!(encode_base64(&[0xfb, 0xff, 0]), "+/8A");
+        assert_eq!(encode_hex(&[0, 255, 16]), "00FF10");
+    }
+
+    #[test]
+    fn test_decode_hex() {
+        assert_eq!(decode_hex("00ff10").unwrap(), vec![0, 255, 16]);
+        assert!(decode_hex("0").is_err());
+        assert!(decode_hex("zz").is_err());
+    }
+}
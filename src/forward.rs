@@ -0,0 +1,426 @@
+use crate::{
+    http::doh::DohStream,
+    message::{domain::in_zone, header::Truncation, Message},
+    querylog,
+    socket::{tcp::DnsStream, DnsSocket, RetryPolicy, SocketError},
+};
+use anyhow::{Context, Result};
+use std::{
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// Idle connections kept open to each upstream.
+const MAX_IDLE: usize = 4;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    /// DNS over HTTP (RFC 8484) to `path`, the server being named `host`.
+    Http {
+        host: String,
+        path: String,
+    },
+}
+
+/// A connection kept open to an upstream between queries.
+#[derive(Debug)]
+enum Connection {
+    Tcp(DnsStream),
+    Http(DohStream),
+}
+
+impl Connection {
+    fn query(&mut self, m: &Message) -> Result<Message> {
+        match self {
+            Self::Tcp(stream) => stream.query(m),
+            Self::Http(stream) => stream.query(m),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Upstream {
+    pub addr: String,
+    pub transport: Transport,
+    idle: Arc<Mutex<Vec<Connection>>>,
+}
+
+/// Splits `host[:port]/path` into the address to connect to, the host name
+/// and the path.
+fn parse_url(rest: &str, port: u16) -> Result<(String, String, String)> {
+    let (authority, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/dns-query"),
+    };
+    anyhow::ensure!(!authority.is_empty(), "Missing upstream host");
+    let host = match authority.rsplit_once(':') {
+        Some((host, p)) if p.parse::<u16>().is_ok() && !host.ends_with(':') => host,
+        _ => authority,
+    };
+    let addr = match host == authority {
+        true => format!("{authority}:{port}"),
+        false => authority.to_string(),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    Ok((addr, host.to_string(), path.to_string()))
+}
+
+impl FromStr for Upstream {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let (transport, addr) = match s.split_once("://") {
+            Some(("udp", addr)) => (Transport::Udp, addr.to_string()),
+            Some(("tcp", addr)) => (Transport::Tcp, addr.to_string()),
+            Some(("http", rest)) => {
+                let (addr, host, path) = parse_url(rest, 80)?;
+                (Transport::Http { host, path }, addr)
+            }
+            Some(("tls" | "https", _)) => anyhow::bail!(
+                "Encrypted upstream {s} needs TLS support, which this build lacks; \
+                 use tcp:// or http:// through a local TLS proxy (see docs/scope.md)"
+            ),
+            Some((scheme, _)) => anyhow::bail!("Unsupported upstream transport: {scheme}"),
+            None => (Transport::Udp, s.to_string()),
+        };
+        anyhow::ensure!(!addr.is_empty(), "Missing upstream address");
+        Ok(Self {
+            addr,
+            transport,
+            idle: Arc::default(),
+        })
+    }
+}
+
+impl Upstream {
+    pub fn query(&self, m: &Message, policy: RetryPolicy) -> Result<Message> {
+        match &self.transport {
+            Transport::Udp => {
+                let res = DnsSocket::connect(&self.addr)?
+                    .with_policy(policy)
+                    .query(m)?;
+                match res.header().tc {
+                    // Asked again over TCP, as RFC 7766 has it.
+                    Truncation::Truncated => self.query_connected(m, policy),
+                    Truncation::Complete => Ok(res),
+                }
+            }
+            _ => self.query_connected(m, policy),
+        }
+    }
+
+    fn connect(&self, policy: RetryPolicy) -> Result<Connection> {
+        Ok(match &self.transport {
+            Transport::Http { host, path } => {
+                Connection::Http(DohStream::connect(&self.addr, host, path, policy)?)
+            }
+            _ => Connection::Tcp(DnsStream::connect(&self.addr, policy)?),
+        })
+    }
+
+    /// Queries over an idle connection when there is one, opening a new one
+    /// when there is none or the upstream closed it in the meantime.
+    fn query_connected(&self, m: &Message, policy: RetryPolicy) -> Result<Message> {
+        let idle = self
+            .idle
+            .lock()
+            .expect("Upstream connections lock poisoned")
+            .pop();
+        let mut reused = None;
+        if let Some(mut conn) = idle {
+            match conn.query(m) {
+                Err(e) if !e.is::<SocketError>() => {}
+                res => reused = Some((conn, res)),
+            }
+        }
+        let (conn, res) = match reused {
+            Some(reused) => reused,
+            None => {
+                let mut conn = self.connect(policy)?;
+                let res = conn.query(m);
+                (conn, res)
+            }
+        };
+
+        if res.is_ok() {
+            let mut idle = self
+                .idle
+                .lock()
+                .expect("Upstream connections lock poisoned");
+            if idle.len() < MAX_IDLE {
+                idle.push(conn);
+            }
+        }
+        res
+    }
+}
+
+/// Upstreams in charge of every name under `suffix`.
+#[derive(Clone, Debug)]
+pub struct Rule {
+    pub suffix: String,
+    pub upstreams: Vec<Upstream>,
+    pub policy: RetryPolicy,
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_start_matches("*.")
+        .trim_end_matches('.')
+        .to_ascii_lowercase()
+}
+
+impl Rule {
+    pub fn new(suffix: &str, upstreams: Vec<Upstream>, policy: RetryPolicy) -> Self {
+        Self {
+            suffix: normalize(suffix),
+            upstreams,
+            policy,
+        }
+    }
+
+    /// Reads a rule as given on the command line: `suffix=upstream[,upstream...][@timeout_ms]`.
+    pub fn parse(spec: &str, policy: RetryPolicy) -> Result<Self> {
+        let (suffix, upstreams) = spec
+            .split_once('=')
+            .with_context(|| format!("Missing upstreams in forward rule: {spec}"))?;
+
+        let mut policy = policy;
+        let upstreams = match upstreams.rsplit_once('@') {
+            Some((upstreams, ms)) => {
+                let ms = ms
+                    .parse()
+                    .with_context(|| format!("Invalid forward timeout: {ms}"))?;
+                policy.timeout = Duration::from_millis(ms);
+                upstreams
+            }
+            None => upstreams,
+        };
+
+        let upstreams = upstreams
+            .split(',')
+            .map(Upstream::from_str)
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self::new(suffix, upstreams, policy))
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        in_zone(name, &self.suffix)
+    }
+
+    /// Asks each upstream in turn until one of them answers, returning its
+    /// response and which one it was.
+    pub fn query(&self, m: &Message) -> Result<(Message, &Upstream)> {
+        let mut err = anyhow::anyhow!("No upstreams for {}", self.suffix);
+        for upstream in self.upstreams.iter() {
+            querylog::record(|s| s.upstream = Some(upstream.addr.clone()));
+            match upstream.query(m, self.policy) {
+                Ok(res) => return Ok((res, upstream)),
+                Err(e) => {
+                    println!("Upstream {} failed: {e}", upstream.addr);
+                    let timeout = e.is::<SocketError>();
+                    querylog::record(|s| s.failures.push((upstream.addr.clone(), timeout)));
+                    err = e
+                }
+            }
+        }
+        Err(err)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ForwardTable {
+    rules: Vec<Rule>,
+}
+
+impl ForwardTable {
+    /// Adds `rule`, replacing any previous one for the same suffix.
+    pub fn insert(&mut self, rule: Rule) {
+        self.rules.retain(|r| r.suffix != rule.suffix);
+        self.rules.push(rule);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Whether `other` sends every name to the same upstreams.
+    pub fn same_upstreams(&self, other: &Self) -> bool {
+        let upstreams = |r: &Rule| {
+            r.upstreams
+                .iter()
+                .map(|u| (u.addr.clone(), u.transport.clone()))
+                .collect::<Vec<_>>()
+        };
+        self.rules.len() == other.rules.len()
+            && self.rules.iter().all(|r| {
+                other
+                    .rules
+                    .iter()
+                    .any(|o| o.suffix == r.suffix && upstreams(o) == upstreams(r))
+            })
+    }
+
+    /// The rule with the longest suffix covering `name`.
+    pub fn route(&self, name: &str) -> Option<&Rule> {
+        self.rules
+            .iter()
+            .filter(|r| r.matches(name))
+            .max_by_key(|r| r.suffix.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        message::{domain::Domain, header::PacketId, Header},
+        socket::tcp::{DnsListener, ListenPolicy},
+    };
+    use std::{collections::HashSet, net::UdpSocket, thread};
+
+    #[test]
+    fn test_upstream_from_str() {
+        let u: Upstream = "1.1.1.1:53".parse().unwrap();
+        assert_eq!(u.transport, Transport::Udp);
+        assert_eq!(u.addr, "1.1.1.1:53");
+
+        let u: Upstream = "tcp://10.0.0.53:53".parse().unwrap();
+        assert_eq!(u.transport, Transport::Tcp);
+        assert_eq!(u.addr, "10.0.0.53:53");
+
+        assert!("quic://10.0.0.53:53".parse::<Upstream>().is_err());
+        assert!("tls://1.1.1.1#cloudflare-dns.com"
+            .parse::<Upstream>()
+            .is_err());
+        assert!("https://dns.google/dns-query".parse::<Upstream>().is_err());
+    }
+
+    #[test]
+    fn test_upstream_http() {
+        let u: Upstream = "http://doh.local/resolve".parse().unwrap();
+        assert_eq!(u.addr, "doh.local:80");
+        let path = "/resolve".to_string();
+        let host = "doh.local".to_string();
+        assert_eq!(u.transport, Transport::Http { host, path });
+
+        let u: Upstream = "http://[::1]:8053".parse().unwrap();
+        assert_eq!(u.addr, "[::1]:8053");
+        let path = "/dns-query".to_string();
+        let host = "::1".to_string();
+        assert_eq!(u.transport, Transport::Http { host, path });
+
+        assert!("http:///dns-query".parse::<Upstream>().is_err());
+    }
+
+    #[test]
+    fn test_upstream_reuses_connections() {
+        let policy = ListenPolicy {
+            idle: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let listener = DnsListener::bind("127.0.0.1:0", policy).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peers = Arc::new(Mutex::new(HashSet::new()));
+        let seen = peers.clone();
+        thread::spawn(move || {
+            listener.serve(move |q, peer| {
+                seen.lock().unwrap().insert(peer);
+                Some(Message::new_response(q))
+            })
+        });
+
+        let mut query = Message::new(Header::query(PacketId(9)));
+        query
+            .set_questions(vec![Domain::new_aa("hernan.rs")])
+            .unwrap();
+        let upstream: Upstream = format!("tcp://{addr}").parse().unwrap();
+        for _ in 0..3 {
+            assert!(upstream.query(&query, RetryPolicy::default()).is_ok());
+        }
+        assert_eq!(peers.lock().unwrap().len(), 1);
+
+        thread::sleep(Duration::from_millis(400));
+        assert!(upstream.query(&query, RetryPolicy::default()).is_ok());
+        assert_eq!(peers.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_upstream_truncated() {
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = udp.local_addr().unwrap();
+        let listener = DnsListener::bind(&addr.to_string(), ListenPolicy::default()).unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (size, from) = udp.recv_from(&mut buf).unwrap();
+            let mut res = Message::new_response(&Message::try_from(&buf[..size]).unwrap());
+            res.set_tc(Truncation::Truncated);
+            udp.send_to(&res.flush(), from).unwrap();
+        });
+        thread::spawn(move || listener.serve(|q, _| Some(Message::new_response(q))));
+
+        let mut query = Message::new(Header::query(PacketId(9)));
+        query
+            .set_questions(vec![Domain::new_aa("hernan.rs")])
+            .unwrap();
+        let upstream: Upstream = addr.to_string().parse().unwrap();
+        let res = upstream.query(&query, RetryPolicy::default()).unwrap();
+        assert_eq!(res.header().tc, Truncation::Complete);
+    }
+
+    #[test]
+    fn test_rule_parse() {
+        let spec = "*.svc.cluster.local=10.96.0.10:53,tcp://10.96.0.11:53@250";
+        let rule = Rule::parse(spec, RetryPolicy::default()).unwrap();
+
+        assert_eq!(rule.suffix, "svc.cluster.local");
+        assert_eq!(rule.upstreams.len(), 2);
+        assert_eq!(rule.upstreams[1].transport, Transport::Tcp);
+        assert_eq!(rule.policy.timeout, Duration::from_millis(250));
+
+        assert!(Rule::parse("corp.internal", RetryPolicy::default()).is_err());
+    }
+
+    #[test]
+    fn test_rule_matches() {
+        let rule = Rule::new("corp.internal", vec![], RetryPolicy::default());
+        assert!(rule.matches("corp.internal"));
+        assert!(rule.matches("wiki.Corp.Internal."));
+        assert!(!rule.matches("notcorp.internal"));
+        assert!(!rule.matches("internal"));
+
+        let root = Rule::new("", vec![], RetryPolicy::default());
+        assert!(root.matches("hernan.rs"));
+    }
+
+    #[test]
+    fn test_table_longest_match() {
+        let mut table = ForwardTable::default();
+        let policy = RetryPolicy::default();
+        table.insert(Rule::new("", vec![], policy));
+        table.insert(Rule::new("cluster.local", vec![], policy));
+        table.insert(Rule::new("svc.cluster.local", vec![], policy));
+
+        let route = |name| table.route(name).map(|r| r.suffix.as_str());
+        assert_eq!(route("db.svc.cluster.local"), Some("svc.cluster.local"));
+        assert_eq!(route("node.cluster.local"), Some("cluster.local"));
+        assert_eq!(route("hernan.rs"), Some(""));
+    }
+
+    #[test]
+    fn test_table_same_upstreams() {
+        let policy = RetryPolicy::default();
+        let table = |specs: &[&str]| {
+            let mut table = ForwardTable::default();
+            for spec in specs {
+                table.insert(Rule::parse(spec, policy).unwrap());
+            }
+            table
+        };
+        let a = table(&["=9.9.9.9:53", "corp=tcp://10.0.0.53:53@250"]);
+        assert!(a.same_upstreams(&table(&["corp=tcp://10.0.0.53:53", "=9.9.9.9:53"])));
+        assert!(!a.same_upstreams(&table(&["=9.9.9.9:53", "corp=10.0.0.53:53"])));
+        assert!(!a.same_upstreams(&table(&["=9.9.9.9:53"])));
+    }
+}
@@ -0,0 +1,128 @@
+use crate::message::{
+    data::Data,
+    domain::{parse_reverse, Domain, Record},
+    route::Route,
+};
+use anyhow::{Context, Result};
+use std::{collections::HashMap, fs, net::IpAddr, path::Path};
+
+const HOSTS_TTL: u32 = 60;
+
+/// Names pinned to addresses, as written in /etc/hosts.
+#[derive(Clone, Debug, Default)]
+pub struct Hosts {
+    addrs: HashMap<String, Vec<IpAddr>>,
+    names: HashMap<IpAddr, Vec<String>>,
+}
+
+impl Hosts {
+    /// Reads `address name [aliases...]` lines. The first name of a line is the
+    /// one reverse lookups of its address answer with.
+    pub fn parse(&mut self, text: &str) {
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let Some(Ok(ip)) = fields.next().map(str::parse::<IpAddr>) else {
+                continue;
+            };
+
+            let mut names = fields.map(|n| n.trim_end_matches('.')).peekable();
+            if let Some(canonical) = names.peek() {
+                let known = self.names.entry(ip).or_default();
+                if !known.iter().any(|n| n.eq_ignore_ascii_case(canonical)) {
+                    known.push(canonical.to_string());
+                }
+            }
+            for name in names {
+                let addrs = self.addrs.entry(name.to_ascii_lowercase()).or_default();
+                if !addrs.contains(&ip) {
+                    addrs.push(ip);
+                }
+            }
+        }
+    }
+
+    pub fn load(&mut self, path: &Path) -> Result<()> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Could not read hosts {}", path.display()))?;
+        self.parse(&text);
+        Ok(())
+    }
+
+    /// Records answering `q`, or `None` when the hosts know nothing about it.
+    /// A known name asked for the other address family gets no records.
+    pub fn look_up(&self, q: &Domain) -> Option<Vec<Route>> {
+        let route = |data| Route::new(q.clone(), HOSTS_TTL, data);
+        match q.record {
+            Record::AA | Record::AAAA => {
+                let addrs = self.addrs.get(&q.name.to_ascii_lowercase())?;
+                let routes = addrs
+                    .iter()
+                    .filter_map(|ip| match (q.record, ip) {
+                        (Record::AA, IpAddr::V4(ip)) => Some(Data::Ipv4(*ip)),
+                        (Record::AAAA, IpAddr::V6(ip)) => Some(Data::Ipv6(*ip)),
+                        _ => None,
+                    })
+                    .map(route)
+                    .collect();
+                Some(routes)
+            }
+            Record::PTR => {
+                let names = self.names.get(&parse_reverse(&q.name)?)?;
+                Some(names.iter().cloned().map(Data::Name).map(route).collect())
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    const HOSTS: &str = r#"
+# comment
+127.0.0.1   localhost
+10.0.0.2    api.dev.local api  # trailing comment
+10.0.0.3    api.dev.local
+::1         localhost ip6-localhost
+not-an-ip   ignored.local
+"#;
+
+    fn hosts() -> Hosts {
+        let mut h = Hosts::default();
+        h.parse(HOSTS);
+        h
+    }
+
+    #[test]
+    fn test_look_up_address() {
+        let res = hosts().look_up(&Domain::new_aa("API.dev.local")).unwrap();
+        assert_eq!(res.len(), 2);
+        assert_eq!(res[0].data(), &Data::Ipv4(Ipv4Addr::new(10, 0, 0, 2)));
+        assert_eq!(res[0].domain().name, "API.dev.local");
+
+        let res = hosts()
+            .look_up(&Domain::new("localhost", Record::AAAA))
+            .unwrap();
+        assert_eq!(res[0].data(), &Data::Ipv6(Ipv6Addr::LOCALHOST));
+
+        let res = hosts().look_up(&Domain::new("api", Record::AAAA)).unwrap();
+        assert!(res.is_empty());
+
+        assert!(hosts().look_up(&Domain::new_aa("ignored.local")).is_none());
+        assert!(hosts().look_up(&Domain::new("api", Record::MX)).is_none());
+    }
+
+    #[test]
+    fn test_look_up_reverse() {
+        let q = Domain::new("2.0.0.10.in-addr.arpa", Record::PTR);
+        let res = hosts().look_up(&q).unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].data(), &Data::Name("api.dev.local".to_string()));
+
+        let q = Domain::new("9.0.0.10.in-addr.arpa", Record::PTR);
+        assert!(hosts().look_up(&q).is_none());
+    }
+}
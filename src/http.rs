@@ -0,0 +1,410 @@
+pub mod doh;
+pub mod metrics;
+use crate::socket::{
+    is_timeout,
+    tcp::{accept, wait_for_data, ListenPolicy},
+};
+use anyhow::{Context, Result};
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    time::Duration,
+};
+
+/// Longest request line or header accepted.
+const MAX_LINE: usize = 8192;
+const MAX_HEADERS: usize = 64;
+/// Largest body accepted, the size of the largest DNS message.
+const MAX_BODY: usize = u16::MAX as usize;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    keep_alive: bool,
+}
+
+impl Request {
+    pub fn new(method: &str, path: &str, body: Vec<u8>) -> Self {
+        Self {
+            method: method.to_string(),
+            path: path.to_string(),
+            body,
+            keep_alive: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The value of `name` in the query string, as written.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.query
+            .split('&')
+            .filter_map(|p| p.split_once('='))
+            .find(|(n, _)| *n == name)
+            .map(|(_, v)| v)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &str, body: Vec<u8>) -> Self {
+        let mut res = Self {
+            status,
+            headers: vec![],
+            body,
+        };
+        res.set_header("Content-Type", content_type);
+        res
+    }
+
+    /// A plain text response telling why a request failed.
+    pub fn error(status: u16) -> Self {
+        let body = format!("{}\n", reason(status));
+        Self::new(status, "text/plain", body.into_bytes())
+    }
+
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        _ => "Unknown",
+    }
+}
+
+fn read_line(r: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut line = vec![];
+    let read = r.take(MAX_LINE as u64).read_until(b'\n', &mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Line too long"));
+    }
+    let line =
+        String::from_utf8(line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+/// Reads one HTTP/1.1 request, `None` once the client hangs up. Failures are
+/// answered with the status to send.
+fn read_request(r: &mut impl BufRead) -> io::Result<Option<Result<Request, u16>>> {
+    let Some(line) = read_line(r)? else {
+        return Ok(None);
+    };
+    let mut parts = line.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Ok(Some(Err(400)));
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let mut req = Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query: query.to_string(),
+        keep_alive: version == "HTTP/1.1",
+        ..Default::default()
+    };
+
+    match read_headers(r)? {
+        Some(headers) => req.headers = headers,
+        None => return Ok(Some(Err(400))),
+    }
+
+    match req.header("Connection").map(str::to_ascii_lowercase) {
+        Some(c) if c == "close" => req.keep_alive = false,
+        Some(c) if c == "keep-alive" => req.keep_alive = true,
+        _ => {}
+    }
+    if req.header("Transfer-Encoding").is_some() {
+        return Ok(Some(Err(501)));
+    }
+    let len = match req.header("Content-Length").map(str::parse::<usize>) {
+        Some(Ok(len)) => len,
+        Some(Err(_)) => return Ok(Some(Err(400))),
+        None if req.method == "POST" => return Ok(Some(Err(411))),
+        None => 0,
+    };
+    if len > MAX_BODY {
+        return Ok(Some(Err(413)));
+    }
+    req.body = vec![0; len];
+    r.read_exact(&mut req.body)?;
+    Ok(Some(Ok(req)))
+}
+
+fn read_headers(r: &mut impl BufRead) -> io::Result<Option<Vec<(String, String)>>> {
+    let mut headers = vec![];
+    loop {
+        let line = read_line(r)?.ok_or(io::ErrorKind::UnexpectedEof)?;
+        if line.is_empty() {
+            return Ok(Some(headers));
+        }
+        if headers.len() == MAX_HEADERS {
+            return Ok(None);
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            return Ok(None);
+        };
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Reads a body sent with chunked transfer encoding.
+fn read_chunked(r: &mut impl BufRead) -> io::Result<Vec<u8>> {
+    let mut body = vec![];
+    loop {
+        let line = read_line(r)?.ok_or(io::ErrorKind::UnexpectedEof)?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| invalid("Invalid chunk size"))?;
+        if size == 0 {
+            read_headers(r)?.ok_or_else(|| invalid("Invalid trailer"))?;
+            return Ok(body);
+        }
+        if body.len() + size > MAX_BODY {
+            return Err(invalid("Response body too long"));
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        r.read_exact(&mut body[start..])?;
+        read_line(r)?;
+    }
+}
+
+/// Reads the response to a request written with [`write_request`].
+pub fn read_response(r: &mut impl BufRead) -> io::Result<Response> {
+    let line = read_line(r)?.ok_or(io::ErrorKind::UnexpectedEof)?;
+    let mut parts = line.splitn(3, ' ');
+    let status = match (parts.next(), parts.next()) {
+        (Some(version), Some(status)) if version.starts_with("HTTP/1.") => status.parse().ok(),
+        _ => None,
+    };
+    let status = status.ok_or_else(|| invalid("Not an HTTP response"))?;
+    let headers = read_headers(r)?.ok_or_else(|| invalid("Invalid response headers"))?;
+    let mut res = Response {
+        status,
+        headers,
+        body: vec![],
+    };
+
+    let chunked = res
+        .header("Transfer-Encoding")
+        .is_some_and(|t| t.eq_ignore_ascii_case("chunked"));
+    res.body = match res.header("Content-Length").map(str::parse::<usize>) {
+        _ if chunked => read_chunked(r)?,
+        Some(Ok(len)) if len <= MAX_BODY => {
+            let mut body = vec![0; len];
+            r.read_exact(&mut body)?;
+            body
+        }
+        _ => return Err(invalid("Response without a usable length")),
+    };
+    Ok(res)
+}
+
+/// Writes `req` for `host`, keeping the connection open for more requests.
+pub fn write_request(w: &mut impl Write, host: &str, req: &Request) -> Result<()> {
+    let target = match req.query.is_empty() {
+        true => req.path.clone(),
+        false => format!("{}?{}", req.path, req.query),
+    };
+    let mut head = format!("{} {target} HTTP/1.1\r\nHost: {host}\r\n", req.method);
+    for (name, value) in &req.headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str(&format!("Content-Length: {}\r\n\r\n", req.body.len()));
+
+    let mut buf = head.into_bytes();
+    buf.extend_from_slice(&req.body);
+    w.write_all(&buf)?;
+    w.flush()?;
+    Ok(())
+}
+
+fn write_response(w: &mut impl Write, res: &Response, keep_alive: bool) -> Result<()> {
+    let mut head = format!("HTTP/1.1 {} {}\r\n", res.status, reason(res.status));
+    for (name, value) in &res.headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str(&format!("Content-Length: {}\r\n", res.body.len()));
+    if !keep_alive {
+        head.push_str("Connection: close\r\n");
+    }
+    head.push_str("\r\n");
+
+    let mut buf = head.into_bytes();
+    buf.extend_from_slice(&res.body);
+    w.write_all(&buf)?;
+    w.flush()?;
+    Ok(())
+}
+
+/// Serves HTTP/1.1 requests with `handler`, each connection on its own
+/// thread; `None` closes the connection without a response.
+pub fn serve<F>(listener: &TcpListener, policy: ListenPolicy, handler: F) -> Result<()>
+where
+    F: Fn(&Request, SocketAddr) -> Option<Response> + Send + Sync + 'static,
+{
+    accept(listener, policy, move |stream| {
+        stream.set_read_timeout(Some(policy.idle))?;
+        stream.set_write_timeout(Some(policy.idle))?;
+        serve_connection(stream, policy.idle, &handler)
+    })
+}
+
+/// Answers the requests of one client until it hangs up, stays idle for too
+/// long, asks to close or the server stops.
+fn serve_connection<F>(stream: TcpStream, idle: Duration, handler: &F) -> Result<()>
+where
+    F: Fn(&Request, SocketAddr) -> Option<Response>,
+{
+    let peer = stream.peer_addr()?;
+    let mut reader = BufReader::new(stream.try_clone().context("Could not clone stream")?);
+    let mut writer = stream;
+    loop {
+        if reader.buffer().is_empty() && !wait_for_data(reader.get_ref(), idle)? {
+            return Ok(());
+        }
+        let req = match read_request(&mut reader) {
+            Ok(Some(req)) => req,
+            Ok(None) => return Ok(()),
+            Err(e) if is_timeout(&e) || e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let (res, keep_alive) = match req {
+            Ok(req) => match handler(&req, peer) {
+                Some(res) => (res, req.keep_alive),
+                None => return Ok(()),
+            },
+            Err(status) => (Response::error(status), false),
+        };
+        write_response(&mut writer, &res, keep_alive)?;
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &str) -> Result<Request, u16> {
+        read_request(&mut raw.as_bytes()).unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_read_request() {
+        let req =
+            parse("GET /dns-query?dns=AAAB&x=1 HTTP/1.1\r\nHost: a\r\naccept: b\r\n\r\n").unwrap();
+        assert_eq!(req.method, "GET");
+        assert_eq!(req.path, "/dns-query");
+        assert_eq!(req.param("dns"), Some("AAAB"));
+        assert_eq!(req.param("y"), None);
+        assert_eq!(req.header("Accept"), Some("b"));
+        assert!(req.keep_alive);
+
+        let req = parse("POST / HTTP/1.0\r\nContent-Length: 3\r\n\r\nabcdef").unwrap();
+        assert_eq!(req.body, b"abc");
+        assert!(!req.keep_alive);
+
+        assert_eq!(parse("POST / HTTP/1.1\r\n\r\n"), Err(411));
+        assert_eq!(parse("GET /\r\n\r\n"), Err(400));
+        assert_eq!(parse("GET / HTTP/1.1\r\nbogus\r\n\r\n"), Err(400));
+        assert_eq!(
+            parse("POST / HTTP/1.1\r\nContent-Length: 70000\r\n\r\n"),
+            Err(413)
+        );
+        assert!(read_request(&mut "".as_bytes()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_write_response() {
+        let mut res = Response::new(200, "text/plain", b"hi".to_vec());
+        res.set_header("Cache-Control", "max-age=60");
+        let mut buf = vec![];
+        write_response(&mut buf, &res, false).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nCache-Control: max-age=60\r\n\
+             Content-Length: 2\r\nConnection: close\r\n\r\nhi"
+        );
+    }
+
+    #[test]
+    fn test_request_round_trip() {
+        let mut req = Request::new("POST", "/dns-query", b"abc".to_vec());
+        req.set_header("Content-Type", "application/dns-message");
+        let mut buf = vec![];
+        write_request(&mut buf, "dns.local", &req).unwrap();
+
+        let read = read_request(&mut buf.as_slice()).unwrap().unwrap().unwrap();
+        assert_eq!(read.header("Host"), Some("dns.local"));
+        assert_eq!(read.header("content-type"), Some("application/dns-message"));
+        assert_eq!(read.body, b"abc");
+    }
+
+    #[test]
+    fn test_read_response() {
+        let raw = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhi";
+        let res = read_response(&mut raw.as_bytes()).unwrap();
+        assert_eq!(res.status, 200);
+        assert_eq!(res.body, b"hi");
+
+        let raw = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                   3\r\nabc\r\n2;x=y\r\nde\r\n0\r\n\r\n";
+        let res = read_response(&mut raw.as_bytes()).unwrap();
+        assert_eq!(res.body, b"abcde");
+
+        let mut buf = vec![];
+        write_response(&mut buf, &Response::error(404), true).unwrap();
+        let res = read_response(&mut buf.as_slice()).unwrap();
+        assert_eq!(res.status, 404);
+
+        assert!(read_response(&mut "SSH-2.0\r\n\r\n".as_bytes()).is_err());
+        assert!(read_response(&mut "HTTP/1.1 200 OK\r\n\r\n".as_bytes()).is_err());
+    }
+}
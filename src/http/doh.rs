@@ -0,0 +1,245 @@
+use super::{read_response, write_request, Request, Response};
+use crate::{
+    encoding::decode_base64url,
+    message::{data::Data, domain::Record, Message},
+    socket::{
+        tcp::{connect, or_timeout},
+        RetryPolicy,
+    },
+};
+use anyhow::{Context, Result};
+use std::{io::BufReader, net::TcpStream};
+
+pub const PATH: &str = "/dns-query";
+pub const CONTENT_TYPE: &str = "application/dns-message";
+
+/// How long a response may be cached: the smallest answer TTL, or for
+/// negative answers the TTL of their SOA capped by its minimum (RFC 2308).
+pub fn max_age(res: &Message) -> Option<u32> {
+    if let Some(ttl) = res.answers().iter().map(|r| r.ttl()).min() {
+        return Some(ttl);
+    }
+    res.authorities()
+        .iter()
+        .filter(|r| r.domain().record == Record::SOA)
+        .map(|r| match r.data() {
+            Data::Soa(soa) => r.ttl().min(soa.minimum),
+            _ => r.ttl(),
+        })
+        .min()
+}
+
+/// The DNS query carried by a DoH request, or the status refusing it.
+fn query(req: &Request) -> Result<Message, u16> {
+    if req.path != PATH {
+        return Err(404);
+    }
+    let buf = match req.method.as_str() {
+        "GET" => {
+            let dns = req.param("dns").ok_or(400u16)?;
+            decode_base64url(dns).map_err(|_| 400u16)?
+        }
+        "POST" => {
+            let content_type = req.header("Content-Type").unwrap_or_default();
+            if !content_type.eq_ignore_ascii_case(CONTENT_TYPE) {
+                return Err(415);
+            }
+            req.body.clone()
+        }
+        _ => return Err(405),
+    };
+    match Message::try_from(buf.as_ref()) {
+        Ok(msg) if msg.is_query() => Ok(msg),
+        _ => Err(400),
+    }
+}
+
+/// Answers a DoH request (RFC 8484) with `resolve`; `None` when the query is
+/// dropped.
+pub fn handle(req: &Request, resolve: impl Fn(&Message) -> Option<Message>) -> Option<Response> {
+    let msg = match query(req) {
+        Ok(msg) => msg,
+        Err(status) => return Some(Response::error(status)),
+    };
+    let res = resolve(&msg)?;
+
+    let mut http = Response::new(200, CONTENT_TYPE, res.flush().to_vec());
+    if let Some(age) = max_age(&res) {
+        http.set_header("Cache-Control", &format!("max-age={age}"));
+    }
+    Some(http)
+}
+
+/// Upstream connection asking a DoH server with POST requests.
+#[derive(Debug)]
+pub struct DohStream {
+    reader: BufReader<TcpStream>,
+    host: String,
+    path: String,
+    policy: RetryPolicy,
+}
+
+impl DohStream {
+    /// Connects to the server at `addr`, named `host` in requests for `path`.
+    pub fn connect(addr: &str, host: &str, path: &str, policy: RetryPolicy) -> Result<Self> {
+        let stream = connect(addr, policy.deadline)?;
+        stream.set_read_timeout(Some(policy.deadline))?;
+        stream.set_write_timeout(Some(policy.deadline))?;
+        Ok(Self {
+            reader: BufReader::new(stream),
+            host: host.to_string(),
+            path: path.to_string(),
+            policy,
+        })
+    }
+
+    pub fn query(&mut self, m: &Message) -> Result<Message> {
+        let mut req = Request::new("POST", &self.path, m.flush().to_vec());
+        req.set_header("Content-Type", CONTENT_TYPE);
+        req.set_header("Accept", CONTENT_TYPE);
+        write_request(self.reader.get_mut(), &self.host, &req)?;
+
+        let res =
+            read_response(&mut self.reader).map_err(|e| or_timeout(e, self.policy.deadline))?;
+        anyhow::ensure!(res.status == 200, "DoH server answered {}", res.status);
+        let content_type = res.header("Content-Type").unwrap_or_default();
+        anyhow::ensure!(
+            content_type.eq_ignore_ascii_case(CONTENT_TYPE),
+            "DoH server answered with {content_type}"
+        );
+
+        let msg = Message::try_from(res.body.as_ref()).context("Invalid DoH response")?;
+        anyhow::ensure!(
+            msg.is_response_to(m),
+            "DoH response does not match the query"
+        );
+        Ok(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{
+        data::Soa,
+        domain::Domain,
+        header::{OpCode, PacketId},
+        route::Route,
+        Header,
+    };
+    use crate::{http::serve, socket::tcp::ListenPolicy};
+    use std::{
+        net::{Ipv4Addr, TcpListener},
+        thread,
+    };
+
+    /// Example query of RFC 8484 section 4.1.1, www.example.com A.
+    const RFC_QUERY: &str = "AAABAAABAAAAAAAAA3d3dwdleGFtcGxlA2NvbQAAAQAB";
+
+    fn query_message() -> Message {
+        let mut msg = Message::new(Header::query(PacketId(0)));
+        msg.set_questions(vec![Domain::new_aa("hernan.rs")])
+            .unwrap();
+        msg
+    }
+
+    fn answer(q: &Message) -> Option<Message> {
+        let mut res = Message::new_response(q);
+        let a = |ttl| {
+            Route::new(
+                Domain::new_aa("hernan.rs"),
+                ttl,
+                Data::Ipv4(Ipv4Addr::LOCALHOST),
+            )
+        };
+        res.set_answers(vec![a(300), a(60)]).unwrap();
+        Some(res)
+    }
+
+    #[test]
+    fn test_rfc_query() {
+        let buf = decode_base64url(RFC_QUERY).unwrap();
+        let msg = Message::try_from(buf.as_ref()).unwrap();
+        assert_eq!(msg.questions()[0].name, "www.example.com");
+    }
+
+    #[test]
+    fn test_handle_get() {
+        let req = Request {
+            method: "GET".to_string(),
+            path: PATH.to_string(),
+            query: format!("dns={RFC_QUERY}"),
+            ..Default::default()
+        };
+
+        let res = handle(&req, answer).unwrap();
+        assert_eq!(res.status, 200);
+        assert_eq!(res.header("Content-Type"), Some(CONTENT_TYPE));
+        assert_eq!(res.header("Cache-Control"), Some("max-age=60"));
+        let msg = Message::try_from(res.body.as_ref()).unwrap();
+        assert_eq!(msg.answers().len(), 2);
+    }
+
+    #[test]
+    fn test_handle_post() {
+        let mut req = Request {
+            method: "POST".to_string(),
+            path: PATH.to_string(),
+            headers: vec![("content-type".to_string(), CONTENT_TYPE.to_string())],
+            body: query_message().flush().to_vec(),
+            ..Default::default()
+        };
+        assert_eq!(handle(&req, answer).unwrap().status, 200);
+        assert!(handle(&req, |_| None).is_none());
+
+        req.headers.clear();
+        assert_eq!(handle(&req, answer).unwrap().status, 415);
+        req.method = "PUT".to_string();
+        assert_eq!(handle(&req, answer).unwrap().status, 405);
+        req.path = "/other".to_string();
+        assert_eq!(handle(&req, answer).unwrap().status, 404);
+    }
+
+    #[test]
+    fn test_max_age() {
+        let q = query_message();
+        assert_eq!(max_age(&answer(&q).unwrap()), Some(60));
+
+        let mut res = Message::new_response(&q);
+        assert_eq!(max_age(&res), None);
+        let soa = Soa {
+            mname: "ns.hernan.rs".to_string(),
+            rname: "hostmaster.hernan.rs".to_string(),
+            serial: 1,
+            refresh: 1,
+            retry: 1,
+            expire: 1,
+            minimum: 30,
+        };
+        let soa = Route::new(Domain::new("hernan.rs", Record::SOA), 3600, Data::Soa(soa));
+        res.set_r_code(OpCode::name_error());
+        res.set_authorities(vec![soa]).unwrap();
+        assert_eq!(max_age(&res), Some(30));
+    }
+
+    #[test]
+    fn test_doh_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            serve(&listener, ListenPolicy::default(), |req, _| {
+                handle(req, answer)
+            })
+        });
+
+        let mut client =
+            DohStream::connect(&addr, "dns.local", PATH, RetryPolicy::default()).unwrap();
+        let q = query_message();
+        assert_eq!(client.query(&q).unwrap().answers().len(), 2);
+        assert_eq!(client.query(&q).unwrap().answers().len(), 2);
+
+        let mut lost =
+            DohStream::connect(&addr, "dns.local", "/other", RetryPolicy::default()).unwrap();
+        assert!(lost.query(&q).is_err());
+    }
+}
@@ -0,0 +1,41 @@
+use super::{Request, Response};
+use crate::{cache::Cache, metrics::Metrics};
+
+pub const PATH: &str = "/metrics";
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Answers a Prometheus scrape with `metrics` and the state of `cache`.
+pub fn handle(req: &Request, metrics: &Metrics, cache: Option<&Cache>) -> Response {
+    if req.path != PATH {
+        return Response::error(404);
+    }
+    if req.method != "GET" {
+        return Response::error(405);
+    }
+    Response::new(200, CONTENT_TYPE, metrics.render(cache).into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handle() {
+        let metrics = Metrics::default();
+        let res = handle(&Request::new("GET", PATH, vec![]), &metrics, None);
+        assert_eq!(res.status, 200);
+        assert_eq!(res.header("Content-Type"), Some(CONTENT_TYPE));
+        assert!(String::from_utf8(res.body)
+            .unwrap()
+            .contains("dns_queries_in_flight 0"));
+
+        assert_eq!(
+            handle(&Request::new("POST", PATH, vec![]), &metrics, None).status,
+            405
+        );
+        assert_eq!(
+            handle(&Request::new("GET", "/", vec![]), &metrics, None).status,
+            404
+        );
+    }
+}
@@ -0,0 +1,21 @@
+//! The server's building blocks, shared with the `dnsq` client in `examples/`.
+pub mod acl;
+pub mod block;
+pub mod cache;
+pub mod cidr;
+pub mod dnstap;
+pub mod encoding;
+pub mod forward;
+pub mod hosts;
+pub mod http;
+pub mod message;
+pub mod metrics;
+pub mod parser;
+pub mod querylog;
+pub mod rpz;
+pub mod rrl;
+pub mod signal;
+pub mod socket;
+pub mod tsig;
+pub mod writer;
+pub mod zone;
@@ -1,19 +1,131 @@
-mod message;
-mod parser;
-mod socket;
-mod writer;
 use anyhow::{Context, Result};
-use message::{data::Data, route::Route, Message};
-use socket::DnsSocket;
-use std::{env, net::Ipv4Addr};
+use dns_starter_rust::{
+    acl::{Acl, Denial},
+    block::{Action, BlockList, Blocker},
+    cache::Cache,
+    dnstap::{self, Dnstap, Kind, Protocol},
+    forward::{ForwardTable, Rule, Transport, Upstream},
+    hosts::Hosts,
+    http,
+    message::{
+        data::Data,
+        domain::{Domain, Record},
+        edns::Edns,
+        header::{Authenticity, Authoritative, OpCode, PacketId, Recursion, Truncation},
+        Header, Message,
+    },
+    metrics::Metrics,
+    querylog::{self, QueryLog, Source},
+    rpz::{self, Hit, Policy, Rpz},
+    rrl, signal,
+    socket::{
+        self,
+        pool::Pool,
+        tcp::{DnsListener, ListenPolicy},
+        DnsService, DnsSocket, Malformed, RetryPolicy, SocketError,
+    },
+    tsig::{self, KeyStore},
+    zone::{
+        self,
+        dnssec::{self, key::Key, Signer},
+        master, notify,
+        transfer::Secondary,
+        update, Staged, Zone, Zones,
+    },
+};
+use std::{
+    env, fs,
+    net::{IpAddr, SocketAddr, TcpListener},
+    path::Path,
+    sync::{Arc, RwLock},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
 
+/// How often the UDP loop and the reload thread look out for signals.
+const POLL: Duration = Duration::from_millis(250);
+
+/// Everything given on the command line. What outlives a reload, such as
+/// zones and counters, is shared with the configuration it replaces.
 #[derive(Debug)]
 struct Args {
-    resolver: Option<String>,
+    rpz: Rpz,
+    blocker: Blocker,
+    hosts: Hosts,
+    zones: Arc<Zones>,
+    forwards: ForwardTable,
+    /// Upstream responses, emptied on reload as the upstreams may change.
+    cache: Option<Arc<Cache>>,
+    randomize_case: bool,
+    tcp: Option<String>,
+    doh: Option<String>,
+    metrics_addr: Option<String>,
+    listen: ListenPolicy,
+    keys: KeyStore,
+    allow_transfer: Acl,
+    /// Who may query, update, or get recursion; anyone when unset.
+    allow_query: Option<Acl>,
+    allow_recursion: Option<Acl>,
+    allow_update_from: Option<Acl>,
+    denial: Denial,
+    secondaries: Vec<Secondary>,
+    notify: Vec<notify::Target>,
+    update_policy: update::Policy,
+    rrl: Arc<rrl::Limiter>,
+    query_log: Option<Arc<QueryLog>>,
+    metrics: Arc<Metrics>,
+    dnstap: Option<Arc<Dnstap>>,
+    /// How long in-flight queries may take to finish on shutdown.
+    drain: Duration,
+}
+
+fn parse_millis(value: Option<String>, name: &str) -> Result<Duration> {
+    let ms = value.with_context(|| format!("Missing {name} milliseconds"))?;
+    let ms = ms
+        .parse()
+        .with_context(|| format!("Invalid {name}: {ms}"))?;
+    Ok(Duration::from_millis(ms))
 }
 
-fn parse_args() -> Result<Args> {
+fn parse_number<T: std::str::FromStr>(value: Option<String>, name: &str) -> Result<T> {
+    let n = value.with_context(|| format!("Missing {name}"))?;
+    n.parse()
+        .ok()
+        .with_context(|| format!("Invalid {name}: {n}"))
+}
+
+/// Reads the command line and every file it names, along with the zones to
+/// swap in once the configuration takes effect. On reload, `previous` keeps
+/// its listeners, secondary zones, NOTIFY targets, query log, rate limiter and
+/// dnstap output.
+fn parse_args(previous: Option<&Args>) -> Result<(Args, Staged)> {
     let mut resolver = None;
+    let mut rules = vec![];
+    let mut zones = vec![];
+    let mut dnssec_keys = vec![];
+    let mut validity = dnssec::DEFAULT_VALIDITY;
+    let mut rpz = Rpz::default();
+    let mut blocker = Blocker::default();
+    let mut hosts = Hosts::default();
+    let mut policy = RetryPolicy::default();
+    let mut randomize_case = false;
+    let mut cache_size = 0;
+    let mut tcp = None;
+    let mut doh = None;
+    let mut metrics_addr = None;
+    let mut listen = ListenPolicy::default();
+    let mut keys = KeyStore::default();
+    let mut allow_transfer = Acl::default();
+    let (mut allow_query, mut allow_recursion, mut allow_update_from) = (None, None, None);
+    let mut denial = Denial::default();
+    let mut secondaries = vec![];
+    let mut notify = vec![];
+    let mut update_policy = update::Policy::default();
+    let mut rrl = rrl::Config::default();
+    let mut query_log = None;
+    let mut dnstap = None;
+    let mut drain = Duration::from_secs(5);
+    let mut log = querylog::Config::default();
     let mut all = env::args().skip(1);
     while let Some(arg) = all.next() {
         match arg.as_str() {
@@ -21,65 +133,926 @@ fn parse_args() -> Result<Args> {
                 let ip = all.next().context("Missing resolver IPv4")?;
                 resolver = Some(ip)
             }
+            "--forward" => rules.push(all.next().context("Missing forward rule")?),
+            "--zone" => {
+                let path = all.next().context("Missing zone file")?;
+                let zone = Zone::load(Path::new(&path))?;
+                println!("Serving zone {}", zone.origin());
+                zones.push(zone);
+            }
+            "--dnssec-key" => {
+                let key = Key::load(&all.next().context("Missing DNSSEC key")?)?;
+                println!("Signing zone {} with key {}", key.owner, key.tag());
+                dnssec_keys.push(key);
+            }
+            "--signature-validity" => {
+                let ttl = all.next().context("Missing signature validity")?;
+                validity = master::parse_ttl(&ttl)?;
+            }
+            "--rpz" => {
+                let path = all.next().context("Missing policy zone file")?;
+                let zone = rpz.load(Path::new(&path))?;
+                println!("Applying policy zone {}", zone.origin());
+            }
+            "--hosts" => {
+                let path = all.next().context("Missing hosts file")?;
+                hosts.load(Path::new(&path))?;
+            }
+            "--blocklist" => {
+                let spec = all.next().context("Missing blocklist file")?;
+                let (path, action) = match spec.rsplit_once('=') {
+                    Some((path, action)) => (path, action.parse()?),
+                    None => (spec.as_str(), Action::Null),
+                };
+                blocker.push(BlockList::load(Path::new(path), action)?);
+            }
+            "--allowlist" => {
+                let path = all.next().context("Missing allowlist file")?;
+                let text = fs::read_to_string(&path)
+                    .with_context(|| format!("Could not read allowlist {path}"))?;
+                blocker.allow(&text);
+            }
+            "--timeout" => policy.timeout = parse_millis(all.next(), "timeout")?,
+            "--deadline" => policy.deadline = parse_millis(all.next(), "deadline")?,
+            "--retries" => {
+                let n = all.next().context("Missing number of retries")?;
+                let n: u32 = n.parse().with_context(|| format!("Invalid retries: {n}"))?;
+                policy.attempts = n + 1;
+            }
+            "--0x20" => randomize_case = true,
+            "--cache-size" => cache_size = parse_number(all.next(), "cache size")?,
+            "--tcp" => tcp = Some(all.next().context("Missing TCP address")?),
+            "--doh" => doh = Some(all.next().context("Missing DoH address")?),
+            "--metrics" => metrics_addr = Some(all.next().context("Missing metrics address")?),
+            "--idle-timeout" => listen.idle = parse_millis(all.next(), "idle timeout")?,
+            "--max-connections" => {
+                let n = all.next().context("Missing number of connections")?;
+                listen.max_connections = n
+                    .parse()
+                    .with_context(|| format!("Invalid max connections: {n}"))?;
+            }
+            "--udp-workers" => {
+                listen.udp_workers = parse_number(all.next(), "UDP workers")?;
+            }
+            "--tsig-key" => {
+                let key: tsig::Key = all.next().context("Missing TSIG key")?.parse()?;
+                println!("Accepting TSIG key {}", key.name);
+                keys.insert(key);
+            }
+            "--allow-transfer" => {
+                let spec = all
+                    .next()
+                    .context("Missing network or key allowed to transfer")?;
+                allow_transfer.allow(&spec)?;
+            }
+            "--allow-query" => {
+                let spec = all.next().context("Missing network allowed to query")?;
+                allow_query.get_or_insert_with(Acl::default).allow(&spec)?;
+            }
+            "--allow-recursion" => {
+                let spec = all.next().context("Missing network allowed recursion")?;
+                allow_recursion
+                    .get_or_insert_with(Acl::default)
+                    .allow(&spec)?;
+            }
+            "--allow-update-from" => {
+                let spec = all.next().context("Missing network allowed to update")?;
+                allow_update_from
+                    .get_or_insert_with(Acl::default)
+                    .allow(&spec)?;
+            }
+            "--deny" => denial = all.next().context("Missing denial")?.parse()?,
+            "--secondary" => secondaries.push(all.next().context("Missing secondary zone")?),
+            "--notify" => notify.push(all.next().context("Missing zone to notify")?),
+            "--rrl-answers" => rrl.answers = parse_number(all.next(), "answers per second")?,
+            "--rrl-nxdomains" => rrl.nxdomains = parse_number(all.next(), "NXDOMAIN per second")?,
+            "--rrl-errors" => rrl.errors = parse_number(all.next(), "errors per second")?,
+            "--rrl-slip" => rrl.slip = parse_number(all.next(), "slip")?,
+            "--rrl-log-only" => rrl.log_only = true,
+            "--rrl-exempt" => rrl.exempt.push(parse_number(all.next(), "exempt network")?),
+            "--rrl-ipv4-prefix" => rrl.ipv4_prefix = parse_number(all.next(), "IPv4 prefix")?,
+            "--rrl-ipv6-prefix" => rrl.ipv6_prefix = parse_number(all.next(), "IPv6 prefix")?,
+            "--allow-update" => {
+                let spec = all.next().context("Missing key and names to update")?;
+                update_policy.allow(&spec)?;
+            }
+            "--query-log" => {
+                let path = all.next().context("Missing query log file")?;
+                log.path = (path != "-").then(|| path.into());
+                query_log = Some(());
+            }
+            "--query-log-format" => {
+                log.format = all.next().context("Missing query log format")?.parse()?
+            }
+            "--query-log-size" => log.max_size = parse_number(all.next(), "query log size")?,
+            "--query-log-keep" => log.keep = parse_number(all.next(), "rotated query logs")?,
+            "--query-log-sample" => log.sample = parse_number(all.next(), "query log sample")?,
+            "--dnstap" => {
+                let output: dnstap::Output =
+                    all.next().context("Missing dnstap output")?.parse()?;
+                dnstap = Some(output);
+            }
+            "--drain-timeout" => drain = parse_millis(all.next(), "drain timeout")?,
             u => println!("Unknown argument: {u}"),
         }
     }
-    Ok(Args { resolver })
+
+    let mut forwards = ForwardTable::default();
+    if let Some(addr) = resolver {
+        let upstream: Upstream = addr.parse()?;
+        forwards.insert(Rule::new("", vec![upstream], policy));
+    }
+    for spec in rules {
+        forwards.insert(Rule::parse(&spec, policy)?);
+    }
+
+    let secondaries = secondaries
+        .iter()
+        .map(|spec| Secondary::parse(spec, &keys))
+        .collect::<Result<Vec<_>>>()?;
+    let notify = notify
+        .iter()
+        .map(|spec| notify::Target::parse(spec, &keys))
+        .collect::<Result<Vec<_>>>()?;
+    anyhow::ensure!(log.sample > 0, "Query log sample must be at least 1");
+    anyhow::ensure!(
+        validity >= 3600,
+        "Signature validity must be at least an hour"
+    );
+    let signer = Signer::new(dnssec_keys, validity);
+    for ds in signer.ds() {
+        println!("DS for the parent zone: {ds}");
+    }
+
+    let Some(previous) = previous else {
+        let shared = Zones::default();
+        let staged = shared.stage(signer, zones, |_| true);
+        let query_log = query_log.map(|()| QueryLog::new(log)).transpose()?;
+        let args = Args {
+            rpz,
+            blocker,
+            hosts,
+            zones: Arc::new(shared),
+            forwards,
+            cache: (cache_size > 0).then(|| Arc::new(Cache::new(cache_size))),
+            randomize_case,
+            tcp,
+            doh,
+            metrics_addr,
+            listen,
+            keys,
+            allow_transfer,
+            allow_query,
+            allow_recursion,
+            allow_update_from,
+            denial,
+            secondaries,
+            notify,
+            update_policy,
+            rrl: Arc::new(rrl::Limiter::new(rrl)),
+            query_log: query_log.map(Arc::new),
+            metrics: Arc::default(),
+            dnstap: dnstap.map(|output| Arc::new(Dnstap::new(output))),
+            drain,
+        };
+        return Ok((args, staged));
+    };
+
+    blocker.carry_hits(&previous.blocker);
+    // Changed zones go through the journal and NOTIFY as any other change.
+    let staged = previous.zones.stage(signer, zones, |origin| {
+        previous
+            .secondaries
+            .iter()
+            .any(|s| s.origin.eq_ignore_ascii_case(origin))
+    });
+
+    let args = Args {
+        rpz,
+        blocker,
+        hosts,
+        zones: previous.zones.clone(),
+        forwards,
+        cache: previous.cache.clone(),
+        randomize_case,
+        tcp: previous.tcp.clone(),
+        doh: previous.doh.clone(),
+        metrics_addr: previous.metrics_addr.clone(),
+        listen: previous.listen,
+        keys,
+        allow_transfer,
+        allow_query,
+        allow_recursion,
+        allow_update_from,
+        denial,
+        secondaries: previous.secondaries.clone(),
+        notify: previous.notify.clone(),
+        update_policy,
+        rrl: previous.rrl.clone(),
+        query_log: previous.query_log.clone(),
+        metrics: previous.metrics.clone(),
+        dnstap: previous.dnstap.clone(),
+        drain,
+    };
+    Ok((args, staged))
+}
+
+/// The configuration in effect, replaced as a whole on reload.
+type Config = RwLock<Arc<Args>>;
+
+fn current(config: &Config) -> Arc<Args> {
+    config.read().expect("Configuration lock poisoned").clone()
+}
+
+/// Re-reads the command line and the files it names, then swaps the result
+/// in at once, zones included; the running configuration stays when that
+/// fails. Cached responses are dropped when forwarding changed.
+fn reload(config: &Config) {
+    let previous = current(config);
+    let (args, staged) = match parse_args(Some(&previous)) {
+        Ok(parsed) => parsed,
+        Err(e) => return println!("Could not reload configuration: {e}"),
+    };
+    let mut config = config.write().expect("Configuration lock poisoned");
+    args.zones.commit(staged);
+    if let Some(cache) = &args.cache {
+        if !args.forwards.same_upstreams(&previous.forwards) {
+            cache.clear();
+        }
+    }
+    *config = Arc::new(args);
+    println!("Reloaded configuration");
 }
 
 fn main() -> Result<()> {
     println!("Starting DNS...");
 
-    let args = parse_args()?;
-    let srv = DnsSocket::listen("127.0.0.1:2053")?;
+    signal::install()?;
+    let (args, staged) = parse_args(None)?;
+    args.zones.commit(staged);
+    let config = Arc::new(RwLock::new(Arc::new(args)));
+    let args = current(&config);
+    let srv = Arc::new(DnsSocket::listen("127.0.0.1:2053")?);
+    srv.set_read_timeout(Some(POLL))?;
 
-    while let Ok((q, addr)) = srv.read() {
-        let res: Message = if let Some(ref addr) = args.resolver {
-            resolve_from(addr, &q)?
-        } else {
-            look_up_local(q)?
+    if let Some(addr) = &args.tcp {
+        let listener = DnsListener::bind(addr, args.listen)?;
+        println!("Listening for TCP on {}", listener.local_addr()?);
+        let config = config.clone();
+        thread::spawn(move || {
+            let handler = move |q: &Message, addr: SocketAddr| {
+                let args = current(&config);
+                let started = args.metrics.begin();
+                tap(&args, Kind::ClientQuery, Protocol::Tcp, addr, q);
+                let res = respond_stream(&args, q, addr.ip(), Protocol::Tcp);
+                for r in &res {
+                    tap(&args, Kind::ClientResponse, Protocol::Tcp, addr, r);
+                }
+                let first = res.first().map(|r| (r, r.flush().len()));
+                observe(&args, "tcp", addr.ip(), q, first, started);
+                res
+            };
+            if let Err(e) = listener.serve(handler) {
+                println!("TCP listener stopped: {e}");
+            }
+        });
+    }
+
+    if let Some(addr) = &args.doh {
+        let listener =
+            TcpListener::bind(addr).with_context(|| format!("Could not listen on {addr}"))?;
+        println!("Serving {} on {}", http::doh::PATH, listener.local_addr()?);
+        let (config, policy) = (config.clone(), args.listen);
+        let handler = move |req: &http::Request, addr: SocketAddr| {
+            let args = current(&config);
+            http::doh::handle(req, |q| {
+                let started = args.metrics.begin();
+                tap(&args, Kind::ClientQuery, Protocol::Doh, addr, q);
+                let res = respond(&args, q, addr.ip(), Protocol::Doh);
+                if let Some(res) = &res {
+                    tap(&args, Kind::ClientResponse, Protocol::Doh, addr, res);
+                }
+                let sized = res.as_ref().map(|r| (r, r.flush().len()));
+                observe(&args, "doh", addr.ip(), q, sized, started);
+                res
+            })
         };
+        thread::spawn(move || {
+            if let Err(e) = http::serve(&listener, policy, handler) {
+                println!("DoH listener stopped: {e}");
+            }
+        });
+    }
+
+    if let Some(addr) = &args.metrics_addr {
+        let listener =
+            TcpListener::bind(addr).with_context(|| format!("Could not listen on {addr}"))?;
+        println!(
+            "Serving {} on {}",
+            http::metrics::PATH,
+            listener.local_addr()?
+        );
+        let (metrics, cache, policy) = (args.metrics.clone(), args.cache.clone(), args.listen);
+        let handler = move |req: &http::Request, _| {
+            Some(http::metrics::handle(req, &metrics, cache.as_deref()))
+        };
+        thread::spawn(move || {
+            if let Err(e) = http::serve(&listener, policy, handler) {
+                println!("Metrics listener stopped: {e}");
+            }
+        });
+    }
+
+    if !args.notify.is_empty() {
+        let (args, changes) = (args.clone(), args.zones.watch());
+        thread::spawn(move || notify::run(changes, &args.notify, notify::RETRY));
+    }
+
+    // Policy files are read again off the query path; a reload brings in
+    // new ones, so the policy zones in effect are looked up each time.
+    {
+        let config = config.clone();
+        thread::spawn(move || loop {
+            thread::sleep(rpz::RELOAD_INTERVAL);
+            current(&config).rpz.refresh();
+        });
+    }
+
+    // Signatures are renewed before they expire, see `Signer::period`.
+    {
+        let zones = args.zones.clone();
+        thread::spawn(move || loop {
+            thread::sleep(dnssec::RESIGN_INTERVAL);
+            zones.resign(tsig::now() as u32);
+        });
+    }
+
+    for secondary in args.secondaries.iter().cloned() {
+        println!(
+            "Serving secondary zone {} from {}",
+            secondary.origin, secondary.primary
+        );
+        let zones = args.zones.clone();
+        thread::spawn(move || secondary.run(&zones, RetryPolicy::default()));
+    }
+
+    // Files are re-read off the serving threads, which keep answering with
+    // the previous configuration until the new one is swapped in.
+    {
+        let config = config.clone();
+        thread::spawn(move || {
+            while !signal::terminating() {
+                thread::sleep(POLL);
+                if signal::hangup() {
+                    reload(&config);
+                }
+            }
+        });
+    }
 
-        srv.send_to(&res, addr)?;
+    // Queries are answered off the reading thread, by as many workers as
+    // allowed; when they are all busy, clients will ask again.
+    let workers = {
+        let srv = srv.clone();
+        Pool::new(args.listen.udp_workers, move |job: UdpJob| {
+            let (args, q, addr, started) = job;
+            answer_udp(&args, &srv, &q, addr, started)
+        })
+    };
+    while !signal::terminating() {
+        let (q, wire, addr) = match srv.read() {
+            Ok(read) => read,
+            Err(e) if socket::is_idle(&e) => continue,
+            Err(e) => {
+                println!("Could not read UDP query: {e}");
+                if let Some(Malformed {
+                    addr,
+                    reply: Some(reply),
+                    ..
+                }) = e.downcast_ref()
+                {
+                    if let Err(e) = srv.send_to(reply, *addr) {
+                        println!("Could not answer {addr}: {e}");
+                    }
+                }
+                continue;
+            }
+        };
+        let args = current(&config);
+        let started = args.metrics.begin();
+        if let Some(dnstap) = &args.dnstap {
+            dnstap.log(dnstap::Event {
+                kind: Kind::ClientQuery,
+                protocol: Protocol::Udp,
+                peer: Some(addr),
+                wire,
+                at: SystemTime::now(),
+            });
+        }
+        if let Err((args, q, addr, started)) = workers.submit((args, q, addr, started)) {
+            println!("Dropped query from {addr}, every UDP worker is busy");
+            observe(&args, "udp", addr.ip(), &q, None, started);
+        }
     }
 
+    shut_down(&current(&config));
     Ok(())
 }
 
-fn resolve_from(addr: &str, msg: &Message) -> Result<Message> {
-    let client = DnsSocket::connect(addr)?;
+/// A UDP query waiting for a worker: the configuration it came in under, the
+/// query, its client and when it came in.
+type UdpJob = (Arc<Args>, Message, SocketAddr, Instant);
 
-    let mut answers = vec![];
-    for q in msg.questions().iter() {
-        let mut query = Message::new(*msg.header());
-        let qs = vec![q.clone()];
-        query.set_questions(qs)?;
+/// Answers `q` from `addr` over `srv`, once `rrl` lets the response through.
+fn answer_udp(
+    args: &Args,
+    srv: &DnsSocket<DnsService>,
+    q: &Message,
+    addr: SocketAddr,
+    started: Instant,
+) {
+    // Only UDP can be spoofed into reflecting responses at a victim.
+    let res = respond(args, q, addr.ip(), Protocol::Udp).and_then(|res| {
+        match args.rrl.check(addr.ip(), &res, Instant::now()) {
+            rrl::Verdict::Send => Some(res),
+            rrl::Verdict::Slip => {
+                let mut slip = Message::new_response(q);
+                slip.set_tc(Truncation::Truncated);
+                Some(slip)
+            }
+            rrl::Verdict::Drop => None,
+        }
+    });
+    let sent = res.as_ref().and_then(|res| match srv.send_to(res, addr) {
+        Ok(size) => {
+            tap(args, Kind::ClientResponse, Protocol::Udp, addr, res);
+            Some((res, size))
+        }
+        Err(e) => {
+            println!("Could not answer {addr}: {e}");
+            None
+        }
+    });
+    observe(args, "udp", addr.ip(), q, sent, started);
+}
+
+/// Waits for the queries still being answered or waiting for a UDP worker,
+/// up to the drain timeout, then ends the dnstap stream.
+fn shut_down(args: &Args) {
+    let deadline = Instant::now() + args.drain;
+    println!("Stopping, {} queries in flight", args.metrics.in_flight());
+    while args.metrics.in_flight() > 0 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    if args.metrics.in_flight() > 0 {
+        println!("Gave up on {} queries", args.metrics.in_flight());
+    }
+    if let Some(dnstap) = &args.dnstap {
+        dnstap.close();
+    }
+    println!("Stopped");
+}
+
+/// Captures `msg`, exchanged with `peer` over `protocol`, if dnstap is on.
+fn tap(args: &Args, kind: Kind, protocol: Protocol, peer: SocketAddr, msg: &Message) {
+    if let Some(dnstap) = &args.dnstap {
+        dnstap.log(dnstap::Event {
+            kind,
+            protocol,
+            peer: Some(peer),
+            wire: msg.flush().to_vec(),
+            at: SystemTime::now(),
+        });
+    }
+}
 
-        client.send(&query)?;
+/// Logs and counts how `q` from `client` was answered over `transport`, `res`
+/// being the response and its size on the wire, `None` when it was dropped.
+fn observe(
+    args: &Args,
+    transport: &'static str,
+    client: IpAddr,
+    q: &Message,
+    res: Option<(&Message, usize)>,
+    started: Instant,
+) {
+    let (span, latency) = (querylog::take(), started.elapsed());
+    args.metrics.end(transport, q, res, latency, &span);
+    if let Some(log) = &args.query_log {
+        log.log(&querylog::Entry {
+            transport,
+            client,
+            query: q,
+            response: res,
+            latency,
+            span,
+        });
+    }
+}
 
-        let msg = client.recv()?;
+/// Response to `q` from `client` over `protocol`, SERVFAIL when it could not
+/// be resolved. Signed queries get signed responses, or NOTAUTH when their
+/// TSIG fails. NOTIFY and UPDATE messages are handled rather than resolved.
+/// Clients left out by the query ACL are turned away. Responses too big for
+/// UDP are truncated before being signed, leaving room for the signature.
+fn respond(args: &Args, q: &Message, client: IpAddr, protocol: Protocol) -> Option<Message> {
+    let now = tsig::now();
+    let session = match tsig::accept(&args.keys, q, now) {
+        Ok(session) => session,
+        Err(e) => {
+            println!("Rejected query from {client}: {e}");
+            return tsig::reject(&args.keys, q, e, now).ok();
+        }
+    };
 
-        let mut ans: Vec<Route> = msg.answers().clone();
-        answers.append(&mut ans)
+    let key = session.as_ref().map(|s| s.key().name.as_str());
+    let res = match q.header().op_code {
+        _ if !admitted(&args.allow_query, client, key) => {
+            println!("Denied query from {client}");
+            Ok(args.denial.response(q))
+        }
+        op if op == OpCode::notify() => Ok(Some(notified(args, q, client, key))),
+        op if op == OpCode::update() => Ok(updated(args, q, client, key)),
+        _ => resolve(args, q, client, key, protocol),
+    };
+    let mut res = res.unwrap_or_else(|e| {
+        if e.is::<SocketError>() {
+            println!("Upstream timed out: {e}");
+        } else {
+            println!("Could not resolve query: {e}");
+        }
+        let mut msg = Message::new_response(q);
+        msg.set_r_code(OpCode::server_failure());
+        Some(msg)
+    })?;
+    if protocol == Protocol::Udp {
+        let tsig = session.as_ref().map_or(0, |s| s.size());
+        res.truncate(q.udp_limit().saturating_sub(tsig));
     }
+    if let Some(mut session) = session {
+        if let Err(e) = session.sign(&mut res, now) {
+            println!("Could not sign response: {e}");
+            return None;
+        }
+    }
+    Some(res)
+}
 
-    let mut msg = Message::new_response(msg);
-    msg.set_answers(answers)?;
-    Ok(msg)
+/// Whether `acl`, open to everyone when unset, lets in `client` signing with
+/// `key` if any.
+fn admitted(acl: &Option<Acl>, client: IpAddr, key: Option<&str>) -> bool {
+    match acl {
+        Some(acl) => acl.allows(client, key),
+        None => true,
+    }
 }
 
-fn look_up_local(query: Message) -> Result<Message> {
-    let mut msg = Message::new_response(&query);
+/// Acknowledges a NOTIFY from `client`, signed with `key` if any, telling that
+/// a secondary zone changed on its primary, which is then checked right away.
+fn notified(args: &Args, q: &Message, client: IpAddr, key: Option<&str>) -> Message {
+    let mut res = Message::new_response(q);
+    res.set_aa(Authoritative::Owned);
+    let Some(d) = q.questions().first().filter(|d| d.record == Record::SOA) else {
+        res.set_r_code(OpCode::format_error());
+        return res;
+    };
+    let origin = d.name.trim_end_matches('.');
+    match args
+        .secondaries
+        .iter()
+        .find(|s| s.origin.eq_ignore_ascii_case(origin))
+    {
+        Some(secondary) if secondary.is_primary(client, key) => {
+            println!("Zone {origin} changed on {client}");
+            secondary.notify();
+        }
+        Some(_) => {
+            println!("Refused NOTIFY for {origin} from {client}");
+            res.set_r_code(OpCode::refused());
+        }
+        None => res.set_r_code(OpCode::not_auth()),
+    }
+    res
+}
 
-    let ip = Ipv4Addr::new(8, 8, 8, 8);
-    let data = Data::Ipv4(ip);
-    let ans: Vec<_> = query
+/// Applies an UPDATE from `client`, signed with `key` if any, to a local zone.
+/// Secondary zones only change through their primary.
+fn updated(args: &Args, q: &Message, client: IpAddr, key: Option<&str>) -> Option<Message> {
+    let origin = q
         .questions()
+        .first()
+        .map_or("", |d| d.name.trim_end_matches('.'));
+    if !admitted(&args.allow_update_from, client, key) {
+        println!("Denied UPDATE of {origin} from {client}");
+        return args.denial.response(q);
+    }
+    let secondary = args
+        .secondaries
         .iter()
-        .cloned()
-        .map(|d| Route::new(d, 60, data))
-        .collect();
-    msg.set_answers(ans)?;
+        .any(|s| s.origin.eq_ignore_ascii_case(origin));
+    let code = match secondary {
+        true => OpCode::not_auth(),
+        false => update::update(&args.zones, q, &args.update_policy, key),
+    };
+    println!("UPDATE of {origin} from {client}: code {}", code.0);
+    let mut res = Message::new_response(q);
+    res.set_r_code(code);
+    Some(res)
+}
+
+/// Responses to `q` from `client` over a stream, where zone transfers run.
+fn respond_stream(args: &Args, q: &Message, client: IpAddr, protocol: Protocol) -> Vec<Message> {
+    match q.questions().first() {
+        Some(d) if matches!(d.record, Record::AXFR | Record::IXFR) => transfer(args, q, client),
+        _ => respond(args, q, client, protocol).into_iter().collect(),
+    }
+}
+
+/// Streams a zone, or its changes for IXFR, to `client` if the transfer ACL
+/// allows it, signing every message when the query was signed.
+fn transfer(args: &Args, q: &Message, client: IpAddr) -> Vec<Message> {
+    let now = tsig::now();
+    let mut session = match tsig::accept(&args.keys, q, now) {
+        Ok(session) => session,
+        Err(e) => {
+            println!("Rejected transfer for {client}: {e}");
+            return tsig::reject(&args.keys, q, e, now).into_iter().collect();
+        }
+    };
+    let key = session.as_ref().map(|s| s.key().name.as_str());
+    let origin = &q.questions()[0].name;
+    let zone = args.zones.get(origin);
+
+    let messages = match zone {
+        Some(zone) if args.allow_transfer.allows(client, key) => {
+            let record = q.questions()[0].record;
+            println!("Transferring zone {} to {client} ({record})", zone.origin());
+            match record {
+                Record::IXFR => zone::transfer::ixfr(&zone, q),
+                _ => zone::transfer::axfr(&zone, q),
+            }
+        }
+        _ => {
+            println!("Refused transfer of {origin} to {client}");
+            Ok(args.denial.response(q).into_iter().collect())
+        }
+    };
+    let mut messages = messages.unwrap_or_else(|e| {
+        println!("Could not transfer {origin}: {e}");
+        let mut res = Message::new_response(q);
+        res.set_r_code(OpCode::server_failure());
+        vec![res]
+    });
+
+    if let Some(session) = session.as_mut() {
+        for m in messages.iter_mut() {
+            if let Err(e) = session.sign(m, now) {
+                println!("Could not sign transfer of {origin}: {e}");
+                return vec![];
+            }
+        }
+    }
+    messages
+}
+
+/// Answers every question of `msg` from `client`, signed with `key` if any;
+/// `None` when a policy drops it.
+fn resolve(
+    args: &Args,
+    msg: &Message,
+    client: IpAddr,
+    key: Option<&str>,
+    protocol: Protocol,
+) -> Result<Option<Message>> {
+    let mut responses = vec![];
+    for q in msg.questions().iter() {
+        let mut query = Message::new(*msg.header());
+        query.set_questions(vec![q.clone()])?;
+        query.set_edns(msg.edns().copied())?;
+        match answer(args, &query, client, key, protocol)? {
+            Some(res) => responses.push(res),
+            None => return Ok(None),
+        }
+    }
+
+    let mut res = Message::new_merged(msg, responses)?;
+    res.set_ra(
+        match !args.forwards.is_empty() && admitted(&args.allow_recursion, client, key) {
+            true => Recursion::Enabled,
+            false => Recursion::Disabled,
+        },
+    );
+    Ok(Some(res))
+}
+
+/// Applies policy zones around everything else: blocked names are answered on
+/// behalf of their blocklist, pinned hosts and names of local zones from local
+/// data, and the rest is forwarded as long as the client asked for recursion.
+/// TCP-only policies only apply to queries over UDP.
+fn answer(
+    args: &Args,
+    query: &Message,
+    client: IpAddr,
+    key: Option<&str>,
+    protocol: Protocol,
+) -> Result<Option<Message>> {
+    let Some(q) = query.questions().first() else {
+        return Ok(Some(Message::new_response(query)));
+    };
+    // Zone transfers only run over TCP, see `transfer`.
+    if matches!(q.record, Record::AXFR | Record::IXFR) {
+        let mut res = Message::new_response(query);
+        res.set_r_code(OpCode::refused());
+        return Ok(Some(res));
+    }
+
+    let applies = |hit: &Hit| match hit.policy {
+        Policy::Passthru => false,
+        Policy::TcpOnly => protocol == Protocol::Udp,
+        _ => true,
+    };
+    let passthru = match args.rpz.check_query(q, client) {
+        Some(hit) if applies(&hit) => return apply_policy(args, query, client, key, &hit),
+        Some(_) => true,
+        None => false,
+    };
+
+    if let Some(list) = args.blocker.check(q) {
+        println!("Blocked {} by {} ({} hits)", q.name, list.name, list.hits());
+        querylog::record(|s| s.source = Some(Source::Blocked));
+        return list.action.answer(query).map(Some);
+    }
+    if let Some(res) = look_up_local(args, query)? {
+        querylog::record(|s| s.source = Some(Source::Local));
+        return Ok(Some(res));
+    }
+    if args.forwards.is_empty() || query.header().rd == Recursion::Disabled {
+        let mut res = Message::new_response(query);
+        res.set_r_code(OpCode::refused());
+        return Ok(Some(res));
+    }
+    if !admitted(&args.allow_recursion, client, key) {
+        println!("Denied recursion for {} to {client}", q.name);
+        return Ok(args.denial.response(query));
+    }
+
+    querylog::record(|s| s.source = Some(Source::Upstream));
+    let res = resolve_from(args, query)?;
+    match args.rpz.check_response(&res) {
+        Some(hit) if !passthru && applies(&hit) => apply_policy(args, query, client, key, &hit),
+        _ => Ok(Some(res)),
+    }
+}
+
+/// Answers `query` from `client` as a policy zone says, resolving the target
+/// of a CNAME rewrite for clients allowed recursion.
+fn apply_policy(
+    args: &Args,
+    query: &Message,
+    client: IpAddr,
+    key: Option<&str>,
+    hit: &Hit,
+) -> Result<Option<Message>> {
+    let q = &query.questions()[0];
+    println!("Policy zone {} applied to {}", hit.zone, q.name);
+    querylog::record(|s| s.source = Some(Source::Policy));
+    let Some(mut res) = hit.policy.answer(query)? else {
+        return Ok(None);
+    };
+
+    let target = match res.answers().last().map(|r| (r.domain().record, r.data())) {
+        Some((Record::CNAME, Data::Name(target))) => target.clone(),
+        _ => return Ok(Some(res)),
+    };
+    if q.record == Record::CNAME
+        || args.forwards.is_empty()
+        || query.header().rd == Recursion::Disabled
+        || !admitted(&args.allow_recursion, client, key)
+    {
+        return Ok(Some(res));
+    }
+
+    let mut chase = Message::new(*query.header());
+    chase.set_questions(vec![Domain {
+        name: target,
+        record: q.record,
+        class: q.class,
+    }])?;
+    chase.set_edns(query.edns().copied())?;
+    let chased = resolve_from(args, &chase)?;
+    let mut answers = res.answers().clone();
+    answers.extend(chased.answers().iter().cloned());
+    res.set_answers(answers)?;
+    res.set_r_code(chased.header().r_code);
+    Ok(Some(res))
+}
+
+/// Forwards each question of `msg` to the upstreams of its name. The DO and
+/// CD bits of the client go along so DNSSEC records come back, but nothing
+/// is validated here: the AD bit of upstreams is cleared, since it cannot be
+/// trusted over an unauthenticated path (RFC 6840 section 5.8). Responses
+/// are taken from the cache when it has them, and answered exchanges are
+/// captured by dnstap if on.
+fn resolve_from(args: &Args, msg: &Message) -> Result<Message> {
+    let mut responses = vec![];
+    for q in msg.questions().iter() {
+        let mut header = Header::query(PacketId::random());
+        header.rd = msg.header().rd;
+        header.cd = msg.header().cd;
+        let mut query = Message::new(header);
+        let dnssec_ok = msg.edns().is_some_and(|e| e.dnssec_ok);
+        query.set_edns(Some(Edns::new(dnssec_ok)))?;
+        let qs = match args.randomize_case {
+            true => vec![q.randomize_case()],
+            false => vec![q.clone()],
+        };
+        query.set_questions(qs)?;
+
+        let Some(rule) = args.forwards.route(&q.name) else {
+            let mut res = Message::new_response(&query);
+            res.set_r_code(OpCode::refused());
+            responses.push(res);
+            continue;
+        };
+
+        let now = Instant::now();
+        let cached = args.cache.as_ref().and_then(|c| c.get(&query, now));
+        if args.cache.is_some() {
+            querylog::record(|s| s.cached = Some(cached.is_some()));
+        }
+        let mut res = match cached {
+            Some(res) => res,
+            None => {
+                let res = forward(args, rule, &query)?;
+                if let Some(cache) = &args.cache {
+                    cache.insert(&query, &res, now);
+                }
+                res
+            }
+        };
+        res.set_ad(Authenticity::Unverified);
+        res.records_mut()
+            .filter(|r| r.domain().name.eq_ignore_ascii_case(&q.name))
+            .for_each(|r| r.rename(&q.name));
+        responses.push(res)
+    }
+
+    Message::new_merged(msg, responses)
+}
+
+/// Asks the upstreams of `rule`, capturing the exchange if dnstap is on.
+fn forward(args: &Args, rule: &Rule, query: &Message) -> Result<Message> {
+    let sent = SystemTime::now();
+    let (res, upstream) = rule.query(query)?;
+    if let Some(dnstap) = &args.dnstap {
+        let protocol = match upstream.transport {
+            Transport::Udp => Protocol::Udp,
+            Transport::Tcp => Protocol::Tcp,
+            Transport::Http { .. } => Protocol::Doh,
+        };
+        let peer = upstream.addr.parse().ok();
+        for (kind, msg, at) in [
+            (Kind::ForwarderQuery, query, sent),
+            (Kind::ForwarderResponse, &res, SystemTime::now()),
+        ] {
+            dnstap.log(dnstap::Event {
+                kind,
+                protocol,
+                peer,
+                wire: msg.flush().to_vec(),
+                at,
+            });
+        }
+    }
+    Ok(res)
+}
+
+/// Answers `query` from pinned hosts or local zones, `None` when neither knows the name.
+fn look_up_local(args: &Args, query: &Message) -> Result<Option<Message>> {
+    let Some(q) = query.questions().first() else {
+        return Ok(None);
+    };
+    let mut msg = Message::new_response(query);
+
+    if let Some(answers) = args.hosts.look_up(q) {
+        msg.set_answers(answers)?;
+        return Ok(Some(msg));
+    }
+
+    if let Some(zone) = args.zones.find(&q.name) {
+        let mut lookup = zone.look_up(q);
+        if query.edns().is_some_and(|e| e.dnssec_ok) {
+            zone.add_dnssec(q, &mut lookup);
+        }
+        if lookup.authoritative {
+            msg.set_aa(Authoritative::Owned);
+        }
+        msg.set_r_code(lookup.r_code);
+        msg.set_answers(lookup.answers)?;
+        msg.set_authorities(lookup.authorities)?;
+        msg.set_additionals(lookup.additionals)?;
+        return Ok(Some(msg));
+    }
 
-    Ok(msg)
+    Ok(None)
 }
@@ -1,18 +1,26 @@
 pub mod data;
 pub mod domain;
+pub mod edns;
 pub mod header;
 pub mod route;
+pub mod tsig;
 use anyhow::Result;
 use domain::Domain;
+use edns::Edns;
 pub use header::Header;
-use header::{OpCode, QueryMode};
+use header::{Authenticity, Authoritative, OpCode, QueryMode, Recursion, Truncation};
 use route::Route;
+use tsig::Tsig;
 
 #[derive(Clone, Debug)]
 pub struct Message {
     header: Header,
     questions: Vec<Domain>,
     answers: Vec<Route>,
+    authorities: Vec<Route>,
+    additionals: Vec<Route>,
+    edns: Option<Edns>,
+    tsig: Option<Tsig>,
 }
 
 impl Message {
@@ -20,28 +28,88 @@ impl Message {
         let mut header = header;
         header.qd_count = 0;
         header.an_count = 0;
+        header.ns_count = 0;
+        header.ar_count = 0;
         Self {
             header,
             questions: Default::default(),
             answers: Default::default(),
+            authorities: Default::default(),
+            additionals: Default::default(),
+            edns: None,
+            tsig: None,
         }
     }
 
+    /// Response to `query` without any record yet. EDNS queries get EDNS
+    /// responses, their DO bit echoed; operations other than QUERY, NOTIFY
+    /// and UPDATE are not implemented.
     pub fn new_response(query: &Message) -> Self {
         let mut header = Header::response(query.header.id);
         header.op_code = query.header.op_code;
         header.rd = query.header.rd;
-        header.r_code = if query.header.op_code == OpCode(0) {
-            OpCode::no_error()
-        } else {
-            OpCode::not_implemented()
-        };
+        header.cd = query.header.cd;
+        header.r_code =
+            if [OpCode(0), OpCode::notify(), OpCode::update()].contains(&query.header.op_code) {
+                OpCode::no_error()
+            } else {
+                OpCode::not_implemented()
+            };
         header.qd_count = query.header.qd_count;
+        let edns = query.edns.map(|e| Edns::new(e.dnssec_ok));
+        header.ar_count = edns.is_some() as u16;
         Self {
             header,
             questions: query.questions.clone(),
             answers: Default::default(),
+            authorities: Default::default(),
+            additionals: Default::default(),
+            edns,
+            tsig: None,
+        }
+    }
+
+    /// Answers `query` with the responses obtained for each of its questions,
+    /// keeping their records, response codes and flags.
+    pub fn new_merged(query: &Message, responses: Vec<Message>) -> Result<Self> {
+        let mut msg = Self::new_response(query);
+        if responses.is_empty() {
+            return Ok(msg);
+        }
+
+        let all = |f: fn(&Header) -> bool| responses.iter().all(|r| f(&r.header));
+        if all(|h| h.aa == Authoritative::Owned) {
+            msg.header.aa = Authoritative::Owned;
+        }
+        if all(|h| h.ra == Recursion::Enabled) {
+            msg.header.ra = Recursion::Enabled;
+        }
+        if all(|h| h.ad == Authenticity::Authentic) {
+            msg.header.ad = Authenticity::Authentic;
+        }
+        if !all(|h| h.tc == Truncation::Complete) {
+            msg.header.tc = Truncation::Truncated;
+        }
+        msg.header.r_code = OpCode::combine(responses.iter().map(|r| r.header.r_code));
+
+        let (mut answers, mut authorities, mut additionals) = (vec![], vec![], vec![]);
+        for r in responses {
+            answers.extend(r.answers);
+            for ns in r.authorities {
+                if !authorities.contains(&ns) {
+                    authorities.push(ns)
+                }
+            }
+            for ar in r.additionals {
+                if !additionals.contains(&ar) {
+                    additionals.push(ar)
+                }
+            }
         }
+        msg.set_answers(answers)?;
+        msg.set_authorities(authorities)?;
+        msg.set_additionals(additionals)?;
+        Ok(msg)
     }
 }
 
@@ -50,10 +118,36 @@ impl Message {
         &self.header
     }
 
+    pub fn set_r_code(&mut self, code: OpCode) {
+        self.header.r_code = code;
+    }
+
+    pub fn set_aa(&mut self, aa: Authoritative) {
+        self.header.aa = aa;
+    }
+
+    pub fn set_tc(&mut self, tc: Truncation) {
+        self.header.tc = tc;
+    }
+
+    pub fn set_ra(&mut self, ra: Recursion) {
+        self.header.ra = ra;
+    }
+
+    pub fn set_ad(&mut self, ad: Authenticity) {
+        self.header.ad = ad;
+    }
+
     pub fn is_query(&self) -> bool {
         self.header.qr == QueryMode::Query
     }
 
+    /// Whether this message answers `query`: same ID and the exact same questions,
+    /// letter case included.
+    pub fn is_response_to(&self, query: &Message) -> bool {
+        !self.is_query() && self.header.id == query.header.id && self.questions == query.questions
+    }
+
     pub fn questions(&self) -> &Vec<Domain> {
         &self.questions
     }
@@ -85,12 +179,100 @@ impl Message {
         self.answers = ans;
         Ok(())
     }
+
+    pub fn authorities(&self) -> &Vec<Route> {
+        &self.authorities
+    }
+
+    pub fn set_authorities(&mut self, ns: Vec<Route>) -> Result<()> {
+        anyhow::ensure!(
+            ns.len() <= u16::MAX as usize,
+            "Exceed supported max number of authorities: {}",
+            ns.len()
+        );
+
+        self.header.ns_count = ns.len() as u16;
+        self.authorities = ns;
+        Ok(())
+    }
+
+    pub fn additionals(&self) -> &Vec<Route> {
+        &self.additionals
+    }
+
+    pub fn set_additionals(&mut self, ar: Vec<Route>) -> Result<()> {
+        anyhow::ensure!(
+            ar.len() < u16::MAX as usize,
+            "Exceed supported max number of additionals: {}",
+            ar.len()
+        );
+
+        self.header.ar_count =
+            ar.len() as u16 + self.edns.is_some() as u16 + self.tsig.is_some() as u16;
+        self.additionals = ar;
+        Ok(())
+    }
+
+    /// The OPT pseudo-record, written after every additional record.
+    pub fn edns(&self) -> Option<&Edns> {
+        self.edns.as_ref()
+    }
+
+    pub fn set_edns(&mut self, edns: Option<Edns>) -> Result<()> {
+        self.edns = edns;
+        let ar = std::mem::take(&mut self.additionals);
+        self.set_additionals(ar)
+    }
+
+    /// The TSIG pseudo-record, written last.
+    pub fn tsig(&self) -> Option<&Tsig> {
+        self.tsig.as_ref()
+    }
+
+    pub fn set_tsig(&mut self, tsig: Option<Tsig>) -> Result<()> {
+        self.tsig = tsig;
+        let ar = std::mem::take(&mut self.additionals);
+        self.set_additionals(ar)
+    }
+
+    /// Largest UDP response the sender of this query takes: its EDNS payload
+    /// size within 512 and [`edns::UDP_PAYLOAD`], or 512 without EDNS.
+    pub fn udp_limit(&self) -> usize {
+        match self.edns {
+            Some(e) => e.udp_payload.clamp(512, edns::UDP_PAYLOAD) as usize,
+            None => 512,
+        }
+    }
+
+    /// Drops every record but OPT and TSIG and sets TC when the message takes
+    /// more than `limit` bytes, so the client asks again over TCP.
+    pub fn truncate(&mut self, limit: usize) {
+        if self.flush().len() <= limit {
+            return;
+        }
+        self.header.tc = Truncation::Truncated;
+        self.answers.clear();
+        self.authorities.clear();
+        self.additionals.clear();
+        self.header.an_count = 0;
+        self.header.ns_count = 0;
+        self.header.ar_count = self.edns.is_some() as u16 + self.tsig.is_some() as u16;
+    }
+
+    /// Every record of the message, answers first.
+    pub fn records_mut(&mut self) -> impl Iterator<Item = &mut Route> {
+        self.answers
+            .iter_mut()
+            .chain(self.authorities.iter_mut())
+            .chain(self.additionals.iter_mut())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use domain::Domain;
+    use edns::Edns;
     use header::PacketId;
     use route::Route;
     use std::net::Ipv4Addr;
@@ -128,6 +310,51 @@ mod tests {
         assert!(msg.questions().contains(&q));
     }
 
+    #[test]
+    fn test_message_is_response_to() {
+        let mut query = Message::new(Header::query(PacketId(42)));
+        query
+            .set_questions(vec![Domain::new_aa("HeRnAn.rs")])
+            .unwrap();
+
+        let msg = Message::new_response(&query);
+        assert!(msg.is_response_to(&query));
+        assert!(!query.is_response_to(&query));
+
+        let mut other = Message::new_response(&query);
+        other.header.id = PacketId(43);
+        assert!(!other.is_response_to(&query));
+
+        let mut other = Message::new_response(&query);
+        other
+            .set_questions(vec![Domain::new_aa("hernan.rs")])
+            .unwrap();
+        assert!(!other.is_response_to(&query));
+    }
+
+    #[test]
+    fn test_message_merged() {
+        let mut query = Message::new(Header::query(PacketId(42)));
+        let q = Domain::new_aa("hernan.rs");
+        query.set_questions(vec![q.clone()]).unwrap();
+
+        let mut upstream = Message::new_response(&query);
+        upstream.header.id = PacketId(7);
+        upstream.header.ra = Recursion::Enabled;
+        upstream.header.r_code = OpCode::name_error();
+        let ns = Route::new(q, 60, data::Data::Ipv4(Ipv4Addr::LOCALHOST));
+        upstream.set_authorities(vec![ns.clone()]).unwrap();
+
+        let msg = Message::new_merged(&query, vec![upstream]).unwrap();
+
+        assert_eq!(msg.header().id, PacketId(42));
+        assert_eq!(msg.header().r_code, OpCode::name_error());
+        assert_eq!(msg.header().ra, Recursion::Enabled);
+        assert_eq!(msg.header().aa, Authoritative::Unowned);
+        assert_eq!(msg.header().ns_count, 1);
+        assert!(msg.authorities().contains(&ns));
+    }
+
     #[test]
     fn test_message_answers() {
         let h = Header {
@@ -144,4 +371,55 @@ mod tests {
         assert_eq!(msg.header().an_count, 1);
         assert!(msg.answers().contains(&a));
     }
+
+    #[test]
+    fn test_message_edns() {
+        let mut query = Message::new(Header::query(PacketId(42)));
+        assert_eq!(Message::new_response(&query).edns(), None);
+
+        query.set_edns(Some(Edns::from_ttl(4096, 0x8000))).unwrap();
+        assert_eq!(query.header().ar_count, 1);
+
+        let mut msg = Message::new_response(&query);
+        assert_eq!(msg.edns(), Some(&Edns::new(true)));
+        assert_eq!(msg.header().ar_count, 1);
+
+        let a = Route::new(
+            Domain::new_aa("hernan.rs"),
+            60,
+            data::Data::Ipv4(Ipv4Addr::LOCALHOST),
+        );
+        msg.set_additionals(vec![a]).unwrap();
+        assert_eq!(msg.header().ar_count, 2);
+        msg.set_edns(None).unwrap();
+        assert_eq!(msg.header().ar_count, 1);
+    }
+
+    #[test]
+    fn test_message_truncate() {
+        let mut query = Message::new(Header::query(PacketId(42)));
+        assert_eq!(query.udp_limit(), 512);
+        query.set_edns(Some(Edns::from_ttl(4096, 0))).unwrap();
+        assert_eq!(query.udp_limit(), 1232);
+        query.set_edns(Some(Edns::from_ttl(100, 0))).unwrap();
+        assert_eq!(query.udp_limit(), 512);
+
+        let mut msg = Message::new_response(&query);
+        let a = Route::new(
+            Domain::new_aa("hernan.rs"),
+            60,
+            data::Data::Ipv4(Ipv4Addr::LOCALHOST),
+        );
+        msg.set_answers(vec![a; 40]).unwrap();
+        let size = msg.flush().len();
+        msg.truncate(size);
+        assert_eq!(msg.header().tc, Truncation::Complete);
+
+        msg.truncate(512);
+        assert_eq!(msg.header().tc, Truncation::Truncated);
+        assert!(msg.answers().is_empty());
+        assert_eq!(msg.header().an_count, 0);
+        assert_eq!(msg.header().ar_count, 1);
+        assert!(msg.edns().is_some());
+    }
 }
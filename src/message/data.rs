@@ -1,14 +1,93 @@
-use std::net::Ipv4Addr;
+use std::{
+    fmt,
+    net::{Ipv4Addr, Ipv6Addr},
+};
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Soa {
+    pub mname: String,
+    pub rname: String,
+    pub serial: u32,
+    pub refresh: u32,
+    pub retry: u32,
+    pub expire: u32,
+    pub minimum: u32,
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum Data {
     Ipv4(Ipv4Addr),
+    Ipv6(Ipv6Addr),
+    /// Target of NS, CNAME and PTR records.
+    Name(String),
+    Mx {
+        preference: u16,
+        exchange: String,
+    },
+    Soa(Soa),
+    /// Record data kept as it was on the wire.
+    Raw(Vec<u8>),
+}
+
+/// Length of `name` once written out as uncompressed labels.
+pub fn name_len(name: &str) -> u16 {
+    name.split('.')
+        .filter(|l| !l.is_empty())
+        .map(|l| l.len() as u16 + 1)
+        .sum::<u16>()
+        + 1
 }
 
 impl Data {
+    /// Length of the record data on the wire, which may well be zero.
+    #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> u16 {
         match self {
             Self::Ipv4(ip) => ip.octets().len() as u16,
+            Self::Ipv6(ip) => ip.octets().len() as u16,
+            Self::Name(name) => name_len(name),
+            Self::Mx { exchange, .. } => 2 + name_len(exchange),
+            Self::Soa(soa) => name_len(&soa.mname) + name_len(&soa.rname) + 20,
+            Self::Raw(bytes) => bytes.len() as u16,
+        }
+    }
+}
+
+/// `name` with its trailing dot, as in presentation format.
+pub fn absolute(name: &str) -> String {
+    format!("{}.", name.trim_end_matches('.'))
+}
+
+impl fmt::Display for Data {
+    /// Presentation format (RFC 1035 section 5.1), with data the parser kept
+    /// raw in the generic encoding of RFC 3597.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ipv4(ip) => write!(f, "{ip}"),
+            Self::Ipv6(ip) => write!(f, "{ip}"),
+            Self::Name(name) => write!(f, "{}", absolute(name)),
+            Self::Mx {
+                preference,
+                exchange,
+            } => write!(f, "{preference} {}", absolute(exchange)),
+            Self::Soa(soa) => write!(
+                f,
+                "{} {} {} {} {} {} {}",
+                absolute(&soa.mname),
+                absolute(&soa.rname),
+                soa.serial,
+                soa.refresh,
+                soa.retry,
+                soa.expire,
+                soa.minimum
+            ),
+            Self::Raw(bytes) => {
+                write!(f, "\\# {}", bytes.len())?;
+                if !bytes.is_empty() {
+                    write!(f, " ")?;
+                }
+                bytes.iter().try_for_each(|b| write!(f, "{b:02X}"))
+            }
         }
     }
 }
@@ -21,5 +100,45 @@ mod tests {
     fn test_data_len() {
         let d = Data::Ipv4(Ipv4Addr::new(1, 1, 1, 1));
         assert_eq!(d.len(), 4);
+
+        let d = Data::Ipv6(Ipv6Addr::LOCALHOST);
+        assert_eq!(d.len(), 16);
+
+        let d = Data::Name("hernan.rs".to_string());
+        assert_eq!(d.len(), 11);
+    }
+
+    #[test]
+    fn test_display() {
+        let d = Data::Mx {
+            preference: 10,
+            exchange: "mail.hernan.rs".to_string(),
+        };
+        assert_eq!(d.to_string(), "10 mail.hernan.rs.");
+
+        let d = Data::Soa(Soa {
+            mname: "ns.hernan.rs".to_string(),
+            rname: "admin.hernan.rs.".to_string(),
+            serial: 1,
+            refresh: 7200,
+            retry: 3600,
+            expire: 1209600,
+            minimum: 300,
+        });
+        assert_eq!(
+            d.to_string(),
+            "ns.hernan.rs. admin.hernan.rs. 1 7200 3600 1209600 300"
+        );
+
+        assert_eq!(Data::Name(String::new()).to_string(), ".");
+        assert_eq!(Data::Raw(vec![0, 0xab]).to_string(), "\\# 2 00AB");
+        assert_eq!(Data::Raw(vec![]).to_string(), "\\# 0");
+    }
+
+    #[test]
+    fn test_name_len() {
+        assert_eq!(name_len(""), 1);
+        assert_eq!(name_len("rs"), 4);
+        assert_eq!(name_len("hernan.rs."), 11);
     }
 }
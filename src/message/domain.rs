@@ -1,49 +1,398 @@
 use anyhow::Result;
+use rand::Rng;
+use std::{
+    cmp::Ordering,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Record {
-    AA = 1,
+    AA,
+    NS,
+    CNAME,
+    SOA,
+    PTR,
+    MX,
+    TXT,
+    AAAA,
+    SRV,
+    DS,
+    RRSIG,
+    NSEC,
+    DNSKEY,
+    NSEC3,
+    NSEC3PARAM,
+    SVCB,
+    HTTPS,
+    /// Question type asking for the changes to a zone (RFC 1995).
+    IXFR,
+    /// Question type asking for a whole zone (RFC 5936).
+    AXFR,
+    /// Question type matching every record.
+    ANY,
+    CAA,
+    /// Any other type, its data kept as it was on the wire (RFC 3597).
+    Unknown(u16),
 }
 
-impl TryFrom<u16> for Record {
-    type Error = anyhow::Error;
-    fn try_from(value: u16) -> Result<Self> {
-        match value {
-            x if x == Self::AA as u16 => Ok(Self::AA),
-            _ => Err(anyhow::anyhow!("Not a record: {value}")),
+impl Record {
+    const ALL: [Self; 21] = [
+        Self::AA,
+        Self::NS,
+        Self::CNAME,
+        Self::SOA,
+        Self::PTR,
+        Self::MX,
+        Self::TXT,
+        Self::AAAA,
+        Self::SRV,
+        Self::DS,
+        Self::RRSIG,
+        Self::NSEC,
+        Self::DNSKEY,
+        Self::NSEC3,
+        Self::NSEC3PARAM,
+        Self::SVCB,
+        Self::HTTPS,
+        Self::IXFR,
+        Self::AXFR,
+        Self::ANY,
+        Self::CAA,
+    ];
+}
+
+impl From<Record> for u16 {
+    fn from(record: Record) -> Self {
+        match record {
+            Record::AA => 1,
+            Record::NS => 2,
+            Record::CNAME => 5,
+            Record::SOA => 6,
+            Record::PTR => 12,
+            Record::MX => 15,
+            Record::TXT => 16,
+            Record::AAAA => 28,
+            Record::SRV => 33,
+            Record::DS => 43,
+            Record::RRSIG => 46,
+            Record::NSEC => 47,
+            Record::DNSKEY => 48,
+            Record::NSEC3 => 50,
+            Record::NSEC3PARAM => 51,
+            Record::SVCB => 64,
+            Record::HTTPS => 65,
+            Record::IXFR => 251,
+            Record::AXFR => 252,
+            Record::ANY => 255,
+            Record::CAA => 257,
+            Record::Unknown(value) => value,
+        }
+    }
+}
+
+impl From<u16> for Record {
+    fn from(value: u16) -> Self {
+        Self::ALL
+            .into_iter()
+            .find(|r| u16::from(*r) == value)
+            .unwrap_or(Self::Unknown(value))
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AA => write!(f, "A"),
+            Self::Unknown(value) => write!(f, "TYPE{value}"),
+            r => write!(f, "{r:?}"),
         }
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+/// `n` out of `s` written as `<prefix>n`, as RFC 3597 names unknown types
+/// and classes.
+fn generic(s: &str, prefix: &str) -> Option<u16> {
+    s.get(..prefix.len())
+        .filter(|p| p.eq_ignore_ascii_case(prefix))
+        .and_then(|_| s[prefix.len()..].parse().ok())
+}
+
+impl FromStr for Record {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|r| r.to_string().eq_ignore_ascii_case(s))
+            .or_else(|| generic(s, "TYPE").map(Self::from))
+            .ok_or_else(|| anyhow::anyhow!("Not a record: {s}"))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Class {
-    IN = 1,
+    IN,
+    /// Chaos, where servers answer questions about themselves.
+    CH,
+    /// Class of UPDATE records deleting or asking for an absent RRset (RFC 2136).
+    NONE,
+    /// Class of UPDATE records deleting or asking for any RRset.
+    ANY,
+    Unknown(u16),
 }
 
-impl TryFrom<u16> for Class {
-    type Error = anyhow::Error;
-    fn try_from(value: u16) -> Result<Self> {
-        match value {
-            x if x == Self::IN as u16 => Ok(Self::IN),
-            _ => Err(anyhow::anyhow!("Not a class: {value}")),
+impl Class {
+    const ALL: [Self; 4] = [Self::IN, Self::CH, Self::NONE, Self::ANY];
+}
+
+impl From<Class> for u16 {
+    fn from(class: Class) -> Self {
+        match class {
+            Class::IN => 1,
+            Class::CH => 3,
+            Class::NONE => 254,
+            Class::ANY => 255,
+            Class::Unknown(value) => value,
+        }
+    }
+}
+
+impl From<u16> for Class {
+    fn from(value: u16) -> Self {
+        Self::ALL
+            .into_iter()
+            .find(|c| u16::from(*c) == value)
+            .unwrap_or(Self::Unknown(value))
+    }
+}
+
+impl fmt::Display for Class {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unknown(value) => write!(f, "CLASS{value}"),
+            c => write!(f, "{c:?}"),
+        }
+    }
+}
+
+impl FromStr for Class {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            s if s.eq_ignore_ascii_case("IN") => Ok(Self::IN),
+            s if s.eq_ignore_ascii_case("CH") => Ok(Self::CH),
+            _ => generic(s, "CLASS")
+                .map(Self::from)
+                .ok_or_else(|| anyhow::anyhow!("Not a class: {s}")),
+        }
+    }
+}
+
+/// Whether `name` is `zone` itself or one of its subdomains. Case and trailing
+/// dots are ignored; the empty zone is the root and holds every name.
+pub fn in_zone(name: &str, zone: &str) -> bool {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    let zone = zone.trim_end_matches('.').to_ascii_lowercase();
+    zone.is_empty() || name == zone || name.strip_suffix(&zone).is_some_and(|n| n.ends_with('.'))
+}
+
+/// Canonical DNS name order (RFC 4034 section 6.1): labels compared from the
+/// root down, case folded.
+pub fn canonical_cmp(a: &str, b: &str) -> Ordering {
+    let labels = |n: &str| {
+        n.trim_end_matches('.')
+            .rsplit('.')
+            .filter(|l| !l.is_empty())
+            .map(str::to_ascii_lowercase)
+            .collect::<Vec<_>>()
+    };
+    labels(a).cmp(&labels(b))
+}
+
+/// Name under `in-addr.arpa` or `ip6.arpa` pointing back at `ip`.
+#[cfg(test)]
+pub fn reverse_name(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, d] = ip.octets();
+            format!("{d}.{c}.{b}.{a}.in-addr.arpa")
+        }
+        IpAddr::V6(ip) => {
+            let nibbles: Vec<String> = ip
+                .octets()
+                .iter()
+                .rev()
+                .flat_map(|o| [o & 0xf, o >> 4])
+                .map(|n| format!("{n:x}"))
+                .collect();
+            format!("{}.ip6.arpa", nibbles.join("."))
         }
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+/// The address a full reverse name points back at, if it is one.
+pub fn parse_reverse(name: &str) -> Option<IpAddr> {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    if let Some(labels) = name.strip_suffix(".in-addr.arpa") {
+        let mut octets: Vec<u8> = labels
+            .split('.')
+            .map(|l| l.parse().ok())
+            .collect::<Option<_>>()?;
+        octets.reverse();
+        let octets: [u8; 4] = octets.try_into().ok()?;
+        return Some(IpAddr::V4(Ipv4Addr::from(octets)));
+    }
+
+    let labels = name.strip_suffix(".ip6.arpa")?;
+    let nibbles: Vec<u8> = labels
+        .split('.')
+        .rev()
+        .map(|l| match l.len() {
+            1 => u8::from_str_radix(l, 16).ok(),
+            _ => None,
+        })
+        .collect::<Option<_>>()?;
+    if nibbles.len() != 32 {
+        return None;
+    }
+    let octets: Vec<u8> = nibbles.chunks(2).map(|n| (n[0] << 4) | n[1]).collect();
+    let octets: [u8; 16] = octets.try_into().ok()?;
+    Some(IpAddr::V6(Ipv6Addr::from(octets)))
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub struct Domain {
     pub name: String,
     pub record: Record,
     pub class: Class,
 }
 
-#[cfg(test)]
 impl Domain {
-    pub fn new_aa(name: &str) -> Self {
+    pub fn new(name: &str, record: Record) -> Self {
         Self {
             name: name.to_string(),
-            record: Record::AA,
+            record,
             class: Class::IN,
         }
     }
+
+    /// Flips the case of every letter at random, as in draft-vixie-dnsext-dns0x20.
+    pub fn randomize_case(&self) -> Self {
+        let mut rng = rand::thread_rng();
+        let name = self
+            .name
+            .chars()
+            .map(|c| match rng.gen::<bool>() {
+                true => c.to_ascii_uppercase(),
+                false => c.to_ascii_lowercase(),
+            })
+            .collect();
+        Self {
+            name,
+            ..self.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+impl Domain {
+    pub fn new_aa(name: &str) -> Self {
+        Self::new(name, Record::AA)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_from() {
+        assert_eq!(Record::from(1), Record::AA);
+        assert_eq!(Record::from(28), Record::AAAA);
+        assert_eq!(Record::from(46), Record::RRSIG);
+        assert_eq!(Record::from(52), Record::Unknown(52));
+        assert_eq!(u16::from(Record::Unknown(52)), 52);
+        assert_eq!(u16::from(Record::CAA), 257);
+    }
+
+    #[test]
+    fn test_class_from() {
+        assert_eq!(Class::from(1), Class::IN);
+        assert_eq!(Class::from(3), Class::CH);
+        assert_eq!(Class::from(4), Class::Unknown(4));
+        assert_eq!(Class::Unknown(4).to_string(), "CLASS4");
+        assert_eq!("ch".parse::<Class>().unwrap(), Class::CH);
+        assert_eq!("CLASS4".parse::<Class>().unwrap(), Class::Unknown(4));
+        assert!("XX".parse::<Class>().is_err());
+    }
+
+    #[test]
+    fn test_record_from_str() {
+        assert_eq!("A".parse::<Record>().unwrap(), Record::AA);
+        assert_eq!("cname".parse::<Record>().unwrap(), Record::CNAME);
+        assert_eq!(Record::AA.to_string(), "A");
+        assert!("AA".parse::<Record>().is_err());
+        assert_eq!("TYPE52".parse::<Record>().unwrap(), Record::Unknown(52));
+        assert_eq!("type1".parse::<Record>().unwrap(), Record::AA);
+        assert_eq!(Record::Unknown(52).to_string(), "TYPE52");
+    }
+
+    #[test]
+    fn test_in_zone() {
+        assert!(in_zone("hernan.rs", "hernan.rs."));
+        assert!(in_zone("WWW.Hernan.rs", "hernan.rs"));
+        assert!(in_zone("hernan.rs", ""));
+        assert!(!in_zone("nothernan.rs", "hernan.rs"));
+        assert!(!in_zone("rs", "hernan.rs"));
+    }
+
+    #[test]
+    fn test_canonical_cmp() {
+        let mut names = vec![
+            "z.hernan.rs",
+            "Hernan.rs.",
+            "*.z.hernan.rs",
+            "a.hernan.rs",
+            "rs",
+        ];
+        names.sort_by(|a, b| canonical_cmp(a, b));
+        let order = [
+            "rs",
+            "Hernan.rs.",
+            "a.hernan.rs",
+            "z.hernan.rs",
+            "*.z.hernan.rs",
+        ];
+        assert_eq!(names, order);
+        assert_eq!(canonical_cmp("HERNAN.rs", "hernan.RS"), Ordering::Equal);
+    }
+
+    #[test]
+    fn test_reverse_name() {
+        let v4 = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1));
+        assert_eq!(reverse_name(v4), "1.0.168.192.in-addr.arpa");
+        assert_eq!(parse_reverse("1.0.168.192.IN-ADDR.ARPA."), Some(v4));
+
+        let v6 = IpAddr::V6("2001:db8::1".parse().unwrap());
+        let name = reverse_name(v6);
+        assert!(name.starts_with("1.0.0.0.0.0.0.0"));
+        assert!(name.ends_with("8.b.d.0.1.0.0.2.ip6.arpa"));
+        assert_eq!(parse_reverse(&name), Some(v6));
+
+        assert_eq!(parse_reverse("0.168.192.in-addr.arpa"), None);
+        assert_eq!(parse_reverse("1.0.ip6.arpa"), None);
+        assert_eq!(parse_reverse("hernan.rs"), None);
+    }
+
+    #[test]
+    fn test_randomize_case() {
+        let d = Domain::new_aa("abcdefghijklmnopqrstuvwxyz.hernan.rs");
+        let r = d.randomize_case();
+        assert!(r.name.eq_ignore_ascii_case(&d.name));
+        assert_eq!(r.record, d.record);
+        assert_eq!(r.class, d.class);
+    }
 }
@@ -0,0 +1,58 @@
+/// Record type of the OPT pseudo-record.
+pub const OPT: u16 = 41;
+
+/// UDP payload advertised, small enough to avoid IP fragmentation.
+pub const UDP_PAYLOAD: u16 = 1232;
+
+/// The OPT pseudo-record of EDNS(0) (RFC 6891). Options are not kept.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Edns {
+    pub udp_payload: u16,
+    /// Upper eight bits of the twelve bit response code.
+    pub ext_r_code: u8,
+    pub version: u8,
+    /// The DO bit: DNSSEC records are wanted (RFC 3225).
+    pub dnssec_ok: bool,
+}
+
+impl Edns {
+    pub fn new(dnssec_ok: bool) -> Self {
+        Self {
+            udp_payload: UDP_PAYLOAD,
+            ext_r_code: 0,
+            version: 0,
+            dnssec_ok,
+        }
+    }
+
+    /// The TTL field of the OPT record, where the extended flags live.
+    pub fn ttl(&self) -> u32 {
+        let dnssec_ok = if self.dnssec_ok { 0x8000 } else { 0 };
+        (self.ext_r_code as u32) << 24 | (self.version as u32) << 16 | dnssec_ok
+    }
+
+    pub fn from_ttl(udp_payload: u16, ttl: u32) -> Self {
+        Self {
+            udp_payload,
+            ext_r_code: (ttl >> 24) as u8,
+            version: (ttl >> 16) as u8,
+            dnssec_ok: ttl & 0x8000 != 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ttl() {
+        let edns = Edns::new(true);
+        assert_eq!(edns.ttl(), 0x8000);
+        assert_eq!(Edns::from_ttl(UDP_PAYLOAD, edns.ttl()), edns);
+
+        let edns = Edns::from_ttl(4096, 0x0100_0000);
+        assert_eq!(edns.ext_r_code, 1);
+        assert!(!edns.dnssec_ok);
+    }
+}
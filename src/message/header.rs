@@ -1,6 +1,12 @@
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct PacketId(pub u16);
 
+impl PacketId {
+    pub fn random() -> Self {
+        Self(rand::random())
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum QueryMode {
     Query = 0,
@@ -25,9 +31,90 @@ impl OpCode {
         OpCode(0)
     }
 
+    pub fn format_error() -> Self {
+        OpCode(1)
+    }
+
+    pub fn server_failure() -> Self {
+        OpCode(2)
+    }
+
+    pub fn name_error() -> Self {
+        OpCode(3)
+    }
+
     pub fn not_implemented() -> Self {
         OpCode(4)
     }
+
+    pub fn refused() -> Self {
+        OpCode(5)
+    }
+
+    pub fn not_auth() -> Self {
+        OpCode(9)
+    }
+
+    /// Operation code announcing a zone change (RFC 1996).
+    pub fn notify() -> Self {
+        OpCode(4)
+    }
+
+    /// Operation code changing records of a zone (RFC 2136).
+    pub fn update() -> Self {
+        OpCode(5)
+    }
+
+    pub fn yx_domain() -> Self {
+        OpCode(6)
+    }
+
+    pub fn yx_rrset() -> Self {
+        OpCode(7)
+    }
+
+    pub fn nx_rrset() -> Self {
+        OpCode(8)
+    }
+
+    pub fn not_zone() -> Self {
+        OpCode(10)
+    }
+
+    /// Mnemonic of this value as a response code (RFC 6895 section 2.3).
+    pub fn r_code_name(self) -> &'static str {
+        match self.0 {
+            0 => "NOERROR",
+            1 => "FORMERR",
+            2 => "SERVFAIL",
+            3 => "NXDOMAIN",
+            4 => "NOTIMP",
+            5 => "REFUSED",
+            6 => "YXDOMAIN",
+            7 => "YXRRSET",
+            8 => "NXRRSET",
+            9 => "NOTAUTH",
+            10 => "NOTZONE",
+            _ => "RESERVED",
+        }
+    }
+
+    /// Folds the response codes of several answers into one: any failure wins,
+    /// NXDOMAIN only holds when every name is missing.
+    pub fn combine(codes: impl IntoIterator<Item = Self>) -> Self {
+        let mut all_missing = None;
+        for code in codes {
+            match code {
+                c if c == Self::no_error() => all_missing = Some(false),
+                c if c == Self::name_error() => all_missing = all_missing.or(Some(true)),
+                c => return c,
+            }
+        }
+        match all_missing {
+            Some(true) => Self::name_error(),
+            _ => Self::no_error(),
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -79,7 +166,39 @@ impl From<u8> for Recursion {
 }
 
 #[derive(Clone, Copy, Debug)]
-pub struct Reserved; // 3 bits
+pub struct Reserved; // 1 bit
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Authenticity {
+    Unverified = 0,
+    Authentic = 1,
+}
+
+impl From<u8> for Authenticity {
+    fn from(value: u8) -> Self {
+        if value == Self::Authentic as u8 {
+            Self::Authentic
+        } else {
+            Self::Unverified
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Checking {
+    Enabled = 0,
+    Disabled = 1,
+}
+
+impl From<u8> for Checking {
+    fn from(value: u8) -> Self {
+        if value == Self::Disabled as u8 {
+            Self::Disabled
+        } else {
+            Self::Enabled
+        }
+    }
+}
 
 #[allow(dead_code)]
 #[derive(Clone, Copy, Debug)]
@@ -92,6 +211,8 @@ pub struct Header {
     pub rd: Recursion,
     pub ra: Recursion,
     pub z: Reserved,
+    pub ad: Authenticity,
+    pub cd: Checking,
     pub r_code: OpCode,
     pub qd_count: u16,
     pub an_count: u16,
@@ -110,6 +231,8 @@ impl Default for Header {
             ra: Recursion::Disabled,
             rd: Recursion::Disabled,
             z: Reserved,
+            ad: Authenticity::Unverified,
+            cd: Checking::Enabled,
             r_code: OpCode::default(),
             qd_count: 0,
             an_count: 0,
@@ -120,6 +243,14 @@ impl Default for Header {
 }
 
 impl Header {
+    pub fn query(id: PacketId) -> Self {
+        Self {
+            id,
+            qr: QueryMode::Query,
+            ..Default::default()
+        }
+    }
+
     pub fn response(id: PacketId) -> Self {
         Self {
             id,
@@ -139,6 +270,14 @@ mod tests {
         assert_eq!(h.id, id);
     }
 
+    #[test]
+    fn test_query() {
+        let id = PacketId(42);
+        let h = Header::query(id);
+        assert_eq!(h.id, id);
+        assert_eq!(h.qr, QueryMode::Query);
+    }
+
     #[test]
     fn test_recursion_from() {
         let e = Recursion::from(1u8);
@@ -174,6 +313,22 @@ mod tests {
     #[test]
     fn test_op_codes() {
         assert_eq!(OpCode::no_error().0, 0);
+        assert_eq!(OpCode::server_failure().0, 2);
+        assert_eq!(OpCode::name_error().0, 3);
         assert_eq!(OpCode::not_implemented().0, 4);
+        assert_eq!(OpCode::refused().0, 5);
+        assert_eq!(OpCode::name_error().r_code_name(), "NXDOMAIN");
+        assert_eq!(OpCode(15).r_code_name(), "RESERVED");
+    }
+
+    #[test]
+    fn test_combine_r_codes() {
+        let ok = OpCode::no_error();
+        let nx = OpCode::name_error();
+        let fail = OpCode::server_failure();
+        assert_eq!(OpCode::combine([]), ok);
+        assert_eq!(OpCode::combine([ok, nx]), ok);
+        assert_eq!(OpCode::combine([nx, nx]), nx);
+        assert_eq!(OpCode::combine([nx, fail, ok]), fail);
     }
 }
@@ -1,6 +1,10 @@
-use super::{data::Data, domain::Domain};
+use super::{
+    data::{absolute, Data},
+    domain::{Domain, Record},
+};
+use std::fmt::{self, Write};
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub struct Route {
     domain: Domain,
     ttl: u32,
@@ -16,13 +20,74 @@ impl Route {
         &self.domain
     }
 
+    pub fn rename(&mut self, name: &str) {
+        self.domain.name = name.to_string();
+    }
+
     pub fn ttl(&self) -> u32 {
         self.ttl
     }
 
+    pub fn set_ttl(&mut self, ttl: u32) {
+        self.ttl = ttl;
+    }
+
     pub fn data(&self) -> &Data {
         &self.data
     }
+
+    /// The data in presentation format, TXT strings quoted.
+    pub fn rdata(&self) -> String {
+        match (&self.data, self.domain.record) {
+            (Data::Raw(bytes), Record::TXT) if !bytes.is_empty() => {
+                quoted_strings(bytes).unwrap_or_else(|| self.data.to_string())
+            }
+            (data, _) => data.to_string(),
+        }
+    }
+}
+
+/// The character strings of TXT data, quoted, or `None` when they overrun it.
+fn quoted_strings(mut bytes: &[u8]) -> Option<String> {
+    let mut out = String::new();
+    while let Some((&len, rest)) = bytes.split_first() {
+        let s = rest.get(..len as usize)?;
+        if !out.is_empty() {
+            out.push(' ');
+        }
+        out.push('"');
+        for &b in s {
+            match b {
+                b'"' | b'\\' => {
+                    out.push('\\');
+                    out.push(b as char);
+                }
+                0x20..=0x7e => out.push(b as char),
+                _ => {
+                    let _ = write!(out, "\\{b:03}");
+                }
+            }
+        }
+        out.push('"');
+        bytes = &rest[len as usize..];
+    }
+    Some(out)
+}
+
+impl fmt::Display for Route {
+    /// A master file line: owner, TTL, class, type and data.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let d = &self.domain;
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}",
+            absolute(&d.name),
+            self.ttl,
+            d.class,
+            d.record,
+            self.rdata()
+        )
+    }
 }
 
 #[cfg(test)]
@@ -34,9 +99,25 @@ mod tests {
     fn test_answer_aa() {
         let dn = Domain::new_aa("hernan.rs");
         let d = Data::Ipv4(Ipv4Addr::new(1, 1, 1, 1));
-        let a = Route::new(dn.clone(), 60, d);
+        let a = Route::new(dn.clone(), 60, d.clone());
         assert_eq!(a.domain, dn);
         assert_eq!(a.ttl(), 60);
         assert_eq!(a.data(), &d);
     }
+
+    #[test]
+    fn test_display() {
+        let d = Data::Ipv4(Ipv4Addr::new(1, 1, 1, 1));
+        let a = Route::new(Domain::new_aa("hernan.rs"), 60, d);
+        assert_eq!(a.to_string(), "hernan.rs.\t60\tIN\tA\t1.1.1.1");
+
+        let txt = Domain::new("hernan.rs", Record::TXT);
+        let a = Route::new(txt.clone(), 0, Data::Raw(b"\x02a\"\x02\x01b".to_vec()));
+        assert_eq!(
+            a.to_string(),
+            "hernan.rs.\t0\tIN\tTXT\t\"a\\\"\" \"\\001b\""
+        );
+        let a = Route::new(txt, 0, Data::Raw(vec![5, b'a']));
+        assert_eq!(a.to_string(), "hernan.rs.\t0\tIN\tTXT\t\\# 2 0561");
+    }
 }
@@ -0,0 +1,28 @@
+/// Record type of the TSIG pseudo-record.
+pub const TSIG: u16 = 250;
+
+/// Class of TSIG records.
+pub const ANY: u16 = 255;
+
+/// Errors carried in the TSIG record itself (RFC 8945 section 3).
+pub const BADSIG: u16 = 16;
+pub const BADKEY: u16 = 17;
+pub const BADTIME: u16 = 18;
+
+/// The TSIG pseudo-record signing a message (RFC 8945), always its last
+/// additional record.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Tsig {
+    pub key_name: String,
+    pub algorithm: String,
+    /// Seconds since the epoch, 48 bits on the wire.
+    pub time_signed: u64,
+    pub fudge: u16,
+    pub mac: Vec<u8>,
+    pub original_id: u16,
+    pub error: u16,
+    pub other: Vec<u8>,
+    /// The message covered by the MAC: as it went on the wire before this
+    /// record was added, with its original ID.
+    pub signed: Vec<u8>,
+}
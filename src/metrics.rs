@@ -0,0 +1,336 @@
+use crate::{
+    cache::Cache,
+    message::{domain::Record, Message},
+    querylog::{Source, Span},
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Upper bounds of the resolution latency buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0,
+];
+/// Upper bounds of the response size buckets, in bytes.
+const SIZE_BUCKETS: [f64; 9] = [
+    64.0, 128.0, 256.0, 512.0, 1232.0, 2048.0, 4096.0, 16384.0, 65535.0,
+];
+
+#[derive(Debug)]
+struct Histogram {
+    bounds: &'static [f64],
+    /// Observations per bucket, not cumulative.
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(i) = self.bounds.iter().position(|b| value <= *b) {
+            self.counts[i] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str) {
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulative += count;
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {cumulative}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {}", self.count);
+        let _ = writeln!(out, "{name}_sum {}", self.sum);
+        let _ = writeln!(out, "{name}_count {}", self.count);
+    }
+}
+
+#[derive(Debug)]
+struct Counts {
+    /// Queries by transport, type and response code.
+    queries: BTreeMap<(&'static str, String, &'static str), u64>,
+    answers: BTreeMap<String, u64>,
+    /// Failures by upstream and whether they timed out.
+    upstream_errors: BTreeMap<(String, bool), u64>,
+    cache_hits: u64,
+    cache_misses: u64,
+    latency: Histogram,
+    size: Histogram,
+}
+
+impl Default for Counts {
+    fn default() -> Self {
+        Self {
+            queries: Default::default(),
+            answers: Default::default(),
+            upstream_errors: Default::default(),
+            cache_hits: 0,
+            cache_misses: 0,
+            latency: Histogram::new(&LATENCY_BUCKETS),
+            size: Histogram::new(&SIZE_BUCKETS),
+        }
+    }
+}
+
+/// Counters of everything served, in the Prometheus text format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    in_flight: AtomicI64,
+    counts: Mutex<Counts>,
+}
+
+/// Writes the HELP and TYPE lines of a metric.
+fn describe(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// `s` as a label value.
+fn label(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl Metrics {
+    /// Counts a query as in flight, returning when it started.
+    pub fn begin(&self) -> Instant {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        Instant::now()
+    }
+
+    /// Counts how a query that `begin` took in was answered over `transport`,
+    /// with `res` the response and its size on the wire, `None` when it was
+    /// dropped. Types without a name of their own are counted as `other`.
+    pub fn end(
+        &self,
+        transport: &'static str,
+        query: &Message,
+        res: Option<(&Message, usize)>,
+        latency: Duration,
+        span: &Span,
+    ) {
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+        let record = match query.questions().first().map(|d| d.record) {
+            Some(Record::Unknown(_)) => "other".to_string(),
+            record => record.map_or_else(String::new, |r| r.to_string()),
+        };
+        let code = res.map_or("DROPPED", |(r, _)| r.header().r_code.r_code_name());
+
+        let mut counts = self.counts.lock().expect("Metrics lock poisoned");
+        *counts.queries.entry((transport, record, code)).or_default() += 1;
+        if let Some(source) = span.source {
+            *counts.answers.entry(source.to_string()).or_default() += 1;
+        }
+        for (upstream, timeout) in &span.failures {
+            *counts
+                .upstream_errors
+                .entry((upstream.clone(), *timeout))
+                .or_default() += 1;
+        }
+        match span.cached {
+            Some(true) => counts.cache_hits += 1,
+            Some(false) => counts.cache_misses += 1,
+            None => {}
+        }
+        counts.latency.observe(latency.as_secs_f64());
+        if let Some((_, size)) = res {
+            counts.size.observe(size as f64);
+        }
+    }
+
+    /// Queries between `begin` and `end`.
+    pub fn in_flight(&self) -> i64 {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Everything counted, along with the state of `cache` if there is one.
+    pub fn render(&self, cache: Option<&Cache>) -> String {
+        let counts = self.counts.lock().expect("Metrics lock poisoned");
+        let mut out = String::new();
+
+        describe(
+            &mut out,
+            "dns_queries_total",
+            "counter",
+            "Queries answered.",
+        );
+        for ((transport, record, code), n) in &counts.queries {
+            let _ = writeln!(
+                out,
+                "dns_queries_total{{transport=\"{transport}\",type=\"{}\",rcode=\"{code}\"}} {n}",
+                label(record)
+            );
+        }
+        describe(
+            &mut out,
+            "dns_answers_total",
+            "counter",
+            "Questions answered, by where the answer came from.",
+        );
+        for source in [
+            Source::Local,
+            Source::Blocked,
+            Source::Policy,
+            Source::Upstream,
+        ] {
+            let source = source.to_string();
+            let n = counts.answers.get(&source).copied().unwrap_or_default();
+            let _ = writeln!(out, "dns_answers_total{{source=\"{source}\"}} {n}");
+        }
+        describe(
+            &mut out,
+            "dns_upstream_errors_total",
+            "counter",
+            "Failed upstream queries.",
+        );
+        for ((upstream, timeout), n) in &counts.upstream_errors {
+            let kind = match timeout {
+                true => "timeout",
+                false => "error",
+            };
+            let _ = writeln!(
+                out,
+                "dns_upstream_errors_total{{upstream=\"{}\",kind=\"{kind}\"}} {n}",
+                label(upstream)
+            );
+        }
+        if let Some(cache) = cache {
+            describe(
+                &mut out,
+                "dns_cache_lookups_total",
+                "counter",
+                "Upstream responses looked for in the cache, by whether it had them.",
+            );
+            let _ = writeln!(
+                out,
+                "dns_cache_lookups_total{{result=\"hit\"}} {}",
+                counts.cache_hits
+            );
+            let _ = writeln!(
+                out,
+                "dns_cache_lookups_total{{result=\"miss\"}} {}",
+                counts.cache_misses
+            );
+            describe(
+                &mut out,
+                "dns_cache_evictions_total",
+                "counter",
+                "Responses dropped from the full cache before expiring.",
+            );
+            let _ = writeln!(out, "dns_cache_evictions_total {}", cache.evictions());
+            describe(
+                &mut out,
+                "dns_cache_entries",
+                "gauge",
+                "Responses in the cache.",
+            );
+            let _ = writeln!(out, "dns_cache_entries {}", cache.len());
+        }
+        describe(
+            &mut out,
+            "dns_resolution_seconds",
+            "histogram",
+            "Time taken to answer queries.",
+        );
+        counts.latency.render(&mut out, "dns_resolution_seconds");
+        describe(
+            &mut out,
+            "dns_response_size_bytes",
+            "histogram",
+            "Size of responses sent.",
+        );
+        counts.size.render(&mut out, "dns_response_size_bytes");
+        describe(
+            &mut out,
+            "dns_queries_in_flight",
+            "gauge",
+            "Queries being answered.",
+        );
+        let _ = writeln!(out, "dns_queries_in_flight {}", self.in_flight());
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{
+        domain::Domain,
+        header::{OpCode, PacketId},
+        Header,
+    };
+
+    #[test]
+    fn test_histogram() {
+        let mut h = Histogram::new(&[1.0, 10.0]);
+        for v in [0.5, 1.0, 5.0, 50.0] {
+            h.observe(v);
+        }
+        let mut out = String::new();
+        h.render(&mut out, "h");
+        assert_eq!(
+            out,
+            "h_bucket{le=\"1\"} 2\nh_bucket{le=\"10\"} 3\nh_bucket{le=\"+Inf\"} 4\n\
+             h_sum 56.5\nh_count 4\n"
+        );
+    }
+
+    #[test]
+    fn test_metrics() {
+        let metrics = Metrics::default();
+        let mut q = Message::new(Header::query(PacketId(1)));
+        q.set_questions(vec![Domain::new_aa("hernan.rs")]).unwrap();
+        let mut res = Message::new_response(&q);
+        res.set_r_code(OpCode::server_failure());
+        let span = Span {
+            source: Some(Source::Upstream),
+            upstream: Some("9.9.9.9:53".into()),
+            failures: vec![("9.9.9.9:53".into(), true)],
+            cached: Some(false),
+        };
+
+        let started = metrics.begin();
+        metrics.begin();
+        metrics.end("udp", &q, Some((&res, 30)), started.elapsed(), &span);
+        let mut private = q.clone();
+        private
+            .set_questions(vec![Domain::new("hernan.rs", Record::Unknown(65280))])
+            .unwrap();
+        metrics.end("udp", &private, None, started.elapsed(), &Span::default());
+        let out = metrics.render(Some(&Cache::new(10)));
+        for line in [
+            "dns_queries_total{transport=\"udp\",type=\"A\",rcode=\"SERVFAIL\"} 1",
+            "dns_queries_total{transport=\"udp\",type=\"other\",rcode=\"DROPPED\"} 1",
+            "dns_answers_total{source=\"upstream\"} 1",
+            "dns_answers_total{source=\"local\"} 0",
+            "dns_upstream_errors_total{upstream=\"9.9.9.9:53\",kind=\"timeout\"} 1",
+            "dns_resolution_seconds_count 2",
+            "dns_response_size_bytes_bucket{le=\"64\"} 1",
+            "dns_queries_in_flight 0",
+            "dns_cache_lookups_total{result=\"hit\"} 0",
+            "dns_cache_lookups_total{result=\"miss\"} 1",
+            "dns_cache_evictions_total 0",
+            "dns_cache_entries 0",
+        ] {
+            assert!(out.lines().any(|l| l == line), "{line} missing from {out}");
+        }
+        assert!(!metrics.render(None).contains("dns_cache"));
+    }
+}
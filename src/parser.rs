@@ -1,16 +1,23 @@
-use std::net::Ipv4Addr;
+// The parsers hide the input lifetime in `ByteResult`, which compilers newer
+// than the 1.77 this is built with warn about.
+#![allow(unknown_lints, mismatched_lifetime_syntaxes)]
+
+use std::net::{Ipv4Addr, Ipv6Addr};
 
 use crate::message::{
-    data::Data,
+    data::{Data, Soa},
     domain::{Class, Domain, Record},
-    header::{OpCode, PacketId, Reserved},
+    edns::{self, Edns},
+    header::{OpCode, PacketId, QueryMode, Reserved},
     route::Route,
+    tsig::{self, Tsig},
     Header, Message,
 };
 use nom::{
     bits,
     bytes::complete::take,
-    combinator::{map, map_res, peek},
+    combinator::{map, map_res},
+    error::{make_error, ErrorKind},
     number::complete::{be_u16, be_u32, be_u8},
     sequence::tuple,
     IResult, Parser,
@@ -36,7 +43,7 @@ fn take_enum<T: From<u8>>(i: BitInput) -> BitResult<T> {
 }
 
 fn take_reserved(i: BitInput) -> BitResult<Reserved> {
-    map(bits::complete::take(3u8), |_: u8| Reserved).parse(i)
+    map(bits::complete::take(1u8), |_: u8| Reserved).parse(i)
 }
 
 fn parse_header(i: &[u8]) -> ByteResult<Header> {
@@ -49,14 +56,16 @@ fn parse_header(i: &[u8]) -> ByteResult<Header> {
         take_enum,
         take_enum,
         take_reserved,
+        take_enum,
+        take_enum,
         take_opcode,
     )))
     .parse(i)?;
 
     let (i, qd_count) = be_u16(i)?;
     let (i, an_count) = be_u16(i)?;
-    let (i, ar_count) = be_u16(i)?;
     let (i, ns_count) = be_u16(i)?;
+    let (i, ar_count) = be_u16(i)?;
 
     let header = Header {
         id,
@@ -67,7 +76,9 @@ fn parse_header(i: &[u8]) -> ByteResult<Header> {
         rd: flags.4,
         ra: flags.5,
         z: flags.6,
-        r_code: flags.7,
+        ad: flags.7,
+        cd: flags.8,
+        r_code: flags.9,
         qd_count,
         an_count,
         ar_count,
@@ -77,36 +88,58 @@ fn parse_header(i: &[u8]) -> ByteResult<Header> {
     ByteResult::Ok((i, header))
 }
 
-// TODO: Cache parsed domain parts
+/// Longest a name may be on the wire, labels and lengths together (RFC 1035
+/// section 3.1).
+const MAX_NAME_LEN: usize = 255;
+/// Most compression pointers followed in one name; pointers only go
+/// backwards, so this only bounds the work of crafted messages.
+const MAX_POINTERS: usize = 64;
+
+/// Reads the labels of a name, following compression pointers through `buf`.
+/// Every pointer has to point before the one followed last, so that names
+/// cannot loop, and into `buf`.
 fn collect_domain_name<'a>(i: &'a [u8], buf: &'a [u8]) -> ByteResult<'a, Vec<String>> {
+    let fail = |at| nom::Err::Error(make_error(at, ErrorKind::Verify));
     let mut v = vec![];
-    let mut i = i;
+    let mut len = 1;
+    let mut pointers = 0;
+    // Where the name ends in `i`, once past its first pointer.
+    let mut rest = None;
+    // Pointers must point before this offset in `buf`.
+    let mut limit = (i.as_ptr() as usize)
+        .wrapping_sub(buf.as_ptr() as usize)
+        .min(buf.len());
+    let mut at = i;
 
     loop {
-        let (_, msb) = peek(be_u8).parse(i)?;
-        if msb == 0 {
-            i = &i[1..];
-            break;
+        let (after, n) = be_u8(at)?;
+        if n == 0 {
+            return Ok((rest.unwrap_or(after), v));
         }
 
-        let is_ref = (msb & STR_REF_MSB) != 0;
-        if is_ref {
-            let pos;
-            (i, pos) = be_u16(i)?;
+        if n & STR_REF_MSB == STR_REF_MSB {
+            let (after, pos) = be_u16(at)?;
             let pos = (pos & !STR_REF_MSB_U16) as usize;
-            let j = &buf[pos..];
-            let (_, mut other) = collect_domain_name(j, buf)?;
-            v.append(&mut other);
-            break;
+            pointers += 1;
+            if pos >= limit || pointers > MAX_POINTERS {
+                return Err(fail(at));
+            }
+            rest.get_or_insert(after);
+            limit = pos;
+            at = &buf[pos..];
+        } else if n & STR_REF_MSB != 0 {
+            // Extended label types (RFC 6891 section 5) are not supported.
+            return Err(fail(at));
         } else {
-            let n;
-            let s;
-            (i, n) = be_u8(i)?;
-            (i, s) = map_res(take(n), std::str::from_utf8).parse(i)?;
+            len += n as usize + 1;
+            if len > MAX_NAME_LEN {
+                return Err(fail(at));
+            }
+            let (after, s) = map_res(take(n), std::str::from_utf8).parse(after)?;
             v.push(s.to_string());
+            at = after;
         }
     }
-    Ok((i, v))
 }
 
 fn parse_domain_name<'a>(i: &'a [u8], buf: &'a [u8]) -> ByteResult<'a, String> {
@@ -116,11 +149,11 @@ fn parse_domain_name<'a>(i: &'a [u8], buf: &'a [u8]) -> ByteResult<'a, String> {
 }
 
 fn parse_record(i: &[u8]) -> ByteResult<Record> {
-    map_res(be_u16, Record::try_from).parse(i)
+    map(be_u16, Record::from).parse(i)
 }
 
 fn parse_class(i: &[u8]) -> ByteResult<Class> {
-    map_res(be_u16, Class::try_from).parse(i)
+    map(be_u16, Class::from).parse(i)
 }
 
 fn parse_domain<'a>(i: &'a [u8], buf: &'a [u8]) -> ByteResult<'a, Domain> {
@@ -143,30 +176,151 @@ fn parse_questions<'a>(i: &'a [u8], c: u16, buf: &'a [u8]) -> ByteResult<'a, Vec
     })
 }
 
-fn parse_answers<'a>(i: &'a [u8], c: u16, buf: &'a [u8]) -> ByteResult<'a, Vec<Route>> {
+fn parse_soa<'a>(i: &'a [u8], buf: &'a [u8]) -> ByteResult<'a, Soa> {
+    let (i, mname) = parse_domain_name(i, buf)?;
+    let (i, rname) = parse_domain_name(i, buf)?;
+    let (i, (serial, refresh, retry, expire, minimum)) =
+        tuple((be_u32, be_u32, be_u32, be_u32, be_u32)).parse(i)?;
+    let soa = Soa {
+        mname,
+        rname,
+        serial,
+        refresh,
+        retry,
+        expire,
+        minimum,
+    };
+    Ok((i, soa))
+}
+
+fn parse_data<'a>(i: &'a [u8], record: Record, buf: &'a [u8]) -> ByteResult<'a, Data> {
+    match record {
+        Record::AA => map(be_u32, |ip| Data::Ipv4(Ipv4Addr::from(ip))).parse(i),
+        Record::AAAA => map(take(16u8), |ip: &[u8]| {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(ip);
+            Data::Ipv6(Ipv6Addr::from(octets))
+        })
+        .parse(i),
+        Record::NS | Record::CNAME | Record::PTR => {
+            map(|i| parse_domain_name(i, buf), Data::Name).parse(i)
+        }
+        Record::MX => {
+            let (i, preference) = be_u16(i)?;
+            let (i, exchange) = parse_domain_name(i, buf)?;
+            Ok((
+                i,
+                Data::Mx {
+                    preference,
+                    exchange,
+                },
+            ))
+        }
+        Record::SOA => map(|i| parse_soa(i, buf), Data::Soa).parse(i),
+        _ => Ok((&i[i.len()..], Data::Raw(i.to_vec()))),
+    }
+}
+
+fn parse_route<'a>(i: &'a [u8], buf: &'a [u8]) -> ByteResult<'a, Route> {
+    let (i, domain) = parse_domain(i, buf)?;
+    let (i, ttl) = be_u32(i)?;
+    let (i, len) = be_u16(i)?;
+    let (i, rdata) = take(len).parse(i)?;
+    // UPDATE names whole RRsets with empty data.
+    let data = match rdata.is_empty() {
+        true => Data::Raw(vec![]),
+        false => parse_data(rdata, domain.record, buf)?.1,
+    };
+    Ok((i, Route::new(domain, ttl, data)))
+}
+
+fn parse_routes<'a>(i: &'a [u8], c: u16, buf: &'a [u8]) -> ByteResult<'a, Vec<Route>> {
     (0..c).try_fold((i, vec![]), |(i, mut v), _| {
-        let (i, domain) = parse_domain(i, buf)?;
-        let (i, ttl) = be_u32(i)?;
-        let (i, len) = be_u16(i)?;
-        let (i, ip) = take(len).parse(i)?;
-        let ip = Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]);
-        let data = Data::Ipv4(ip);
-        let route = Route::new(domain, ttl, data);
+        let (i, route) = parse_route(i, buf)?;
         v.push(route);
         Ok((i, v))
     })
 }
 
-// TODO: Propagate Message error instead of panic.
+fn parse_tsig<'a>(i: &'a [u8], buf: &'a [u8]) -> ByteResult<'a, Tsig> {
+    let start = buf.len() - i.len();
+    let (i, key_name) = parse_domain_name(i, buf)?;
+    let (i, (_, _, _, _)) = tuple((be_u16, be_u16, be_u32, be_u16)).parse(i)?;
+    let (i, algorithm) = parse_domain_name(i, buf)?;
+    let (i, (high, low, fudge, mac_len)) = tuple((be_u16, be_u32, be_u16, be_u16)).parse(i)?;
+    let (i, mac) = take(mac_len).parse(i)?;
+    let (i, (original_id, error, other_len)) = tuple((be_u16, be_u16, be_u16)).parse(i)?;
+    let (i, other) = take(other_len).parse(i)?;
+
+    // The MAC covers the message as it was before signing.
+    let mut signed = buf[..start].to_vec();
+    signed[..2].copy_from_slice(&original_id.to_be_bytes());
+    let ar_count = u16::from_be_bytes([signed[10], signed[11]]).saturating_sub(1);
+    signed[10..12].copy_from_slice(&ar_count.to_be_bytes());
+
+    let tsig = Tsig {
+        key_name,
+        algorithm,
+        time_signed: (high as u64) << 32 | low as u64,
+        fudge,
+        mac: mac.to_vec(),
+        original_id,
+        error,
+        other: other.to_vec(),
+        signed,
+    };
+    Ok((i, tsig))
+}
+
+type Additionals = (Vec<Route>, Option<Edns>, Option<Tsig>);
+
+/// Reads the additional records, setting the OPT and TSIG pseudo-records
+/// apart. Nothing may follow a TSIG record.
+fn parse_additionals<'a>(i: &'a [u8], c: u16, buf: &'a [u8]) -> ByteResult<'a, Additionals> {
+    (0..c).try_fold(
+        (i, (vec![], None, None)),
+        |(i, (mut v, mut opt, sig)), _| {
+            if sig.is_some() {
+                return Err(nom::Err::Error(make_error(i, ErrorKind::Verify)));
+            }
+            let (rest, _) = parse_domain_name(i, buf)?;
+            let (rest, record) = be_u16(rest)?;
+            if record == tsig::TSIG {
+                let (i, sig) = parse_tsig(i, buf)?;
+                return Ok((i, (v, opt, Some(sig))));
+            }
+            if record != edns::OPT {
+                let (i, route) = parse_route(i, buf)?;
+                v.push(route);
+                return Ok((i, (v, opt, sig)));
+            }
+
+            let (rest, (udp_payload, ttl, len)) = tuple((be_u16, be_u32, be_u16)).parse(rest)?;
+            let (rest, _options) = take(len).parse(rest)?;
+            opt = Some(Edns::from_ttl(udp_payload, ttl));
+            Ok((rest, (v, opt, sig)))
+        },
+    )
+}
+
 fn parse_message(buf: &[u8]) -> ByteResult<Message> {
     let (i, header) = parse_header(buf)?;
     let (i, questions) = parse_questions(i, header.qd_count, buf)?;
-    let (i, answers) = parse_answers(i, header.an_count, buf)?;
+    let (i, answers) = parse_routes(i, header.an_count, buf)?;
+    let (i, authorities) = parse_routes(i, header.ns_count, buf)?;
+    let (i, (additionals, edns, tsig)) = parse_additionals(i, header.ar_count, buf)?;
     let mut msg = Message::new(header);
-    msg.set_questions(questions)
-        .expect("Could not build questions");
-    msg.set_answers(answers).expect("Could not build answers");
-    Ok((i, msg))
+    let built = msg
+        .set_questions(questions)
+        .and_then(|_| msg.set_answers(answers))
+        .and_then(|_| msg.set_authorities(authorities))
+        .and_then(|_| msg.set_additionals(additionals))
+        .and_then(|_| msg.set_edns(edns))
+        .and_then(|_| msg.set_tsig(tsig));
+    match built {
+        Ok(()) => Ok((i, msg)),
+        Err(_) => Err(nom::Err::Error(make_error(i, ErrorKind::TooLarge))),
+    }
 }
 
 impl TryFrom<&[u8]> for Message {
@@ -178,6 +332,22 @@ impl TryFrom<&[u8]> for Message {
     }
 }
 
+impl Message {
+    /// FORMERR response to `buf`, a query that could not be read, as long as
+    /// its header could.
+    pub fn format_error(buf: &[u8]) -> Option<Self> {
+        let (_, query) = parse_header(buf).ok()?;
+        if query.qr != QueryMode::Query {
+            return None;
+        }
+        let mut header = Header::response(query.id);
+        header.op_code = query.op_code;
+        header.rd = query.rd;
+        header.r_code = OpCode::format_error();
+        Some(Self::new(header))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -241,6 +411,81 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_bad_pointers() {
+        let header = [0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        let query = |name: &[u8]| [&header[..], name, &[0, 1, 0, 1]].concat();
+
+        // Pointing at itself, forwards, and past the end of the message.
+        for name in [&[0xc0, 12][..], &[0xc0, 14, 1, 97, 0], &[0xc0, 0xff]] {
+            assert!(parse_message(&query(name)).is_err(), "{name:?}");
+        }
+        // Two names pointing at each other.
+        let data = [
+            0x12, 0x34, 0x01, 0x00, 0, 2, 0, 0, 0, 0, 0, 0, 1, 97, 0xc0, 18, 0, 1, 0, 1, 1, 98,
+            0xc0, 12, 0, 1, 0, 1,
+        ];
+        assert!(parse_message(&data).is_err());
+
+        let long: Vec<u8> = (0..128).flat_map(|_| [1, 97]).chain([0]).collect();
+        assert!(parse_message(&query(&long)).is_err());
+        assert!(parse_message(&query(&long[2..])).is_ok());
+    }
+
+    #[test]
+    fn test_format_error() {
+        let data = [
+            0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0, 0xc0, 12, 0, 1, 0, 1,
+        ];
+        assert!(Message::try_from(data.as_ref()).is_err());
+
+        let res = Message::format_error(&data).unwrap();
+        assert_eq!(res.header().id, PacketId(0x1234));
+        assert_eq!(res.header().r_code, OpCode::format_error());
+        assert_eq!(res.header().rd, Recursion::Enabled);
+        assert!(res.questions().is_empty());
+
+        let mut response = data;
+        response[2] |= 0x80;
+        assert!(Message::format_error(&response).is_none());
+        assert!(Message::format_error(&data[..6]).is_none());
+    }
+
+    #[test]
+    fn test_parse_edns() {
+        // dig +dnssec hernan.rs, with a cookie option.
+        let data = [
+            0x12, 0x34, 0x01, 0x20, 0, 1, 0, 0, 0, 0, 0, 1, 6, 104, 101, 114, 110, 97, 110, 2, 114,
+            115, 0, 0, 1, 0, 1, 0, 0, 41, 0x04, 0xd0, 0, 0, 0x80, 0, 0, 12, 0, 10, 0, 8, 1, 2, 3,
+            4, 5, 6, 7, 8,
+        ];
+
+        let (i, msg) = parse_message(data.as_ref()).unwrap();
+        assert!(i.is_empty());
+        assert!(msg.additionals().is_empty());
+        assert_eq!(msg.header().ar_count, 1);
+
+        let edns = msg.edns().unwrap();
+        assert_eq!(edns.udp_payload, 1232);
+        assert!(edns.dnssec_ok);
+    }
+
+    #[test]
+    fn test_parse_unknown_types() {
+        // A TLSA answer to a CH question, neither of them known here.
+        let data = [
+            0, 7, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0, 2, 104, 105, 0, 0, 52, 0, 3, 2, 104, 105, 0,
+            0, 52, 0, 3, 0, 0, 0, 60, 0, 3, 3, 1, 1,
+        ];
+
+        let (i, msg) = parse_message(data.as_ref()).unwrap();
+        assert!(i.is_empty());
+        assert_eq!(msg.questions()[0].record, Record::Unknown(52));
+        assert_eq!(msg.questions()[0].class, Class::CH);
+        assert_eq!(msg.answers()[0].data(), &Data::Raw(vec![3, 1, 1]));
+        assert_eq!(msg.flush().as_ref(), data.as_ref());
+    }
+
     #[test]
     fn test_parse_response() {
         let data = std::fs::read("response_packet.bin").unwrap();
@@ -0,0 +1,372 @@
+use crate::{encoding::json_string, message::Message};
+use anyhow::{Context, Result};
+use std::{
+    cell::RefCell,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+thread_local! {
+    static SPAN: RefCell<Span> = RefCell::default();
+}
+
+/// Where an answer came from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Source {
+    /// Pinned hosts or a local zone.
+    Local,
+    Blocked,
+    /// A policy zone rewrite.
+    Policy,
+    Upstream,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Local => write!(f, "local"),
+            Self::Blocked => write!(f, "blocked"),
+            Self::Policy => write!(f, "policy"),
+            Self::Upstream => write!(f, "upstream"),
+        }
+    }
+}
+
+/// What was learnt about the query being answered on this thread.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Span {
+    pub source: Option<Source>,
+    /// Upstream asked last.
+    pub upstream: Option<String>,
+    /// Upstreams that failed, and whether they timed out.
+    pub failures: Vec<(String, bool)>,
+    /// Whether the cache had the upstream answer, when it was looked in.
+    pub cached: Option<bool>,
+}
+
+/// Notes something about the query being answered on this thread.
+pub fn record(f: impl FnOnce(&mut Span)) {
+    SPAN.with(|span| f(&mut span.borrow_mut()))
+}
+
+/// What was noted about the query answered on this thread, starting afresh
+/// for the next one.
+pub fn take() -> Span {
+    SPAN.with(|span| span.take())
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Format {
+    /// `key=value` pairs.
+    #[default]
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => anyhow::bail!("Unknown query log format: {s}"),
+        }
+    }
+}
+
+/// How a query was answered.
+#[derive(Debug)]
+pub struct Entry<'a> {
+    pub transport: &'static str,
+    pub client: IpAddr,
+    pub query: &'a Message,
+    /// The response and its size on the wire, `None` when the query was
+    /// dropped.
+    pub response: Option<(&'a Message, usize)>,
+    pub latency: Duration,
+    pub span: Span,
+}
+
+impl Entry<'_> {
+    /// The log line for this entry, written at `at`.
+    pub fn line(&self, format: Format, at: SystemTime) -> String {
+        let at = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let ts = format!("{}.{:03}", at.as_secs(), at.subsec_millis());
+        let (name, record, class) = match self.query.questions().first() {
+            Some(d) => (d.name.as_str(), d.record.to_string(), d.class.to_string()),
+            None => ("", String::new(), String::new()),
+        };
+        let res = self.response.map(|(res, size)| {
+            let code = res.header().r_code.r_code_name();
+            (code, res.answers().len(), size)
+        });
+        let latency = format!("{:.3}", self.latency.as_secs_f64() * 1000.0);
+        let source = self.span.source.map(|s| s.to_string());
+        let upstream = self.span.upstream.as_deref();
+        let cache = self.span.cached.map(|hit| match hit {
+            true => "hit",
+            false => "miss",
+        });
+        let id = self.query.header().id.0;
+
+        match format {
+            Format::Text => {
+                let (code, answers, size) = match res {
+                    Some((code, answers, size)) => (code, answers.to_string(), size.to_string()),
+                    None => ("DROPPED", "-".into(), "-".into()),
+                };
+                format!(
+                    "{ts} {} client={} id={id} name={name} class={class} type={record} \
+                     rcode={code} answers={answers} size={size} latency={latency}ms \
+                     source={} cache={} upstream={}",
+                    self.transport,
+                    self.client,
+                    source.as_deref().unwrap_or("-"),
+                    cache.unwrap_or("-"),
+                    upstream.unwrap_or("-"),
+                )
+            }
+            Format::Json => {
+                let (code, answers, size) = match res {
+                    Some((code, answers, size)) => (json_string(code), answers, size),
+                    None => ("null".into(), 0, 0),
+                };
+                let or_null = |s: Option<&str>| s.map_or("null".into(), json_string);
+                format!(
+                    "{{\"ts\":{ts},\"transport\":{},\"client\":{},\"id\":{id},\"name\":{},\
+                     \"class\":{},\"type\":{},\"rcode\":{code},\"answers\":{answers},\
+                     \"size\":{size},\"latency_ms\":{latency},\"source\":{},\"cache\":{},\"upstream\":{}}}",
+                    json_string(self.transport),
+                    json_string(&self.client.to_string()),
+                    json_string(name),
+                    json_string(&class),
+                    json_string(&record),
+                    or_null(source.as_deref()),
+                    or_null(cache),
+                    or_null(upstream),
+                )
+            }
+        }
+    }
+}
+
+/// Where and how much to log.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub format: Format,
+    /// File to append to, stdout when unset.
+    pub path: Option<PathBuf>,
+    /// Size past which the file is rotated, never when zero.
+    pub max_size: u64,
+    /// Rotated files kept, `<path>.1` being the latest.
+    pub keep: u32,
+    /// Log one query out of this many.
+    pub sample: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            format: Format::Text,
+            path: None,
+            max_size: 0,
+            keep: 5,
+            sample: 1,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Output {
+    file: File,
+    size: u64,
+}
+
+fn open(path: &Path) -> Result<Output> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Could not open query log {}", path.display()))?;
+    let size = file.metadata()?.len();
+    Ok(Output { file, size })
+}
+
+/// `<path>.<n>`
+fn rotated(path: &Path, n: u32) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{n}"));
+    PathBuf::from(rotated)
+}
+
+/// Per-query log lines.
+#[derive(Debug)]
+pub struct QueryLog {
+    config: Config,
+    seen: AtomicU64,
+    out: Mutex<Option<Output>>,
+}
+
+impl QueryLog {
+    pub fn new(config: Config) -> Result<Self> {
+        let out = config.path.as_deref().map(open).transpose()?;
+        Ok(Self {
+            config,
+            seen: AtomicU64::new(0),
+            out: Mutex::new(out),
+        })
+    }
+
+    /// Logs `entry` if it is among the sampled queries.
+    pub fn log(&self, entry: &Entry) {
+        let seen = self.seen.fetch_add(1, Ordering::Relaxed);
+        if seen.checked_rem(self.config.sample) != Some(0) {
+            return;
+        }
+        let mut line = entry.line(self.config.format, SystemTime::now());
+        line.push('\n');
+        if let Err(e) = self.write(&line) {
+            println!("Could not write query log: {e}");
+        }
+    }
+
+    fn write(&self, line: &str) -> Result<()> {
+        let Some(path) = &self.config.path else {
+            return Ok(io::stdout().lock().write_all(line.as_bytes())?);
+        };
+        let mut out = self.out.lock().expect("Query log lock poisoned");
+        let len = line.len() as u64;
+        let full = out
+            .as_ref()
+            .is_some_and(|o| o.size > 0 && o.size + len > self.config.max_size);
+        if self.config.max_size > 0 && full {
+            *out = None;
+            self.rotate(path)?;
+        }
+        let output = match out.as_mut() {
+            Some(output) => output,
+            None => out.insert(open(path)?),
+        };
+        output.file.write_all(line.as_bytes())?;
+        output.size += len;
+        Ok(())
+    }
+
+    /// Moves `path` to `<path>.1`, shifting older files along and removing
+    /// the oldest.
+    fn rotate(&self, path: &Path) -> Result<()> {
+        if self.config.keep == 0 {
+            return Ok(fs::remove_file(path)?);
+        }
+        let _ = fs::remove_file(rotated(path, self.config.keep));
+        for n in (1..self.config.keep).rev() {
+            let _ = fs::rename(rotated(path, n), rotated(path, n + 1));
+        }
+        fs::rename(path, rotated(path, 1))
+            .with_context(|| format!("Could not rotate query log {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{
+        domain::Domain,
+        header::{OpCode, PacketId},
+        Header,
+    };
+
+    fn query() -> Message {
+        let mut q = Message::new(Header::query(PacketId(42)));
+        q.set_questions(vec![Domain::new_aa("hernan.rs")]).unwrap();
+        q
+    }
+
+    fn entry<'a>(q: &'a Message, res: Option<&'a Message>) -> Entry<'a> {
+        Entry {
+            transport: "udp",
+            client: "10.0.0.1".parse().unwrap(),
+            query: q,
+            response: res.map(|res| (res, res.flush().len())),
+            latency: Duration::from_micros(1500),
+            span: Span {
+                source: Some(Source::Upstream),
+                upstream: Some("9.9.9.9:53".into()),
+                failures: vec![],
+                cached: Some(false),
+            },
+        }
+    }
+
+    #[test]
+    fn test_span() {
+        record(|s| s.source = Some(Source::Local));
+        assert_eq!(take().source, Some(Source::Local));
+        assert_eq!(take(), Span::default());
+    }
+
+    #[test]
+    fn test_line() {
+        let q = query();
+        let mut res = Message::new_response(&q);
+        res.set_r_code(OpCode::name_error());
+        let at = UNIX_EPOCH + Duration::from_millis(1_700_000_000_250);
+
+        let text = entry(&q, Some(&res)).line(Format::Text, at);
+        assert_eq!(
+            text,
+            "1700000000.250 udp client=10.0.0.1 id=42 name=hernan.rs class=IN type=A \
+             rcode=NXDOMAIN answers=0 size=27 latency=1.500ms source=upstream \
+             cache=miss upstream=9.9.9.9:53"
+        );
+        assert!(entry(&q, None)
+            .line(Format::Text, at)
+            .contains("rcode=DROPPED answers=- size=-"));
+
+        let json = entry(&q, Some(&res)).line(Format::Json, at);
+        assert_eq!(
+            json,
+            "{\"ts\":1700000000.250,\"transport\":\"udp\",\"client\":\"10.0.0.1\",\
+             \"id\":42,\"name\":\"hernan.rs\",\"class\":\"IN\",\"type\":\"A\",\
+             \"rcode\":\"NXDOMAIN\",\"answers\":0,\"size\":27,\"latency_ms\":1.500,\
+             \"source\":\"upstream\",\"cache\":\"miss\",\"upstream\":\"9.9.9.9:53\"}"
+        );
+    }
+
+    #[test]
+    fn test_rotate_and_sample() {
+        let dir = std::env::temp_dir().join(format!("querylog-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("queries.log");
+        let log = QueryLog::new(Config {
+            path: Some(path.clone()),
+            max_size: 400,
+            keep: 2,
+            sample: 2,
+            ..Config::default()
+        })
+        .unwrap();
+
+        let q = query();
+        for _ in 0..20 {
+            log.log(&entry(&q, None));
+        }
+        let lines = |p: &Path| fs::read_to_string(p).unwrap_or_default().lines().count();
+        let kept = lines(&path) + lines(&rotated(&path, 1)) + lines(&rotated(&path, 2));
+        assert!(fs::metadata(&path).unwrap().len() <= 400);
+        assert!(rotated(&path, 2).exists() && !rotated(&path, 3).exists());
+        assert!(kept < 10 && kept > 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
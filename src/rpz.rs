@@ -0,0 +1,527 @@
+use crate::{
+    cidr::Cidr,
+    message::{
+        data::Data,
+        domain::{in_zone, Domain, Record},
+        header::{OpCode, Truncation},
+        route::Route,
+        Message,
+    },
+    zone::{master, Zone},
+};
+use anyhow::{Context, Result};
+use std::{
+    collections::HashMap,
+    fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::RwLock,
+    time::{Duration, SystemTime},
+};
+
+/// How often policy files are checked for changes.
+pub const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What a triggered rule does to a query (RPZ draft, section 4).
+#[derive(Clone, Debug, PartialEq)]
+pub enum Policy {
+    NxDomain,
+    NoData,
+    /// Resolve as if no policy existed.
+    Passthru,
+    /// Send no response at all.
+    Drop,
+    /// Answer UDP queries truncated so the client retries over TCP, and
+    /// resolve the others as with passthru.
+    TcpOnly,
+    /// Answer with these records, a CNAME among them rewriting the name.
+    Local(Vec<Route>),
+}
+
+impl Policy {
+    /// The policy written by the records of one trigger owner.
+    fn from_records(records: Vec<Route>) -> Self {
+        let targets = records.iter().filter_map(|r| match r.data() {
+            Data::Name(target) if r.domain().record == Record::CNAME => Some(target.as_str()),
+            _ => None,
+        });
+        for target in targets {
+            match target {
+                "" => return Self::NxDomain,
+                "*" => return Self::NoData,
+                "rpz-passthru" => return Self::Passthru,
+                "rpz-drop" => return Self::Drop,
+                "rpz-tcp-only" => return Self::TcpOnly,
+                _ => {}
+            }
+        }
+        Self::Local(records)
+    }
+
+    /// Answers `query` under this policy; `None` means the query is dropped.
+    /// Passthru answers like NODATA, callers resolve such queries themselves.
+    pub fn answer(&self, query: &Message) -> Result<Option<Message>> {
+        let mut msg = Message::new_response(query);
+        match self {
+            Self::Drop => return Ok(None),
+            Self::NxDomain => msg.set_r_code(OpCode::name_error()),
+            Self::NoData | Self::Passthru => {}
+            Self::TcpOnly => msg.set_tc(Truncation::Truncated),
+            Self::Local(records) => {
+                let mut answers = vec![];
+                for q in query.questions() {
+                    let cname = records.iter().find(|r| r.domain().record == Record::CNAME);
+                    let matching: Vec<&Route> = match cname {
+                        Some(r) => vec![r],
+                        None => records
+                            .iter()
+                            .filter(|r| r.domain().record == q.record)
+                            .collect(),
+                    };
+                    answers.extend(matching.into_iter().map(|r| {
+                        let mut r = r.clone();
+                        r.rename(&q.name);
+                        r
+                    }));
+                }
+                msg.set_answers(answers)?;
+            }
+        }
+        Ok(Some(msg))
+    }
+}
+
+/// Names triggering a policy, exactly or through `*.` wildcard owners, which
+/// match every name below theirs but not their own.
+#[derive(Debug, Default)]
+struct NameTriggers {
+    exact: HashMap<String, Policy>,
+    wildcard: HashMap<String, Policy>,
+}
+
+impl NameTriggers {
+    fn insert(&mut self, name: &str, policy: Policy) {
+        let name = name.to_ascii_lowercase();
+        match name.strip_prefix("*.") {
+            Some(parent) => self.wildcard.insert(parent.to_string(), policy),
+            None => self.exact.insert(name, policy),
+        };
+    }
+
+    /// The exact trigger of `name`, or else the wildcard of its closest ancestor.
+    fn get(&self, name: &str) -> Option<&Policy> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        if let Some(policy) = self.exact.get(&name) {
+            return Some(policy);
+        }
+        let mut rest = name.as_str();
+        while let Some((_, parent)) = rest.split_once('.') {
+            if let Some(policy) = self.wildcard.get(parent) {
+                return Some(policy);
+            }
+            rest = parent;
+        }
+        None
+    }
+}
+
+/// Networks triggering a policy; the longest prefix containing an address wins.
+#[derive(Debug, Default)]
+struct IpTriggers(Vec<(Cidr, Policy)>);
+
+impl IpTriggers {
+    fn get(&self, ip: IpAddr) -> Option<&Policy> {
+        self.0
+            .iter()
+            .filter(|(net, _)| net.contains(ip))
+            .max_by_key(|(net, _)| net.prefix())
+            .map(|(_, policy)| policy)
+    }
+}
+
+/// Reads the network of an IP trigger owner, its prefix length followed by the
+/// reversed address: `24.0.2.0.192` or `48.zz.db8.2001`, `zz` standing for `::`.
+fn parse_trigger_ip(labels: &str) -> Result<Cidr> {
+    let context = || format!("Not an IP trigger: {labels}");
+    let mut parts = labels.split('.');
+    let prefix: u8 = parts
+        .next()
+        .unwrap_or_default()
+        .parse()
+        .with_context(context)?;
+    let mut parts: Vec<&str> = parts.collect();
+    parts.reverse();
+
+    let addr = match parts.len() {
+        4 if parts.iter().all(|p| p.parse::<u8>().is_ok()) => parts.join("."),
+        _ => {
+            let mut addr = parts.join(":").replace("zz", "");
+            if addr.starts_with(':') {
+                addr.insert(0, ':');
+            }
+            if addr.ends_with(':') {
+                addr.push(':');
+            }
+            addr
+        }
+    };
+    Cidr::new(addr.parse().with_context(context)?, prefix)
+}
+
+/// The triggers of one policy zone.
+#[derive(Debug, Default)]
+pub struct PolicyZone {
+    origin: String,
+    qnames: NameTriggers,
+    nsdnames: NameTriggers,
+    client_ips: IpTriggers,
+    response_ips: IpTriggers,
+    ns_ips: IpTriggers,
+}
+
+impl PolicyZone {
+    /// Sorts the records of an RPZ zone into triggers by the suffix of their owner.
+    pub fn new(zone: Zone) -> Result<Self> {
+        let origin = zone.origin().to_ascii_lowercase();
+        let mut rpz = Self {
+            origin: origin.clone(),
+            ..Default::default()
+        };
+
+        let mut owners: HashMap<String, Vec<Route>> = HashMap::new();
+        for r in zone.into_records() {
+            let owner = r.domain().name.to_ascii_lowercase();
+            let Some(relative) = owner.strip_suffix(&origin) else {
+                continue;
+            };
+            let Some(relative) = relative.strip_suffix('.') else {
+                continue; // SOA and NS of the zone itself
+            };
+            owners.entry(relative.to_string()).or_default().push(r);
+        }
+
+        for (owner, records) in owners {
+            let policy = Policy::from_records(records);
+            let ip = |suffix| owner.strip_suffix(suffix).map(parse_trigger_ip);
+            if let Some(net) = ip(".rpz-client-ip") {
+                rpz.client_ips.0.push((net?, policy));
+            } else if let Some(net) = ip(".rpz-ip") {
+                rpz.response_ips.0.push((net?, policy));
+            } else if let Some(net) = ip(".rpz-nsip") {
+                rpz.ns_ips.0.push((net?, policy));
+            } else if let Some(name) = owner.strip_suffix(".rpz-nsdname") {
+                rpz.nsdnames.insert(name, policy);
+            } else {
+                rpz.qnames.insert(&owner, policy);
+            }
+        }
+        Ok(rpz)
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Could not read policy zone {}", path.display()))?;
+        let records = master::parse(&text, "")
+            .with_context(|| format!("Could not parse policy zone {}", path.display()))?;
+        Self::new(Zone::new(records)?)
+    }
+
+    pub fn origin(&self) -> &str {
+        &self.origin
+    }
+
+    /// Triggers known before resolving: the client address, then the name asked.
+    fn check_query(&self, q: &Domain, client: IpAddr) -> Option<&Policy> {
+        self.client_ips
+            .get(client)
+            .or_else(|| self.qnames.get(&q.name))
+    }
+
+    /// Triggers found in an upstream response: addresses answered, then the
+    /// name servers in it and their addresses.
+    fn check_response(&self, res: &Message) -> Option<&Policy> {
+        let ip = |r: &Route| match r.data() {
+            Data::Ipv4(ip) => Some(IpAddr::V4(*ip)),
+            Data::Ipv6(ip) => Some(IpAddr::V6(*ip)),
+            _ => None,
+        };
+        let servers: Vec<&String> = res
+            .answers()
+            .iter()
+            .chain(res.authorities())
+            .filter(|r| r.domain().record == Record::NS)
+            .filter_map(|r| match r.data() {
+                Data::Name(ns) => Some(ns),
+                _ => None,
+            })
+            .collect();
+
+        let mut answered = res.answers().iter().filter_map(ip);
+        let mut ns_ips = res
+            .additionals()
+            .iter()
+            .filter(|r| servers.iter().any(|ns| in_zone(&r.domain().name, ns)))
+            .filter_map(ip);
+
+        answered
+            .find_map(|ip| self.response_ips.get(ip))
+            .or_else(|| servers.iter().find_map(|ns| self.nsdnames.get(ns)))
+            .or_else(|| ns_ips.find_map(|ip| self.ns_ips.get(ip)))
+    }
+}
+
+/// A policy triggered by one of the zones.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hit {
+    pub zone: String,
+    pub policy: Policy,
+}
+
+#[derive(Debug)]
+struct Source {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    zone: PolicyZone,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Every policy zone, searched in the order given; each is read again once its
+/// file changes, see [`Rpz::refresh`].
+#[derive(Debug, Default)]
+pub struct Rpz {
+    sources: RwLock<Vec<Source>>,
+}
+
+impl Rpz {
+    pub fn load(&mut self, path: &Path) -> Result<&PolicyZone> {
+        let source = Source {
+            path: path.to_path_buf(),
+            modified: modified(path),
+            zone: PolicyZone::load(path)?,
+        };
+        let sources = self.sources.get_mut().expect("Policy zones lock poisoned");
+        sources.push(source);
+        Ok(&sources[sources.len() - 1].zone)
+    }
+
+    /// Reads changed policy files again, meant to run every [`RELOAD_INTERVAL`]
+    /// off the query path. Files are parsed before taking the lock, which is
+    /// only held to swap the new zones in. A file that no longer parses keeps
+    /// its previous policy.
+    pub fn refresh(&self) {
+        let changed: Vec<(usize, PathBuf, Option<SystemTime>)> = {
+            let sources = self.sources.read().expect("Policy zones lock poisoned");
+            sources
+                .iter()
+                .enumerate()
+                .map(|(i, s)| (i, s.path.clone(), modified(&s.path), s.modified))
+                .filter(|(_, _, modified, previous)| modified != previous)
+                .map(|(i, path, modified, _)| (i, path, modified))
+                .collect()
+        };
+
+        for (i, path, modified) in changed {
+            let loaded = PolicyZone::load(&path);
+            let mut sources = self.sources.write().expect("Policy zones lock poisoned");
+            let source = &mut sources[i];
+            source.modified = modified;
+            match loaded {
+                Ok(zone) => {
+                    println!("Reloaded policy zone {}", zone.origin());
+                    source.zone = zone;
+                }
+                Err(e) => println!("Kept previous policy zone {}: {e:#}", source.zone.origin()),
+            }
+        }
+    }
+
+    fn find(&self, check: impl Fn(&PolicyZone) -> Option<&Policy>) -> Option<Hit> {
+        let sources = self.sources.read().expect("Policy zones lock poisoned");
+        sources.iter().find_map(|s| {
+            let policy = check(&s.zone)?.clone();
+            Some(Hit {
+                zone: s.zone.origin.clone(),
+                policy,
+            })
+        })
+    }
+
+    /// The first policy triggered by `q` asked by `client`.
+    pub fn check_query(&self, q: &Domain, client: IpAddr) -> Option<Hit> {
+        self.find(|z| z.check_query(q, client))
+    }
+
+    /// The first policy triggered by the contents of an upstream response.
+    pub fn check_response(&self, res: &Message) -> Option<Hit> {
+        self.find(|z| z.check_response(res))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{header::PacketId, Header};
+    use std::net::Ipv4Addr;
+
+    const RPZ: &str = r#"
+$ORIGIN rpz.test.
+@                               SOA localhost. root.localhost. 1 1h 15m 30d 2h
+                                NS  localhost.
+bad.example                     CNAME   .
+*.bad.example                   CNAME   .
+empty.example                   CNAME   *.
+ok.bad.example                  CNAME   rpz-passthru.
+silent.example                  CNAME   rpz-drop.
+big.example                     CNAME   rpz-tcp-only.
+walled.example                  A       10.0.0.1
+walled.example                  TXT     "blocked"
+moved.example                   CNAME   garden.example.
+32.1.0.0.10.rpz-client-ip       CNAME   rpz-drop.
+24.0.2.0.192.rpz-ip             CNAME   .
+48.zz.db8.2001.rpz-ip           CNAME   *.
+ns.evil.example.rpz-nsdname     CNAME   .
+32.9.9.9.9.rpz-nsip             CNAME   *.
+"#;
+
+    fn zone() -> PolicyZone {
+        PolicyZone::new(Zone::new(master::parse(RPZ, "").unwrap()).unwrap()).unwrap()
+    }
+
+    fn client() -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))
+    }
+
+    fn query(name: &str, record: Record) -> Message {
+        let mut msg = Message::new(Header::query(PacketId(7)));
+        msg.set_questions(vec![Domain::new(name, record)]).unwrap();
+        msg
+    }
+
+    #[test]
+    fn test_parse_trigger_ip() {
+        assert_eq!(
+            parse_trigger_ip("24.0.2.0.192").unwrap(),
+            "192.0.2.0/24".parse().unwrap()
+        );
+        assert_eq!(
+            parse_trigger_ip("48.zz.db8.2001").unwrap(),
+            "2001:db8::/48".parse().unwrap()
+        );
+        assert_eq!(
+            parse_trigger_ip("128.1.zz").unwrap(),
+            "::1/128".parse().unwrap()
+        );
+        assert!(parse_trigger_ip("33.1.0.0.10").is_err());
+        assert!(parse_trigger_ip("x.1.0.0.10").is_err());
+    }
+
+    #[test]
+    fn test_check_qname() {
+        let z = zone();
+        let check = |name| z.check_query(&Domain::new_aa(name), client()).cloned();
+        assert_eq!(check("BAD.example"), Some(Policy::NxDomain));
+        assert_eq!(check("x.y.bad.example"), Some(Policy::NxDomain));
+        assert_eq!(check("ok.bad.example"), Some(Policy::Passthru));
+        assert_eq!(check("empty.example"), Some(Policy::NoData));
+        assert_eq!(check("silent.example"), Some(Policy::Drop));
+        assert_eq!(check("big.example"), Some(Policy::TcpOnly));
+        assert_eq!(check("good.example"), None);
+        assert!(matches!(check("walled.example"), Some(Policy::Local(r)) if r.len() == 2));
+
+        let q = Domain::new_aa("good.example");
+        let from = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(z.check_query(&q, from), Some(&Policy::Drop));
+    }
+
+    #[test]
+    fn test_check_response() {
+        let z = zone();
+        let route = |name: &str, record, data| Route::new(Domain::new(name, record), 60, data);
+        let mut res = Message::new_response(&query("a.example", Record::AA));
+        res.set_answers(vec![route(
+            "a.example",
+            Record::AA,
+            Data::Ipv4(Ipv4Addr::new(192, 0, 2, 80)),
+        )])
+        .unwrap();
+        assert_eq!(z.check_response(&res), Some(&Policy::NxDomain));
+
+        let mut res = Message::new_response(&query("b.example", Record::AAAA));
+        res.set_answers(vec![route(
+            "b.example",
+            Record::AAAA,
+            Data::Ipv6("2001:db8::5".parse().unwrap()),
+        )])
+        .unwrap();
+        assert_eq!(z.check_response(&res), Some(&Policy::NoData));
+
+        let mut res = Message::new_response(&query("c.example", Record::AA));
+        let ns = route("example", Record::NS, Data::Name("ns.evil.example".into()));
+        res.set_authorities(vec![ns]).unwrap();
+        assert_eq!(z.check_response(&res), Some(&Policy::NxDomain));
+
+        let ns = route("example", Record::NS, Data::Name("ns.other.example".into()));
+        let glue = route(
+            "ns.other.example",
+            Record::AA,
+            Data::Ipv4(Ipv4Addr::new(9, 9, 9, 9)),
+        );
+        res.set_authorities(vec![ns]).unwrap();
+        res.set_additionals(vec![glue]).unwrap();
+        assert_eq!(z.check_response(&res), Some(&Policy::NoData));
+
+        res.set_additionals(vec![]).unwrap();
+        assert_eq!(z.check_response(&res), None);
+    }
+
+    #[test]
+    fn test_policy_answer() {
+        let z = zone();
+        let q = query("walled.example", Record::AA);
+        let local = z.check_query(&q.questions()[0], client()).unwrap();
+        let res = local.answer(&q).unwrap().unwrap();
+        assert_eq!(res.answers().len(), 1);
+        assert_eq!(res.answers()[0].domain().name, "walled.example");
+        assert_eq!(
+            res.answers()[0].data(),
+            &Data::Ipv4(Ipv4Addr::new(10, 0, 0, 1))
+        );
+
+        let q = query("moved.example", Record::AA);
+        let rewrite = z.check_query(&q.questions()[0], client()).unwrap();
+        let res = rewrite.answer(&q).unwrap().unwrap();
+        assert_eq!(res.answers()[0].domain().record, Record::CNAME);
+
+        let res = Policy::TcpOnly.answer(&q).unwrap().unwrap();
+        assert_eq!(res.header().tc, Truncation::Truncated);
+        assert!(res.answers().is_empty());
+
+        let res = Policy::NxDomain.answer(&q).unwrap().unwrap();
+        assert_eq!(res.header().r_code, OpCode::name_error());
+        assert!(Policy::Drop.answer(&q).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_reload() {
+        let path = std::env::temp_dir().join(format!("rpz-{}.zone", std::process::id()));
+        fs::write(&path, RPZ).unwrap();
+        let mut rpz = Rpz::default();
+        assert_eq!(rpz.load(&path).unwrap().origin(), "rpz.test");
+
+        let q = Domain::new_aa("new.example");
+        assert_eq!(rpz.check_query(&q, client()), None);
+
+        fs::write(&path, format!("{RPZ}new.example CNAME .\n")).unwrap();
+        rpz.sources.get_mut().unwrap()[0].modified = None;
+        assert_eq!(rpz.check_query(&q, client()), None);
+
+        rpz.refresh();
+        let hit = rpz.check_query(&q, client()).unwrap();
+        assert_eq!(hit.zone, "rpz.test");
+        assert_eq!(hit.policy, Policy::NxDomain);
+        fs::remove_file(path).unwrap();
+    }
+}
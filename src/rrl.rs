@@ -0,0 +1,260 @@
+use crate::{
+    cidr::Cidr,
+    message::{header::OpCode, Message},
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Most client networks tracked at once; idle ones are forgotten past it.
+const MAX_BUCKETS: usize = 65536;
+
+/// Kind of response, each limited on its own.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Category {
+    /// Answers, including those without records.
+    Answer,
+    NxDomain,
+    /// Every other response code.
+    Error,
+}
+
+impl Category {
+    pub fn of(res: &Message) -> Self {
+        match res.header().r_code {
+            c if c == OpCode::no_error() => Self::Answer,
+            c if c == OpCode::name_error() => Self::NxDomain,
+            _ => Self::Error,
+        }
+    }
+}
+
+impl fmt::Display for Category {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Answer => write!(f, "answers"),
+            Self::NxDomain => write!(f, "NXDOMAIN"),
+            Self::Error => write!(f, "errors"),
+        }
+    }
+}
+
+/// What to do with a response.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verdict {
+    Send,
+    /// Send an empty, truncated response instead, so that real clients retry
+    /// over TCP.
+    Slip,
+    Drop,
+}
+
+/// How many responses each client network gets per second.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Responses per second for each category, unlimited when zero.
+    pub answers: u32,
+    pub nxdomains: u32,
+    pub errors: u32,
+    /// Every how many limited responses one is slipped, never when zero.
+    pub slip: u32,
+    /// Only log what would be limited.
+    pub log_only: bool,
+    /// Networks never limited.
+    pub exempt: Vec<Cidr>,
+    /// Prefix lengths grouping clients into networks.
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            answers: 0,
+            nxdomains: 0,
+            errors: 0,
+            slip: 2,
+            log_only: false,
+            exempt: vec![],
+            ipv4_prefix: 24,
+            ipv6_prefix: 56,
+        }
+    }
+}
+
+impl Config {
+    fn rate(&self, category: Category) -> u32 {
+        match category {
+            Category::Answer => self.answers,
+            Category::NxDomain => self.nxdomains,
+            Category::Error => self.errors,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// Responses limited since the last one sent.
+    limited: u32,
+}
+
+/// Response rate limiting: a token bucket per client network and category,
+/// holding one second worth of responses.
+#[derive(Debug, Default)]
+pub struct Limiter {
+    config: Config,
+    buckets: Mutex<HashMap<(Cidr, Category), Bucket>>,
+}
+
+impl Limiter {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            buckets: Default::default(),
+        }
+    }
+
+    /// What to do with `res` to `client` at `now`.
+    pub fn check(&self, client: IpAddr, res: &Message, now: Instant) -> Verdict {
+        let category = Category::of(res);
+        let rate = self.config.rate(category);
+        if rate == 0 || self.config.exempt.iter().any(|n| n.contains(client)) {
+            return Verdict::Send;
+        }
+        let prefix = match client {
+            IpAddr::V4(_) => self.config.ipv4_prefix,
+            IpAddr::V6(_) => self.config.ipv6_prefix,
+        };
+        let net = Cidr::network(client, prefix);
+
+        let mut buckets = self.buckets.lock().expect("Limiter lock poisoned");
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(&(net, category)) {
+            // Buckets idle for a second are full again, as good as new.
+            buckets
+                .retain(|_, b| now.saturating_duration_since(b.updated) < Duration::from_secs(1));
+            if buckets.len() >= MAX_BUCKETS {
+                buckets.clear();
+            }
+        }
+        let bucket = buckets.entry((net, category)).or_insert(Bucket {
+            tokens: rate as f64,
+            updated: now,
+            limited: 0,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate as f64).min(rate as f64);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.limited = 0;
+            return Verdict::Send;
+        }
+        bucket.limited += 1;
+        if bucket.limited == 1 {
+            let only = match self.config.log_only {
+                true => " (log only)",
+                false => "",
+            };
+            println!("Limiting {category} to {net}{only}");
+        }
+        match self.config.log_only {
+            true => Verdict::Send,
+            false if bucket.limited.checked_rem(self.config.slip) == Some(0) => Verdict::Slip,
+            false => Verdict::Drop,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{header::PacketId, Header};
+
+    fn response(code: OpCode) -> Message {
+        let mut res = Message::new(Header::response(PacketId(1)));
+        res.set_r_code(code);
+        res
+    }
+
+    fn limiter(config: Config) -> Limiter {
+        Limiter::new(Config {
+            answers: 2,
+            nxdomains: 1,
+            ..config
+        })
+    }
+
+    #[test]
+    fn test_category() {
+        assert_eq!(
+            Category::of(&response(OpCode::no_error())),
+            Category::Answer
+        );
+        assert_eq!(
+            Category::of(&response(OpCode::name_error())),
+            Category::NxDomain
+        );
+        assert_eq!(Category::of(&response(OpCode::refused())), Category::Error);
+    }
+
+    #[test]
+    fn test_limit() {
+        let rrl = limiter(Config::default());
+        let (ok, now) = (response(OpCode::no_error()), Instant::now());
+        let client = "10.0.0.1".parse().unwrap();
+        let verdicts: Vec<_> = (0..6).map(|_| rrl.check(client, &ok, now)).collect();
+        use Verdict::*;
+        assert_eq!(verdicts, [Send, Send, Drop, Slip, Drop, Slip]);
+
+        // Same network, other category or network, and later on.
+        assert_eq!(rrl.check("10.0.0.2".parse().unwrap(), &ok, now), Drop);
+        let nx = response(OpCode::name_error());
+        assert_eq!(rrl.check(client, &nx, now), Send);
+        assert_eq!(rrl.check(client, &nx, now), Drop);
+        assert_eq!(rrl.check("10.0.1.1".parse().unwrap(), &ok, now), Send);
+        assert_eq!(
+            rrl.check(client, &ok, now + Duration::from_millis(500)),
+            Send
+        );
+        assert_eq!(
+            rrl.check(client, &ok, now + Duration::from_millis(500)),
+            Drop
+        );
+
+        // Errors are not limited here.
+        let refused = response(OpCode::refused());
+        assert!((0..10).all(|_| rrl.check(client, &refused, now) == Send));
+    }
+
+    #[test]
+    fn test_modes() {
+        let client: IpAddr = "10.0.0.1".parse().unwrap();
+        let (ok, now) = (response(OpCode::no_error()), Instant::now());
+
+        let rrl = limiter(Config {
+            log_only: true,
+            ..Config::default()
+        });
+        assert!((0..10).all(|_| rrl.check(client, &ok, now) == Verdict::Send));
+
+        let rrl = limiter(Config {
+            exempt: vec!["10.0.0.0/8".parse().unwrap()],
+            ..Config::default()
+        });
+        assert!((0..10).all(|_| rrl.check(client, &ok, now) == Verdict::Send));
+
+        let rrl = limiter(Config {
+            slip: 0,
+            ..Config::default()
+        });
+        let verdicts: Vec<_> = (0..6).map(|_| rrl.check(client, &ok, now)).collect();
+        assert_eq!(verdicts[2..], [Verdict::Drop; 4]);
+    }
+}
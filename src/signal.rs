@@ -0,0 +1,59 @@
+use anyhow::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const SIGHUP: i32 = 1;
+const SIGINT: i32 = 2;
+const SIGTERM: i32 = 15;
+/// What `signal` returns on failure.
+const SIG_ERR: usize = usize::MAX;
+
+static HANGUP: AtomicBool = AtomicBool::new(false);
+static TERMINATE: AtomicBool = AtomicBool::new(false);
+
+// From the C library std links against.
+extern "C" {
+    fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+}
+
+/// Only notes the signal: nothing else is safe to do in a handler.
+extern "C" fn handle(signum: i32) {
+    match signum {
+        SIGHUP => HANGUP.store(true, Ordering::SeqCst),
+        _ => TERMINATE.store(true, Ordering::SeqCst),
+    }
+}
+
+/// Catches SIGHUP, asking for a reload, and SIGINT and SIGTERM, asking to
+/// stop. Blocking calls are restarted rather than interrupted, so whoever
+/// waits on them should poll `hangup` and `terminating` on a timeout.
+pub fn install() -> Result<()> {
+    for signum in [SIGHUP, SIGINT, SIGTERM] {
+        // SAFETY: the handler only stores into atomics.
+        let previous = unsafe { signal(signum, handle) };
+        anyhow::ensure!(previous != SIG_ERR, "Could not catch signal {signum}");
+    }
+    Ok(())
+}
+
+/// Whether a reload was asked for since the last call.
+pub fn hangup() -> bool {
+    HANGUP.swap(false, Ordering::SeqCst)
+}
+
+/// Whether the server was asked to stop.
+pub fn terminating() -> bool {
+    TERMINATE.load(Ordering::SeqCst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handle() {
+        handle(SIGHUP);
+        assert!(hangup());
+        assert!(!hangup());
+        assert!(!terminating());
+    }
+}
@@ -1,15 +1,63 @@
+pub mod pool;
+pub mod tcp;
 use crate::message::Message;
-use anyhow::Result;
+use anyhow::{Context, Result};
 use std::{
+    io,
     marker::PhantomData,
-    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    time::{Duration, Instant},
 };
 
+/// Largest datagram read, as big as EDNS peers may send.
+const MAX_UDP: usize = 4096;
+
 pub struct DnsClient;
 pub struct DnsService;
 
+#[derive(Debug, thiserror::Error)]
+pub enum SocketError {
+    #[error("No response received after {0:?}")]
+    Timeout(Duration),
+}
+
+/// A query that could not be read, with the FORMERR answering it when its
+/// header could be.
+#[derive(Debug, thiserror::Error)]
+#[error("Malformed query from {addr}: {reason}")]
+pub struct Malformed {
+    pub addr: SocketAddr,
+    pub reply: Option<Message>,
+    reason: String,
+}
+
+/// How long a client waits for an upstream answer, and how often it asks again.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Read timeout of the first attempt.
+    pub timeout: Duration,
+    /// Total number of times the query is sent.
+    pub attempts: u32,
+    /// Factor applied to the read timeout after every failed attempt.
+    pub backoff: u32,
+    /// Upper bound for the whole query, across all attempts.
+    pub deadline: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_millis(1000),
+            attempts: 3,
+            backoff: 2,
+            deadline: Duration::from_millis(5000),
+        }
+    }
+}
+
 pub struct DnsSocket<T> {
     socket: UdpSocket,
+    policy: RetryPolicy,
     mode: PhantomData<T>,
 }
 
@@ -20,44 +68,69 @@ impl DnsSocket<DnsService> {
         let socket = UdpSocket::bind(addr)?;
         Ok(Self {
             socket,
+            policy: RetryPolicy::default(),
             mode: PhantomData,
         })
     }
 
-    pub fn read(&self) -> Result<(Message, SocketAddr)> {
-        let mut buf = [0; 512];
+    /// How long `read` waits for a query before failing, forever when `None`.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        Ok(self.socket.set_read_timeout(timeout)?)
+    }
+
+    /// Next query, along with its wire bytes and who sent it.
+    pub fn read(&self) -> Result<(Message, Vec<u8>, SocketAddr)> {
+        let mut buf = [0; MAX_UDP];
         let (size, addr) = self.socket.recv_from(&mut buf)?;
         anyhow::ensure!(size > 12, "Packet is not long enough: {size}");
 
-        let msg = Message::try_from(buf.as_ref())?;
-        anyhow::ensure!(msg.is_query());
+        let msg = match Message::try_from(&buf[..size]) {
+            Ok(msg) => msg,
+            Err(e) => {
+                return Err(Malformed {
+                    addr,
+                    reply: Message::format_error(&buf[..size]),
+                    reason: e.to_string(),
+                }
+                .into())
+            }
+        };
+        anyhow::ensure!(msg.is_query(), "Not a query from {addr}");
 
-        Ok((msg, addr))
+        Ok((msg, buf[..size].to_vec(), addr))
     }
 
-    pub fn send_to(&self, m: &Message, addr: SocketAddr) -> Result<()> {
+    /// Sends `m` to `addr`, returning its size on the wire.
+    pub fn send_to(&self, m: &Message, addr: SocketAddr) -> Result<usize> {
         let buf = m.flush();
         let sent = self.socket.send_to(&buf, addr)?;
         anyhow::ensure!(sent == buf.len());
-        Ok(())
+        Ok(sent)
     }
 }
 
 impl DnsSocket<DnsClient> {
+    /// Opens a socket of the same address family as `addr`, talking to it alone.
     pub fn connect(addr: &str) -> Result<Self> {
-        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
-        socket.connect(addr)?;
+        let peer = addr
+            .to_socket_addrs()?
+            .next()
+            .with_context(|| format!("Could not resolve {addr}"))?;
+        let local: IpAddr = match peer {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        let socket = UdpSocket::bind((local, 0))?;
+        socket.connect(peer)?;
         Ok(Self {
             socket,
+            policy: RetryPolicy::default(),
             mode: PhantomData,
         })
     }
 
-    pub fn recv(&self) -> Result<Message> {
-        let mut buf = [0; 512];
-        self.socket.recv(&mut buf)?;
-        let msg = Message::try_from(buf.as_ref())?;
-        Ok(msg)
+    pub fn with_policy(self, policy: RetryPolicy) -> Self {
+        Self { policy, ..self }
     }
 
     pub fn send(&self, m: &Message) -> Result<()> {
@@ -66,4 +139,169 @@ impl DnsSocket<DnsClient> {
         anyhow::ensure!(sent == buf.len());
         Ok(())
     }
+
+    /// Sends `m` and waits for its response, resending on every read timeout
+    /// until the policy runs out of attempts or hits its deadline. Datagrams
+    /// that do not answer `m` are dropped while the wait goes on.
+    pub fn query(&self, m: &Message) -> Result<Message> {
+        let start = Instant::now();
+        let mut timeout = self.policy.timeout;
+
+        for _ in 0..self.policy.attempts.max(1) {
+            let left = self.policy.deadline.saturating_sub(start.elapsed());
+            if left.is_zero() {
+                break;
+            }
+
+            self.send(m)?;
+            match self.recv(m, timeout.min(left)) {
+                Err(e) if e.is::<SocketError>() => {
+                    timeout = timeout.saturating_mul(self.policy.backoff.max(1))
+                }
+                res => return res,
+            }
+        }
+
+        Err(SocketError::Timeout(start.elapsed()).into())
+    }
+
+    fn recv(&self, query: &Message, timeout: Duration) -> Result<Message> {
+        let peer = self.socket.peer_addr()?;
+        let until = Instant::now() + timeout;
+
+        let mut buf = [0; MAX_UDP];
+        loop {
+            let left = until.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(SocketError::Timeout(timeout).into());
+            }
+            self.socket.set_read_timeout(Some(left))?;
+
+            let (size, from) = match self.socket.recv_from(&mut buf) {
+                Ok(r) => r,
+                Err(e) if is_timeout(&e) => return Err(SocketError::Timeout(timeout).into()),
+                Err(e) => return Err(e.into()),
+            };
+            if from != peer {
+                continue;
+            }
+
+            match Message::try_from(&buf[..size]) {
+                Ok(msg) if msg.is_response_to(query) => return Ok(msg),
+                _ => continue,
+            }
+        }
+    }
+}
+
+pub fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// Whether `DnsSocket::read` failed only because no query came in time or a
+/// signal cut the wait short, as it does for reads with a timeout.
+pub fn is_idle(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<io::Error>()
+        .is_some_and(|e| is_timeout(e) || e.kind() == io::ErrorKind::Interrupted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{domain::Domain, header::PacketId, Header};
+    use std::thread;
+
+    #[test]
+    fn test_query_timeout() {
+        let silent = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = silent.local_addr().unwrap().to_string();
+
+        let policy = RetryPolicy {
+            timeout: Duration::from_millis(10),
+            attempts: 3,
+            backoff: 2,
+            deadline: Duration::from_millis(1000),
+        };
+        let client = DnsSocket::connect(&addr).unwrap().with_policy(policy);
+
+        let mut query = Message::new(Header::query(PacketId(1)));
+        query
+            .set_questions(vec![Domain::new_aa("hernan.rs")])
+            .unwrap();
+
+        let err = client.query(&query).unwrap_err();
+        assert!(err.is::<SocketError>());
+
+        let mut buf = [0; MAX_UDP];
+        silent.set_nonblocking(true).unwrap();
+        let sent = std::iter::from_fn(|| silent.recv(&mut buf).ok()).count();
+        assert_eq!(sent, 3);
+    }
+
+    #[test]
+    fn test_connect_ipv6() {
+        // Hosts without IPv6 have nothing to test.
+        let Ok(upstream) = UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)) else {
+            return;
+        };
+        let addr = upstream.local_addr().unwrap().to_string();
+        let client = DnsSocket::connect(&addr).unwrap();
+
+        let mut query = Message::new(Header::query(PacketId(6)));
+        query
+            .set_questions(vec![Domain::new_aa("hernan.rs")])
+            .unwrap();
+        let fake = thread::spawn(move || {
+            let mut buf = [0; MAX_UDP];
+            let (size, from) = upstream.recv_from(&mut buf).unwrap();
+            let query = Message::try_from(&buf[..size]).unwrap();
+            upstream
+                .send_to(&Message::new_response(&query).flush(), from)
+                .unwrap();
+        });
+
+        assert!(client.query(&query).unwrap().is_response_to(&query));
+        fake.join().unwrap();
+    }
+
+    #[test]
+    fn test_query_discards_mismatches() {
+        let upstream = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = upstream.local_addr().unwrap().to_string();
+        let client = DnsSocket::connect(&addr).unwrap();
+
+        let mut query = Message::new(Header::query(PacketId(7)));
+        query
+            .set_questions(vec![Domain::new_aa("hErNaN.rs")])
+            .unwrap();
+
+        let fake = thread::spawn(move || {
+            let mut buf = [0; MAX_UDP];
+            let (size, from) = upstream.recv_from(&mut buf).unwrap();
+            let query = Message::try_from(&buf[..size]).unwrap();
+
+            let wrong_id = Message::new(Header::response(PacketId(8)));
+            upstream.send_to(&wrong_id.flush(), from).unwrap();
+
+            let mut wrong_name = Message::new_response(&query);
+            wrong_name
+                .set_questions(vec![Domain::new_aa("hernan.rs")])
+                .unwrap();
+            upstream.send_to(&wrong_name.flush(), from).unwrap();
+
+            let spoofer = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            let answer = Message::new_response(&query);
+            spoofer.send_to(&answer.flush(), from).unwrap();
+
+            upstream.send_to(&answer.flush(), from).unwrap();
+        });
+
+        let res = client.query(&query).unwrap();
+        fake.join().unwrap();
+
+        assert!(res.is_response_to(&query));
+    }
 }
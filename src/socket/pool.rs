@@ -0,0 +1,91 @@
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+/// A fixed number of threads doing jobs handed to them, with room for as
+/// many more waiting. Jobs beyond that are turned down rather than queued,
+/// as connections over the limit are closed by `tcp::accept`.
+pub struct Pool<T> {
+    queue: SyncSender<T>,
+}
+
+impl<T: Send + 'static> Pool<T> {
+    /// Starts `workers` threads doing every job with `work`. A job that
+    /// panics does not take its thread down.
+    pub fn new<F>(workers: usize, work: F) -> Self
+    where
+        F: Fn(T) + Send + Sync + 'static,
+    {
+        let (queue, jobs) = mpsc::sync_channel(workers);
+        let (jobs, work) = (Arc::new(Mutex::new(jobs)), Arc::new(work));
+        for _ in 0..workers.max(1) {
+            let (jobs, work) = (jobs.clone(), work.clone());
+            thread::spawn(move || {
+                while let Some(job) = next(&jobs) {
+                    let _ = panic::catch_unwind(AssertUnwindSafe(|| work(job)));
+                }
+            });
+        }
+        Self { queue }
+    }
+
+    /// Hands `job` to a worker, giving it back when every worker is busy
+    /// and the queue is full.
+    pub fn submit(&self, job: T) -> Result<(), T> {
+        self.queue.try_send(job).map_err(|e| match e {
+            TrySendError::Full(job) | TrySendError::Disconnected(job) => job,
+        })
+    }
+}
+
+/// The next job, `None` once the pool is dropped and its queue drained.
+fn next<T>(jobs: &Mutex<Receiver<T>>) -> Option<T> {
+    let jobs = jobs.lock().unwrap_or_else(|e| e.into_inner());
+    jobs.recv().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Barrier;
+
+    #[test]
+    fn test_pool() {
+        let (started, taken) = mpsc::channel();
+        let (done, results) = mpsc::channel();
+        let gate = Arc::new(Barrier::new(3));
+        let pool = {
+            let gate = gate.clone();
+            Pool::new(2, move |n: u32| {
+                started.send(n).unwrap();
+                if n == 0 {
+                    panic!("job failed");
+                }
+                if n < 10 {
+                    gate.wait();
+                }
+                done.send(n).unwrap();
+            })
+        };
+
+        // A panicking job leaves both workers, which then block on the gate.
+        for n in 0..3 {
+            assert_eq!(pool.submit(n), Ok(()));
+            assert_eq!(taken.recv().unwrap(), n);
+        }
+        // Two jobs wait in the queue, and the next one is turned down.
+        assert_eq!(pool.submit(10), Ok(()));
+        assert_eq!(pool.submit(11), Ok(()));
+        assert_eq!(pool.submit(12), Err(12));
+
+        gate.wait();
+        let mut got: Vec<u32> = results.iter().take(4).collect();
+        got.sort();
+        assert_eq!(got, [1, 2, 10, 11]);
+    }
+}
@@ -0,0 +1,417 @@
+use super::{is_timeout, RetryPolicy, SocketError};
+use crate::{message::Message, signal};
+use anyhow::{Context, Result};
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Reads one message framed with its two byte length, as in RFC 1035 4.2.2.
+pub fn read_frame(r: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut len = [0; 2];
+    r.read_exact(&mut len)?;
+    let mut buf = vec![0; u16::from_be_bytes(len) as usize];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+pub fn write_frame(w: &mut impl Write, buf: &[u8]) -> Result<()> {
+    let len = u16::try_from(buf.len()).context("Message too long for TCP")?;
+    let mut frame = Vec::with_capacity(buf.len() + 2);
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(buf);
+    w.write_all(&frame)?;
+    w.flush()?;
+    Ok(())
+}
+
+/// Opens a connection to `addr`, giving up after `timeout`.
+pub fn connect(addr: &str, timeout: Duration) -> Result<TcpStream> {
+    let addr = addr
+        .to_socket_addrs()?
+        .next()
+        .with_context(|| format!("Could not resolve {addr}"))?;
+    let stream = TcpStream::connect_timeout(&addr, timeout).map_err(|e| or_timeout(e, timeout))?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+/// Upstream connection speaking DNS over TCP.
+#[derive(Debug)]
+pub struct DnsStream {
+    stream: TcpStream,
+    policy: RetryPolicy,
+}
+
+impl DnsStream {
+    pub fn connect(addr: &str, policy: RetryPolicy) -> Result<Self> {
+        let stream = connect(addr, policy.deadline)?;
+        Ok(Self { stream, policy })
+    }
+
+    pub fn send(&mut self, m: &Message) -> Result<()> {
+        self.stream.set_write_timeout(Some(self.policy.deadline))?;
+        write_frame(&mut self.stream, &m.flush())
+    }
+
+    /// Reads the next message on the stream, whatever it answers.
+    pub fn recv(&mut self) -> Result<Message> {
+        self.stream.set_read_timeout(Some(self.policy.deadline))?;
+        let buf = read_frame(&mut self.stream).map_err(|e| or_timeout(e, self.policy.deadline))?;
+        Message::try_from(buf.as_ref())
+    }
+
+    /// Sends `m` and reads until its response shows up, within the deadline of
+    /// the policy. Anything else on the stream is dropped.
+    pub fn query(&mut self, m: &Message) -> Result<Message> {
+        let deadline = self.policy.deadline;
+        let until = Instant::now() + deadline;
+        self.send(m)?;
+        loop {
+            let left = until.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(SocketError::Timeout(deadline).into());
+            }
+            self.stream.set_read_timeout(Some(left))?;
+            let buf = read_frame(&mut self.stream).map_err(|e| or_timeout(e, deadline))?;
+            match Message::try_from(buf.as_ref()) {
+                Ok(msg) if msg.is_response_to(m) => return Ok(msg),
+                _ => continue,
+            }
+        }
+    }
+}
+
+/// How long clients may keep a connection open doing nothing, and how many
+/// of them may be connected at once (RFC 7766 section 6.2).
+#[derive(Clone, Copy, Debug)]
+pub struct ListenPolicy {
+    pub idle: Duration,
+    pub max_connections: usize,
+    /// Threads answering UDP queries, see `pool::Pool`.
+    pub udp_workers: usize,
+}
+
+impl Default for ListenPolicy {
+    fn default() -> Self {
+        Self {
+            idle: Duration::from_secs(10),
+            max_connections: 64,
+            udp_workers: 64,
+        }
+    }
+}
+
+/// Service side of DNS over TCP, answering each connection on its own thread.
+/// Clients may send any number of queries over one connection.
+pub struct DnsListener {
+    listener: TcpListener,
+    policy: ListenPolicy,
+}
+
+impl DnsListener {
+    pub fn bind(addr: &str, policy: ListenPolicy) -> Result<Self> {
+        let listener =
+            TcpListener::bind(addr).with_context(|| format!("Could not listen on {addr}"))?;
+        Ok(Self { listener, policy })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Answers queries with `handler` until the server stops. Handlers
+    /// return every message answering a query, none to leave it unanswered.
+    pub fn serve<F, R>(self, handler: F) -> Result<()>
+    where
+        F: Fn(&Message, SocketAddr) -> R + Send + Sync + 'static,
+        R: IntoIterator<Item = Message>,
+    {
+        let idle = self.policy.idle;
+        accept(&self.listener, self.policy, move |stream| {
+            serve_connection(stream, idle, &handler)
+        })
+    }
+}
+
+/// A connection counted against the most allowed, given back when dropped,
+/// even by a thread unwinding from a panic.
+struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// How long to wait after failing to accept a connection.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// How often listeners look out for connections and shutdown, kept short as
+/// it adds up to as much to connecting.
+const ACCEPT_POLL: Duration = Duration::from_millis(10);
+
+/// How often idle connections look out for shutdown.
+const POLL: Duration = Duration::from_millis(250);
+
+/// Hands every connection to `serve` on its own thread, as long as fewer than
+/// the most allowed are open. Connections over the limit are closed right
+/// away. Stops accepting once the server is asked to stop.
+pub fn accept<F>(listener: &TcpListener, policy: ListenPolicy, serve: F) -> Result<()>
+where
+    F: Fn(TcpStream) -> Result<()> + Send + Sync + 'static,
+{
+    let serve = Arc::new(serve);
+    let open = Arc::new(AtomicUsize::new(0));
+    listener.set_nonblocking(true)?;
+    while !signal::terminating() {
+        // Failing to accept, as when out of file descriptors, should not
+        // stop the listener: back off and let the pressure go down.
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if is_timeout(&e) || e.kind() == io::ErrorKind::Interrupted => {
+                thread::sleep(ACCEPT_POLL);
+                continue;
+            }
+            Err(e) => {
+                println!("Could not accept connection: {e}");
+                thread::sleep(ACCEPT_BACKOFF);
+                continue;
+            }
+        };
+        if stream.set_nonblocking(false).is_err() {
+            continue;
+        }
+        if open.load(Ordering::Relaxed) >= policy.max_connections {
+            continue;
+        }
+        open.fetch_add(1, Ordering::Relaxed);
+
+        let (slot, serve) = (Slot(open.clone()), serve.clone());
+        thread::spawn(move || {
+            let _slot = slot;
+            if let Err(e) = serve(stream) {
+                println!("TCP connection failed: {e}");
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Waits up to `idle` for the client to send something, giving up early once
+/// the server is asked to stop. `false` when nothing came, or the client hung
+/// up.
+pub fn wait_for_data(stream: &TcpStream, idle: Duration) -> io::Result<bool> {
+    let until = Instant::now() + idle;
+    loop {
+        let left = until.saturating_duration_since(Instant::now());
+        if signal::terminating() || left.is_zero() {
+            return Ok(false);
+        }
+        stream.set_read_timeout(Some(left.min(POLL)))?;
+        match stream.peek(&mut [0]) {
+            Ok(n) => {
+                stream.set_read_timeout(Some(idle))?;
+                return Ok(n > 0);
+            }
+            Err(e) if is_timeout(&e) || e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Answers the queries of one client until it hangs up, stays idle for too
+/// long, sends something that is not a query or the server stops. Queries
+/// that cannot be read get FORMERR.
+fn serve_connection<F, R>(mut stream: TcpStream, idle: Duration, handler: &F) -> Result<()>
+where
+    F: Fn(&Message, SocketAddr) -> R,
+    R: IntoIterator<Item = Message>,
+{
+    let peer = stream.peer_addr()?;
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(idle))?;
+    stream.set_write_timeout(Some(idle))?;
+    while wait_for_data(&stream, idle)? {
+        let buf = match read_frame(&mut stream) {
+            Ok(buf) => buf,
+            Err(e) if is_timeout(&e) || e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let msg = match Message::try_from(buf.as_ref()) {
+            Ok(msg) if msg.is_query() => msg,
+            Ok(_) => return Ok(()),
+            Err(e) => match Message::format_error(&buf) {
+                Some(res) => {
+                    println!("Malformed query from {peer}: {e}");
+                    write_frame(&mut stream, &res.flush())?;
+                    continue;
+                }
+                None => return Ok(()),
+            },
+        };
+        for res in handler(&msg, peer) {
+            write_frame(&mut stream, &res.flush())?;
+        }
+    }
+    Ok(())
+}
+
+pub fn or_timeout(e: io::Error, after: Duration) -> anyhow::Error {
+    match is_timeout(&e) {
+        true => SocketError::Timeout(after).into(),
+        false => e.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{
+        domain::Domain,
+        header::{OpCode, PacketId},
+        Header,
+    };
+    use std::{
+        net::{Ipv4Addr, TcpListener},
+        thread,
+    };
+
+    #[test]
+    fn test_frame_round_trip() {
+        let mut buf = vec![];
+        write_frame(&mut buf, &[1, 2, 3]).unwrap();
+        assert_eq!(buf, vec![0, 3, 1, 2, 3]);
+
+        let frame = read_frame(&mut buf.as_slice()).unwrap();
+        assert_eq!(frame, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_query() {
+        let upstream = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = upstream.local_addr().unwrap().to_string();
+
+        let fake = thread::spawn(move || {
+            let (mut stream, _) = upstream.accept().unwrap();
+            let buf = read_frame(&mut stream).unwrap();
+            let query = Message::try_from(buf.as_ref()).unwrap();
+
+            let stale = Message::new(Header::response(PacketId(0)));
+            write_frame(&mut stream, &stale.flush()).unwrap();
+            let answer = Message::new_response(&query);
+            write_frame(&mut stream, &answer.flush()).unwrap();
+        });
+
+        let mut query = Message::new(Header::query(PacketId(3)));
+        query
+            .set_questions(vec![Domain::new_aa("hernan.rs")])
+            .unwrap();
+
+        let mut client = DnsStream::connect(&addr, RetryPolicy::default()).unwrap();
+        let res = client.query(&query).unwrap();
+        fake.join().unwrap();
+
+        assert!(res.is_response_to(&query));
+    }
+
+    #[test]
+    fn test_query_deadline() {
+        let upstream = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = upstream.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let (mut stream, _) = upstream.accept().unwrap();
+            read_frame(&mut stream).unwrap();
+            let stale = Message::new(Header::response(PacketId(0)));
+            while write_frame(&mut stream, &stale.flush()).is_ok() {
+                thread::sleep(Duration::from_millis(20));
+            }
+        });
+
+        let mut query = Message::new(Header::query(PacketId(4)));
+        query
+            .set_questions(vec![Domain::new_aa("hernan.rs")])
+            .unwrap();
+        let policy = RetryPolicy {
+            deadline: Duration::from_millis(100),
+            ..RetryPolicy::default()
+        };
+
+        let mut client = DnsStream::connect(&addr, policy).unwrap();
+        let started = Instant::now();
+        let err = client.query(&query).unwrap_err();
+        assert!(err.is::<SocketError>());
+        assert!(started.elapsed() < Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_listener() {
+        let policy = ListenPolicy {
+            idle: Duration::from_millis(200),
+            max_connections: 1,
+            ..Default::default()
+        };
+        let listener = DnsListener::bind("127.0.0.1:0", policy).unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || listener.serve(|q, _| Some(Message::new_response(q))));
+
+        let mut query = Message::new(Header::query(PacketId(5)));
+        query
+            .set_questions(vec![Domain::new_aa("hernan.rs")])
+            .unwrap();
+
+        let mut client = DnsStream::connect(&addr, RetryPolicy::default()).unwrap();
+        assert!(client.query(&query).is_ok());
+        assert!(client.query(&query).is_ok());
+
+        let mut other = DnsStream::connect(&addr, RetryPolicy::default()).unwrap();
+        assert!(other.query(&query).is_err());
+
+        thread::sleep(Duration::from_millis(400));
+        assert!(client.query(&query).is_err());
+    }
+
+    #[test]
+    fn test_listener_malformed() {
+        let policy = ListenPolicy {
+            idle: Duration::from_millis(500),
+            max_connections: 1,
+            ..Default::default()
+        };
+        let listener = DnsListener::bind("127.0.0.1:0", policy).unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            listener.serve(|q, _| match q.header().id {
+                PacketId(1) => panic!("handler failed"),
+                _ => Some(Message::new_response(q)),
+            })
+        });
+
+        let mut client = DnsStream::connect(&addr, RetryPolicy::default()).unwrap();
+        let bad = [0, 7, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0xc0, 0xff, 0, 1, 0, 1];
+        write_frame(&mut client.stream, &bad).unwrap();
+        let res = client.recv().unwrap();
+        assert_eq!(res.header().id, PacketId(7));
+        assert_eq!(res.header().r_code, OpCode::format_error());
+
+        // A handler panicking gives the connection back all the same.
+        let mut query = Message::new(Header::query(PacketId(1)));
+        query
+            .set_questions(vec![Domain::new_aa("hernan.rs")])
+            .unwrap();
+        assert!(client.query(&query).is_err());
+        thread::sleep(Duration::from_millis(100));
+        let mut client = DnsStream::connect(&addr, RetryPolicy::default()).unwrap();
+        let mut query = Message::new(Header::query(PacketId(2)));
+        query
+            .set_questions(vec![Domain::new_aa("hernan.rs")])
+            .unwrap();
+        assert!(client.query(&query).is_ok());
+    }
+}
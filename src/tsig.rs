@@ -0,0 +1,447 @@
+pub mod hmac;
+use crate::{
+    encoding::decode_base64,
+    message::{
+        data::name_len,
+        header::OpCode,
+        tsig::{self, Tsig},
+        Message,
+    },
+};
+use anyhow::{Context, Result};
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Seconds of clock skew tolerated between signer and verifier.
+const FUDGE: u16 = 300;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    HmacSha256,
+    HmacSha512,
+}
+
+impl Algorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::HmacSha256 => "hmac-sha256",
+            Self::HmacSha512 => "hmac-sha512",
+        }
+    }
+
+    /// Bytes in a MAC.
+    fn mac_len(&self) -> usize {
+        match self {
+            Self::HmacSha256 => 32,
+            Self::HmacSha512 => 64,
+        }
+    }
+
+    fn mac(&self, secret: &[u8], data: &[u8]) -> Vec<u8> {
+        match self {
+            Self::HmacSha256 => hmac::hmac_sha256(secret, data),
+            Self::HmacSha512 => hmac::hmac_sha512(secret, data),
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        [Self::HmacSha256, Self::HmacSha512]
+            .into_iter()
+            .find(|a| a.name().eq_ignore_ascii_case(s.trim_end_matches('.')))
+            .with_context(|| format!("Unsupported TSIG algorithm: {s}"))
+    }
+}
+
+/// A secret shared with a peer, known to both by `name`.
+#[derive(Clone)]
+pub struct Key {
+    pub name: String,
+    pub algorithm: Algorithm,
+    secret: Vec<u8>,
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Key")
+            .field("name", &self.name)
+            .field("algorithm", &self.algorithm)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FromStr for Key {
+    type Err = anyhow::Error;
+    /// Reads `[algorithm:]name:secret` like `dig -y`, the secret in base64.
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.split(':').collect();
+        let (algorithm, name, secret) = match parts[..] {
+            [algorithm, name, secret] => (algorithm.parse()?, name, secret),
+            [name, secret] => (Algorithm::HmacSha256, name, secret),
+            _ => anyhow::bail!("Invalid TSIG key, expected [algorithm:]name:secret"),
+        };
+        let secret = decode_base64(secret).context("Invalid TSIG secret")?;
+        anyhow::ensure!(!name.is_empty(), "Missing TSIG key name");
+        anyhow::ensure!(!secret.is_empty(), "Empty TSIG secret for {name}");
+        Ok(Self {
+            name: name.trim_end_matches('.').to_ascii_lowercase(),
+            algorithm,
+            secret,
+        })
+    }
+}
+
+/// Keys peers may sign messages with, by name.
+#[derive(Clone, Debug, Default)]
+pub struct KeyStore {
+    keys: HashMap<String, Key>,
+}
+
+impl KeyStore {
+    pub fn insert(&mut self, key: Key) {
+        self.keys.insert(key.name.clone(), key);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Key> {
+        self.keys
+            .get(&name.trim_end_matches('.').to_ascii_lowercase())
+    }
+}
+
+/// TSIG failures, named after their RFC 8945 error codes.
+#[derive(Debug, PartialEq, thiserror::Error)]
+#[allow(clippy::enum_variant_names)]
+pub enum TsigError {
+    #[error("Unknown TSIG key")]
+    BadKey,
+    #[error("Invalid TSIG signature")]
+    BadSig,
+    #[error("TSIG signature time outside the allowed window")]
+    BadTime,
+}
+
+impl TsigError {
+    pub fn code(&self) -> u16 {
+        match self {
+            Self::BadKey => tsig::BADKEY,
+            Self::BadSig => tsig::BADSIG,
+            Self::BadTime => tsig::BADTIME,
+        }
+    }
+}
+
+/// Seconds since the epoch, as TSIG records count time.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// `name` in canonical wire format, as MACs and DNSSEC digests cover it.
+pub fn wire_name(name: &str) -> Vec<u8> {
+    let mut bytes = vec![];
+    for label in name.split('.').filter(|l| !l.is_empty()) {
+        bytes.push(label.len() as u8);
+        bytes.extend(label.to_ascii_lowercase().bytes());
+    }
+    bytes.push(0);
+    bytes
+}
+
+/// A signed exchange with a peer: each MAC covers the one before it, so a
+/// response is tied to its request and every message of a multi-message
+/// response to the previous ones (RFC 8945 section 5.3.1).
+#[derive(Debug)]
+pub struct Session {
+    key: Key,
+    prior: Option<Vec<u8>>,
+    /// Whether the last message of the exchange was signed (`true`) or
+    /// verified (`false`) here.
+    sending: Option<bool>,
+}
+
+impl Session {
+    pub fn new(key: Key) -> Self {
+        Self {
+            key,
+            prior: None,
+            sending: None,
+        }
+    }
+
+    pub fn key(&self) -> &Key {
+        &self.key
+    }
+
+    /// Bytes the TSIG record added by `sign` takes on the wire.
+    pub fn size(&self) -> usize {
+        let names = name_len(&self.key.name) + name_len(self.key.algorithm.name());
+        names as usize + 10 + 16 + self.key.algorithm.mac_len()
+    }
+
+    /// What the MAC of `tsig` covers (RFC 8945 section 4.3). Messages after
+    /// the first of a response only add their timers.
+    fn digest(&self, tsig: &Tsig, continued: bool) -> Vec<u8> {
+        let mut data = vec![];
+        if let Some(prior) = &self.prior {
+            data.extend((prior.len() as u16).to_be_bytes());
+            data.extend(prior);
+        }
+        data.extend(&tsig.signed);
+        if !continued {
+            data.extend(wire_name(&tsig.key_name));
+            data.extend(tsig::ANY.to_be_bytes());
+            data.extend(0u32.to_be_bytes());
+            data.extend(wire_name(&tsig.algorithm));
+        }
+        data.extend(&tsig.time_signed.to_be_bytes()[2..]);
+        data.extend(tsig.fudge.to_be_bytes());
+        if !continued {
+            data.extend(tsig.error.to_be_bytes());
+            data.extend((tsig.other.len() as u16).to_be_bytes());
+            data.extend(&tsig.other);
+        }
+        data
+    }
+
+    /// Signs `msg`, the next message sent in the exchange.
+    pub fn sign(&mut self, msg: &mut Message, now: u64) -> Result<()> {
+        self.sign_with(msg, now, 0, vec![])
+    }
+
+    fn sign_with(
+        &mut self,
+        msg: &mut Message,
+        time: u64,
+        error: u16,
+        other: Vec<u8>,
+    ) -> Result<()> {
+        msg.set_tsig(None)?;
+        let mut tsig = Tsig {
+            key_name: self.key.name.clone(),
+            algorithm: self.key.algorithm.name().to_string(),
+            time_signed: time,
+            fudge: FUDGE,
+            mac: vec![],
+            original_id: msg.header().id.0,
+            error,
+            other,
+            signed: msg.flush().to_vec(),
+        };
+        let continued = self.sending == Some(true);
+        tsig.mac = self
+            .key
+            .algorithm
+            .mac(&self.key.secret, &self.digest(&tsig, continued));
+        self.prior = Some(tsig.mac.clone());
+        self.sending = Some(true);
+        msg.set_tsig(Some(tsig))
+    }
+
+    /// Checks the signature of `msg`, the next message received in the
+    /// exchange. The MAC is checked before the time, so a BADTIME message
+    /// still came from the key holder.
+    pub fn verify(&mut self, msg: &Message, now: u64) -> Result<(), TsigError> {
+        let tsig = msg.tsig().ok_or(TsigError::BadSig)?;
+        let same_algorithm = tsig.algorithm.parse().ok() == Some(self.key.algorithm);
+        if !tsig.key_name.eq_ignore_ascii_case(&self.key.name) || !same_algorithm {
+            return Err(TsigError::BadKey);
+        }
+
+        let continued = self.sending == Some(false);
+        let expected = self
+            .key
+            .algorithm
+            .mac(&self.key.secret, &self.digest(tsig, continued));
+        if !hmac::verify(&expected, &tsig.mac) {
+            return Err(TsigError::BadSig);
+        }
+        self.prior = Some(tsig.mac.clone());
+        self.sending = Some(false);
+
+        if now.abs_diff(tsig.time_signed) > tsig.fudge as u64 {
+            return Err(TsigError::BadTime);
+        }
+        Ok(())
+    }
+}
+
+/// Checks the TSIG of `query`, if any, against `keys`: the session signing
+/// the responses when it holds.
+pub fn accept(keys: &KeyStore, query: &Message, now: u64) -> Result<Option<Session>, TsigError> {
+    let Some(tsig) = query.tsig() else {
+        return Ok(None);
+    };
+    let key = keys.get(&tsig.key_name).ok_or(TsigError::BadKey)?;
+    let mut session = Session {
+        key: key.clone(),
+        prior: None,
+        sending: None,
+    };
+    session.verify(query, now)?;
+    Ok(Some(session))
+}
+
+/// NOTAUTH response to `query`, whose TSIG failed with `err` (RFC 8945
+/// section 5.2). Only BADTIME responses can be signed.
+pub fn reject(keys: &KeyStore, query: &Message, err: TsigError, now: u64) -> Result<Message> {
+    let tsig = query.tsig().context("Query is not signed")?;
+    let mut res = Message::new_response(query);
+    res.set_r_code(OpCode::not_auth());
+
+    match keys.get(&tsig.key_name) {
+        Some(key) if err == TsigError::BadTime => {
+            let mut session = Session {
+                key: key.clone(),
+                prior: Some(tsig.mac.clone()),
+                sending: Some(false),
+            };
+            let other = now.to_be_bytes()[2..].to_vec();
+            session.sign_with(&mut res, tsig.time_signed, err.code(), other)?;
+        }
+        _ => {
+            let unsigned = Tsig {
+                key_name: tsig.key_name.clone(),
+                algorithm: tsig.algorithm.clone(),
+                time_signed: tsig.time_signed,
+                fudge: tsig.fudge,
+                original_id: res.header().id.0,
+                error: err.code(),
+                signed: res.flush().to_vec(),
+                ..Default::default()
+            };
+            res.set_tsig(Some(unsigned))?;
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{domain::Domain, header::PacketId, Header};
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn key() -> Key {
+        "hmac-sha512:transfer.key:c2VjcmV0".parse().unwrap()
+    }
+
+    fn keys() -> KeyStore {
+        let mut keys = KeyStore::default();
+        keys.insert(key());
+        keys
+    }
+
+    fn query() -> Message {
+        let mut msg = Message::new(Header::query(PacketId(42)));
+        msg.set_questions(vec![Domain::new_aa("hernan.rs")])
+            .unwrap();
+        msg
+    }
+
+    /// `msg` as the peer reads it off the wire.
+    fn wire(msg: &Message) -> Message {
+        Message::try_from(msg.flush().as_ref()).unwrap()
+    }
+
+    #[test]
+    fn test_key_from_str() {
+        let key: Key = "Transfer.Key.:c2VjcmV0".parse().unwrap();
+        assert_eq!(key.name, "transfer.key");
+        assert_eq!(key.algorithm, Algorithm::HmacSha256);
+        assert_eq!(key.secret, b"secret");
+        assert_eq!(key.algorithm, "HMAC-SHA256.".parse().unwrap());
+        assert!(!format!("{key:?}").contains("secret"));
+
+        assert_eq!(self::key().algorithm, Algorithm::HmacSha512);
+        assert!("hmac-md5:k:c2VjcmV0".parse::<Key>().is_err());
+        assert!("transfer.key".parse::<Key>().is_err());
+        assert!("k:".parse::<Key>().is_err());
+        assert!(keys().get("TRANSFER.key.").is_some());
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let mut client = Session::new(key());
+        let mut q = query();
+        let unsigned = q.flush().len();
+        client.sign(&mut q, NOW).unwrap();
+        assert_eq!(q.header().ar_count, 1);
+        assert_eq!(q.flush().len(), unsigned + client.size());
+
+        let q = wire(&q);
+        let mut server = accept(&keys(), &q, NOW + 10).unwrap().unwrap();
+        assert!(accept(&keys(), &query(), NOW).unwrap().is_none());
+
+        // A multi-message response, each message chained to the previous.
+        let mut responses = vec![];
+        for _ in 0..3 {
+            let mut res = Message::new_response(&q);
+            server.sign(&mut res, NOW + 10).unwrap();
+            responses.push(wire(&res));
+        }
+        for res in responses.iter() {
+            assert_eq!(client.verify(res, NOW + 20), Ok(()));
+        }
+        assert_eq!(
+            client.verify(&responses[0], NOW + 20),
+            Err(TsigError::BadSig)
+        );
+    }
+
+    #[test]
+    fn test_verify_errors() {
+        let mut q = query();
+        Session::new(key()).sign(&mut q, NOW).unwrap();
+
+        let mut tampered = q.clone();
+        tampered
+            .set_questions(vec![Domain::new_aa("evil.rs")])
+            .unwrap();
+        tampered.set_tsig(q.tsig().cloned()).unwrap();
+        let tampered = wire(&tampered);
+        assert_eq!(
+            accept(&keys(), &tampered, NOW).err(),
+            Some(TsigError::BadSig)
+        );
+
+        let q = wire(&q);
+        let late = NOW + FUDGE as u64 + 1;
+        assert_eq!(accept(&keys(), &q, late).err(), Some(TsigError::BadTime));
+        let other: Key = "transfer.key:c2VjcmV0".parse().unwrap();
+        let mut other_keys = KeyStore::default();
+        other_keys.insert(other);
+        assert_eq!(accept(&other_keys, &q, NOW).err(), Some(TsigError::BadKey));
+        let other_keys = KeyStore::default();
+        assert_eq!(accept(&other_keys, &q, NOW).err(), Some(TsigError::BadKey));
+    }
+
+    #[test]
+    fn test_reject() {
+        let mut client = Session::new(key());
+        let mut q = query();
+        client.sign(&mut q, NOW).unwrap();
+        let q = wire(&q);
+
+        let res = wire(&reject(&keys(), &q, TsigError::BadSig, NOW).unwrap());
+        assert_eq!(res.header().r_code, OpCode::not_auth());
+        let tsig = res.tsig().unwrap();
+        assert_eq!(tsig.error, tsig::BADSIG);
+        assert!(tsig.mac.is_empty());
+
+        let late = NOW + 1000;
+        let res = wire(&reject(&keys(), &q, TsigError::BadTime, late).unwrap());
+        let tsig = res.tsig().unwrap();
+        assert_eq!(tsig.error, tsig::BADTIME);
+        assert_eq!(tsig.time_signed, NOW);
+        assert_eq!(tsig.other, late.to_be_bytes()[2..]);
+        assert_eq!(client.verify(&res, NOW), Ok(()));
+    }
+}
@@ -1,12 +1,11 @@
-use crate::{
-    message::{
-        data::Data,
-        domain::Domain,
-        header::{Authoritative, QueryMode, Recursion, Truncation},
-        route::Route,
-        Header,
-    },
-    Message,
+use crate::message::{
+    data::{name_len, Data, Soa},
+    domain::Domain,
+    edns::{self, Edns},
+    header::{Authenticity, Authoritative, Checking, QueryMode, Recursion, Truncation},
+    route::Route,
+    tsig::{self, Tsig},
+    Header, Message,
 };
 use bytes::{BufMut, Bytes, BytesMut};
 
@@ -16,7 +15,7 @@ trait Serialize {
 
 impl Serialize for &str {
     fn write(&self, buf: &mut BytesMut) {
-        self.split('.').for_each(|l| {
+        self.split('.').filter(|l| !l.is_empty()).for_each(|l| {
             buf.put_u8(l.len() as u8);
             buf.put(l.as_bytes())
         });
@@ -52,7 +51,13 @@ impl Serialize for Header {
 
         flags = 0u8;
         if self.ra == Recursion::Enabled {
-            flags &= 0b1_0000000;
+            flags |= 0b1_0000000;
+        }
+        if self.ad == Authenticity::Authentic {
+            flags |= 0b0010_0000;
+        }
+        if self.cd == Checking::Disabled {
+            flags |= 0b0001_0000;
         }
         flags |= 0b0000_1111 & self.r_code.0;
         buf.put_u8(flags);
@@ -67,8 +72,20 @@ impl Serialize for Header {
 impl Serialize for Domain {
     fn write(&self, buf: &mut BytesMut) {
         self.name.as_str().write(buf);
-        buf.put_u16(self.record as u16);
-        buf.put_u16(self.class as u16);
+        buf.put_u16(self.record.into());
+        buf.put_u16(self.class.into());
+    }
+}
+
+impl Serialize for Soa {
+    fn write(&self, buf: &mut BytesMut) {
+        self.mname.as_str().write(buf);
+        self.rname.as_str().write(buf);
+        buf.put_u32(self.serial);
+        buf.put_u32(self.refresh);
+        buf.put_u32(self.retry);
+        buf.put_u32(self.expire);
+        buf.put_u32(self.minimum);
     }
 }
 
@@ -76,6 +93,17 @@ impl Serialize for Data {
     fn write(&self, buf: &mut BytesMut) {
         match self {
             Self::Ipv4(ip) => buf.put_slice(&ip.octets()),
+            Self::Ipv6(ip) => buf.put_slice(&ip.octets()),
+            Self::Name(name) => name.as_str().write(buf),
+            Self::Mx {
+                preference,
+                exchange,
+            } => {
+                buf.put_u16(*preference);
+                exchange.as_str().write(buf);
+            }
+            Self::Soa(soa) => soa.write(buf),
+            Self::Raw(bytes) => buf.put_slice(bytes),
         }
     }
 }
@@ -89,19 +117,61 @@ impl Serialize for Route {
     }
 }
 
+impl Serialize for Edns {
+    fn write(&self, buf: &mut BytesMut) {
+        "".write(buf);
+        buf.put_u16(edns::OPT);
+        buf.put_u16(self.udp_payload);
+        buf.put_u32(self.ttl());
+        buf.put_u16(0);
+    }
+}
+
+impl Serialize for Tsig {
+    fn write(&self, buf: &mut BytesMut) {
+        self.key_name.as_str().write(buf);
+        buf.put_u16(tsig::TSIG);
+        buf.put_u16(tsig::ANY);
+        buf.put_u32(0);
+        let len = name_len(&self.algorithm) as usize + 16 + self.mac.len() + self.other.len();
+        buf.put_u16(len as u16);
+        self.algorithm.as_str().write(buf);
+        buf.put_u16((self.time_signed >> 32) as u16);
+        buf.put_u32(self.time_signed as u32);
+        buf.put_u16(self.fudge);
+        buf.put_u16(self.mac.len() as u16);
+        buf.put_slice(&self.mac);
+        buf.put_u16(self.original_id);
+        buf.put_u16(self.error);
+        buf.put_u16(self.other.len() as u16);
+        buf.put_slice(&self.other);
+    }
+}
+
 impl Message {
     pub fn flush(&self) -> Bytes {
         let mut buf = BytesMut::with_capacity(12);
         self.header().write(&mut buf);
         self.questions().write(&mut buf);
         self.answers().write(&mut buf);
+        self.authorities().write(&mut buf);
+        self.additionals().write(&mut buf);
+        if let Some(edns) = self.edns() {
+            edns.write(&mut buf);
+        }
+        if let Some(tsig) = self.tsig() {
+            tsig.write(&mut buf);
+        }
         buf.freeze()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::message::header::{OpCode, PacketId, Reserved};
+    use crate::message::{
+        domain::Record,
+        header::{OpCode, PacketId, Reserved},
+    };
     use std::net::Ipv4Addr;
 
     use super::*;
@@ -129,6 +199,8 @@ mod tests {
             rd: Recursion::Enabled,
             ra: Recursion::Disabled,
             z: Reserved,
+            ad: Authenticity::Unverified,
+            cd: Checking::Enabled,
             r_code: OpCode::no_error(),
             qd_count: 1,
             an_count: 0,
@@ -158,4 +230,91 @@ mod tests {
         ];
         assert_eq!(buf.as_ref(), chunk);
     }
+
+    #[test]
+    fn test_write_header_flags() {
+        let h = Header {
+            ra: Recursion::Enabled,
+            ad: Authenticity::Authentic,
+            r_code: OpCode::name_error(),
+            ..Header::response(PacketId(1))
+        };
+
+        let mut buf = BytesMut::new();
+        h.write(&mut buf);
+
+        assert_eq!(buf[2], 0b1000_0000);
+        assert_eq!(buf[3], 0b1010_0011);
+    }
+
+    #[test]
+    fn test_write_root() {
+        let mut buf = BytesMut::new();
+        "".write(&mut buf);
+        assert_eq!(buf.as_ref(), &[0]);
+    }
+
+    #[test]
+    fn test_message_round_trip() {
+        let mut msg = Message::new(Header::response(PacketId(9)));
+        let q = Domain::new_aa("hernan.rs");
+        let soa = Soa {
+            mname: "ns.hernan.rs".to_string(),
+            rname: "hostmaster.hernan.rs".to_string(),
+            serial: 1,
+            refresh: 2,
+            retry: 3,
+            expire: 4,
+            minimum: 5,
+        };
+        let zone = Domain {
+            record: Record::SOA,
+            ..q.clone()
+        };
+        let ns = Route::new(zone, 60, Data::Soa(soa));
+        let ar = Route::new(q.clone(), 60, Data::Ipv4(Ipv4Addr::LOCALHOST));
+        msg.set_questions(vec![q]).unwrap();
+        msg.set_authorities(vec![ns.clone()]).unwrap();
+        msg.set_additionals(vec![ar.clone()]).unwrap();
+        msg.set_edns(Some(Edns::new(true))).unwrap();
+
+        let buf = msg.flush();
+        let parsed = Message::try_from(buf.as_ref()).unwrap();
+
+        assert_eq!(parsed.header().ns_count, 1);
+        assert_eq!(parsed.header().ar_count, 2);
+        assert_eq!(parsed.authorities(), &vec![ns]);
+        assert_eq!(parsed.additionals(), &vec![ar]);
+        assert_eq!(parsed.edns(), Some(&Edns::new(true)));
+    }
+
+    #[test]
+    fn test_tsig_round_trip() {
+        let mut msg = Message::new(Header::query(PacketId(7)));
+        msg.set_questions(vec![Domain::new_aa("hernan.rs")])
+            .unwrap();
+        msg.set_edns(Some(Edns::new(false))).unwrap();
+        let tsig = Tsig {
+            key_name: "transfer.key".to_string(),
+            algorithm: "hmac-sha256".to_string(),
+            time_signed: 0x0001_0000_0002,
+            fudge: 300,
+            mac: vec![1, 2, 3],
+            original_id: 7,
+            error: 0,
+            other: vec![],
+            signed: msg.flush().to_vec(),
+        };
+        msg.set_tsig(Some(tsig.clone())).unwrap();
+
+        let buf = msg.flush();
+        let parsed = Message::try_from(buf.as_ref()).unwrap();
+        assert_eq!(parsed.header().ar_count, 2);
+        assert_eq!(parsed.tsig(), Some(&tsig));
+
+        let mut trailing = buf.to_vec();
+        trailing[11] = 3;
+        trailing.extend_from_slice(&buf[buf.len() - 11..]);
+        assert!(Message::try_from(trailing.as_ref()).is_err());
+    }
 }
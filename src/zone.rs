@@ -0,0 +1,1163 @@
+pub mod dnssec;
+pub mod journal;
+pub mod master;
+pub mod notify;
+pub mod transfer;
+pub mod update;
+use crate::{
+    message::{
+        data::Data,
+        domain::{canonical_cmp, in_zone, Domain, Record},
+        header::OpCode,
+        route::Route,
+    },
+    tsig,
+};
+use anyhow::{Context, Result};
+use dnssec::Signer;
+use journal::{Diff, Journal};
+use std::{
+    cmp::Ordering,
+    fs,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex, RwLock},
+};
+
+/// Longest CNAME chain followed inside a zone.
+const MAX_CHAIN: usize = 8;
+
+/// Record type an RRSIG covers, the first field of its data.
+fn covered(sig: &Route) -> Option<u16> {
+    match sig.data() {
+        Data::Raw(bytes) if bytes.len() >= 2 => Some(u16::from_be_bytes([bytes[0], bytes[1]])),
+        _ => None,
+    }
+}
+
+/// What a zone knows about a question.
+#[derive(Debug, Default, PartialEq)]
+pub struct Lookup {
+    pub r_code: OpCode,
+    pub authoritative: bool,
+    pub answers: Vec<Route>,
+    pub authorities: Vec<Route>,
+    pub additionals: Vec<Route>,
+    /// Name answered from a wildcard, along with the wildcard's owner.
+    pub wildcard: Option<(String, String)>,
+}
+
+/// Records served with authority for every name under `origin`.
+#[derive(Clone, Debug)]
+pub struct Zone {
+    origin: String,
+    records: Vec<Route>,
+    journal: Journal,
+    /// Zone file the zone was loaded from, next to which updates are kept.
+    file: Option<PathBuf>,
+    /// Start of the period the signatures made here date from.
+    signed: Option<u32>,
+}
+
+impl Zone {
+    /// Builds a zone out of its records; the owner of the single SOA is the origin.
+    pub fn new(records: Vec<Route>) -> Result<Self> {
+        let mut soas = records.iter().filter(|r| r.domain().record == Record::SOA);
+        let soa = soas.next().context("Zone has no SOA record")?;
+        anyhow::ensure!(soas.next().is_none(), "Zone has more than one SOA record");
+
+        let origin = soa.domain().name.clone();
+        if let Some(r) = records.iter().find(|r| !in_zone(&r.domain().name, &origin)) {
+            anyhow::bail!("{} is out of zone {origin}", r.domain().name);
+        }
+        Ok(Self {
+            origin,
+            records,
+            journal: Journal::default(),
+            file: None,
+            signed: None,
+        })
+    }
+
+    /// Reads a zone file, along with the changes in its journal that follow
+    /// on from the serial of the file.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Could not read zone {}", path.display()))?;
+        let records = master::parse(&text, "")
+            .with_context(|| format!("Could not parse zone {}", path.display()))?;
+        let mut zone = Self::new(records)?;
+        zone.file = Some(path.to_path_buf());
+
+        let journal = journal::path(path);
+        if journal.exists() {
+            zone = zone.replay(journal::load(&journal)?)?;
+        }
+        Ok(zone)
+    }
+
+    /// Applies the `diffs` starting at the current serial, keeping them in
+    /// the journal.
+    fn replay(self, diffs: Vec<Diff>) -> Result<Self> {
+        let Some(start) = diffs
+            .iter()
+            .position(|d| journal::serial(&d.from) == Some(self.serial()))
+        else {
+            return Ok(self);
+        };
+        let mut records = self.records;
+        let mut history = self.journal;
+        for diff in diffs.into_iter().skip(start) {
+            diff.apply(&mut records)?;
+            history.push(diff);
+        }
+        Ok(Self {
+            journal: history,
+            file: self.file,
+            ..Self::new(records)?
+        })
+    }
+
+    pub fn origin(&self) -> &str {
+        &self.origin
+    }
+
+    pub fn soa(&self) -> &Route {
+        self.records
+            .iter()
+            .find(|r| r.domain().record == Record::SOA)
+            .expect("Zones always hold a SOA")
+    }
+
+    pub fn serial(&self) -> u32 {
+        journal::serial(self.soa()).expect("Zones always hold a SOA")
+    }
+
+    /// The changes that led to this version of the zone.
+    pub fn journal(&self) -> &Journal {
+        &self.journal
+    }
+
+    /// Moves the serial one step forward.
+    fn bump_serial(&mut self) {
+        let soa = self
+            .records
+            .iter_mut()
+            .find(|r| r.domain().record == Record::SOA)
+            .expect("Zones always hold a SOA");
+        if let Data::Soa(data) = soa.data() {
+            let mut data = data.clone();
+            data.serial = data.serial.wrapping_add(1);
+            *soa = Route::new(soa.domain().clone(), soa.ttl(), Data::Soa(data));
+        }
+    }
+
+    pub fn into_records(self) -> Vec<Route> {
+        self.records
+    }
+
+    fn records_at<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Route> {
+        self.records
+            .iter()
+            .filter(move |r| r.domain().name.eq_ignore_ascii_case(name))
+    }
+
+    /// Whether some record lives below `name`, which then exists as an empty non-terminal.
+    fn has_children(&self, name: &str) -> bool {
+        self.records.iter().any(|r| {
+            let owner = &r.domain().name;
+            owner.len() > name.len() && in_zone(owner, name)
+        })
+    }
+
+    /// NS records of the closest zone cut between the origin and `name`, if any.
+    fn delegation(&self, name: &str) -> Vec<Route> {
+        let mut cuts: Vec<&Route> = self
+            .records
+            .iter()
+            .filter(|r| r.domain().record == Record::NS)
+            .filter(|r| !r.domain().name.eq_ignore_ascii_case(&self.origin))
+            .filter(|r| in_zone(name, &r.domain().name))
+            .collect();
+        cuts.sort_by_key(|r| std::cmp::Reverse(r.domain().name.len()));
+        let Some(closest) = cuts.first().map(|r| r.domain().name.clone()) else {
+            return vec![];
+        };
+        cuts.into_iter()
+            .filter(|r| r.domain().name == closest)
+            .cloned()
+            .collect()
+    }
+
+    fn glue(&self, ns: &[Route]) -> Vec<Route> {
+        ns.iter()
+            .filter_map(|r| match r.data() {
+                Data::Name(target) => Some(target),
+                _ => None,
+            })
+            .flat_map(|target| self.records_at(target))
+            .filter(|r| matches!(r.domain().record, Record::AA | Record::AAAA))
+            .cloned()
+            .collect()
+    }
+
+    /// SOA to prove a negative answer, its TTL capped by the SOA minimum (RFC 2308).
+    fn negative_soa(&self) -> Route {
+        let soa = self.soa();
+        let ttl = match soa.data() {
+            Data::Soa(data) => soa.ttl().min(data.minimum),
+            _ => soa.ttl(),
+        };
+        Route::new(soa.domain().clone(), ttl, soa.data().clone())
+    }
+
+    /// The wildcard that would answer for `name`, which does not exist:
+    /// `*` under its closest encloser (RFC 4592 section 3.3.1).
+    fn source_of_synthesis(&self, name: &str) -> String {
+        match self.closest_encloser(name) {
+            "" => "*".to_string(),
+            encloser => format!("*.{encloser}"),
+        }
+    }
+
+    /// The records owned by `name` or, when there is no such node, those of
+    /// the wildcard matching it renamed to `name`, along with the wildcard.
+    fn records_or_wildcard(&self, name: &str) -> (Vec<Route>, Option<String>) {
+        let rrs: Vec<Route> = self.records_at(name).cloned().collect();
+        if !rrs.is_empty() || self.has_children(name) {
+            return (rrs, None);
+        }
+        let source = self.source_of_synthesis(name);
+        let rrs: Vec<Route> = self
+            .records_at(&source)
+            .map(|r| {
+                let mut r = r.clone();
+                r.rename(name);
+                r
+            })
+            .collect();
+        match rrs.is_empty() {
+            true => (rrs, None),
+            false => (rrs, Some(source)),
+        }
+    }
+
+    /// Answers `q` following RFC 1034 4.3.2: referrals below zone cuts, CNAME
+    /// chains inside the zone, answers synthesized from wildcards (RFC 4592),
+    /// NODATA and NXDOMAIN with the SOA as authority. ANY gets every RRset of
+    /// the node but the signatures.
+    pub fn look_up(&self, q: &Domain) -> Lookup {
+        let mut lookup = Lookup {
+            authoritative: true,
+            ..Default::default()
+        };
+
+        let mut name = q.name.clone();
+        for _ in 0..MAX_CHAIN {
+            let mut cut = self.delegation(&name);
+            let at_cut = cut
+                .first()
+                .is_some_and(|r| r.domain().name.eq_ignore_ascii_case(&name));
+            // DS records live on the parent side of a zone cut.
+            if at_cut && q.record == Record::DS {
+                cut.clear();
+            }
+            if !cut.is_empty() {
+                lookup.authoritative = !lookup.answers.is_empty();
+                lookup.additionals = self.glue(&cut);
+                lookup.authorities = cut;
+                return lookup;
+            }
+
+            let (rrs, wildcard) = self.records_or_wildcard(&name);
+            if rrs.is_empty() {
+                if !self.has_children(&name) {
+                    lookup.r_code = OpCode::name_error();
+                }
+                lookup.authorities = vec![self.negative_soa()];
+                return lookup;
+            }
+            if let Some(source) = wildcard {
+                lookup.wildcard = Some((name.clone(), source));
+            }
+
+            let matching: Vec<&Route> = rrs
+                .iter()
+                .filter(|r| match q.record {
+                    Record::ANY => r.domain().record != Record::RRSIG,
+                    record => r.domain().record == record,
+                })
+                .collect();
+            if !matching.is_empty() {
+                lookup.answers.extend(matching.into_iter().cloned());
+                return lookup;
+            }
+
+            let cname = rrs.iter().find(|r| r.domain().record == Record::CNAME);
+            match cname.map(|r| (r, r.data())) {
+                Some((r, Data::Name(target))) => {
+                    lookup.answers.push(r.clone());
+                    if !in_zone(target, &self.origin) {
+                        return lookup;
+                    }
+                    name = target.clone();
+                }
+                _ => {
+                    lookup.authorities = vec![self.negative_soa()];
+                    return lookup;
+                }
+            }
+        }
+        lookup
+    }
+
+    /// Signatures over each RRset of `rrs`. Those of RRsets synthesized from
+    /// a wildcard are the wildcard's, renamed like the RRsets.
+    fn signatures(&self, rrs: &[Route]) -> Vec<Route> {
+        let mut sigs: Vec<Route> = vec![];
+        for rr in rrs {
+            let domain = rr.domain();
+            let owner = match self.records_at(&domain.name).next() {
+                Some(_) => domain.name.clone(),
+                None => self.source_of_synthesis(&domain.name),
+            };
+            let rrsigs = self
+                .records_at(&owner)
+                .filter(|r| r.domain().record == Record::RRSIG)
+                .filter(|r| covered(r) == Some(domain.record.into()));
+            for sig in rrsigs {
+                let mut sig = sig.clone();
+                sig.rename(&domain.name);
+                if !sigs.contains(&sig) {
+                    sigs.push(sig);
+                }
+            }
+        }
+        sigs
+    }
+
+    /// The NSEC record owned by `name`, or else the one before it in
+    /// canonical order, whose span covers `name`.
+    fn nsec_covering(&self, name: &str) -> Option<&Route> {
+        self.records
+            .iter()
+            .filter(|r| r.domain().record == Record::NSEC)
+            .filter(|r| canonical_cmp(&r.domain().name, name) != Ordering::Greater)
+            .max_by(|a, b| canonical_cmp(&a.domain().name, &b.domain().name))
+    }
+
+    /// Salt and iterations of the NSEC3 chain of the zone, as its NSEC3PARAM
+    /// record gives them, when it has one.
+    fn nsec3_param(&self) -> Option<(Vec<u8>, u16)> {
+        self.records_at(&self.origin)
+            .filter(|r| r.domain().record == Record::NSEC3PARAM)
+            .find_map(|r| dnssec::nsec3_param(r.data()))
+    }
+
+    /// The NSEC3 record whose hashed owner is that of `name` or, unless
+    /// `matching`, the one before it in hash order, whose span covers it.
+    fn nsec3_for(
+        &self,
+        name: &str,
+        (salt, iterations): &(Vec<u8>, u16),
+        matching: bool,
+    ) -> Option<&Route> {
+        let hash = dnssec::nsec3_owner(name, "", salt, *iterations);
+        let label = |r: &&Route| {
+            r.domain()
+                .name
+                .split('.')
+                .next()
+                .unwrap_or_default()
+                .to_ascii_lowercase()
+        };
+        let chain = self
+            .records
+            .iter()
+            .filter(|r| r.domain().record == Record::NSEC3);
+        if matching {
+            return chain.clone().find(|r| label(r) == hash);
+        }
+        chain
+            .clone()
+            .filter(|r| label(r) < hash)
+            .max_by_key(label)
+            .or_else(|| chain.max_by_key(label))
+    }
+
+    /// NSEC3 records proving the negative `lookup` of `name` (RFC 5155
+    /// section 7.2): the match of `name` for NODATA, or else the closest
+    /// encloser proof and what the wildcard under it says.
+    fn nsec3_denial(&self, name: &str, lookup: &Lookup, param: &(Vec<u8>, u16)) -> Vec<Route> {
+        let wildcard = lookup.wildcard.as_ref().map(|(_, source)| source.clone());
+        let nodes = match wildcard {
+            None if lookup.r_code != OpCode::name_error() => vec![(name.to_string(), true)],
+            _ => {
+                let encloser = self.closest_encloser(name);
+                let source = self.source_of_synthesis(name);
+                vec![
+                    (encloser.to_string(), true),
+                    (next_closer(name, encloser).to_string(), false),
+                    (source, wildcard.is_some()),
+                ]
+            }
+        };
+        let mut proof: Vec<Route> = vec![];
+        for (node, matching) in nodes {
+            if let Some(nsec3) = self.nsec3_for(&node, param, matching) {
+                if !proof.contains(nsec3) {
+                    proof.push(nsec3.clone());
+                }
+            }
+        }
+        proof
+    }
+
+    /// NSEC records proving the negative `lookup` of `name`: the one covering
+    /// it, and either the one covering the wildcard that would have matched or
+    /// the one of the wildcard that did but lacks the type (RFC 4035 section
+    /// 3.1.3).
+    fn nsec_denial(&self, name: &str, lookup: &Lookup) -> Vec<Route> {
+        let mut proof: Vec<Route> = self.nsec_covering(name).into_iter().cloned().collect();
+        let wildcard = match &lookup.wildcard {
+            Some((_, source)) => Some(source.clone()),
+            None if lookup.r_code == OpCode::name_error() => Some(self.source_of_synthesis(name)),
+            None => None,
+        };
+        if let Some(nsec) = wildcard.and_then(|w| self.nsec_covering(&w)) {
+            if !proof.contains(nsec) {
+                proof.push(nsec.clone());
+            }
+        }
+        proof
+    }
+
+    /// The closest ancestor of `name` that exists in the zone.
+    fn closest_encloser<'a>(&self, mut name: &'a str) -> &'a str {
+        while !name.eq_ignore_ascii_case(&self.origin)
+            && self.records_at(name).next().is_none()
+            && !self.has_children(name)
+        {
+            name = name.split_once('.').map_or("", |(_, parent)| parent);
+        }
+        name
+    }
+
+    /// Adds the DNSSEC records of a signed zone to `lookup` of `q`, as
+    /// clients setting the DO bit expect (RFC 4035 section 3.1): signatures
+    /// over each RRset, the DS records or their absence at referrals, and the
+    /// NSEC or NSEC3 records proving negative answers.
+    pub fn add_dnssec(&self, q: &Domain, lookup: &mut Lookup) {
+        let sigs = self.signatures(&lookup.answers);
+        lookup.answers.extend(sigs);
+        let nsec3 = self.nsec3_param();
+
+        let cut = lookup
+            .authorities
+            .iter()
+            .find(|r| r.domain().record == Record::NS)
+            .map(|r| r.domain().name.clone());
+        if let Some(cut) = cut {
+            let at_cut = |record| {
+                self.records_at(&cut)
+                    .filter(move |r| r.domain().record == record)
+                    .cloned()
+                    .collect::<Vec<_>>()
+            };
+            let mut proof = at_cut(Record::DS);
+            if proof.is_empty() {
+                proof = match &nsec3 {
+                    Some(param) => self
+                        .nsec3_for(&cut, param, true)
+                        .into_iter()
+                        .cloned()
+                        .collect(),
+                    None => at_cut(Record::NSEC),
+                };
+            }
+            let sigs = self.signatures(&proof);
+            lookup.authorities.extend(proof);
+            lookup.authorities.extend(sigs);
+            return;
+        }
+        if lookup.authorities.is_empty() {
+            // An answer from a wildcard proves that the name asked for does
+            // not exist (RFC 4035 section 3.1.3.3).
+            if let Some((name, source)) = &lookup.wildcard {
+                let proof = match &nsec3 {
+                    Some(param) => {
+                        let encloser = source.strip_prefix("*.").unwrap_or_default();
+                        self.nsec3_for(next_closer(name, encloser), param, false)
+                    }
+                    None => self.nsec_covering(name),
+                };
+                let proof: Vec<Route> = proof.into_iter().cloned().collect();
+                let sigs = self.signatures(&proof);
+                lookup.authorities.extend(proof);
+                lookup.authorities.extend(sigs);
+            }
+            return;
+        }
+
+        let name = lookup
+            .answers
+            .iter()
+            .rev()
+            .find_map(|r| match (r.domain().record, r.data()) {
+                (Record::CNAME, Data::Name(target)) => Some(target.clone()),
+                _ => None,
+            })
+            .unwrap_or_else(|| q.name.clone());
+        let proof = match &nsec3 {
+            Some(param) => self.nsec3_denial(&name, lookup, param),
+            None => self.nsec_denial(&name, lookup),
+        };
+        lookup.authorities.extend(proof);
+        let sigs = self.signatures(&lookup.authorities);
+        lookup.authorities.extend(sigs);
+    }
+}
+
+/// The ancestor of `name`, or `name` itself, one label below `encloser`.
+fn next_closer<'a>(mut name: &'a str, encloser: &str) -> &'a str {
+    let depth = |n: &str| n.split('.').filter(|l| !l.is_empty()).count();
+    while depth(name) > depth(encloser) + 1 {
+        name = name.split_once('.').map_or("", |(_, parent)| parent);
+    }
+    name
+}
+
+/// `zone` ready to replace `old`: signed, and with the journal of `old`
+/// when the serial moved forward; otherwise the history starts over.
+fn prepare(signer: &Signer, mut zone: Zone, old: Option<&Zone>, now: u32) -> Zone {
+    sign(signer, &mut zone, old, now);
+    if let Some(old) = old.filter(|old| journal::is_newer(zone.serial(), old.serial())) {
+        zone.journal = old.journal.clone();
+        zone.journal.push(Diff::between(old, &zone));
+    }
+    zone
+}
+
+/// Signs `zone` at `now` if the signer has keys for it. New signatures
+/// for the same serial as `old` move the serial forward, in the journal
+/// file too, so secondaries pick them up.
+fn sign(signer: &Signer, zone: &mut Zone, old: Option<&Zone>, now: u32) {
+    let Some(records) = signer.sign(&zone.records, &zone.origin, now) else {
+        return;
+    };
+    zone.records = records;
+    zone.signed = Some(signer.period(now));
+
+    let Some(old) = old.filter(|old| old.serial() == zone.serial()) else {
+        return;
+    };
+    let diff = Diff::between(old, zone);
+    if diff.added.is_empty() && diff.deleted.is_empty() {
+        return;
+    }
+    zone.bump_serial();
+    if let Some(records) = signer.sign(&zone.records, &zone.origin, now) {
+        zone.records = records;
+    }
+    if let Some(file) = &zone.file {
+        let diff = Diff {
+            from: old.soa().clone(),
+            to: zone.soa().clone(),
+            deleted: vec![],
+            added: vec![],
+        };
+        if let Err(e) = journal::append(&journal::path(file), &diff) {
+            println!("Could not keep serial of {}: {e}", zone.origin);
+        }
+    }
+}
+
+/// Zones read again on reload, signed and waiting for `Zones::commit`.
+#[derive(Debug)]
+pub struct Staged {
+    signer: Arc<Signer>,
+    /// Each zone with the version it replaces, if any.
+    zones: Vec<(Option<Arc<Zone>>, Zone)>,
+    removed: Vec<Arc<Zone>>,
+}
+
+/// Every zone served with authority. Zones may be swapped while serving, as
+/// secondaries transfer new versions. Those the signer has keys for are
+/// signed as they come in.
+#[derive(Debug, Default)]
+pub struct Zones {
+    zones: RwLock<Vec<Arc<Zone>>>,
+    watchers: Mutex<Vec<mpsc::Sender<Arc<Zone>>>>,
+    signer: RwLock<Arc<Signer>>,
+}
+
+impl Zones {
+    /// Adds `zone`, replacing any previous one with the same origin. When the
+    /// serial moved forward, the change is added to the journal of the old one;
+    /// otherwise the history starts over.
+    pub fn insert(&self, zone: Zone) {
+        let mut zones = self.zones.write().expect("Zones lock poisoned");
+        self.replace(&mut zones, zone, tsig::now() as u32);
+    }
+
+    /// Signs the zones added from now on with `signer`.
+    pub fn set_signer(&self, signer: Signer) {
+        *self.signer.write().expect("Zones lock poisoned") = Arc::new(signer);
+    }
+
+    /// Signs again the zones whose signatures are due for renewal at `now`,
+    /// without holding the zones meanwhile. Zones changed while signing were
+    /// signed by that change and are left as they are.
+    pub fn resign(&self, now: u32) {
+        let signer = self.signer.read().expect("Zones lock poisoned").clone();
+        let due: Vec<Arc<Zone>> = self
+            .zones
+            .read()
+            .expect("Zones lock poisoned")
+            .iter()
+            .filter(|z| signer.signs(&z.origin) && z.signed != Some(signer.period(now)))
+            .cloned()
+            .collect();
+        for old in due {
+            let zone = prepare(&signer, Zone::clone(&old), Some(&old), now);
+            let mut zones = self.zones.write().expect("Zones lock poisoned");
+            if zones.iter().any(|z| Arc::ptr_eq(z, &old)) {
+                self.put(&mut zones, zone, Some(old.serial()));
+            }
+        }
+    }
+
+    /// Runs `change` on the zone at `origin`, with no other change to it
+    /// meanwhile, and puts in the new version it returns if any. `None` when
+    /// there is no such zone.
+    pub fn update<T>(
+        &self,
+        origin: &str,
+        change: impl FnOnce(&Zone) -> (Option<Zone>, T),
+    ) -> Option<T> {
+        let mut zones = self.zones.write().expect("Zones lock poisoned");
+        let origin = origin.trim_end_matches('.');
+        let zone = zones
+            .iter()
+            .find(|z| z.origin.eq_ignore_ascii_case(origin))?
+            .clone();
+        let (new, result) = change(&zone);
+        if let Some(new) = new {
+            self.replace(&mut zones, new, tsig::now() as u32);
+        }
+        Some(result)
+    }
+
+    /// Reads nothing but the zones served now and signs `zones` with
+    /// `signer`, so that serving goes on meanwhile. Zones served now that are
+    /// not among `zones` are to be removed, unless `keep` says otherwise.
+    pub fn stage(&self, signer: Signer, zones: Vec<Zone>, keep: impl Fn(&str) -> bool) -> Staged {
+        let current = self.zones.read().expect("Zones lock poisoned").clone();
+        let now = tsig::now() as u32;
+        let removed = current
+            .iter()
+            .filter(|z| !keep(&z.origin))
+            .filter(|z| {
+                !zones
+                    .iter()
+                    .any(|n| n.origin.eq_ignore_ascii_case(&z.origin))
+            })
+            .cloned()
+            .collect();
+        let zones = zones
+            .into_iter()
+            .map(|zone| {
+                let old = current
+                    .iter()
+                    .find(|z| z.origin.eq_ignore_ascii_case(&zone.origin))
+                    .cloned();
+                let zone = prepare(&signer, zone, old.as_deref(), now);
+                (old, zone)
+            })
+            .collect();
+        Staged {
+            signer: Arc::new(signer),
+            zones,
+            removed,
+        }
+    }
+
+    /// Swaps in the zones and signer of `staged` at once. Zones changed since
+    /// they were staged, by a transfer or an update, are left as they are.
+    pub fn commit(&self, staged: Staged) {
+        let mut zones = self.zones.write().expect("Zones lock poisoned");
+        *self.signer.write().expect("Zones lock poisoned") = staged.signer;
+        for (old, zone) in staged.zones {
+            let current = zones
+                .iter()
+                .find(|z| z.origin.eq_ignore_ascii_case(&zone.origin));
+            let unchanged = match (current, &old) {
+                (Some(current), Some(old)) => Arc::ptr_eq(current, old),
+                (current, old) => current.is_none() && old.is_none(),
+            };
+            if !unchanged {
+                println!("Zone {} changed while reloading, keeping it", zone.origin);
+                continue;
+            }
+            self.put(&mut zones, zone, old.map(|old| old.serial()));
+        }
+        for zone in staged.removed {
+            if zones.iter().any(|z| Arc::ptr_eq(z, &zone)) {
+                println!("No longer serving zone {}", zone.origin);
+                zones.retain(|z| !Arc::ptr_eq(z, &zone));
+            }
+        }
+    }
+
+    fn replace(&self, zones: &mut Vec<Arc<Zone>>, zone: Zone, now: u32) {
+        let signer = self.signer.read().expect("Zones lock poisoned").clone();
+        let old = zones
+            .iter()
+            .find(|z| z.origin.eq_ignore_ascii_case(&zone.origin))
+            .cloned();
+        let zone = prepare(&signer, zone, old.as_deref(), now);
+        self.put(zones, zone, old.map(|old| old.serial()));
+    }
+
+    /// Puts `zone` in place of the version with `serial`, if any, telling the
+    /// watchers when the serial changed.
+    fn put(&self, zones: &mut Vec<Arc<Zone>>, zone: Zone, serial: Option<u32>) {
+        let changed = serial != Some(zone.serial());
+        let zone = Arc::new(zone);
+        zones.retain(|z| !z.origin.eq_ignore_ascii_case(&zone.origin));
+        zones.push(zone.clone());
+        if changed {
+            let mut watchers = self.watchers.lock().expect("Zones lock poisoned");
+            watchers.retain(|w| w.send(zone.clone()).is_ok());
+        }
+    }
+
+    /// Every zone added from now on, or whose serial changes.
+    pub fn watch(&self) -> mpsc::Receiver<Arc<Zone>> {
+        let (sender, receiver) = mpsc::channel();
+        let mut watchers = self.watchers.lock().expect("Zones lock poisoned");
+        watchers.push(sender);
+        receiver
+    }
+
+    pub fn remove(&self, origin: &str) {
+        let mut zones = self.zones.write().expect("Zones lock poisoned");
+        zones.retain(|z| !z.origin.eq_ignore_ascii_case(origin));
+    }
+
+    pub fn origins(&self) -> Vec<String> {
+        let zones = self.zones.read().expect("Zones lock poisoned");
+        zones.iter().map(|z| z.origin.clone()).collect()
+    }
+
+    /// The zone whose origin is exactly `origin`.
+    pub fn get(&self, origin: &str) -> Option<Arc<Zone>> {
+        let zones = self.zones.read().expect("Zones lock poisoned");
+        let origin = origin.trim_end_matches('.');
+        zones
+            .iter()
+            .find(|z| z.origin.eq_ignore_ascii_case(origin))
+            .cloned()
+    }
+
+    /// The most specific zone holding `name`.
+    pub fn find(&self, name: &str) -> Option<Arc<Zone>> {
+        let zones = self.zones.read().expect("Zones lock poisoned");
+        zones
+            .iter()
+            .filter(|z| in_zone(name, &z.origin))
+            .max_by_key(|z| z.origin.len())
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const ZONE: &str = r#"
+$ORIGIN hernan.rs.
+@       SOA ns hostmaster 1 3600 600 86400 300
+@       NS  ns
+ns      A   10.0.0.1
+www     CNAME   web
+web     A   10.0.0.2
+ext     CNAME   example.com.
+a.b     A   10.0.0.3
+sub     NS  ns.sub
+ns.sub  A   10.0.0.4
+"#;
+
+    fn zone() -> Zone {
+        Zone::new(master::parse(ZONE, "").unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_zone_new() {
+        let z = zone();
+        assert_eq!(z.origin(), "hernan.rs");
+        assert_eq!(z.soa().domain().record, Record::SOA);
+
+        let stray = master::parse("$ORIGIN rs.\nother A 1.1.1.1", "").unwrap();
+        let mut records = z.records.clone();
+        records.extend(stray);
+        assert!(Zone::new(records).is_err());
+        assert!(Zone::new(vec![]).is_err());
+    }
+
+    #[test]
+    fn test_look_up_answer() {
+        let res = zone().look_up(&Domain::new_aa("NS.hernan.rs"));
+        assert!(res.authoritative);
+        assert_eq!(res.r_code, OpCode::no_error());
+        assert_eq!(res.answers.len(), 1);
+        assert_eq!(
+            res.answers[0].data(),
+            &Data::Ipv4(Ipv4Addr::new(10, 0, 0, 1))
+        );
+    }
+
+    #[test]
+    fn test_look_up_cname_chain() {
+        let res = zone().look_up(&Domain::new_aa("www.hernan.rs"));
+        assert_eq!(res.answers.len(), 2);
+        assert_eq!(res.answers[0].domain().record, Record::CNAME);
+        assert_eq!(res.answers[1].domain().name, "web.hernan.rs");
+
+        let res = zone().look_up(&Domain::new_aa("ext.hernan.rs"));
+        assert_eq!(res.answers.len(), 1);
+        assert_eq!(res.r_code, OpCode::no_error());
+    }
+
+    #[test]
+    fn test_look_up_negative() {
+        let res = zone().look_up(&Domain::new_aa("missing.hernan.rs"));
+        assert_eq!(res.r_code, OpCode::name_error());
+        assert!(res.answers.is_empty());
+        assert_eq!(res.authorities.len(), 1);
+        assert_eq!(res.authorities[0].ttl(), 300);
+
+        let res = zone().look_up(&Domain::new("web.hernan.rs", Record::AAAA));
+        assert_eq!(res.r_code, OpCode::no_error());
+        assert!(res.answers.is_empty());
+        assert_eq!(res.authorities[0].domain().record, Record::SOA);
+
+        let res = zone().look_up(&Domain::new_aa("b.hernan.rs"));
+        assert_eq!(res.r_code, OpCode::no_error());
+    }
+
+    #[test]
+    fn test_look_up_referral() {
+        let res = zone().look_up(&Domain::new_aa("host.sub.hernan.rs"));
+        assert!(!res.authoritative);
+        assert!(res.answers.is_empty());
+        assert_eq!(res.authorities.len(), 1);
+        assert_eq!(res.additionals.len(), 1);
+    }
+
+    #[test]
+    fn test_look_up_wildcard() {
+        let wild = format!("{ZONE}*.w A 10.0.0.5\n*.w MX 10 web\nx.w A 10.0.0.6\ny.z.w A 10.0.0.7\n*.c CNAME web\n");
+        let zone = Zone::new(master::parse(&wild, "").unwrap()).unwrap();
+
+        let res = zone.look_up(&Domain::new_aa("a.b.w.hernan.rs"));
+        assert_eq!(records(&res.answers), [("a.b.w.hernan.rs", Record::AA)]);
+        assert_eq!(
+            res.wildcard,
+            Some(("a.b.w.hernan.rs".to_string(), "*.w.hernan.rs".to_string()))
+        );
+
+        let res = zone.look_up(&Domain::new("a.w.hernan.rs", Record::AAAA));
+        assert_eq!(res.r_code, OpCode::no_error());
+        assert!(res.answers.is_empty());
+
+        // Existing names and empty non-terminals are never synthesized.
+        let res = zone.look_up(&Domain::new("x.w.hernan.rs", Record::MX));
+        assert!(res.answers.is_empty());
+        let res = zone.look_up(&Domain::new_aa("z.w.hernan.rs"));
+        assert!(res.answers.is_empty());
+        assert_eq!(res.r_code, OpCode::no_error());
+        let res = zone.look_up(&Domain::new_aa("a.z.w.hernan.rs"));
+        assert_eq!(res.r_code, OpCode::name_error());
+
+        let res = zone.look_up(&Domain::new_aa("a.c.hernan.rs"));
+        let answers = [
+            ("a.c.hernan.rs", Record::CNAME),
+            ("web.hernan.rs", Record::AA),
+        ];
+        assert_eq!(records(&res.answers), answers);
+    }
+
+    #[test]
+    fn test_look_up_any() {
+        let wild = format!("{ZONE}*.w A 10.0.0.5\n*.w MX 10 web\n");
+        let zone = Zone::new(master::parse(&wild, "").unwrap()).unwrap();
+
+        let res = zone.look_up(&Domain::new("hernan.rs", Record::ANY));
+        let answers = [("hernan.rs", Record::SOA), ("hernan.rs", Record::NS)];
+        assert_eq!(records(&res.answers), answers);
+
+        let res = zone.look_up(&Domain::new("a.w.hernan.rs", Record::ANY));
+        assert_eq!(res.answers.len(), 2);
+
+        let res = zone.look_up(&Domain::new("www.hernan.rs", Record::ANY));
+        assert_eq!(records(&res.answers), [("www.hernan.rs", Record::CNAME)]);
+    }
+
+    const SIGNED: &str = r#"
+$ORIGIN hernan.rs.
+@       SOA ns hostmaster 1 3600 600 86400 300
+@       RRSIG   SOA 13 2 3600 20240201000000 20240101000000 1 @ AA==
+@       NS  ns
+@       RRSIG   NS 13 2 3600 20240201000000 20240101000000 1 @ AA==
+@       NSEC    a.b NS SOA RRSIG NSEC
+@       RRSIG   NSEC 13 2 3600 20240201000000 20240101000000 1 @ AA==
+a.b     A   10.0.0.3
+a.b     RRSIG   A 13 4 3600 20240201000000 20240101000000 1 @ AA==
+a.b     NSEC    ns A RRSIG NSEC
+a.b     RRSIG   NSEC 13 4 3600 20240201000000 20240101000000 1 @ AA==
+ns      A   10.0.0.1
+ns      RRSIG   A 13 3 3600 20240201000000 20240101000000 1 @ AA==
+ns      NSEC    sub A RRSIG NSEC
+ns      RRSIG   NSEC 13 3 3600 20240201000000 20240101000000 1 @ AA==
+sub     NS  ns.sub
+sub     DS  1 13 2 AABB
+sub     RRSIG   DS 13 3 3600 20240201000000 20240101000000 1 @ AA==
+sub     NSEC    @ NS DS RRSIG NSEC
+sub     RRSIG   NSEC 13 3 3600 20240201000000 20240101000000 1 @ AA==
+ns.sub  A   10.0.0.4
+"#;
+
+    fn signed_look_up(q: &Domain) -> Lookup {
+        let zone = Zone::new(master::parse(SIGNED, "").unwrap()).unwrap();
+        let mut lookup = zone.look_up(q);
+        zone.add_dnssec(q, &mut lookup);
+        lookup
+    }
+
+    fn records(rrs: &[Route]) -> Vec<(&str, Record)> {
+        rrs.iter()
+            .map(|r| (r.domain().name.as_str(), r.domain().record))
+            .collect()
+    }
+
+    #[test]
+    fn test_dnssec_answer() {
+        let res = signed_look_up(&Domain::new_aa("ns.hernan.rs"));
+        let answers = [
+            ("ns.hernan.rs", Record::AA),
+            ("ns.hernan.rs", Record::RRSIG),
+        ];
+        assert_eq!(records(&res.answers), answers);
+        assert!(res.authorities.is_empty());
+
+        let res = signed_look_up(&Domain::new("sub.hernan.rs", Record::DS));
+        assert!(res.authoritative);
+        assert_eq!(res.answers.len(), 2);
+        assert_eq!(covered(&res.answers[1]), Some(Record::DS.into()));
+    }
+
+    #[test]
+    fn test_dnssec_denial() {
+        let res = signed_look_up(&Domain::new_aa("missing.hernan.rs"));
+        assert_eq!(res.r_code, OpCode::name_error());
+        let nsecs: Vec<_> = records(&res.authorities)
+            .into_iter()
+            .filter(|r| r.1 == Record::NSEC)
+            .collect();
+        assert_eq!(
+            nsecs,
+            [("a.b.hernan.rs", Record::NSEC), ("hernan.rs", Record::NSEC)]
+        );
+        assert_eq!(res.authorities.len(), 6);
+
+        let res = signed_look_up(&Domain::new("ns.hernan.rs", Record::AAAA));
+        assert_eq!(res.r_code, OpCode::no_error());
+        let authorities = [
+            ("hernan.rs", Record::SOA),
+            ("ns.hernan.rs", Record::NSEC),
+            ("hernan.rs", Record::RRSIG),
+            ("ns.hernan.rs", Record::RRSIG),
+        ];
+        assert_eq!(records(&res.authorities), authorities);
+
+        let res = signed_look_up(&Domain::new_aa("b.hernan.rs"));
+        assert_eq!(res.authorities[1].domain().name, "hernan.rs");
+    }
+
+    #[test]
+    fn test_dnssec_wildcard() {
+        let wild = SIGNED.replace(
+            "ns      A   10.0.0.1\n",
+            "*.ns   A   10.0.0.5\n\
+             *.ns   RRSIG   A 13 3 3600 20240201000000 20240101000000 1 @ AA==\n\
+             ns      A   10.0.0.1\n",
+        );
+        let zone = Zone::new(master::parse(&wild, "").unwrap()).unwrap();
+
+        let q = Domain::new_aa("x.ns.hernan.rs");
+        let mut res = zone.look_up(&q);
+        zone.add_dnssec(&q, &mut res);
+        let answers = [
+            ("x.ns.hernan.rs", Record::AA),
+            ("x.ns.hernan.rs", Record::RRSIG),
+        ];
+        assert_eq!(records(&res.answers), answers);
+        let authorities = [
+            ("ns.hernan.rs", Record::NSEC),
+            ("ns.hernan.rs", Record::RRSIG),
+        ];
+        assert_eq!(records(&res.authorities), authorities);
+    }
+
+    const NSEC3: &str = r#"
+$ORIGIN hernan.rs.
+@       SOA ns hostmaster 1 3600 600 86400 300
+@       NS  ns
+@       NSEC3PARAM  1 0 0 -
+ns      A   10.0.0.1
+*.w     A   10.0.0.5
+sub     NS  ns.sub
+ns.sub  A   10.0.0.4
+1nf11d33qli625qduoqfh53qg97k4tgu NSEC3 1 0 0 - 7laptssai9qjm1h5guqgmh73aoqh83m4 NS SOA NSEC3PARAM
+7laptssai9qjm1h5guqgmh73aoqh83m4 NSEC3 1 0 0 - g27hol5dag2dkc91j1jfc91smd1k0tj6 A
+g27hol5dag2dkc91j1jfc91smd1k0tj6 NSEC3 1 0 0 - iqjmsnsbkrk6b503m4fq51j155dgagrh A
+iqjmsnsbkrk6b503m4fq51j155dgagrh NSEC3 1 0 0 - pc79qprv57gi5c9s3gkbdqsoeppeoiuu
+pc79qprv57gi5c9s3gkbdqsoeppeoiuu NSEC3 1 0 0 - 1nf11d33qli625qduoqfh53qg97k4tgu NS
+"#;
+
+    #[test]
+    fn test_dnssec_nsec3() {
+        let zone = Zone::new(master::parse(NSEC3, "").unwrap()).unwrap();
+        let proof = |q: Domain| {
+            let mut res = zone.look_up(&q);
+            zone.add_dnssec(&q, &mut res);
+            res.authorities
+                .iter()
+                .filter(|r| r.domain().record == Record::NSEC3)
+                .map(|r| r.domain().name[..4].to_string())
+                .collect::<Vec<_>>()
+        };
+
+        // The apex encloses missing.hernan.rs, and its NSEC3 covers the name
+        // too; that of ns.hernan.rs covers the wildcard.
+        assert_eq!(proof(Domain::new_aa("missing.hernan.rs")), ["1nf1", "7lap"]);
+        assert_eq!(proof(Domain::new("ns.hernan.rs", Record::AAAA)), ["7lap"]);
+        assert_eq!(proof(Domain::new_aa("x.w.hernan.rs")), ["iqjm"]);
+        assert_eq!(
+            proof(Domain::new("x.w.hernan.rs", Record::AAAA)),
+            ["iqjm", "g27h"]
+        );
+        assert_eq!(proof(Domain::new_aa("host.sub.hernan.rs")), ["pc79"]);
+    }
+
+    #[test]
+    fn test_dnssec_referral() {
+        let res = signed_look_up(&Domain::new_aa("host.sub.hernan.rs"));
+        let authorities = [
+            ("sub.hernan.rs", Record::NS),
+            ("sub.hernan.rs", Record::DS),
+            ("sub.hernan.rs", Record::RRSIG),
+        ];
+        assert_eq!(records(&res.authorities), authorities);
+        assert_eq!(res.additionals.len(), 1);
+    }
+
+    #[test]
+    fn test_zones_find() {
+        let zones = Zones::default();
+        zones.insert(zone());
+        let sub = master::parse("$ORIGIN sub.hernan.rs.\n@ SOA ns h 1 1 1 1 1", "").unwrap();
+        zones.insert(Zone::new(sub).unwrap());
+
+        let origin = |name| zones.find(name).map(|z| z.origin().to_string());
+        assert_eq!(origin("www.hernan.rs").unwrap(), "hernan.rs");
+        assert_eq!(origin("a.sub.hernan.rs").unwrap(), "sub.hernan.rs");
+        assert_eq!(origin("example.com"), None);
+
+        assert!(zones.get("sub.hernan.rs.").is_some());
+        assert!(zones.get("a.sub.hernan.rs").is_none());
+        zones.remove("SUB.hernan.rs");
+        assert_eq!(origin("a.sub.hernan.rs").unwrap(), "hernan.rs");
+    }
+
+    #[test]
+    fn test_zones_sign() {
+        let zones = Zones::default();
+        let key = dnssec::key::Key::generate("hernan.rs", 257, dnssec::key::ED25519, 1);
+        zones.set_signer(Signer::new(vec![key], 4 * 86400));
+        zones.insert(zone());
+        let signed = zones.get("hernan.rs").unwrap();
+        assert_eq!(signed.serial(), 1);
+        assert!(signed
+            .records_at("hernan.rs")
+            .any(|r| r.domain().record == Record::DNSKEY));
+        let q = Domain::new_aa("ns.hernan.rs");
+        let mut lookup = signed.look_up(&q);
+        signed.add_dnssec(&q, &mut lookup);
+        assert_eq!(lookup.answers.len(), 2);
+
+        // New signatures come with a new serial.
+        let later = tsig::now() as u32 + 4 * 86400;
+        zones.resign(later);
+        let resigned = zones.get("hernan.rs").unwrap();
+        assert_eq!(resigned.serial(), 2);
+        assert_eq!(resigned.journal().since(1).unwrap().len(), 1);
+        zones.resign(later);
+        assert_eq!(zones.get("hernan.rs").unwrap().serial(), 2);
+    }
+
+    #[test]
+    fn test_zones_stage() {
+        let zones = Zones::default();
+        let version = |origin: &str, serial| {
+            let text = format!("$ORIGIN {origin}.\n@ SOA ns h {serial} 1 1 1 1");
+            Zone::new(master::parse(&text, "").unwrap()).unwrap()
+        };
+        zones.insert(version("a.hernan.rs", 1));
+        zones.insert(version("b.hernan.rs", 1));
+        zones.insert(version("c.hernan.rs", 1));
+        let changes = zones.watch();
+
+        let staged = zones.stage(
+            Signer::default(),
+            vec![version("a.hernan.rs", 2), version("b.hernan.rs", 2)],
+            |origin| origin == "keep.hernan.rs",
+        );
+        // Nothing changes until the staged zones are committed, and zones
+        // changed meanwhile stay.
+        assert_eq!(zones.get("a.hernan.rs").unwrap().serial(), 1);
+        zones.insert(version("b.hernan.rs", 3));
+        zones.commit(staged);
+
+        assert_eq!(zones.get("a.hernan.rs").unwrap().serial(), 2);
+        assert_eq!(zones.get("b.hernan.rs").unwrap().serial(), 3);
+        assert!(zones.get("c.hernan.rs").is_none());
+        let serials: Vec<_> = changes.try_iter().map(|z| z.serial()).collect();
+        assert_eq!(serials, [3, 2]);
+    }
+
+    #[test]
+    fn test_zones_journal() {
+        let zones = Zones::default();
+        let version = |serial| {
+            let text = format!(
+                "$ORIGIN sub.hernan.rs.\n@ SOA ns h {serial} 1 1 1 1\nwww A 10.0.0.{serial}"
+            );
+            Zone::new(master::parse(&text, "").unwrap()).unwrap()
+        };
+        zones.insert(version(1));
+        zones.insert(version(2));
+        zones.insert(version(3));
+        let journal = zones.get("sub.hernan.rs").unwrap().journal().clone();
+        assert_eq!(journal.since(1).unwrap().len(), 2);
+
+        zones.insert(version(1));
+        assert!(zones
+            .get("sub.hernan.rs")
+            .unwrap()
+            .journal()
+            .since(1)
+            .is_none());
+    }
+}
@@ -0,0 +1,553 @@
+pub mod ed25519;
+mod field;
+pub mod key;
+pub mod p256;
+pub mod sha1;
+use crate::{
+    encoding::encode_base32hex,
+    message::{
+        data::Data,
+        domain::{canonical_cmp, in_zone, Domain, Record},
+        route::Route,
+    },
+    tsig::wire_name,
+};
+use key::Key;
+use sha1::sha1;
+use std::time::Duration;
+
+/// How often zones are checked for signatures to renew.
+pub const RESIGN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Signature validity when none is given: 30 days.
+pub const DEFAULT_VALIDITY: u32 = 30 * 86400;
+
+/// Seconds signatures start before they are made, for clocks running late.
+const SKEW: u32 = 3600;
+
+/// Hash of `name` as NSEC3 records order owner names (RFC 5155 section 5).
+pub fn nsec3_hash(name: &str, salt: &[u8], iterations: u16) -> Vec<u8> {
+    let mut digest = sha1(&[wire_name(name), salt.to_vec()].concat());
+    for _ in 0..iterations {
+        digest = sha1(&[digest, salt.to_vec()].concat());
+    }
+    digest
+}
+
+/// Owner of the NSEC3 record of `name` in the zone at `origin`.
+pub fn nsec3_owner(name: &str, origin: &str, salt: &[u8], iterations: u16) -> String {
+    let label = encode_base32hex(&nsec3_hash(name, salt, iterations));
+    match origin {
+        "" => label,
+        origin => format!("{label}.{origin}"),
+    }
+}
+
+/// Salt and iterations of NSEC3PARAM data.
+pub fn nsec3_param(data: &Data) -> Option<(Vec<u8>, u16)> {
+    match data {
+        Data::Raw(b) if b.len() >= 5 && b.len() == 5 + b[4] as usize => {
+            Some((b[5..].to_vec(), u16::from_be_bytes([b[2], b[3]])))
+        }
+        _ => None,
+    }
+}
+
+/// The type bitmap of NSEC and NSEC3 records (RFC 4034 section 4.1.2).
+pub fn type_bitmap(types: &[u16]) -> Vec<u8> {
+    let mut types = types.to_vec();
+    types.sort_unstable();
+    types.dedup();
+
+    let mut bytes = vec![];
+    for window in 0..=u8::MAX {
+        let bits: Vec<u8> = types
+            .iter()
+            .filter(|t| (*t >> 8) as u8 == window)
+            .map(|t| *t as u8)
+            .collect();
+        let Some(last) = bits.last() else {
+            continue;
+        };
+        let mut map = vec![0u8; *last as usize / 8 + 1];
+        for bit in bits.iter() {
+            map[*bit as usize / 8] |= 0x80 >> (bit % 8);
+        }
+        bytes.extend_from_slice(&[window, map.len() as u8]);
+        bytes.extend(map);
+    }
+    bytes
+}
+
+/// Record data in canonical form, names in lower case (RFC 4034 section 6.2).
+fn canonical_rdata(data: &Data) -> Vec<u8> {
+    match data {
+        Data::Ipv4(ip) => ip.octets().to_vec(),
+        Data::Ipv6(ip) => ip.octets().to_vec(),
+        Data::Name(name) => wire_name(name),
+        Data::Mx {
+            preference,
+            exchange,
+        } => [preference.to_be_bytes().to_vec(), wire_name(exchange)].concat(),
+        Data::Soa(soa) => {
+            let mut bytes = [wire_name(&soa.mname), wire_name(&soa.rname)].concat();
+            for n in [soa.serial, soa.refresh, soa.retry, soa.expire, soa.minimum] {
+                bytes.extend(n.to_be_bytes());
+            }
+            bytes
+        }
+        Data::Raw(bytes) => bytes.clone(),
+    }
+}
+
+/// The RRSIG of `key` over `rrset`, valid from `inception` to `expiration`
+/// (RFC 4034 section 3.1.8.1).
+fn rrsig(rrset: &[&Route], key: &Key, origin: &str, inception: u32, expiration: u32) -> Route {
+    let first = rrset[0];
+    let (domain, ttl) = (first.domain(), first.ttl());
+    // A wildcard's signature leaves out its `*` label.
+    let labels = domain.name.split('.').filter(|l| !l.is_empty()).count()
+        - domain.name.starts_with('*') as usize;
+
+    let mut rdata = u16::from(domain.record).to_be_bytes().to_vec();
+    rdata.extend([key.algorithm(), labels as u8]);
+    rdata.extend(ttl.to_be_bytes());
+    rdata.extend(expiration.to_be_bytes());
+    rdata.extend(inception.to_be_bytes());
+    rdata.extend(key.tag().to_be_bytes());
+    rdata.extend(wire_name(origin));
+
+    let mut rrs: Vec<Vec<u8>> = rrset.iter().map(|r| canonical_rdata(r.data())).collect();
+    rrs.sort();
+    rrs.dedup();
+    let mut signed = rdata.clone();
+    for rr in rrs {
+        signed.extend(wire_name(&domain.name));
+        signed.extend(u16::from(domain.record).to_be_bytes());
+        signed.extend(u16::from(domain.class).to_be_bytes());
+        signed.extend(ttl.to_be_bytes());
+        signed.extend((rr.len() as u16).to_be_bytes());
+        signed.extend(rr);
+    }
+    rdata.extend(key.sign(&signed));
+
+    let owner = Domain {
+        record: Record::RRSIG,
+        ..domain.clone()
+    };
+    Route::new(owner, ttl, Data::Raw(rdata))
+}
+
+/// Signs the zones it has keys for, online: KSKs sign the DNSKEY RRset and
+/// ZSKs the rest, unless a zone only has one kind, which then signs it all.
+#[derive(Debug, Default)]
+pub struct Signer {
+    keys: Vec<Key>,
+    /// Seconds signatures stay valid for.
+    validity: u32,
+}
+
+impl Signer {
+    pub fn new(keys: Vec<Key>, validity: u32) -> Self {
+        Self { keys, validity }
+    }
+
+    fn keys_of<'a>(&'a self, origin: &'a str) -> impl Iterator<Item = &'a Key> {
+        self.keys
+            .iter()
+            .filter(move |k| k.owner.eq_ignore_ascii_case(origin))
+    }
+
+    pub fn signs(&self, origin: &str) -> bool {
+        self.keys_of(origin).next().is_some()
+    }
+
+    /// Start of the period signatures made at `now` date from. Periods last a
+    /// quarter of the validity, so signatures are renewed with three quarters
+    /// of it left, and signing the same data twice in a period gives the same
+    /// records.
+    pub fn period(&self, now: u32) -> u32 {
+        let step = (self.validity / 4).max(1);
+        now - now % step
+    }
+
+    /// The DS records of the keys signing DNSKEY RRsets, for the parents.
+    pub fn ds(&self) -> Vec<String> {
+        self.keys
+            .iter()
+            .filter(|k| k.is_ksk() || !self.keys_of(&k.owner).any(Key::is_ksk))
+            .map(Key::ds)
+            .collect()
+    }
+
+    /// The records of the zone at `origin` signed at `now`, `None` without
+    /// keys for it. Signatures and NSEC or NSEC3 records already there are
+    /// made again, and the DNSKEY records of the keys added. The zone gets an
+    /// NSEC3 chain when it has an NSEC3PARAM record, and NSEC records
+    /// otherwise. Glue is left unsigned, and only DS RRsets at delegations.
+    pub fn sign(&self, records: &[Route], origin: &str, now: u32) -> Option<Vec<Route>> {
+        let keys: Vec<&Key> = self.keys_of(origin).collect();
+        let soa = records
+            .iter()
+            .find(|r| r.domain().record == Record::SOA)
+            .filter(|_| !keys.is_empty())?;
+        let negative_ttl = match soa.data() {
+            Data::Soa(data) => soa.ttl().min(data.minimum),
+            _ => soa.ttl(),
+        };
+
+        let mut records: Vec<Route> = records
+            .iter()
+            .filter(|r| {
+                !matches!(
+                    r.domain().record,
+                    Record::RRSIG | Record::NSEC | Record::NSEC3
+                )
+            })
+            .cloned()
+            .collect();
+        for key in &keys {
+            let dnskey = key.dnskey(soa.ttl());
+            let published = records.iter().any(|r| {
+                r.domain().record == Record::DNSKEY
+                    && r.domain().name.eq_ignore_ascii_case(origin)
+                    && r.data() == dnskey.data()
+            });
+            if !published {
+                records.push(dnskey);
+            }
+        }
+
+        let zone = Names::new(&records, origin);
+        let param = records
+            .iter()
+            .filter(|r| r.domain().record == Record::NSEC3PARAM)
+            .filter(|r| r.domain().name.eq_ignore_ascii_case(origin))
+            .find_map(|r| nsec3_param(r.data()));
+        let chain = match param {
+            Some((salt, iterations)) => zone.nsec3(&records, &salt, iterations, negative_ttl),
+            None => zone.nsec(&records, negative_ttl),
+        };
+        records.extend(chain);
+
+        let (ksks, zsks): (Vec<&Key>, Vec<&Key>) = keys.iter().partition(|k| k.is_ksk());
+        let period = self.period(now);
+        let (inception, expiration) = (period - SKEW, period + self.validity);
+        let mut sets: Vec<(String, Record)> = vec![];
+        for r in &records {
+            let set = (r.domain().name.to_ascii_lowercase(), r.domain().record);
+            if !sets.contains(&set) {
+                sets.push(set);
+            }
+        }
+        let mut sigs = vec![];
+        for (name, record) in sets {
+            if !zone.is_signed(&name, record) {
+                continue;
+            }
+            let rrset: Vec<&Route> = records
+                .iter()
+                .filter(|r| r.domain().record == record)
+                .filter(|r| r.domain().name.eq_ignore_ascii_case(&name))
+                .collect();
+            let signers = match record {
+                Record::DNSKEY if !ksks.is_empty() => &ksks,
+                _ if zsks.is_empty() => &ksks,
+                _ => &zsks,
+            };
+            for key in signers {
+                sigs.push(rrsig(&rrset, key, origin, inception, expiration));
+            }
+        }
+        records.extend(sigs);
+        Some(records)
+    }
+}
+
+/// The types of the records at `name`.
+fn types(records: &[Route], name: &str) -> Vec<u16> {
+    records
+        .iter()
+        .filter(|r| r.domain().name.eq_ignore_ascii_case(name))
+        .map(|r| u16::from(r.domain().record))
+        .collect()
+}
+
+fn chain_record(owner: &str, record: Record, ttl: u32, rdata: Vec<u8>) -> Route {
+    Route::new(Domain::new(owner, record), ttl, Data::Raw(rdata))
+}
+
+/// The names of a zone as its NSEC or NSEC3 chain covers them.
+struct Names<'a> {
+    origin: &'a str,
+    /// Names with NS records other than the origin, in lower case.
+    cuts: Vec<String>,
+    /// Names the zone has authority for, or delegates, in canonical order
+    /// and lower case.
+    names: Vec<String>,
+}
+
+impl<'a> Names<'a> {
+    fn new(records: &[Route], origin: &'a str) -> Self {
+        let mut cuts: Vec<String> = records
+            .iter()
+            .filter(|r| r.domain().record == Record::NS)
+            .map(|r| r.domain().name.to_ascii_lowercase())
+            .filter(|name| !name.eq_ignore_ascii_case(origin))
+            .collect();
+        cuts.sort();
+        cuts.dedup();
+        let mut zone = Self {
+            origin,
+            cuts,
+            names: vec![],
+        };
+        let mut names: Vec<String> = records
+            .iter()
+            .map(|r| r.domain().name.to_ascii_lowercase())
+            .filter(|name| !zone.is_glue(name))
+            .collect();
+        names.sort_by(|a, b| canonical_cmp(a, b));
+        names.dedup();
+        zone.names = names;
+        zone
+    }
+
+    /// Whether `name` lies below a delegation, where the zone has no authority.
+    fn is_glue(&self, name: &str) -> bool {
+        self.cuts
+            .iter()
+            .any(|cut| !name.eq_ignore_ascii_case(cut) && in_zone(name, cut))
+    }
+
+    fn is_cut(&self, name: &str) -> bool {
+        self.cuts.iter().any(|cut| name.eq_ignore_ascii_case(cut))
+    }
+
+    /// Whether the `record` RRset at `name` gets signed: every one the zone
+    /// has authority for, which at delegations is only DS and NSEC.
+    fn is_signed(&self, name: &str, record: Record) -> bool {
+        match self.is_cut(name) {
+            true => matches!(record, Record::DS | Record::NSEC),
+            false => !self.is_glue(name) && record != Record::RRSIG,
+        }
+    }
+
+    /// NSEC records linking the names in canonical order, the last back to
+    /// the origin (RFC 4035 section 2.3).
+    fn nsec(&self, records: &[Route], ttl: u32) -> Vec<Route> {
+        let mut chain = vec![];
+        for (i, name) in self.names.iter().enumerate() {
+            let next = &self.names[(i + 1) % self.names.len()];
+            let mut types = types(records, name);
+            types.extend([u16::from(Record::RRSIG), u16::from(Record::NSEC)]);
+            let rdata = [wire_name(next), type_bitmap(&types)].concat();
+            chain.push(chain_record(name, Record::NSEC, ttl, rdata));
+        }
+        chain
+    }
+
+    /// NSEC3 records linking the hashes of the names and of the empty
+    /// non-terminals above them, in hash order (RFC 5155 section 7.1), with
+    /// no opt-out.
+    fn nsec3(&self, records: &[Route], salt: &[u8], iterations: u16, ttl: u32) -> Vec<Route> {
+        let mut names = self.names.clone();
+        for name in &self.names {
+            let mut parent = name.as_str();
+            while let Some((_, up)) = parent.split_once('.') {
+                if !in_zone(up, self.origin) || names.iter().any(|n| n == up) {
+                    break;
+                }
+                names.push(up.to_string());
+                parent = up;
+            }
+        }
+        let mut hashed: Vec<(Vec<u8>, &String)> = names
+            .iter()
+            .map(|n| (nsec3_hash(n, salt, iterations), n))
+            .collect();
+        hashed.sort();
+
+        let mut chain = vec![];
+        for (i, (hash, name)) in hashed.iter().enumerate() {
+            let next = &hashed[(i + 1) % hashed.len()].0;
+            let mut types = types(records, name);
+            let signed = !self.is_cut(name) || types.contains(&u16::from(Record::DS));
+            if !types.is_empty() && signed {
+                types.push(u16::from(Record::RRSIG));
+            }
+            let mut rdata = vec![1, 0];
+            rdata.extend(iterations.to_be_bytes());
+            rdata.push(salt.len() as u8);
+            rdata.extend(salt);
+            rdata.push(next.len() as u8);
+            rdata.extend(next);
+            rdata.extend(type_bitmap(&types));
+            let owner = format!("{}.{}", encode_base32hex(hash), self.origin);
+            chain.push(chain_record(&owner, Record::NSEC3, ttl, rdata));
+        }
+        chain
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zone::master;
+
+    #[test]
+    fn test_nsec3_owner() {
+        // RFC 5155 appendix A.
+        let salt = [0xaa, 0xbb, 0xcc, 0xdd];
+        let owner = |name| nsec3_owner(name, "example", &salt, 12);
+        assert_eq!(owner("example"), "0p9mhaveqvm6t7vbl5lop2u3t2rp3tom.example");
+        assert_eq!(
+            owner("A.example"),
+            "35mthgpgcu1qg68fab165klnsnk3dpvl.example"
+        );
+        assert_eq!(
+            owner("*.w.example"),
+            "r53bq7cc2uvmubfu5ocmm6pers9tk9en.example"
+        );
+    }
+
+    const ZONE: &str = r#"
+$ORIGIN hernan.rs.
+@       SOA ns hostmaster 1 3600 600 86400 300
+@       NS  ns
+ns      A   10.0.0.1
+WWW     A   10.0.0.2
+www     A   10.0.0.3
+a.b     A   10.0.0.4
+sub     NS  ns.sub
+ns.sub  A   10.0.0.5
+@       RRSIG   A 13 2 3600 20240201000000 20240101000000 1 @ AA==
+"#;
+
+    fn signer() -> Signer {
+        let keys = vec![
+            Key::generate("hernan.rs", 257, key::ED25519, 1),
+            Key::generate("hernan.rs", 256, key::ECDSAP256SHA256, 2),
+        ];
+        Signer::new(keys, 4 * 86400)
+    }
+
+    fn sets(records: &[Route], record: Record) -> Vec<String> {
+        records
+            .iter()
+            .filter(|r| r.domain().record == record)
+            .map(|r| r.domain().name.to_ascii_lowercase())
+            .collect()
+    }
+
+    /// Type covered and algorithm of each RRSIG, by owner.
+    fn sigs(records: &[Route]) -> Vec<(String, Record, u8)> {
+        records
+            .iter()
+            .filter(|r| r.domain().record == Record::RRSIG)
+            .map(|r| match r.data() {
+                Data::Raw(b) => (
+                    r.domain().name.to_ascii_lowercase(),
+                    Record::from(u16::from_be_bytes([b[0], b[1]])),
+                    b[2],
+                ),
+                _ => panic!("not raw"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_sign_nsec() {
+        let records = master::parse(ZONE, "").unwrap();
+        let now = 1_700_000_000;
+        let signed = signer().sign(&records, "hernan.rs", now).unwrap();
+        assert_eq!(
+            signer().sign(&signed, "hernan.rs", now),
+            Some(signed.clone())
+        );
+        assert!(signer().sign(&signed, "other.rs", now).is_none());
+
+        assert_eq!(sets(&signed, Record::DNSKEY).len(), 2);
+        assert_eq!(
+            sets(&signed, Record::NSEC),
+            [
+                "hernan.rs",
+                "a.b.hernan.rs",
+                "ns.hernan.rs",
+                "sub.hernan.rs",
+                "www.hernan.rs"
+            ]
+        );
+        let nsec = signed
+            .iter()
+            .find(|r| r.domain().record == Record::NSEC && r.domain().name == "sub.hernan.rs")
+            .unwrap();
+        let rdata = [
+            wire_name("www.hernan.rs"),
+            vec![0, 6, 0x20, 0, 0, 0, 0, 0x03],
+        ]
+        .concat();
+        assert_eq!(nsec.data(), &Data::Raw(rdata));
+
+        // The KSK signs the keys and the ZSK the rest, but for NS at the
+        // delegation and glue; the old signature is gone.
+        let sigs = sigs(&signed);
+        assert!(sigs.contains(&("hernan.rs".to_string(), Record::DNSKEY, 15)));
+        assert!(sigs.contains(&("www.hernan.rs".to_string(), Record::AA, 13)));
+        assert!(sigs.contains(&("sub.hernan.rs".to_string(), Record::NSEC, 13)));
+        assert!(!sigs
+            .iter()
+            .any(|s| s.0 == "sub.hernan.rs" && s.1 == Record::NS));
+        assert!(!sigs.iter().any(|s| s.0 == "ns.sub.hernan.rs"));
+        assert!(!sigs.iter().any(|s| s.1 == Record::AA && s.0 == "hernan.rs"));
+        assert_eq!(sigs.len(), 11);
+
+        let www = signed
+            .iter()
+            .find(|r| r.domain().record == Record::RRSIG && r.domain().name == "WWW.hernan.rs")
+            .unwrap();
+        let Data::Raw(rdata) = www.data() else {
+            panic!("not raw")
+        };
+        let period = 1_699_920_000;
+        assert_eq!(rdata[3], 3);
+        assert_eq!(rdata[8..12], (period + 4 * 86400u32).to_be_bytes());
+        assert_eq!(rdata[12..16], (period - SKEW).to_be_bytes());
+        assert_eq!(rdata[18..29], *b"\x06hernan\x02rs\x00");
+        assert_eq!(rdata.len(), 29 + 64);
+    }
+
+    #[test]
+    fn test_sign_nsec3() {
+        let text = format!("{ZONE}@ NSEC3PARAM 1 0 0 -\n");
+        let records = master::parse(&text, "").unwrap();
+        let signed = signer().sign(&records, "hernan.rs", 1_700_000_000).unwrap();
+        assert!(sets(&signed, Record::NSEC).is_empty());
+
+        // The apex, ns, www, a.b and its empty non-terminal b, and sub.
+        let owners = sets(&signed, Record::NSEC3);
+        assert_eq!(owners.len(), 6);
+        let b = nsec3_owner("b.hernan.rs", "hernan.rs", &[], 0);
+        let ent = signed.iter().find(|r| r.domain().name == b).unwrap();
+        let Data::Raw(rdata) = ent.data() else {
+            panic!("not raw")
+        };
+        assert_eq!(rdata.len(), 6 + 20);
+
+        // The delegation without DS has no signature.
+        let sub = nsec3_owner("sub.hernan.rs", "hernan.rs", &[], 0);
+        let sub = signed.iter().find(|r| r.domain().name == sub).unwrap();
+        let Data::Raw(rdata) = sub.data() else {
+            panic!("not raw")
+        };
+        assert_eq!(rdata[26..], [0, 1, 0x20]);
+    }
+
+    #[test]
+    fn test_ds() {
+        let ds = signer().ds();
+        assert_eq!(ds.len(), 1);
+        assert!(ds[0].starts_with("hernan.rs. IN DS "));
+        assert!(ds[0].contains(" 15 2 "));
+    }
+}
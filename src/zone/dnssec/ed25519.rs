@@ -0,0 +1,205 @@
+//! Ed25519 signatures (RFC 8032), DNSSEC algorithm 15 (RFC 8080). Points are
+//! in extended coordinates and added with the complete formulas of Hisil et
+//! al., so the scalar multiplication runs the same steps for every key.
+
+use super::field::{from_le_bytes, select, to_le_bytes, Modulus, U256};
+use crate::tsig::hmac::sha512;
+
+const P: U256 = [
+    0xffffffffffffffed,
+    0xffffffffffffffff,
+    0xffffffffffffffff,
+    0x7fffffffffffffff,
+];
+
+/// `2d`, twice the curve constant.
+const D2: U256 = [
+    0xebd69b9426b2f159,
+    0x00e0149a8283b156,
+    0x198e80f2eef3d130,
+    0x2406d9dc56dffce7,
+];
+
+const BX: U256 = [
+    0xc9562d608f25d51a,
+    0x692cc7609525a7b2,
+    0xc0a4e231fdd6dc5c,
+    0x216936d3cd6e53fe,
+];
+
+const BY: U256 = [
+    0x6666666666666658,
+    0x6666666666666666,
+    0x6666666666666666,
+    0x6666666666666666,
+];
+
+/// The order of the base point.
+const L: U256 = [
+    0x5812631a5cf5d3ed,
+    0x14def9dea2f79cd6,
+    0,
+    0x1000000000000000,
+];
+
+#[derive(Clone, Copy)]
+struct Point {
+    x: U256,
+    y: U256,
+    z: U256,
+    t: U256,
+}
+
+struct Curve {
+    p: Modulus,
+    d2: U256,
+}
+
+impl Curve {
+    fn new() -> Self {
+        let p = Modulus::new(P);
+        let d2 = p.mont(&D2);
+        Self { p, d2 }
+    }
+
+    fn identity(&self) -> Point {
+        Point {
+            x: [0; 4],
+            y: self.p.one(),
+            z: self.p.one(),
+            t: [0; 4],
+        }
+    }
+
+    fn base(&self) -> Point {
+        let (x, y) = (self.p.mont(&BX), self.p.mont(&BY));
+        Point {
+            x,
+            y,
+            z: self.p.one(),
+            t: self.p.mul(&x, &y),
+        }
+    }
+
+    fn add(&self, a: &Point, b: &Point) -> Point {
+        let p = &self.p;
+        let e1 = p.mul(&p.sub(&a.y, &a.x), &p.sub(&b.y, &b.x));
+        let h1 = p.mul(&p.add(&a.y, &a.x), &p.add(&b.y, &b.x));
+        let c = p.mul(&p.mul(&a.t, &self.d2), &b.t);
+        let d = p.mul(&p.add(&a.z, &a.z), &b.z);
+        let (e, f, g, h) = (
+            p.sub(&h1, &e1),
+            p.sub(&d, &c),
+            p.add(&d, &c),
+            p.add(&h1, &e1),
+        );
+        Point {
+            x: p.mul(&e, &f),
+            y: p.mul(&g, &h),
+            z: p.mul(&f, &g),
+            t: p.mul(&e, &h),
+        }
+    }
+
+    /// `n` times the base point, looking at every bit of `n` alike.
+    fn mul_base(&self, n: &U256) -> Point {
+        let base = self.base();
+        let mut acc = self.identity();
+        for bit in (0..256).rev() {
+            acc = self.add(&acc, &acc);
+            let sum = self.add(&acc, &base);
+            let mask = ((n[bit / 64] >> (bit % 64)) & 1).wrapping_neg();
+            acc = Point {
+                x: select(mask, &sum.x, &acc.x),
+                y: select(mask, &sum.y, &acc.y),
+                z: select(mask, &sum.z, &acc.z),
+                t: select(mask, &sum.t, &acc.t),
+            };
+        }
+        acc
+    }
+
+    /// `y` with the low bit of `x` in its top bit.
+    fn encode(&self, point: &Point) -> [u8; 32] {
+        let z = self.p.invert(&point.z);
+        let x = self.p.plain(&self.p.mul(&point.x, &z));
+        let y = self.p.plain(&self.p.mul(&point.y, &z));
+        let mut bytes = to_le_bytes(&y);
+        bytes[31] |= (x[0] as u8 & 1) << 7;
+        bytes
+    }
+}
+
+/// A 512-bit little-endian hash modulo `L`, in Montgomery form.
+fn reduce(l: &Modulus, hash: &[u8]) -> U256 {
+    let lo = from_le_bytes(hash[..32].try_into().expect("64 byte hash"));
+    let hi = from_le_bytes(hash[32..].try_into().expect("64 byte hash"));
+    l.reduce_wide(&hi, &lo)
+}
+
+/// The secret scalar and the nonce prefix of a 32-byte private key.
+fn expand(seed: &[u8; 32]) -> (U256, Vec<u8>) {
+    let hash = sha512(seed);
+    let mut a: [u8; 32] = hash[..32].try_into().expect("64 byte hash");
+    a[0] &= 248;
+    a[31] &= 127;
+    a[31] |= 64;
+    (from_le_bytes(&a), hash[32..].to_vec())
+}
+
+pub fn public_key(seed: &[u8; 32]) -> [u8; 32] {
+    let curve = Curve::new();
+    curve.encode(&curve.mul_base(&expand(seed).0))
+}
+
+pub fn sign(seed: &[u8; 32], message: &[u8]) -> [u8; 64] {
+    let curve = Curve::new();
+    let l = Modulus::new(L);
+    let (a, prefix) = expand(seed);
+    let public = curve.encode(&curve.mul_base(&a));
+
+    let r = reduce(&l, &sha512(&[&prefix, message].concat()));
+    let big_r = curve.encode(&curve.mul_base(&l.plain(&r)));
+    let k = reduce(&l, &sha512(&[&big_r[..], &public, message].concat()));
+    let s = l.add(&r, &l.mul(&k, &l.mont(&a)));
+
+    let mut signature = [0; 64];
+    signature[..32].copy_from_slice(&big_r);
+    signature[32..].copy_from_slice(&to_le_bytes(&l.plain(&s)));
+    signature
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::decode_hex;
+
+    fn hex(s: &str) -> Vec<u8> {
+        decode_hex(s).unwrap()
+    }
+
+    #[test]
+    fn test_sign() {
+        // RFC 8032 section 7.1, tests 1 and 2.
+        for (seed, public, message, signature) in [
+            (
+                "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+                "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+                "",
+                "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e06522490155\
+                 5fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+            ),
+            (
+                "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+                "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+                "72",
+                "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da\
+                 085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+            ),
+        ] {
+            let seed = hex(seed).try_into().unwrap();
+            assert_eq!(public_key(&seed).to_vec(), hex(public));
+            assert_eq!(sign(&seed, &hex(message)).to_vec(), hex(signature));
+        }
+    }
+}
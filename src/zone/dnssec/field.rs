@@ -0,0 +1,255 @@
+//! Arithmetic modulo the 256-bit primes behind Ed25519 and P-256, both their
+//! fields and their group orders. Numbers are four 64-bit limbs, least
+//! significant first, kept in Montgomery form while computing. Nothing
+//! branches on the values involved, as they are secret when signing.
+
+pub type U256 = [u64; 4];
+
+pub fn from_be_bytes(bytes: &[u8; 32]) -> U256 {
+    let mut n = [0; 4];
+    for (i, chunk) in bytes.rchunks(8).enumerate() {
+        n[i] = u64::from_be_bytes(chunk.try_into().expect("Chunks of 8 bytes"));
+    }
+    n
+}
+
+pub fn to_be_bytes(n: &U256) -> [u8; 32] {
+    let mut bytes = [0; 32];
+    for (chunk, limb) in bytes.rchunks_mut(8).zip(n) {
+        chunk.copy_from_slice(&limb.to_be_bytes());
+    }
+    bytes
+}
+
+pub fn from_le_bytes(bytes: &[u8; 32]) -> U256 {
+    let mut n = [0; 4];
+    for (i, chunk) in bytes.chunks(8).enumerate() {
+        n[i] = u64::from_le_bytes(chunk.try_into().expect("Chunks of 8 bytes"));
+    }
+    n
+}
+
+pub fn to_le_bytes(n: &U256) -> [u8; 32] {
+    let mut bytes = [0; 32];
+    for (chunk, limb) in bytes.chunks_mut(8).zip(n) {
+        chunk.copy_from_slice(&limb.to_le_bytes());
+    }
+    bytes
+}
+
+/// `a + b + carry`, and the carry out.
+fn adc(a: u64, b: u64, carry: u64) -> (u64, u64) {
+    let t = a as u128 + b as u128 + carry as u128;
+    (t as u64, (t >> 64) as u64)
+}
+
+/// `a - b - borrow`, and the borrow out, 0 or 1.
+fn sbb(a: u64, b: u64, borrow: u64) -> (u64, u64) {
+    let t = (a as u128).wrapping_sub(b as u128 + borrow as u128);
+    (t as u64, (t >> 127) as u64)
+}
+
+/// `a + b * c + carry`, and the carry out.
+fn mac(a: u64, b: u64, c: u64, carry: u64) -> (u64, u64) {
+    let t = a as u128 + b as u128 * c as u128 + carry as u128;
+    (t as u64, (t >> 64) as u64)
+}
+
+fn sub(a: &U256, b: &U256) -> (U256, u64) {
+    let mut d = [0; 4];
+    let mut borrow = 0;
+    for i in 0..4 {
+        (d[i], borrow) = sbb(a[i], b[i], borrow);
+    }
+    (d, borrow)
+}
+
+/// `a` when `mask` is all ones, `b` when it is zero.
+pub fn select(mask: u64, a: &U256, b: &U256) -> U256 {
+    let mut n = [0; 4];
+    for i in 0..4 {
+        n[i] = (a[i] & mask) | (b[i] & !mask);
+    }
+    n
+}
+
+/// All ones when `n` is zero, zero otherwise.
+pub fn is_zero(n: &U256) -> u64 {
+    let any = n.iter().fold(0, |acc, limb| acc | limb);
+    ((any | any.wrapping_neg()) >> 63).wrapping_sub(1)
+}
+
+/// Whether `a` is less than `b`, for values that are not secret.
+pub fn less_than(a: &U256, b: &U256) -> bool {
+    sub(a, b).1 == 1
+}
+
+/// Integers modulo an odd `m` below 2^256.
+#[derive(Clone, Copy, Debug)]
+pub struct Modulus {
+    m: U256,
+    /// `-m^-1 mod 2^64`.
+    inv: u64,
+    /// `R^2 mod m`, where `R` is 2^256.
+    r2: U256,
+}
+
+impl Modulus {
+    pub fn new(m: U256) -> Self {
+        // Newton's iteration doubles the correct low bits of m^-1 each time.
+        let mut inv: u64 = 1;
+        for _ in 0..6 {
+            inv = inv.wrapping_mul(2u64.wrapping_sub(m[0].wrapping_mul(inv)));
+        }
+        let mut modulus = Self {
+            m,
+            inv: inv.wrapping_neg(),
+            r2: [0; 4],
+        };
+
+        // R mod m is 2^256 - m reduced, then doubled 256 times into R^2.
+        let mut r = sub(&[0; 4], &m).0;
+        while !less_than(&r, &m) {
+            r = sub(&r, &m).0;
+        }
+        for _ in 0..256 {
+            r = modulus.add(&r, &r);
+        }
+        modulus.r2 = r;
+        modulus
+    }
+
+    /// `t - m` when `carry` is set or `t` is at least `m`, else `t`.
+    fn reduce_once(&self, t: &U256, carry: u64) -> U256 {
+        let (d, borrow) = sub(t, &self.m);
+        let keep = (1 ^ carry) & borrow;
+        select(keep.wrapping_neg(), t, &d)
+    }
+
+    pub fn add(&self, a: &U256, b: &U256) -> U256 {
+        let mut s = [0; 4];
+        let mut carry = 0;
+        for i in 0..4 {
+            (s[i], carry) = adc(a[i], b[i], carry);
+        }
+        self.reduce_once(&s, carry)
+    }
+
+    pub fn sub(&self, a: &U256, b: &U256) -> U256 {
+        let (d, borrow) = sub(a, b);
+        let m = select(borrow.wrapping_neg(), &self.m, &[0; 4]);
+        let mut s = [0; 4];
+        let mut carry = 0;
+        for i in 0..4 {
+            (s[i], carry) = adc(d[i], m[i], carry);
+        }
+        s
+    }
+
+    /// `a * b / R mod m`, the Montgomery product.
+    pub fn mul(&self, a: &U256, b: &U256) -> U256 {
+        let mut t = [0u64; 6];
+        for &limb in b {
+            let mut carry = 0;
+            for j in 0..4 {
+                (t[j], carry) = mac(t[j], a[j], limb, carry);
+            }
+            (t[4], t[5]) = adc(t[4], carry, 0);
+
+            let k = t[0].wrapping_mul(self.inv);
+            let (_, mut carry) = mac(t[0], k, self.m[0], 0);
+            for j in 1..4 {
+                (t[j - 1], carry) = mac(t[j], k, self.m[j], carry);
+            }
+            (t[3], carry) = adc(t[4], carry, 0);
+            t[4] = t[5] + carry;
+        }
+        self.reduce_once(&[t[0], t[1], t[2], t[3]], t[4])
+    }
+
+    pub fn square(&self, a: &U256) -> U256 {
+        self.mul(a, a)
+    }
+
+    /// `a` in Montgomery form; `a` may be any number below 2^256.
+    pub fn mont(&self, a: &U256) -> U256 {
+        self.mul(a, &self.r2)
+    }
+
+    /// `a` out of Montgomery form, fully reduced.
+    pub fn plain(&self, a: &U256) -> U256 {
+        self.mul(a, &[1, 0, 0, 0])
+    }
+
+    /// 1 in Montgomery form.
+    pub fn one(&self) -> U256 {
+        self.mont(&[1, 0, 0, 0])
+    }
+
+    /// `a` to the power of `e`, which is not secret, both in Montgomery form
+    /// but for `e`.
+    pub fn pow(&self, a: &U256, e: &U256) -> U256 {
+        let mut acc = self.one();
+        for bit in (0..256).rev() {
+            acc = self.square(&acc);
+            if (e[bit / 64] >> (bit % 64)) & 1 == 1 {
+                acc = self.mul(&acc, a);
+            }
+        }
+        acc
+    }
+
+    /// The inverse of `a` by Fermat's little theorem, `m` being prime.
+    pub fn invert(&self, a: &U256) -> U256 {
+        let e = sub(&self.m, &[2, 0, 0, 0]).0;
+        self.pow(a, &e)
+    }
+
+    /// `hi * 2^256 + lo mod m`, in Montgomery form.
+    pub fn reduce_wide(&self, hi: &U256, lo: &U256) -> U256 {
+        // hi * R^2 / R is hi * 2^256; that once more into Montgomery form.
+        let hi = self.mont(&self.mont(hi));
+        self.add(&hi, &self.mont(lo))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arithmetic() {
+        // The prime of P-256, close to 2^256, and that of Ed25519 below 2^255.
+        for m in [
+            [u64::MAX, 0xffffffff, 0, 0xffffffff00000001],
+            [0xffffffffffffffed, u64::MAX, u64::MAX, 0x7fffffffffffffff],
+        ] {
+            let f = Modulus::new(m);
+            let x = f.mont(&[7, 0, 0, 0]);
+            let y = f.mont(&[5, 0, 0, 0]);
+            assert_eq!(f.plain(&f.mul(&x, &y)), [35, 0, 0, 0]);
+            assert_eq!(f.plain(&f.sub(&y, &x)), sub(&m, &[2, 0, 0, 0]).0);
+            assert_eq!(f.plain(&f.add(&f.sub(&y, &x), &x)), [5, 0, 0, 0]);
+            assert_eq!(f.plain(&f.mul(&f.invert(&x), &x)), [1, 0, 0, 0]);
+            assert_eq!(f.plain(&f.mont(&m)), [0; 4]);
+            // 2^256 + 3, where 2^256 mod m is 1 in Montgomery form.
+            let r = f.reduce_wide(&[1, 0, 0, 0], &[3, 0, 0, 0]);
+            let expected = f.add(&f.one(), &[3, 0, 0, 0]);
+            assert_eq!(f.plain(&r), expected);
+        }
+    }
+
+    #[test]
+    fn test_bytes() {
+        let mut bytes = [0; 32];
+        bytes[31] = 1;
+        bytes[0] = 2;
+        let n = from_be_bytes(&bytes);
+        assert_eq!(n, [1, 0, 0, 2 << 56]);
+        assert_eq!(to_be_bytes(&n), bytes);
+        assert_eq!(from_le_bytes(&bytes), [2, 0, 0, 1 << 56]);
+        assert_eq!(to_le_bytes(&from_le_bytes(&bytes)), bytes);
+        assert_eq!(is_zero(&[0; 4]), u64::MAX);
+        assert_eq!(is_zero(&[0, 0, 1, 0]), 0);
+    }
+}
@@ -0,0 +1,247 @@
+//! Zone signing keys, read from the `.key` and `.private` files that BIND's
+//! `dnssec-keygen` writes.
+
+use super::{ed25519, p256};
+use crate::{
+    encoding::{decode_base64, encode_hex},
+    message::{
+        data::Data,
+        domain::{Domain, Record},
+        route::Route,
+    },
+    tsig::{hmac::sha256, wire_name},
+    zone::master,
+};
+use anyhow::{Context, Result};
+use std::{fmt, fs};
+
+pub const ECDSAP256SHA256: u8 = 13;
+pub const ED25519: u8 = 15;
+
+/// DNSKEY flag of keys whose DS the parent holds, the key signing keys.
+const SEP: u16 = 1;
+
+/// DS digest type of SHA-256 (RFC 4509).
+const SHA256: u8 = 2;
+
+/// A private key and the DNSKEY publishing it.
+#[derive(Clone)]
+pub struct Key {
+    /// The zone the key signs.
+    pub owner: String,
+    flags: u16,
+    algorithm: u8,
+    secret: [u8; 32],
+    public: Vec<u8>,
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Key")
+            .field("owner", &self.owner)
+            .field("flags", &self.flags)
+            .field("algorithm", &self.algorithm)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The public key of `secret` as DNSKEY records hold it.
+fn public_key(algorithm: u8, secret: &[u8; 32]) -> Result<Vec<u8>> {
+    match algorithm {
+        ECDSAP256SHA256 => Ok(p256::public_key(secret).to_vec()),
+        ED25519 => Ok(ed25519::public_key(secret).to_vec()),
+        a => anyhow::bail!("Unsupported DNSSEC algorithm {a}"),
+    }
+}
+
+impl Key {
+    /// Reads the key pair at `path`, the name the two files share with or
+    /// without their extension: `K<zone>+<algorithm>+<tag>`.
+    pub fn load(path: &str) -> Result<Self> {
+        let base = path
+            .strip_suffix(".key")
+            .or_else(|| path.strip_suffix(".private"))
+            .unwrap_or(path);
+        let text = fs::read_to_string(format!("{base}.key"))
+            .with_context(|| format!("Could not read DNSSEC key {base}.key"))?;
+        let records = master::parse(&text, "")
+            .with_context(|| format!("Could not parse DNSSEC key {base}.key"))?;
+        let dnskey = records
+            .iter()
+            .find(|r| r.domain().record == Record::DNSKEY)
+            .with_context(|| format!("No DNSKEY in {base}.key"))?;
+        let rdata = match dnskey.data() {
+            Data::Raw(bytes) if bytes.len() > 4 => bytes,
+            _ => anyhow::bail!("Invalid DNSKEY in {base}.key"),
+        };
+
+        let text = fs::read_to_string(format!("{base}.private"))
+            .with_context(|| format!("Could not read DNSSEC key {base}.private"))?;
+        let field = |name: &str| {
+            text.lines()
+                .find_map(|l| l.strip_prefix(name)?.strip_prefix(':'))
+                .map(str::trim)
+                .with_context(|| format!("Missing {name} in {base}.private"))
+        };
+        let algorithm: u8 = field("Algorithm")?
+            .split_whitespace()
+            .next()
+            .and_then(|a| a.parse().ok())
+            .with_context(|| format!("Invalid algorithm in {base}.private"))?;
+        let secret: [u8; 32] = decode_base64(field("PrivateKey")?)?
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Invalid private key in {base}.private"))?;
+
+        let public = public_key(algorithm, &secret)?;
+        anyhow::ensure!(
+            rdata[3] == algorithm && rdata[4..] == public,
+            "{base}.private does not match {base}.key"
+        );
+        Ok(Self {
+            owner: dnskey.domain().name.to_ascii_lowercase(),
+            flags: u16::from_be_bytes([rdata[0], rdata[1]]),
+            algorithm,
+            secret,
+            public,
+        })
+    }
+
+    /// A key made of `seed` repeated, for tests.
+    #[cfg(test)]
+    pub fn generate(owner: &str, flags: u16, algorithm: u8, seed: u8) -> Self {
+        let secret = [seed; 32];
+        Self {
+            owner: owner.to_string(),
+            flags,
+            algorithm,
+            secret,
+            public: public_key(algorithm, &secret).unwrap(),
+        }
+    }
+
+    pub fn algorithm(&self) -> u8 {
+        self.algorithm
+    }
+
+    /// Whether the key signs the DNSKEY RRset rather than the rest of the zone.
+    pub fn is_ksk(&self) -> bool {
+        self.flags & SEP != 0
+    }
+
+    /// The data of the DNSKEY record of the key (RFC 4034 section 2.1).
+    pub fn rdata(&self) -> Vec<u8> {
+        let mut bytes = self.flags.to_be_bytes().to_vec();
+        bytes.extend([3, self.algorithm]);
+        bytes.extend(&self.public);
+        bytes
+    }
+
+    /// The key tag signatures name the key by (RFC 4034 appendix B).
+    pub fn tag(&self) -> u16 {
+        let mut sum: u32 = 0;
+        for (i, b) in self.rdata().iter().enumerate() {
+            sum += match i & 1 {
+                0 => (*b as u32) << 8,
+                _ => *b as u32,
+            };
+        }
+        (sum + (sum >> 16)) as u16
+    }
+
+    pub fn dnskey(&self, ttl: u32) -> Route {
+        let domain = Domain::new(&self.owner, Record::DNSKEY);
+        Route::new(domain, ttl, Data::Raw(self.rdata()))
+    }
+
+    /// The DS record of the key with its SHA-256 digest, in presentation
+    /// format, for the parent zone to publish (RFC 4509).
+    pub fn ds(&self) -> String {
+        let digest = sha256(&[wire_name(&self.owner), self.rdata()].concat());
+        format!(
+            "{}. IN DS {} {} {SHA256} {}",
+            self.owner,
+            self.tag(),
+            self.algorithm,
+            encode_hex(&digest)
+        )
+    }
+
+    pub fn sign(&self, data: &[u8]) -> Vec<u8> {
+        match self.algorithm {
+            ECDSAP256SHA256 => p256::sign(&self.secret, data).to_vec(),
+            _ => ed25519::sign(&self.secret, data).to_vec(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::encode_base64;
+    use std::env;
+
+    /// Writes the files of `key` under the temporary directory.
+    fn write(key: &Key, secret: &Key) -> String {
+        let base = env::temp_dir().join(format!(
+            "K{}.+{:03}+{}",
+            key.owner,
+            key.algorithm,
+            key.tag()
+        ));
+        let base = base.to_str().unwrap().to_string();
+        let dnskey = format!(
+            "; This is a key-signing key.\n{}. 3600 IN DNSKEY {} 3 {} {}\n",
+            key.owner,
+            key.flags,
+            key.algorithm,
+            encode_base64(&key.public)
+        );
+        fs::write(format!("{base}.key"), dnskey).unwrap();
+        let private = format!(
+            "Private-key-format: v1.3\nAlgorithm: {} (ED25519)\nPrivateKey: {}\n",
+            secret.algorithm,
+            encode_base64(&secret.secret)
+        );
+        fs::write(format!("{base}.private"), private).unwrap();
+        base
+    }
+
+    #[test]
+    fn test_load() {
+        let key = Key::generate("key.hernan.rs", 257, ED25519, 1);
+        let base = write(&key, &key);
+        let loaded = Key::load(&format!("{base}.private")).unwrap();
+        assert_eq!(loaded.owner, "key.hernan.rs");
+        assert!(loaded.is_ksk());
+        assert_eq!(loaded.rdata(), key.rdata());
+        assert_eq!(loaded.secret, key.secret);
+
+        let other = Key::generate("key.hernan.rs", 257, ED25519, 2);
+        assert!(Key::load(&write(&key, &other)).is_err());
+        assert!(Key::load("/nonexistent/Kkey").is_err());
+    }
+
+    #[test]
+    fn test_tag_and_ds() {
+        // The Ed25519 KSK of example.com in RFC 8080 section 6.1.
+        let text = "example.com. 3600 IN DNSKEY 257 3 15 \
+                    l02Woi0iS8Aa25FQkUd9RMzZHJpBoRQwAQEX1SxZJA4=";
+        let dnskey = &master::parse(text, "").unwrap()[0];
+        let Data::Raw(rdata) = dnskey.data() else {
+            panic!("not raw")
+        };
+        let key = Key {
+            owner: "example.com".to_string(),
+            flags: 257,
+            algorithm: ED25519,
+            secret: [0; 32],
+            public: rdata[4..].to_vec(),
+        };
+        assert_eq!(key.tag(), 3613);
+        assert_eq!(
+            key.ds(),
+            "example.com. IN DS 3613 15 2 \
+             3AA5AB37EFCE57F737FC1627013FEE07BDF241BD10F3B1964AB55C78E79A304B"
+        );
+    }
+}
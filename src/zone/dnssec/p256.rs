@@ -0,0 +1,209 @@
+//! ECDSA over P-256 with SHA-256 (FIPS 186-4), DNSSEC algorithm 13
+//! (RFC 6605). Nonces are derived from the key and message (RFC 6979), and
+//! points are added with the complete formulas of Renes, Costello and Batina
+//! for `a = -3`, so the scalar multiplication runs the same steps for every
+//! key.
+
+use super::field::{from_be_bytes, is_zero, less_than, select, to_be_bytes, Modulus, U256};
+use crate::tsig::hmac::{hmac_sha256, sha256};
+
+const P: U256 = [
+    0xffffffffffffffff,
+    0x00000000ffffffff,
+    0,
+    0xffffffff00000001,
+];
+
+/// The order of the base point.
+const N: U256 = [
+    0xf3b9cac2fc632551,
+    0xbce6faada7179e84,
+    0xffffffffffffffff,
+    0xffffffff00000000,
+];
+
+const B: U256 = [
+    0x3bce3c3e27d2604b,
+    0x651d06b0cc53b0f6,
+    0xb3ebbd55769886bc,
+    0x5ac635d8aa3a93e7,
+];
+
+const GX: U256 = [
+    0xf4a13945d898c296,
+    0x77037d812deb33a0,
+    0xf8bce6e563a440f2,
+    0x6b17d1f2e12c4247,
+];
+
+const GY: U256 = [
+    0xcbb6406837bf51f5,
+    0x2bce33576b315ece,
+    0x8ee7eb4a7c0f9e16,
+    0x4fe342e2fe1a7f9b,
+];
+
+/// A point in projective coordinates.
+#[derive(Clone, Copy)]
+struct Point {
+    x: U256,
+    y: U256,
+    z: U256,
+}
+
+struct Curve {
+    p: Modulus,
+    b: U256,
+}
+
+impl Curve {
+    fn new() -> Self {
+        let p = Modulus::new(P);
+        let b = p.mont(&B);
+        Self { p, b }
+    }
+
+    /// Algorithm 4 of "Complete addition formulas for prime order elliptic
+    /// curves", which also doubles.
+    fn add(&self, a: &Point, b: &Point) -> Point {
+        let p = &self.p;
+        let t0 = p.mul(&a.x, &b.x);
+        let t1 = p.mul(&a.y, &b.y);
+        let t2 = p.mul(&a.z, &b.z);
+        let t3 = p.mul(&p.add(&a.x, &a.y), &p.add(&b.x, &b.y));
+        let t3 = p.sub(&t3, &p.add(&t0, &t1));
+        let t4 = p.mul(&p.add(&a.y, &a.z), &p.add(&b.y, &b.z));
+        let t4 = p.sub(&t4, &p.add(&t1, &t2));
+        let y3 = p.mul(&p.add(&a.x, &a.z), &p.add(&b.x, &b.z));
+        let y3 = p.sub(&y3, &p.add(&t0, &t2));
+        let x3 = p.sub(&y3, &p.mul(&self.b, &t2));
+        let x3 = p.add(&x3, &p.add(&x3, &x3));
+        let z3 = p.sub(&t1, &x3);
+        let x3 = p.add(&t1, &x3);
+        let y3 = p.mul(&self.b, &y3);
+        let t2 = p.add(&t2, &p.add(&t2, &t2));
+        let y3 = p.sub(&p.sub(&y3, &t2), &t0);
+        let y3 = p.add(&y3, &p.add(&y3, &y3));
+        let t0 = p.sub(&p.add(&t0, &p.add(&t0, &t0)), &t2);
+        let t1 = p.mul(&t4, &y3);
+        let t2 = p.mul(&t0, &y3);
+        Point {
+            x: p.sub(&p.mul(&t3, &x3), &t1),
+            y: p.add(&p.mul(&x3, &z3), &t2),
+            z: p.add(&p.mul(&t4, &z3), &p.mul(&t3, &t0)),
+        }
+    }
+
+    /// `n` times the base point, looking at every bit of `n` alike.
+    fn mul_base(&self, n: &U256) -> Point {
+        let base = Point {
+            x: self.p.mont(&GX),
+            y: self.p.mont(&GY),
+            z: self.p.one(),
+        };
+        let mut acc = Point {
+            x: [0; 4],
+            y: self.p.one(),
+            z: [0; 4],
+        };
+        for bit in (0..256).rev() {
+            acc = self.add(&acc, &acc);
+            let sum = self.add(&acc, &base);
+            let mask = ((n[bit / 64] >> (bit % 64)) & 1).wrapping_neg();
+            acc = Point {
+                x: select(mask, &sum.x, &acc.x),
+                y: select(mask, &sum.y, &acc.y),
+                z: select(mask, &sum.z, &acc.z),
+            };
+        }
+        acc
+    }
+
+    /// The affine coordinates of `point`, as integers.
+    fn affine(&self, point: &Point) -> (U256, U256) {
+        let z = self.p.invert(&point.z);
+        (
+            self.p.plain(&self.p.mul(&point.x, &z)),
+            self.p.plain(&self.p.mul(&point.y, &z)),
+        )
+    }
+}
+
+/// The nonce for signing `hash` with `secret` (RFC 6979 section 3.2).
+fn nonce(secret: &[u8; 32], hash: &[u8; 32]) -> impl Iterator<Item = U256> {
+    let secret = *secret;
+    let hash = *hash;
+    let mut k = hmac_sha256(&[0; 32], &[&[1; 32][..], &[0], &secret, &hash].concat());
+    let mut v = hmac_sha256(&k, &[1; 32]);
+    k = hmac_sha256(&k, &[&v[..], &[1], &secret, &hash].concat());
+    v = hmac_sha256(&k, &v);
+    std::iter::from_fn(move || loop {
+        v = hmac_sha256(&k, &v);
+        let candidate = from_be_bytes(v[..].try_into().expect("32 byte HMAC"));
+        k = hmac_sha256(&k, &[&v[..], &[0]].concat());
+        v = hmac_sha256(&k, &v);
+        if is_zero(&candidate) == 0 && less_than(&candidate, &N) {
+            return Some(candidate);
+        }
+    })
+}
+
+/// The uncompressed public key of `secret`, `x` then `y`, without the
+/// leading 4 (RFC 6605 section 4).
+pub fn public_key(secret: &[u8; 32]) -> [u8; 64] {
+    let curve = Curve::new();
+    let (x, y) = curve.affine(&curve.mul_base(&from_be_bytes(secret)));
+    let mut key = [0; 64];
+    key[..32].copy_from_slice(&to_be_bytes(&x));
+    key[32..].copy_from_slice(&to_be_bytes(&y));
+    key
+}
+
+/// The signature of `message`, `r` then `s`.
+pub fn sign(secret: &[u8; 32], message: &[u8]) -> [u8; 64] {
+    let curve = Curve::new();
+    let n = Modulus::new(N);
+    let d = n.mont(&from_be_bytes(secret));
+    let z = n.mont(&from_be_bytes(
+        sha256(message)[..].try_into().expect("32 byte hash"),
+    ));
+    // The hash reduced, as RFC 6979 feeds it to the nonce generator.
+    let hash = to_be_bytes(&n.plain(&z));
+
+    for k in nonce(secret, &hash) {
+        let r = n.mont(&curve.affine(&curve.mul_base(&k)).0);
+        let k = n.invert(&n.mont(&k));
+        let s = n.mul(&k, &n.add(&z, &n.mul(&r, &d)));
+        if is_zero(&r) == 0 && is_zero(&s) == 0 {
+            let mut signature = [0; 64];
+            signature[..32].copy_from_slice(&to_be_bytes(&n.plain(&r)));
+            signature[32..].copy_from_slice(&to_be_bytes(&n.plain(&s)));
+            return signature;
+        }
+    }
+    unreachable!("The nonce generator never ends")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::decode_hex;
+
+    fn hex(s: &str) -> Vec<u8> {
+        decode_hex(s).unwrap()
+    }
+
+    #[test]
+    fn test_sign() {
+        // RFC 6979 appendix A.2.5, SHA-256 with the message "sample".
+        let secret = hex("C9AFA9D845BA75166B5C215767B1D6934E50C3DB36E89B127B8A622B120F6721")
+            .try_into()
+            .unwrap();
+        let public = "60FED4BA255A9D31C961EB74C6356D68C049B8923B61FA6CE669622E60F29FB6\
+                      7903FE1008B8BC99A41AE9E95628BC64F2F1B20C2D7E9F5177A3C294D4462299";
+        let signature = "EFD48B2AACB6A8FD1140DD9CD45E81D69D2C877B56AAF991C34D0EA84EAF3716\
+                         F7CB1C942D657C41D436C7A1B6E29F65F3E900DBB9AFF4064DC4AB2F843ACDA8";
+        assert_eq!(public_key(&secret).to_vec(), hex(public));
+        assert_eq!(sign(&secret, b"sample").to_vec(), hex(signature));
+    }
+}
@@ -0,0 +1,278 @@
+use super::Zone;
+use crate::message::{
+    data::Data,
+    domain::Record,
+    header::{PacketId, QueryMode},
+    route::Route,
+    Header, Message,
+};
+use anyhow::{Context, Result};
+use std::{
+    collections::HashSet,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// Most changes remembered per zone; older ones are answered with the whole zone.
+const MAX_DIFFS: usize = 64;
+
+/// Whether `serial` comes after `than` in serial number arithmetic (RFC 1982).
+pub fn is_newer(serial: u32, than: u32) -> bool {
+    serial != than && serial.wrapping_sub(than) < 1 << 31
+}
+
+/// Serial of a SOA record.
+pub fn serial(r: &Route) -> Option<u32> {
+    match (r.domain().record, r.data()) {
+        (Record::SOA, Data::Soa(soa)) => Some(soa.serial),
+        _ => None,
+    }
+}
+
+/// One change to a zone: the records deleted from the version with SOA
+/// `from` and those added to make the version with SOA `to`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diff {
+    pub from: Route,
+    pub to: Route,
+    pub deleted: Vec<Route>,
+    pub added: Vec<Route>,
+}
+
+impl Diff {
+    /// The change turning `old` into `new`.
+    pub fn between(old: &Zone, new: &Zone) -> Self {
+        let body = |z: &Zone| -> HashSet<Route> {
+            z.records
+                .iter()
+                .filter(|r| r.domain().record != Record::SOA)
+                .cloned()
+                .collect()
+        };
+        let (old_body, new_body) = (body(old), body(new));
+        let in_order = |z: &Zone, skip: &HashSet<Route>| -> Vec<Route> {
+            z.records
+                .iter()
+                .filter(|r| r.domain().record != Record::SOA && !skip.contains(r))
+                .cloned()
+                .collect()
+        };
+        Self {
+            from: old.soa().clone(),
+            to: new.soa().clone(),
+            deleted: in_order(old, &new_body),
+            added: in_order(new, &old_body),
+        }
+    }
+
+    /// Applies the change to the records of the version it starts from.
+    pub fn apply(&self, records: &mut Vec<Route>) -> Result<()> {
+        let from = serial(&self.from).context("Change does not start with a SOA")?;
+        let at = records
+            .iter()
+            .position(|r| r.domain().record == Record::SOA)
+            .context("Zone has no SOA record")?;
+        anyhow::ensure!(
+            serial(&records[at]) == Some(from),
+            "Change from serial {from} does not apply to serial {:?}",
+            serial(&records[at])
+        );
+        let deleted: HashSet<&Route> = self.deleted.iter().collect();
+        records[at] = self.to.clone();
+        records.retain(|r| !deleted.contains(r));
+        records.extend(self.added.iter().cloned());
+        Ok(())
+    }
+}
+
+/// File keeping the changes made to the zone file at `zone` (`<zone>.jnl`).
+pub fn path(zone: &Path) -> PathBuf {
+    let mut path = zone.as_os_str().to_owned();
+    path.push(".jnl");
+    PathBuf::from(path)
+}
+
+/// Adds `diff` to the journal file at `path`, as a DNS message whose answers
+/// are laid out as in IXFR: `from`, deletions, `to`, additions.
+pub fn append(path: &Path, diff: &Diff) -> Result<()> {
+    let mut msg = Message::new(Header::response(PacketId(0)));
+    let records = [&diff.from]
+        .into_iter()
+        .chain(&diff.deleted)
+        .chain([&diff.to])
+        .chain(&diff.added);
+    msg.set_answers(records.cloned().collect())?;
+    let buf = msg.flush();
+    let len = u16::try_from(buf.len()).context("Change too large for the journal")?;
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Could not open journal {}", path.display()))?;
+    file.write_all(&[&len.to_be_bytes()[..], &buf].concat())
+        .with_context(|| format!("Could not write journal {}", path.display()))
+}
+
+/// Every change kept in the journal file at `path`, oldest first.
+pub fn load(path: &Path) -> Result<Vec<Diff>> {
+    let buf =
+        fs::read(path).with_context(|| format!("Could not read journal {}", path.display()))?;
+    let mut rest = &buf[..];
+    let mut diffs = vec![];
+    while let [high, low, tail @ ..] = rest {
+        let len = u16::from_be_bytes([*high, *low]) as usize;
+        anyhow::ensure!(tail.len() >= len, "Journal {} is truncated", path.display());
+        let msg = Message::try_from(&tail[..len])?;
+        anyhow::ensure!(
+            msg.header().qr == QueryMode::Response,
+            "Not a journal: {}",
+            path.display()
+        );
+        let records = msg.answers();
+        let to = records
+            .iter()
+            .skip(1)
+            .position(|r| serial(r).is_some())
+            .map(|i| i + 1)
+            .with_context(|| format!("Invalid change in journal {}", path.display()))?;
+        anyhow::ensure!(
+            serial(&records[0]).is_some(),
+            "Invalid change in journal {}",
+            path.display()
+        );
+        diffs.push(Diff {
+            from: records[0].clone(),
+            to: records[to].clone(),
+            deleted: records[1..to].to_vec(),
+            added: records[to + 1..].to_vec(),
+        });
+        rest = &tail[len..];
+    }
+    anyhow::ensure!(rest.is_empty(), "Journal {} is truncated", path.display());
+    Ok(diffs)
+}
+
+/// The latest changes to a zone, oldest first, each starting at the serial
+/// the previous one ended at.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Journal {
+    diffs: Vec<Diff>,
+}
+
+impl Journal {
+    /// Records `diff`, forgetting everything when it does not follow on from
+    /// the last change, and the oldest change when there are too many.
+    pub fn push(&mut self, diff: Diff) {
+        if self
+            .diffs
+            .last()
+            .is_some_and(|last| serial(&last.to) != serial(&diff.from))
+        {
+            self.diffs.clear();
+        }
+        self.diffs.push(diff);
+        if self.diffs.len() > MAX_DIFFS {
+            self.diffs.remove(0);
+        }
+    }
+
+    /// The changes from the version with serial `from` up to the latest, if known.
+    pub fn since(&self, from: u32) -> Option<&[Diff]> {
+        let start = self
+            .diffs
+            .iter()
+            .position(|d| serial(&d.from) == Some(from))?;
+        Some(&self.diffs[start..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zone::master;
+
+    fn zone(serial: u32, hosts: &str) -> Zone {
+        let text = format!(
+            "$ORIGIN hernan.rs.\n@ SOA ns hostmaster {serial} 60 30 600 60\n@ NS ns\n{hosts}"
+        );
+        Zone::new(master::parse(&text, "").unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_is_newer() {
+        assert!(is_newer(2, 1));
+        assert!(!is_newer(1, 1));
+        assert!(!is_newer(1, 2));
+        assert!(is_newer(1, u32::MAX));
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = zone(1, "ns A 10.0.0.1\nwww A 10.0.0.2\n");
+        let new = zone(2, "ns A 10.0.0.1\nwww A 10.0.0.3\n");
+        let diff = Diff::between(&old, &new);
+        assert_eq!(serial(&diff.from), Some(1));
+        assert_eq!(serial(&diff.to), Some(2));
+        assert_eq!(diff.deleted.len(), 1);
+        assert_eq!(diff.added.len(), 1);
+        assert_eq!(diff.added[0].data(), &Data::Ipv4([10, 0, 0, 3].into()));
+
+        let mut records = old.clone().into_records();
+        diff.apply(&mut records).unwrap();
+        let applied = Zone::new(records).unwrap();
+        assert!(Diff::between(&applied, &new).added.is_empty());
+        assert!(Diff::between(&applied, &new).deleted.is_empty());
+        assert_eq!(applied.serial(), 2);
+
+        let mut records = new.into_records();
+        assert!(diff.apply(&mut records).is_err());
+    }
+
+    #[test]
+    fn test_journal() {
+        let zones: Vec<Zone> = (1..=3)
+            .map(|s| zone(s, &format!("www A 10.0.0.{s}\n")))
+            .collect();
+        let mut journal = Journal::default();
+        journal.push(Diff::between(&zones[0], &zones[1]));
+        journal.push(Diff::between(&zones[1], &zones[2]));
+        assert_eq!(journal.since(1).unwrap().len(), 2);
+        assert_eq!(journal.since(2).unwrap().len(), 1);
+        assert!(journal.since(3).is_none());
+
+        journal.push(Diff::between(&zones[0], &zones[2]));
+        assert!(journal.since(2).is_none());
+        assert_eq!(journal.since(1).unwrap().len(), 1);
+
+        for _ in 0..MAX_DIFFS {
+            journal.push(Diff::between(&zones[2], &zones[2]));
+        }
+        assert!(journal.since(1).is_none());
+    }
+
+    #[test]
+    fn test_journal_file() {
+        let file = std::env::temp_dir().join(format!("journal-{}.zone", std::process::id()));
+        let path = path(&file);
+        assert!(path.to_string_lossy().ends_with(".zone.jnl"));
+        let _ = fs::remove_file(&path);
+
+        let zones: Vec<Zone> = (1..=3)
+            .map(|s| zone(s, &format!("www A 10.0.0.{s}\nmail{s} A 10.0.1.1\n")))
+            .collect();
+        let diffs = vec![
+            Diff::between(&zones[0], &zones[1]),
+            Diff::between(&zones[1], &zones[2]),
+        ];
+        for diff in &diffs {
+            append(&path, diff).unwrap();
+        }
+        assert_eq!(load(&path).unwrap(), diffs);
+
+        fs::write(&path, [0, 40, 1]).unwrap();
+        assert!(load(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,486 @@
+use super::dnssec;
+use crate::{
+    encoding::{decode_base32hex, decode_base64, decode_hex},
+    message::{
+        data::{Data, Soa},
+        domain::{Class, Domain, Record},
+        route::Route,
+    },
+};
+use anyhow::{Context, Result};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+const DEFAULT_TTL: u32 = 3600;
+
+/// One logical line of a master file, parentheses already joined.
+struct Entry {
+    line: usize,
+    has_owner: bool,
+    tokens: Vec<String>,
+}
+
+fn entries(text: &str) -> Result<Vec<Entry>> {
+    let mut all = vec![];
+    let mut entry = Entry {
+        line: 1,
+        has_owner: false,
+        tokens: vec![],
+    };
+    let mut token: Option<String> = None;
+    let (mut depth, mut quoted, mut comment) = (0, false, false);
+    let mut line = 1;
+    let mut first = true;
+
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if first {
+            entry.line = line;
+            entry.has_owner = !c.is_whitespace();
+            first = false;
+        }
+        match c {
+            '\n' => {
+                line += 1;
+                comment = false;
+                anyhow::ensure!(!quoted, "Unterminated string on line {}", line - 1);
+                if let Some(t) = token.take() {
+                    entry.tokens.push(t);
+                }
+                if depth == 0 {
+                    if !entry.tokens.is_empty() {
+                        all.push(entry);
+                    }
+                    entry = Entry {
+                        line,
+                        has_owner: false,
+                        tokens: vec![],
+                    };
+                    first = true;
+                }
+            }
+            _ if comment => {}
+            '\\' if quoted => {
+                if let Some(n) = chars.next() {
+                    token.get_or_insert_with(String::new).push(n);
+                }
+            }
+            '"' => {
+                quoted = !quoted;
+                token.get_or_insert_with(String::new);
+            }
+            _ if quoted => token.get_or_insert_with(String::new).push(c),
+            ';' => comment = true,
+            '(' => depth += 1,
+            ')' => {
+                anyhow::ensure!(depth > 0, "Unbalanced parenthesis on line {line}");
+                depth -= 1
+            }
+            c if c.is_whitespace() => {
+                if let Some(t) = token.take() {
+                    entry.tokens.push(t);
+                }
+            }
+            c => token.get_or_insert_with(String::new).push(c),
+        }
+    }
+    anyhow::ensure!(depth == 0 && !quoted, "Unexpected end of zone file");
+    if let Some(t) = token.take() {
+        entry.tokens.push(t);
+    }
+    if !entry.tokens.is_empty() {
+        all.push(entry);
+    }
+    Ok(all)
+}
+
+/// Turns `name` into a fully qualified name without its trailing dot.
+fn absolute(name: &str, origin: &str) -> String {
+    match name {
+        "@" => origin.to_string(),
+        "." => String::new(),
+        n if n.ends_with('.') => n.trim_end_matches('.').to_string(),
+        n if origin.is_empty() => n.to_string(),
+        n => format!("{n}.{origin}"),
+    }
+}
+
+/// Reads a TTL, either in seconds or with `s`, `m`, `h`, `d` and `w` units.
+pub fn parse_ttl(s: &str) -> Result<u32> {
+    if let Ok(n) = s.parse() {
+        return Ok(n);
+    }
+
+    let mut total: u64 = 0;
+    let mut n: u64 = 0;
+    for c in s.chars() {
+        let unit = match c.to_ascii_lowercase() {
+            d @ '0'..='9' => {
+                n = n * 10 + d.to_digit(10).unwrap_or_default() as u64;
+                anyhow::ensure!(n <= u32::MAX as u64, "Not a TTL: {s}");
+                continue;
+            }
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604800,
+            _ => anyhow::bail!("Not a TTL: {s}"),
+        };
+        total += n * unit;
+        n = 0;
+    }
+    anyhow::ensure!(n == 0 && total > 0, "Not a TTL: {s}");
+    u32::try_from(total).with_context(|| format!("Not a TTL: {s}"))
+}
+
+/// Reads a record type by mnemonic or in the `TYPEn` form of RFC 3597.
+fn parse_type(s: &str) -> Result<u16> {
+    s.parse::<Record>().map(u16::from)
+}
+
+/// Reads an RRSIG validity time, either `YYYYMMDDHHmmSS` in UTC or seconds
+/// since the epoch (RFC 4034 section 3.2).
+fn parse_time(s: &str) -> Result<u32> {
+    if s.len() != 14 {
+        return s.parse().with_context(|| format!("Not a time: {s}"));
+    }
+    let num = |range: std::ops::Range<usize>| -> Result<i64> {
+        s.get(range)
+            .and_then(|n| n.parse().ok())
+            .with_context(|| format!("Not a time: {s}"))
+    };
+    let (y, m, d) = (num(0..4)?, num(4..6)?, num(6..8)?);
+    anyhow::ensure!(
+        (1..=12).contains(&m) && (1..=31).contains(&d),
+        "Not a time: {s}"
+    );
+
+    // Days since 1970-01-01 of a proleptic Gregorian date.
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((m + 9) % 12) + 2) / 5 + d - 1;
+    let days = era * 146097 + yoe * 365 + yoe / 4 - yoe / 100 + doy - 719468;
+
+    let secs = days * 86400 + num(8..10)? * 3600 + num(10..12)? * 60 + num(12..14)?;
+    u32::try_from(secs).with_context(|| format!("Time out of range: {s}"))
+}
+
+/// Writes `name` as uncompressed labels, as DNSSEC record data requires.
+fn name_bytes(name: &str) -> Result<Vec<u8>> {
+    let mut bytes = vec![];
+    for label in name.split('.').filter(|l| !l.is_empty()) {
+        anyhow::ensure!(label.len() < 64, "Label too long: {label}");
+        bytes.push(label.len() as u8);
+        bytes.extend_from_slice(label.as_bytes());
+    }
+    bytes.push(0);
+    Ok(bytes)
+}
+
+/// Reads the types of NSEC and NSEC3 records into their bitmap.
+fn type_bitmap(types: &[String]) -> Result<Vec<u8>> {
+    let types = types
+        .iter()
+        .map(|t| parse_type(t))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(dnssec::type_bitmap(&types))
+}
+
+/// Hex data where `-` stands for none, as NSEC3 salts are written.
+fn salt_bytes(s: &str) -> Result<Vec<u8>> {
+    let salt = match s {
+        "-" => vec![],
+        s => decode_hex(s)?,
+    };
+    anyhow::ensure!(salt.len() <= 255, "Salt too long: {s}");
+    Ok(salt)
+}
+
+fn parse_data(record: Record, rdata: &[String], origin: &str) -> Result<Data> {
+    let field = |i: usize| {
+        rdata
+            .get(i)
+            .map(String::as_str)
+            .with_context(|| format!("Missing field {} of {record}", i + 1))
+    };
+
+    // RFC 3597 generic encoding works for every record type.
+    if field(0)? == "\\#" {
+        let len: usize = field(1)?.parse()?;
+        let bytes = decode_hex(&rdata[2..].concat())?;
+        anyhow::ensure!(bytes.len() == len, "Wrong length of generic data");
+        return Ok(Data::Raw(bytes));
+    }
+
+    let data = match record {
+        Record::AA => Data::Ipv4(field(0)?.parse::<Ipv4Addr>()?),
+        Record::AAAA => Data::Ipv6(field(0)?.parse::<Ipv6Addr>()?),
+        Record::NS | Record::CNAME | Record::PTR => Data::Name(absolute(field(0)?, origin)),
+        Record::MX => Data::Mx {
+            preference: field(0)?.parse()?,
+            exchange: absolute(field(1)?, origin),
+        },
+        Record::SOA => Data::Soa(Soa {
+            mname: absolute(field(0)?, origin),
+            rname: absolute(field(1)?, origin),
+            serial: field(2)?.parse()?,
+            refresh: parse_ttl(field(3)?)?,
+            retry: parse_ttl(field(4)?)?,
+            expire: parse_ttl(field(5)?)?,
+            minimum: parse_ttl(field(6)?)?,
+        }),
+        Record::TXT => {
+            let mut bytes = vec![];
+            for s in rdata {
+                anyhow::ensure!(s.len() <= 255, "TXT string too long: {s}");
+                bytes.push(s.len() as u8);
+                bytes.extend_from_slice(s.as_bytes());
+            }
+            Data::Raw(bytes)
+        }
+        Record::DS => {
+            let mut bytes = field(0)?.parse::<u16>()?.to_be_bytes().to_vec();
+            bytes.push(field(1)?.parse()?);
+            bytes.push(field(2)?.parse()?);
+            bytes.extend(decode_hex(&rdata[3..].concat())?);
+            Data::Raw(bytes)
+        }
+        Record::DNSKEY => {
+            let mut bytes = field(0)?.parse::<u16>()?.to_be_bytes().to_vec();
+            bytes.push(field(1)?.parse()?);
+            bytes.push(field(2)?.parse()?);
+            bytes.extend(decode_base64(&rdata[3..].concat())?);
+            Data::Raw(bytes)
+        }
+        Record::RRSIG => {
+            let mut bytes = parse_type(field(0)?)?.to_be_bytes().to_vec();
+            bytes.push(field(1)?.parse()?);
+            bytes.push(field(2)?.parse()?);
+            bytes.extend(parse_ttl(field(3)?)?.to_be_bytes());
+            bytes.extend(parse_time(field(4)?)?.to_be_bytes());
+            bytes.extend(parse_time(field(5)?)?.to_be_bytes());
+            bytes.extend(field(6)?.parse::<u16>()?.to_be_bytes());
+            bytes.extend(name_bytes(&absolute(field(7)?, origin))?);
+            bytes.extend(decode_base64(&rdata[8..].concat())?);
+            Data::Raw(bytes)
+        }
+        Record::NSEC => {
+            let mut bytes = name_bytes(&absolute(field(0)?, origin))?;
+            bytes.extend(type_bitmap(&rdata[1..])?);
+            Data::Raw(bytes)
+        }
+        Record::NSEC3PARAM => {
+            let mut bytes = vec![field(0)?.parse()?, field(1)?.parse()?];
+            bytes.extend(field(2)?.parse::<u16>()?.to_be_bytes());
+            let salt = salt_bytes(field(3)?)?;
+            bytes.push(salt.len() as u8);
+            bytes.extend(salt);
+            Data::Raw(bytes)
+        }
+        Record::NSEC3 => {
+            let mut bytes = vec![field(0)?.parse()?, field(1)?.parse()?];
+            bytes.extend(field(2)?.parse::<u16>()?.to_be_bytes());
+            let salt = salt_bytes(field(3)?)?;
+            bytes.push(salt.len() as u8);
+            bytes.extend(salt);
+            let next = decode_base32hex(field(4)?)?;
+            anyhow::ensure!(next.len() <= 255, "Hashed name too long");
+            bytes.push(next.len() as u8);
+            bytes.extend(next);
+            bytes.extend(type_bitmap(&rdata[5..])?);
+            Data::Raw(bytes)
+        }
+        r => anyhow::bail!("Record {r} must use the generic \\# format"),
+    };
+    Ok(data)
+}
+
+/// Reads the records of a master file (RFC 1035 section 5). Relative names are
+/// completed with `origin` until a `$ORIGIN` directive says otherwise.
+pub fn parse(text: &str, origin: &str) -> Result<Vec<Route>> {
+    let mut origin = origin.trim_end_matches('.').to_string();
+    let mut default_ttl = None;
+    let mut last_owner: Option<String> = None;
+    let mut last_ttl = DEFAULT_TTL;
+    let mut routes = vec![];
+
+    for entry in entries(text)? {
+        let line = entry.line;
+        let mut tokens = entry.tokens.iter().map(String::as_str).peekable();
+        let context = || format!("Invalid zone entry on line {line}");
+
+        match tokens.peek().copied() {
+            Some("$ORIGIN") => {
+                let name = entry.tokens.get(1).with_context(context)?;
+                origin = absolute(name, &origin);
+                continue;
+            }
+            Some("$TTL") => {
+                let ttl = entry.tokens.get(1).with_context(context)?;
+                default_ttl = Some(parse_ttl(ttl).with_context(context)?);
+                continue;
+            }
+            Some(d) if d.starts_with('$') => {
+                anyhow::bail!("Unsupported directive {d} on line {line}")
+            }
+            _ => {}
+        }
+
+        let owner = match entry.has_owner {
+            true => absolute(tokens.next().with_context(context)?, &origin),
+            false => last_owner.clone().with_context(context)?,
+        };
+
+        let mut ttl = None;
+        let mut class = Class::IN;
+        let record = loop {
+            let token = tokens.next().with_context(context)?;
+            if let Ok(t) = parse_ttl(token) {
+                ttl = Some(t);
+            } else if let Ok(c) = token.parse::<Class>() {
+                class = c;
+            } else {
+                break token.parse::<Record>().with_context(context)?;
+            }
+        };
+
+        let rdata: Vec<String> = tokens.map(str::to_string).collect();
+        let data = parse_data(record, &rdata, &origin).with_context(context)?;
+
+        let ttl = ttl.or(default_ttl).unwrap_or(last_ttl);
+        let domain = Domain {
+            name: owner.clone(),
+            record,
+            class,
+        };
+        routes.push(Route::new(domain, ttl, data));
+
+        last_owner = Some(owner);
+        last_ttl = ttl;
+    }
+
+    Ok(routes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ZONE: &str = r#"
+$ORIGIN hernan.rs.
+$TTL 1h
+@   IN  SOA ns hostmaster (
+            2024010101 ; serial
+            1d 2h 4w 1h )
+    IN  NS  ns
+ns      A   10.0.0.1
+www 60  IN  CNAME   @
+@       AAAA    ::1
+        MX  10 mail.example.com.
+txt     TXT "hello world" "a\"b"
+srv     SRV \# 6 0001 0002 0035
+"#;
+
+    #[test]
+    fn test_parse_zone() {
+        let routes = parse(ZONE, "").unwrap();
+        assert_eq!(routes.len(), 8);
+
+        let soa = &routes[0];
+        assert_eq!(soa.domain().name, "hernan.rs");
+        assert_eq!(soa.ttl(), 3600);
+        let Data::Soa(soa) = soa.data() else {
+            panic!("not a SOA")
+        };
+        assert_eq!(soa.mname, "ns.hernan.rs");
+        assert_eq!(soa.serial, 2024010101);
+        assert_eq!(soa.refresh, 86400);
+        assert_eq!(soa.expire, 2419200);
+
+        assert_eq!(routes[1].domain().name, "hernan.rs");
+        assert_eq!(routes[1].data(), &Data::Name("ns.hernan.rs".to_string()));
+        assert_eq!(routes[2].data(), &Data::Ipv4(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(routes[3].ttl(), 60);
+        assert_eq!(routes[3].domain().record, Record::CNAME);
+        assert_eq!(routes[4].data(), &Data::Ipv6(Ipv6Addr::LOCALHOST));
+        assert_eq!(routes[5].domain().name, "hernan.rs");
+        assert_eq!(
+            routes[6].data(),
+            &Data::Raw(b"\x0bhello world\x03a\"b".to_vec())
+        );
+        assert_eq!(routes[7].data(), &Data::Raw(vec![0, 1, 0, 2, 0, 0x35]));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse("a A 1.2.3.4", "").is_ok());
+        assert!(parse("  A 1.2.3.4", "").is_err());
+        assert!(parse("a A (1.2.3.4", "").is_err());
+        assert!(parse("a BOGUS 1", "").is_err());
+        assert!(parse("$INCLUDE other", "").is_err());
+    }
+
+    const SIGNED: &str = r#"
+$ORIGIN hernan.rs.
+@   DNSKEY  257 3 13 ( mdsswUyr3DPW132mOi8V9xESWE8jTo0d
+                       xCjjnopKl+GqJxpVXckHAeF+KkxLbxIL )
+@   DS      60485 5 1 2BB183AF5F22588179A53B0A98631FAD1A292118
+@   RRSIG   A 13 2 3600 20240201000000 1706745600 60485 @ ( AAEC )
+@   NSEC    www A NS SOA RRSIG NSEC DNSKEY TYPE1234
+@   NSEC3PARAM  1 0 10 AABBCCDD
+@   NSEC3   1 1 10 - CPNMUOG A RRSIG
+"#;
+
+    #[test]
+    fn test_parse_dnssec() {
+        let routes = parse(SIGNED, "").unwrap();
+        let raw = |i: usize| match routes[i].data() {
+            Data::Raw(bytes) => bytes.clone(),
+            d => panic!("not raw: {d:?}"),
+        };
+
+        let dnskey = raw(0);
+        assert_eq!(dnskey[..4], [1, 1, 3, 13]);
+        assert_eq!(dnskey.len(), 4 + 48);
+        assert_eq!(raw(1)[..4], [0xec, 0x45, 5, 1]);
+        assert_eq!(raw(1).len(), 4 + 20);
+
+        let rrsig = raw(2);
+        assert_eq!(rrsig[..4], [0, 1, 13, 2]);
+        assert_eq!(rrsig[4..8], 3600u32.to_be_bytes());
+        assert_eq!(rrsig[8..12], 1706745600u32.to_be_bytes());
+        assert_eq!(rrsig[12..16], 1706745600u32.to_be_bytes());
+        assert_eq!(rrsig[16..18], 60485u16.to_be_bytes());
+        assert_eq!(rrsig[18..29], *b"\x06hernan\x02rs\x00");
+        assert_eq!(rrsig[29..], [0, 1, 2]);
+
+        let nsec = raw(3);
+        assert_eq!(nsec[..15], *b"\x03www\x06hernan\x02rs\x00");
+        let mut bitmap = vec![0, 7, 0x62, 0, 0, 0, 0, 0x03, 0x80, 4, 27];
+        bitmap.extend([0; 26]);
+        bitmap.push(0x20);
+        assert_eq!(nsec[15..], bitmap);
+
+        assert_eq!(raw(4), [1, 0, 0, 10, 4, 0xaa, 0xbb, 0xcc, 0xdd]);
+        assert_eq!(raw(5)[..6], [1, 1, 0, 10, 0, 4]);
+        assert_eq!(raw(5)[6..10], *b"foob");
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time("19700101000000").unwrap(), 0);
+        assert_eq!(parse_time("20240201000000").unwrap(), 1706745600);
+        assert_eq!(parse_time("1706745600").unwrap(), 1706745600);
+        assert!(parse_time("20241301000000").is_err());
+        assert!(parse_type("TYPE").is_err());
+    }
+
+    #[test]
+    fn test_parse_ttl() {
+        assert_eq!(parse_ttl("300").unwrap(), 300);
+        assert_eq!(parse_ttl("1h30m").unwrap(), 5400);
+        assert!(parse_ttl("IN").is_err());
+        assert!(parse_ttl("10x").is_err());
+    }
+}
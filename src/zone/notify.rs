@@ -0,0 +1,180 @@
+use super::{transfer, Zone};
+use crate::{
+    message::{
+        domain::{Domain, Record},
+        header::{Authoritative, OpCode, PacketId},
+        Header, Message,
+    },
+    socket::{DnsSocket, RetryPolicy},
+    tsig::{self, Key, KeyStore, Session},
+};
+use anyhow::Result;
+use std::{
+    sync::{mpsc::Receiver, Arc},
+    thread,
+    time::Duration,
+};
+
+/// How NOTIFY is sent again until acknowledged, about a minute in all
+/// (RFC 1996 section 3.6).
+pub const RETRY: RetryPolicy = RetryPolicy {
+    timeout: Duration::from_secs(2),
+    attempts: 5,
+    backoff: 2,
+    deadline: Duration::from_secs(120),
+};
+
+/// A secondary told whenever a zone changes.
+#[derive(Clone, Debug)]
+pub struct Target {
+    pub origin: String,
+    pub addr: String,
+    pub key: Option<Key>,
+}
+
+impl Target {
+    /// Reads a NOTIFY target as given on the command line:
+    /// `origin=secondary[#key]`, the key taken from `keys`.
+    pub fn parse(spec: &str, keys: &KeyStore) -> Result<Self> {
+        let (origin, addr, key) = transfer::parse_peer(spec, keys)?;
+        Ok(Self { origin, addr, key })
+    }
+
+    /// Tells the secondary that `zone` is at a new serial, until it says it
+    /// heard.
+    pub fn send(&self, zone: &Zone, policy: RetryPolicy) -> Result<()> {
+        let mut header = Header::query(PacketId::random());
+        header.op_code = OpCode::notify();
+        header.aa = Authoritative::Owned;
+        let mut msg = Message::new(header);
+        msg.set_questions(vec![Domain::new(zone.origin(), Record::SOA)])?;
+        msg.set_answers(vec![zone.soa().clone()])?;
+        let mut session = self.key.clone().map(Session::new);
+        if let Some(session) = session.as_mut() {
+            session.sign(&mut msg, tsig::now())?;
+        }
+
+        let res = DnsSocket::connect(&self.addr)?
+            .with_policy(policy)
+            .query(&msg)?;
+        if let Some(session) = session.as_mut() {
+            session.verify(&res, tsig::now())?;
+        }
+        anyhow::ensure!(
+            res.header().op_code == OpCode::notify() && res.header().r_code == OpCode::no_error(),
+            "{} answered NOTIFY for {} with code {}",
+            self.addr,
+            self.origin,
+            res.header().r_code.0
+        );
+        Ok(())
+    }
+}
+
+/// Notifies the `targets` of every zone in `changes`, each from its own
+/// thread so that slow secondaries do not hold the others back.
+pub fn run(changes: Receiver<Arc<Zone>>, targets: &[Target], policy: RetryPolicy) {
+    for zone in changes {
+        let origin = zone.origin().trim_end_matches('.');
+        for target in targets
+            .iter()
+            .filter(|t| t.origin.eq_ignore_ascii_case(origin))
+        {
+            let (target, zone) = (target.clone(), zone.clone());
+            thread::spawn(move || match target.send(&zone, policy) {
+                Ok(()) => println!(
+                    "Notified {} of zone {} serial {}",
+                    target.addr,
+                    target.origin,
+                    zone.serial()
+                ),
+                Err(e) => println!(
+                    "Could not notify {} of zone {}: {e}",
+                    target.addr, target.origin
+                ),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zone::{journal, master, Zones};
+    use std::{net::UdpSocket, sync::mpsc};
+
+    fn zone(serial: u32) -> Zone {
+        let text = format!("$ORIGIN hernan.rs.\n@ SOA ns hostmaster {serial} 60 30 600 60\n");
+        Zone::new(master::parse(&text, "").unwrap()).unwrap()
+    }
+
+    /// A secondary answering the first `ignore` messages with nothing, then
+    /// acknowledging and reporting the NOTIFY it got.
+    fn secondary(ignore: usize) -> (String, mpsc::Receiver<Message>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            for seen in 0.. {
+                let (size, from) = socket.recv_from(&mut buf).unwrap();
+                let msg = Message::try_from(&buf[..size]).unwrap();
+                if seen < ignore {
+                    continue;
+                }
+                let res = Message::new_response(&msg);
+                socket.send_to(&res.flush(), from).unwrap();
+                sender.send(msg).unwrap();
+            }
+        });
+        (addr, receiver)
+    }
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            timeout: Duration::from_millis(100),
+            ..RETRY
+        }
+    }
+
+    #[test]
+    fn test_send() {
+        let (addr, received) = secondary(2);
+        let target = Target::parse(&format!("hernan.rs={addr}"), &KeyStore::default()).unwrap();
+        target.send(&zone(7), policy()).unwrap();
+
+        let msg = received.recv().unwrap();
+        assert_eq!(msg.header().op_code, OpCode::notify());
+        assert_eq!(msg.questions()[0], Domain::new("hernan.rs", Record::SOA));
+        assert_eq!(msg.answers()[0].data(), zone(7).soa().data());
+
+        let silent = Target::parse("hernan.rs=127.0.0.1:9", &KeyStore::default()).unwrap();
+        let policy = RetryPolicy {
+            attempts: 1,
+            ..policy()
+        };
+        assert!(silent.send(&zone(7), policy).is_err());
+    }
+
+    #[test]
+    fn test_run() {
+        let (addr, received) = secondary(0);
+        let targets = vec![
+            Target::parse(&format!("hernan.rs={addr}"), &KeyStore::default()).unwrap(),
+            Target::parse(&format!("other.rs={addr}"), &KeyStore::default()).unwrap(),
+        ];
+        let zones = Zones::default();
+        let changes = zones.watch();
+        thread::spawn(move || run(changes, &targets, policy()));
+
+        zones.insert(zone(1));
+        zones.insert(zone(2));
+        let serials: Vec<_> = received
+            .iter()
+            .take(2)
+            .map(|m| journal::serial(&m.answers()[0]).unwrap())
+            .collect();
+        assert!(serials.contains(&1) && serials.contains(&2));
+        assert!(received.recv_timeout(Duration::from_millis(300)).is_err());
+    }
+}
@@ -0,0 +1,603 @@
+use super::{
+    journal::{self, is_newer, Diff},
+    Zone, Zones,
+};
+use crate::{
+    message::{
+        data::{name_len, Data, Soa},
+        domain::{Domain, Record},
+        header::{Authoritative, OpCode, PacketId},
+        route::Route,
+        Header, Message,
+    },
+    socket::{tcp::DnsStream, DnsSocket, RetryPolicy},
+    tsig::{self, Key, KeyStore, Session},
+};
+use anyhow::{Context, Result};
+use std::{
+    iter,
+    net::{IpAddr, ToSocketAddrs},
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Most bytes of records packed in one transfer message.
+const MESSAGE_SIZE: usize = 16384;
+
+/// Wait between attempts until the primary told its SOA timers.
+const INITIAL_RETRY: Duration = Duration::from_secs(60);
+
+fn route_len(r: &Route) -> usize {
+    name_len(&r.domain().name) as usize + 10 + r.data().len() as usize
+}
+
+fn transfer_message(query: &Message, answers: Vec<Route>, first: bool) -> Result<Message> {
+    let mut res = Message::new_response(query);
+    res.set_aa(Authoritative::Owned);
+    if !first {
+        res.set_questions(vec![])?;
+    }
+    res.set_answers(answers)?;
+    Ok(res)
+}
+
+/// Spreads `records` over as many answers to `query` as needed, only the
+/// first one repeating the question.
+fn pack<'a>(query: &Message, records: impl Iterator<Item = &'a Route>) -> Result<Vec<Message>> {
+    let mut messages = vec![];
+    let (mut batch, mut size) = (vec![], 0);
+    for r in records {
+        if size + route_len(r) > MESSAGE_SIZE && !batch.is_empty() {
+            let first = messages.is_empty();
+            messages.push(transfer_message(query, std::mem::take(&mut batch), first)?);
+            size = 0;
+        }
+        size += route_len(r);
+        batch.push(r.clone());
+    }
+    let first = messages.is_empty();
+    messages.push(transfer_message(query, batch, first)?);
+    Ok(messages)
+}
+
+/// Answers an AXFR `query` with every record of `zone` between two copies of
+/// its SOA, spread over as many messages as needed (RFC 5936 section 2.2).
+pub fn axfr(zone: &Zone, query: &Message) -> Result<Vec<Message>> {
+    let soa = zone.soa();
+    let body = zone
+        .records
+        .iter()
+        .filter(|r| r.domain().record != Record::SOA);
+    pack(query, iter::once(soa).chain(body).chain(iter::once(soa)))
+}
+
+/// Answers an IXFR `query` (RFC 1995) with the changes since the serial of
+/// the SOA in its authority section: just the SOA when the client is up to
+/// date, and the whole zone as AXFR does when the journal does not go back
+/// that far.
+pub fn ixfr(zone: &Zone, query: &Message) -> Result<Vec<Message>> {
+    let soa = zone.soa();
+    let Some(client) = query.authorities().iter().find_map(journal::serial) else {
+        return axfr(zone, query);
+    };
+    if !is_newer(zone.serial(), client) {
+        return pack(query, iter::once(soa));
+    }
+    let Some(diffs) = zone.journal().since(client) else {
+        return axfr(zone, query);
+    };
+    let changes = diffs.iter().flat_map(|d| {
+        iter::once(&d.from)
+            .chain(&d.deleted)
+            .chain(iter::once(&d.to))
+            .chain(&d.added)
+    });
+    pack(query, iter::once(soa).chain(changes).chain(iter::once(soa)))
+}
+
+/// What a transfer brought.
+#[derive(Debug, PartialEq)]
+enum Changes {
+    /// The copy asking with IXFR is up to date.
+    None,
+    /// Every record of the zone.
+    Zone(Vec<Route>),
+    /// The changes since the copy asking with IXFR.
+    Diffs(Vec<Diff>),
+}
+
+/// Reads the records of a transfer received so far, `None` while more are to
+/// come. `ours` is the serial an IXFR asked from; an answer whose second
+/// record is its SOA lists changes, anything else is the whole zone.
+fn read_changes(records: &[Route], ours: Option<u32>) -> Result<Option<Changes>> {
+    let Some(first) = records.first() else {
+        return Ok(None);
+    };
+    let last = journal::serial(first).context("Transfer does not start with a SOA")?;
+    let Some(second) = records.get(1) else {
+        // A lone SOA ends an IXFR only where the message ended.
+        return Ok(ours
+            .filter(|&ours| !is_newer(last, ours))
+            .map(|_| Changes::None));
+    };
+
+    let incremental =
+        ours.is_some_and(|ours| ours != last && journal::serial(second) == Some(ours));
+    if !incremental {
+        let Some(end) = records[1..]
+            .iter()
+            .position(|r| journal::serial(r).is_some())
+        else {
+            return Ok(None);
+        };
+        anyhow::ensure!(
+            end + 2 == records.len(),
+            "Records after the end of the transfer"
+        );
+        return Ok(Some(Changes::Zone(records[..=end].to_vec())));
+    }
+
+    let next_soa =
+        |from: usize| (from..records.len()).find(|&i| journal::serial(&records[i]).is_some());
+    let mut diffs = vec![];
+    let mut at = 1;
+    while journal::serial(&records[at]) != Some(last) {
+        let Some(to) = next_soa(at + 1) else {
+            return Ok(None);
+        };
+        let Some(end) = next_soa(to + 1) else {
+            return Ok(None);
+        };
+        diffs.push(Diff {
+            from: records[at].clone(),
+            to: records[to].clone(),
+            deleted: records[at + 1..to].to_vec(),
+            added: records[to + 1..end].to_vec(),
+        });
+        at = end;
+    }
+    anyhow::ensure!(
+        at + 1 == records.len(),
+        "Records after the end of the transfer"
+    );
+    Ok(Some(Changes::Diffs(diffs)))
+}
+
+/// Asks the primary at `addr` for `question`, with `authorities` telling
+/// which version an IXFR starts from, and reads the whole answer.
+fn transfer(
+    addr: &str,
+    question: Domain,
+    authorities: Vec<Route>,
+    key: Option<&Key>,
+    policy: RetryPolicy,
+) -> Result<Changes> {
+    let origin = question.name.clone();
+    let ours = authorities.iter().find_map(journal::serial);
+    let mut query = Message::new(Header::query(PacketId::random()));
+    query.set_questions(vec![question])?;
+    query.set_authorities(authorities)?;
+    let mut session = key.cloned().map(Session::new);
+    if let Some(session) = session.as_mut() {
+        session.sign(&mut query, tsig::now())?;
+    }
+
+    let mut stream = DnsStream::connect(addr, policy)?;
+    stream.send(&query)?;
+    let mut records: Vec<Route> = vec![];
+    loop {
+        let res = stream.recv()?;
+        anyhow::ensure!(
+            !res.is_query() && res.header().id == query.header().id,
+            "Unexpected message while transferring {origin}"
+        );
+        anyhow::ensure!(
+            res.header().r_code == OpCode::no_error(),
+            "Transfer of {origin} refused with code {}",
+            res.header().r_code.0
+        );
+        if let Some(session) = session.as_mut() {
+            session.verify(&res, tsig::now())?;
+        }
+        anyhow::ensure!(!res.answers().is_empty(), "Empty transfer message");
+
+        records.extend(res.answers().iter().cloned());
+        if let Some(changes) =
+            read_changes(&records, ours).with_context(|| format!("Invalid transfer of {origin}"))?
+        {
+            return Ok(changes);
+        }
+    }
+}
+
+/// Pulls the zone at `origin` from the primary at `addr` with AXFR, signing
+/// with `key` when given.
+pub fn fetch(addr: &str, origin: &str, key: Option<&Key>, policy: RetryPolicy) -> Result<Zone> {
+    match transfer(addr, Domain::new(origin, Record::AXFR), vec![], key, policy)? {
+        Changes::Zone(records) => Zone::new(records),
+        _ => anyhow::bail!("Transfer of {origin} is not a whole zone"),
+    }
+}
+
+/// Pulls the changes to `zone` from the primary at `addr` with IXFR, signing
+/// with `key` when given. `None` when the copy is up to date.
+pub fn fetch_changes(
+    addr: &str,
+    zone: &Zone,
+    key: Option<&Key>,
+    policy: RetryPolicy,
+) -> Result<Option<Zone>> {
+    let question = Domain::new(zone.origin(), Record::IXFR);
+    match transfer(addr, question, vec![zone.soa().clone()], key, policy)? {
+        Changes::None => Ok(None),
+        Changes::Zone(records) => Zone::new(records).map(Some),
+        Changes::Diffs(diffs) => {
+            let mut records = zone.records.clone();
+            for diff in &diffs {
+                diff.apply(&mut records)?;
+            }
+            Zone::new(records).map(Some)
+        }
+    }
+}
+
+/// Reads a zone and the server it is exchanged with as given on the command
+/// line: `origin=server[#key]`, the key taken from `keys`.
+pub fn parse_peer(spec: &str, keys: &KeyStore) -> Result<(String, String, Option<Key>)> {
+    let (origin, server) = spec
+        .split_once('=')
+        .with_context(|| format!("Missing server in {spec}"))?;
+    let (server, key) = match server.split_once('#') {
+        Some((server, name)) => {
+            let key = keys
+                .get(name)
+                .with_context(|| format!("Unknown TSIG key: {name}"))?;
+            (server, Some(key.clone()))
+        }
+        None => (server, None),
+    };
+    anyhow::ensure!(!server.is_empty(), "Missing server in {spec}");
+    let origin = origin.trim_end_matches('.').to_ascii_lowercase();
+    Ok((origin, server.to_string(), key))
+}
+
+/// A zone copied from its primary server and kept up to date.
+#[derive(Clone, Debug)]
+pub struct Secondary {
+    pub origin: String,
+    pub primary: String,
+    pub key: Option<Key>,
+    /// Set when the primary announced a change, waking `run` up.
+    notified: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl Secondary {
+    /// Reads a secondary zone as given on the command line:
+    /// `origin=primary[#key]`, the key taken from `keys`.
+    pub fn parse(spec: &str, keys: &KeyStore) -> Result<Self> {
+        let (origin, primary, key) = parse_peer(spec, keys)?;
+        Ok(Self {
+            origin,
+            primary,
+            key,
+            notified: Default::default(),
+        })
+    }
+
+    /// Whether `client`, whose message was signed with `key` if any, is the
+    /// primary: it signs with the key of the zone or has its address.
+    pub fn is_primary(&self, client: IpAddr, key: Option<&str>) -> bool {
+        let own = self.key.as_ref().map(|k| k.name.trim_end_matches('.'));
+        let signed = key
+            .zip(own)
+            .is_some_and(|(key, own)| key.trim_end_matches('.').eq_ignore_ascii_case(own));
+        signed
+            || self
+                .primary
+                .to_socket_addrs()
+                .is_ok_and(|mut addrs| addrs.any(|a| a.ip() == client))
+    }
+
+    /// Makes `run` check the primary right away, as it announced a change.
+    pub fn notify(&self) {
+        let (notified, wakeup) = &*self.notified;
+        *notified.lock().expect("Secondary lock poisoned") = true;
+        wakeup.notify_all();
+    }
+
+    /// Waits for `wait`, or less when notified meanwhile.
+    fn sleep(&self, wait: Duration) {
+        let (notified, wakeup) = &*self.notified;
+        let guard = notified.lock().expect("Secondary lock poisoned");
+        let (mut guard, _) = wakeup
+            .wait_timeout_while(guard, wait, |notified| !*notified)
+            .expect("Secondary lock poisoned");
+        *guard = false;
+    }
+
+    /// The SOA of the zone on the primary, asked over UDP.
+    fn primary_soa(&self, policy: RetryPolicy) -> Result<Soa> {
+        let mut query = Message::new(Header::query(PacketId::random()));
+        query.set_questions(vec![Domain::new(&self.origin, Record::SOA)])?;
+        let mut session = self.key.clone().map(Session::new);
+        if let Some(session) = session.as_mut() {
+            session.sign(&mut query, tsig::now())?;
+        }
+
+        let res = DnsSocket::connect(&self.primary)?
+            .with_policy(policy)
+            .query(&query)?;
+        if let Some(session) = session.as_mut() {
+            session.verify(&res, tsig::now())?;
+        }
+        res.answers()
+            .iter()
+            .find_map(|r| match r.data() {
+                Data::Soa(soa) if r.domain().name.eq_ignore_ascii_case(&self.origin) => {
+                    Some(soa.clone())
+                }
+                _ => None,
+            })
+            .with_context(|| format!("{} has no SOA for {}", self.primary, self.origin))
+    }
+
+    /// Transfers the zone into `zones` unless the copy there is up to date,
+    /// returning the SOA of the primary.
+    pub fn refresh(&self, zones: &Zones, policy: RetryPolicy) -> Result<Soa> {
+        let soa = self.primary_soa(policy)?;
+        let current = zones.get(&self.origin);
+        if current
+            .as_ref()
+            .is_some_and(|z| !is_newer(soa.serial, z.serial()))
+        {
+            return Ok(soa);
+        }
+
+        let key = self.key.as_ref();
+        let zone = match current {
+            Some(current) => match fetch_changes(&self.primary, &current, key, policy) {
+                Ok(Some(zone)) => zone,
+                Ok(None) => return Ok(soa),
+                Err(e) => {
+                    println!("IXFR of {} failed, trying AXFR: {e}", self.origin);
+                    fetch(&self.primary, &self.origin, key, policy)?
+                }
+            },
+            None => fetch(&self.primary, &self.origin, key, policy)?,
+        };
+        anyhow::ensure!(
+            zone.origin().eq_ignore_ascii_case(&self.origin),
+            "{} sent zone {} for {}",
+            self.primary,
+            zone.origin(),
+            self.origin
+        );
+        println!("Transferred zone {} serial {}", self.origin, soa.serial);
+        zones.insert(zone);
+        Ok(soa)
+    }
+
+    /// Keeps the zone in `zones` up to date, checking the primary every SOA
+    /// refresh interval, or retry interval after failures, and whenever
+    /// notified. The zone is dropped once it could not be refreshed for longer
+    /// than its expire time.
+    pub fn run(&self, zones: &Zones, policy: RetryPolicy) {
+        let (mut retry, mut expires) = (INITIAL_RETRY, None);
+        loop {
+            let wait = match self.refresh(zones, policy) {
+                Ok(soa) => {
+                    retry = Duration::from_secs(soa.retry as u64);
+                    expires = Some(Instant::now() + Duration::from_secs(soa.expire as u64));
+                    Duration::from_secs(soa.refresh as u64)
+                }
+                Err(e) => {
+                    println!("Could not refresh zone {}: {e}", self.origin);
+                    if expires.is_some_and(|at| at <= Instant::now()) {
+                        println!("Zone {} expired", self.origin);
+                        zones.remove(&self.origin);
+                        expires = None;
+                    }
+                    retry
+                }
+            };
+            self.sleep(wait);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        socket::tcp::{DnsListener, ListenPolicy},
+        zone::master,
+    };
+    use std::{fmt::Write, thread};
+
+    fn zone(serial: u32, hosts: usize) -> Zone {
+        let mut text = format!("$ORIGIN hernan.rs.\n@ SOA ns hostmaster {serial} 60 30 600 60\n");
+        text.push_str("@ NS ns\nns A 10.0.0.1\n");
+        for i in 0..hosts {
+            writeln!(text, "host{i} A 10.1.{}.{}", i / 256, i % 256).unwrap();
+        }
+        Zone::new(master::parse(&text, "").unwrap()).unwrap()
+    }
+
+    fn transfer_query() -> Message {
+        let mut q = Message::new(Header::query(PacketId(5)));
+        q.set_questions(vec![Domain::new("hernan.rs", Record::AXFR)])
+            .unwrap();
+        q
+    }
+
+    #[test]
+    fn test_axfr() {
+        let messages = axfr(&zone(1, 0), &transfer_query()).unwrap();
+        assert_eq!(messages.len(), 1);
+        let answers = messages[0].answers();
+        assert_eq!(answers.len(), 4);
+        assert_eq!(answers[0].domain().record, Record::SOA);
+        assert_eq!(answers[3].domain().record, Record::SOA);
+
+        let messages = axfr(&zone(1, 2000), &transfer_query()).unwrap();
+        assert!(messages.len() > 1);
+        assert!(messages.iter().all(|m| m.flush().len() < u16::MAX as usize));
+        assert_eq!(messages[0].questions().len(), 1);
+        assert!(messages[1].questions().is_empty());
+        let total: usize = messages.iter().map(|m| m.answers().len()).sum();
+        assert_eq!(total, 2004);
+    }
+
+    /// `zone` at `to` with the change from `from` in its journal.
+    fn journaled(from: Zone, to: Zone) -> Zone {
+        let zones = Zones::default();
+        zones.insert(from);
+        zones.insert(to);
+        Arc::unwrap_or_clone(zones.get("hernan.rs").unwrap())
+    }
+
+    fn ixfr_query(from: &Zone) -> Message {
+        let mut q = Message::new(Header::query(PacketId(6)));
+        q.set_questions(vec![Domain::new("hernan.rs", Record::IXFR)])
+            .unwrap();
+        q.set_authorities(vec![from.soa().clone()]).unwrap();
+        q
+    }
+
+    #[test]
+    fn test_ixfr() {
+        let (old, new) = (zone(1, 10), zone(2, 12));
+        let served = journaled(old.clone(), new.clone());
+        let answers = |messages: Vec<Message>| -> Vec<Route> {
+            messages.iter().flat_map(|m| m.answers().clone()).collect()
+        };
+
+        let changes = answers(ixfr(&served, &ixfr_query(&old)).unwrap());
+        let serials: Vec<_> = changes.iter().filter_map(journal::serial).collect();
+        assert_eq!(serials, [2, 1, 2, 2]);
+        assert_eq!(changes.len(), 6);
+        assert_eq!(
+            read_changes(&changes, Some(1)).unwrap(),
+            Some(Changes::Diffs(vec![Diff::between(&old, &new)]))
+        );
+        assert_eq!(read_changes(&changes[..5], Some(1)).unwrap(), None);
+
+        let current = answers(ixfr(&served, &ixfr_query(&new)).unwrap());
+        assert_eq!(current.len(), 1);
+        assert_eq!(
+            read_changes(&current, Some(2)).unwrap(),
+            Some(Changes::None)
+        );
+
+        let whole = answers(ixfr(&served, &ixfr_query(&zone(0, 0))).unwrap());
+        assert_eq!(whole.len(), 16);
+        assert!(matches!(
+            read_changes(&whole, Some(0)).unwrap(),
+            Some(Changes::Zone(records)) if records.len() == 15
+        ));
+        assert!(read_changes(&whole[1..], Some(0)).is_err());
+    }
+
+    #[test]
+    fn test_fetch_changes() {
+        let (old, new) = (zone(1, 10), zone(2, 12));
+        let addr = primary(journaled(old.clone(), new.clone()), None);
+        let policy = RetryPolicy::default();
+        let copy = fetch_changes(&addr, &old, None, policy).unwrap().unwrap();
+        assert_eq!(copy.serial(), 2);
+        assert_eq!(Diff::between(&copy, &new).added, vec![]);
+        assert_eq!(Diff::between(&copy, &new).deleted, vec![]);
+        assert!(fetch_changes(&addr, &new, None, policy).unwrap().is_none());
+
+        let copy = fetch_changes(&addr, &zone(0, 3), None, policy)
+            .unwrap()
+            .unwrap();
+        assert_eq!(copy.records.len(), 15);
+    }
+
+    /// Serves `zone` over AXFR and IXFR on a local port, signing with `key` if given.
+    fn primary(zone: Zone, key: Option<Key>) -> String {
+        let listener = DnsListener::bind("127.0.0.1:0", ListenPolicy::default()).unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            listener.serve(move |q, _| {
+                let mut messages = match q.questions()[0].record {
+                    Record::IXFR => ixfr(&zone, q).unwrap(),
+                    _ => axfr(&zone, q).unwrap(),
+                };
+                let mut keys = KeyStore::default();
+                if let Some(key) = &key {
+                    keys.insert(key.clone());
+                }
+                match tsig::accept(&keys, q, tsig::now()) {
+                    Ok(Some(mut session)) => messages
+                        .iter_mut()
+                        .for_each(|m| session.sign(m, tsig::now()).unwrap()),
+                    Ok(None) => {}
+                    Err(e) => return vec![tsig::reject(&keys, q, e, tsig::now()).unwrap()],
+                }
+                messages
+            })
+        });
+        addr
+    }
+
+    #[test]
+    fn test_fetch() {
+        let addr = primary(zone(7, 2000), None);
+        let copy = fetch(&addr, "hernan.rs", None, RetryPolicy::default()).unwrap();
+        assert_eq!(copy.origin(), "hernan.rs");
+        assert_eq!(copy.records.len(), 2003);
+        assert!(fetch(&addr, "hernan.rs", Some(&key()), RetryPolicy::default()).is_err());
+    }
+
+    fn key() -> Key {
+        "transfer.key:c2VjcmV0".parse().unwrap()
+    }
+
+    #[test]
+    fn test_fetch_signed() {
+        let addr = primary(zone(7, 2000), Some(key()));
+        let copy = fetch(&addr, "hernan.rs", Some(&key()), RetryPolicy::default()).unwrap();
+        assert_eq!(copy.records.len(), 2003);
+
+        let other = "transfer.key:b3RoZXI=".parse().unwrap();
+        assert!(fetch(&addr, "hernan.rs", Some(&other), RetryPolicy::default()).is_err());
+    }
+
+    #[test]
+    fn test_secondary_parse() {
+        let mut keys = KeyStore::default();
+        keys.insert(key());
+        let secondary = Secondary::parse("Hernan.rs.=10.0.0.1:53#transfer.key", &keys).unwrap();
+        assert_eq!(secondary.origin, "hernan.rs");
+        assert_eq!(secondary.primary, "10.0.0.1:53");
+        assert!(secondary.key.is_some());
+
+        assert!(Secondary::parse("hernan.rs=10.0.0.1:53", &keys)
+            .unwrap()
+            .key
+            .is_none());
+        assert!(Secondary::parse("hernan.rs=10.0.0.1:53#other", &keys).is_err());
+        assert!(Secondary::parse("hernan.rs", &keys).is_err());
+    }
+
+    #[test]
+    fn test_secondary_notify() {
+        let mut keys = KeyStore::default();
+        keys.insert(key());
+        let secondary = Secondary::parse("hernan.rs=127.0.0.1:53#transfer.key", &keys).unwrap();
+        let other: IpAddr = "10.0.0.1".parse().unwrap();
+        assert!(secondary.is_primary("127.0.0.1".parse().unwrap(), None));
+        assert!(secondary.is_primary(other, Some("Transfer.Key.")));
+        assert!(!secondary.is_primary(other, Some("other.key")));
+        assert!(!secondary.is_primary(other, None));
+
+        let start = Instant::now();
+        secondary.clone().notify();
+        secondary.sleep(Duration::from_secs(60));
+        secondary.sleep(Duration::from_millis(50));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+}
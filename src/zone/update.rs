@@ -0,0 +1,570 @@
+use super::{
+    journal::{self, is_newer, Diff},
+    Zone, Zones,
+};
+use crate::message::{
+    data::Data,
+    domain::{in_zone, Class, Domain, Record},
+    header::OpCode,
+    route::Route,
+    Message,
+};
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+
+/// Which names and types a key may change.
+#[derive(Clone, Debug)]
+struct Grant {
+    key: String,
+    name: String,
+    /// Whether the grant is for the names below `name` rather than `name`.
+    below: bool,
+    /// Types that may change, all of them when empty.
+    types: Vec<Record>,
+}
+
+/// Who may change what with UPDATE. Only signed updates can be allowed.
+#[derive(Clone, Debug, Default)]
+pub struct Policy {
+    grants: Vec<Grant>,
+}
+
+impl Policy {
+    /// Lets a key change some records: `key=name[/type,...]`, where `*.name`
+    /// stands for every name below `name`.
+    pub fn allow(&mut self, spec: &str) -> Result<()> {
+        let (key, names) = spec
+            .split_once('=')
+            .with_context(|| format!("Missing names in update policy: {spec}"))?;
+        let (name, types) = match names.split_once('/') {
+            Some((name, types)) => {
+                let types = types
+                    .split(',')
+                    .map(str::parse)
+                    .collect::<Result<Vec<Record>>>()?;
+                (name, types)
+            }
+            None => (names, vec![]),
+        };
+        let (name, below) = match name.strip_prefix("*.") {
+            Some(name) => (name, true),
+            None => (name, false),
+        };
+        anyhow::ensure!(!key.is_empty(), "Missing key in update policy: {spec}");
+        self.grants.push(Grant {
+            key: key.trim_end_matches('.').to_ascii_lowercase(),
+            name: name.trim_end_matches('.').to_ascii_lowercase(),
+            below,
+            types,
+        });
+        Ok(())
+    }
+
+    /// Whether `key` may change the `record` RRset at `name`; `Record::ANY`
+    /// stands for every RRset there.
+    pub fn allows(&self, key: &str, name: &str, record: Record) -> bool {
+        let key = key.trim_end_matches('.').to_ascii_lowercase();
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        self.grants.iter().any(|g| {
+            let name_ok = match g.below {
+                true => name != g.name && in_zone(&name, &g.name),
+                false => name == g.name,
+            };
+            g.key == key && name_ok && (g.types.is_empty() || g.types.contains(&record))
+        })
+    }
+}
+
+fn is_meta(record: Record) -> bool {
+    matches!(record, Record::ANY | Record::AXFR | Record::IXFR)
+}
+
+fn is_empty(r: &Route) -> bool {
+    matches!(r.data(), Data::Raw(data) if data.is_empty())
+}
+
+fn same_set(r: &Route, name: &str, record: Record) -> bool {
+    r.domain().name.eq_ignore_ascii_case(name) && r.domain().record == record
+}
+
+/// Checks the prerequisites of an update against `zone` (RFC 2136 section 3.2).
+fn check(zone: &Zone, prerequisites: &[Route]) -> Result<(), OpCode> {
+    let mut values: HashMap<(String, Record), Vec<&Data>> = HashMap::new();
+    for r in prerequisites {
+        let Domain { name, record, .. } = r.domain();
+        if !in_zone(name, zone.origin()) {
+            return Err(OpCode::not_zone());
+        }
+        if r.ttl() != 0 || (r.domain().class != Class::IN && !is_empty(r)) {
+            return Err(OpCode::format_error());
+        }
+        let in_use = zone.records_at(name).next().is_some();
+        let exists = zone.records_at(name).any(|x| x.domain().record == *record);
+        match (r.domain().class, record) {
+            (Class::ANY, Record::ANY) if !in_use => return Err(OpCode::name_error()),
+            (Class::ANY, _) if *record != Record::ANY && !exists => return Err(OpCode::nx_rrset()),
+            (Class::NONE, Record::ANY) if in_use => return Err(OpCode::yx_domain()),
+            (Class::NONE, _) if *record != Record::ANY && exists => return Err(OpCode::yx_rrset()),
+            (Class::IN, _) if is_meta(*record) => return Err(OpCode::format_error()),
+            (Class::IN, _) => values
+                .entry((name.to_ascii_lowercase(), *record))
+                .or_default()
+                .push(r.data()),
+            _ => {}
+        }
+    }
+
+    for ((name, record), wanted) in values {
+        let present: Vec<&Data> = zone
+            .records_at(&name)
+            .filter(|x| x.domain().record == record)
+            .map(Route::data)
+            .collect();
+        let same = wanted.iter().all(|d| present.contains(d))
+            && present.iter().all(|d| wanted.contains(d));
+        if !same {
+            return Err(OpCode::nx_rrset());
+        }
+    }
+    Ok(())
+}
+
+/// Checks the changes of an update before any is made, and that `key` may
+/// make them (RFC 2136 sections 3.3 and 3.4.1).
+fn prescan(
+    zone: &Zone,
+    changes: &[Route],
+    policy: &Policy,
+    key: Option<&str>,
+) -> Result<(), OpCode> {
+    for r in changes {
+        let Domain {
+            name,
+            record,
+            class,
+        } = r.domain();
+        if !in_zone(name, zone.origin()) {
+            return Err(OpCode::not_zone());
+        }
+        let valid = match class {
+            // Adds carry the data to add (RFC 2136 section 3.4.1.2).
+            Class::IN => !is_meta(*record) && !is_empty(r),
+            Class::ANY => {
+                r.ttl() == 0 && is_empty(r) && !matches!(record, Record::AXFR | Record::IXFR)
+            }
+            Class::NONE => r.ttl() == 0 && !is_meta(*record),
+            Class::CH | Class::Unknown(_) => false,
+        };
+        if !valid {
+            return Err(OpCode::format_error());
+        }
+        if !key.is_some_and(|key| policy.allows(key, name, *record)) {
+            return Err(OpCode::refused());
+        }
+    }
+    Ok(())
+}
+
+/// Makes one change of an update to `records` of the zone at `origin`
+/// (RFC 2136 section 3.4.2).
+fn apply(records: &mut Vec<Route>, origin: &str, r: &Route) {
+    let Domain {
+        name,
+        record,
+        class,
+    } = r.domain();
+    let (name, record) = (name.trim_end_matches('.'), *record);
+    let apex = name.eq_ignore_ascii_case(origin);
+    match class {
+        Class::IN if record == Record::SOA => {
+            let current = records
+                .iter_mut()
+                .find(|x| x.domain().record == Record::SOA);
+            if let Some(current) = current {
+                let newer = journal::serial(r)
+                    .zip(journal::serial(current))
+                    .is_some_and(|(new, old)| is_newer(new, old));
+                if apex && newer {
+                    *current = Route::new(Domain::new(name, record), r.ttl(), r.data().clone());
+                }
+            }
+        }
+        Class::IN => {
+            let types: Vec<Record> = records
+                .iter()
+                .filter(|x| x.domain().name.eq_ignore_ascii_case(name))
+                .map(|x| x.domain().record)
+                .collect();
+            let dnssec = |t: &Record| matches!(t, Record::RRSIG | Record::NSEC);
+            if record == Record::CNAME && types.iter().any(|t| *t != Record::CNAME && !dnssec(t)) {
+                return;
+            }
+            if record != Record::CNAME && !dnssec(&record) && types.contains(&Record::CNAME) {
+                return;
+            }
+            records.retain(|x| {
+                !same_set(x, name, record) || (record != Record::CNAME && x.data() != r.data())
+            });
+            records.push(Route::new(
+                Domain::new(name, record),
+                r.ttl(),
+                r.data().clone(),
+            ));
+        }
+        Class::ANY => records.retain(|x| {
+            let kept = apex && matches!(x.domain().record, Record::SOA | Record::NS);
+            !x.domain().name.eq_ignore_ascii_case(name)
+                || kept
+                || (record != Record::ANY && x.domain().record != record)
+        }),
+        Class::NONE => {
+            let last_ns = records.iter().filter(|x| same_set(x, name, record)).count() == 1;
+            if record == Record::SOA || (apex && record == Record::NS && last_ns) {
+                return;
+            }
+            records.retain(|x| !same_set(x, name, record) || x.data() != r.data());
+        }
+        // Turned away by `prescan`.
+        Class::CH | Class::Unknown(_) => {}
+    }
+}
+
+/// The version of `zone` after the update `msg`, `None` when nothing changed,
+/// or the response code refusing it. Changes are kept in the journal of the
+/// zone file.
+fn change(
+    zone: &Zone,
+    msg: &Message,
+    policy: &Policy,
+    key: Option<&str>,
+) -> Result<Option<Zone>, OpCode> {
+    check(zone, msg.answers())?;
+    prescan(zone, msg.authorities(), policy, key)?;
+
+    let mut records = zone.records.clone();
+    for r in msg.authorities() {
+        apply(&mut records, zone.origin(), r);
+    }
+    let mut new = Zone::new(records).map_err(|_| OpCode::server_failure())?;
+    let diff = Diff::between(zone, &new);
+    if diff.added.is_empty() && diff.deleted.is_empty() && diff.from == diff.to {
+        return Ok(None);
+    }
+    if diff.from == diff.to {
+        new.bump_serial();
+    }
+
+    new.file = zone.file.clone();
+    if let Some(file) = &zone.file {
+        if let Err(e) = journal::append(&journal::path(file), &Diff::between(zone, &new)) {
+            println!("Could not keep update of {}: {e}", zone.origin());
+            return Err(OpCode::server_failure());
+        }
+    }
+    Ok(Some(new))
+}
+
+/// Applies the UPDATE `msg`, signed with `key` if any, to its zone in
+/// `zones` (RFC 2136). The response code tells how it went.
+pub fn update(zones: &Zones, msg: &Message, policy: &Policy, key: Option<&str>) -> OpCode {
+    let [zone] = &msg.questions()[..] else {
+        return OpCode::format_error();
+    };
+    if zone.record != Record::SOA {
+        return OpCode::format_error();
+    }
+    let result = zones.update(&zone.name, |zone| match change(zone, msg, policy, key) {
+        Ok(new) => (new, OpCode::no_error()),
+        Err(code) => (None, code),
+    });
+    result.unwrap_or_else(OpCode::not_auth)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        message::{header::PacketId, Header},
+        zone::master,
+    };
+    use std::{fs, net::Ipv4Addr};
+
+    const ZONE: &str = "$ORIGIN hernan.rs.
+@ SOA ns hostmaster 1 60 30 600 60
+@ NS ns
+ns A 10.0.0.1
+www A 10.0.0.2
+www A 10.0.0.3
+web CNAME www
+";
+
+    fn zones() -> Zones {
+        let zones = Zones::default();
+        zones.insert(Zone::new(master::parse(ZONE, "").unwrap()).unwrap());
+        zones
+    }
+
+    fn policy() -> Policy {
+        let mut policy = Policy::default();
+        policy.allow("dhcp.key=*.hernan.rs/A,AAAA,CNAME").unwrap();
+        policy.allow("admin.key=hernan.rs").unwrap();
+        policy
+    }
+
+    fn record(name: &str, record: Record, class: Class, ttl: u32, data: Data) -> Route {
+        let mut domain = Domain::new(name, record);
+        domain.class = class;
+        Route::new(domain, ttl, data)
+    }
+
+    fn a(name: &str, ip: [u8; 4]) -> Route {
+        record(
+            name,
+            Record::AA,
+            Class::IN,
+            300,
+            Data::Ipv4(Ipv4Addr::from(ip)),
+        )
+    }
+
+    fn empty(name: &str, r: Record, class: Class) -> Route {
+        record(name, r, class, 0, Data::Raw(vec![]))
+    }
+
+    fn message(prerequisites: Vec<Route>, changes: Vec<Route>) -> Message {
+        let mut header = Header::query(PacketId(9));
+        header.op_code = OpCode::update();
+        let mut msg = Message::new(header);
+        msg.set_questions(vec![Domain::new("hernan.rs", Record::SOA)])
+            .unwrap();
+        msg.set_answers(prerequisites).unwrap();
+        msg.set_authorities(changes).unwrap();
+        msg
+    }
+
+    fn records(zones: &Zones, name: &str) -> Vec<Route> {
+        zones
+            .get("hernan.rs")
+            .unwrap()
+            .records_at(name)
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn test_policy() {
+        let policy = policy();
+        assert!(policy.allows("DHCP.key.", "host.hernan.rs", Record::AA));
+        assert!(!policy.allows("dhcp.key", "hernan.rs", Record::AA));
+        assert!(!policy.allows("dhcp.key", "host.hernan.rs", Record::MX));
+        assert!(!policy.allows("dhcp.key", "host.example.com", Record::AA));
+        assert!(policy.allows("admin.key", "hernan.rs", Record::ANY));
+        assert!(!policy.allows("admin.key", "www.hernan.rs", Record::AA));
+
+        let mut policy = Policy::default();
+        assert!(policy.allow("dhcp.key").is_err());
+        assert!(policy.allow("=hernan.rs").is_err());
+        assert!(policy.allow("dhcp.key=hernan.rs/BOGUS").is_err());
+    }
+
+    #[test]
+    fn test_prerequisites() {
+        let zones = zones();
+        let run = |prerequisites| {
+            let msg = message(prerequisites, vec![]);
+            update(&zones, &msg, &policy(), Some("dhcp.key"))
+        };
+        assert_eq!(
+            run(vec![empty("www.hernan.rs", Record::ANY, Class::ANY)]),
+            OpCode::no_error()
+        );
+        assert_eq!(
+            run(vec![empty("new.hernan.rs", Record::ANY, Class::ANY)]),
+            OpCode::name_error()
+        );
+        assert_eq!(
+            run(vec![empty("www.hernan.rs", Record::MX, Class::ANY)]),
+            OpCode::nx_rrset()
+        );
+        assert_eq!(
+            run(vec![empty("www.hernan.rs", Record::ANY, Class::NONE)]),
+            OpCode::yx_domain()
+        );
+        assert_eq!(
+            run(vec![empty("www.hernan.rs", Record::AA, Class::NONE)]),
+            OpCode::yx_rrset()
+        );
+        assert_eq!(
+            run(vec![empty("www.example.com", Record::ANY, Class::ANY)]),
+            OpCode::not_zone()
+        );
+
+        let exact = |ips: &[[u8; 4]]| {
+            ips.iter()
+                .map(|ip| {
+                    record(
+                        "www.hernan.rs",
+                        Record::AA,
+                        Class::IN,
+                        0,
+                        Data::Ipv4(Ipv4Addr::from(*ip)),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            run(exact(&[[10, 0, 0, 3], [10, 0, 0, 2]])),
+            OpCode::no_error()
+        );
+        assert_eq!(run(exact(&[[10, 0, 0, 2]])), OpCode::nx_rrset());
+        assert_eq!(
+            run(exact(&[[10, 0, 0, 2], [10, 0, 0, 4]])),
+            OpCode::nx_rrset()
+        );
+    }
+
+    #[test]
+    fn test_update() {
+        let zones = zones();
+        let key = Some("dhcp.key");
+
+        let msg = message(vec![], vec![a("host.hernan.rs", [10, 0, 0, 9])]);
+        assert_eq!(update(&zones, &msg, &policy(), key), OpCode::no_error());
+        assert_eq!(records(&zones, "host.hernan.rs").len(), 1);
+        let zone = zones.get("hernan.rs").unwrap();
+        assert_eq!(zone.serial(), 2);
+        assert_eq!(zone.journal().since(1).unwrap().len(), 1);
+
+        // Adding what is there already changes nothing.
+        assert_eq!(update(&zones, &msg, &policy(), key), OpCode::no_error());
+        assert_eq!(zones.get("hernan.rs").unwrap().serial(), 2);
+
+        let delete = record(
+            "www.hernan.rs",
+            Record::AA,
+            Class::NONE,
+            0,
+            Data::Ipv4([10, 0, 0, 2].into()),
+        );
+        let msg = message(vec![], vec![delete]);
+        assert_eq!(update(&zones, &msg, &policy(), key), OpCode::no_error());
+        assert_eq!(records(&zones, "www.hernan.rs").len(), 1);
+
+        let msg = message(vec![], vec![empty("www.hernan.rs", Record::AA, Class::ANY)]);
+        assert_eq!(update(&zones, &msg, &policy(), key), OpCode::no_error());
+        assert!(records(&zones, "www.hernan.rs").is_empty());
+        assert_eq!(zones.get("hernan.rs").unwrap().serial(), 4);
+
+        // Adds without data are malformed, and make no change.
+        let msg = message(vec![], vec![empty("new.hernan.rs", Record::AA, Class::IN)]);
+        assert_eq!(update(&zones, &msg, &policy(), key), OpCode::format_error());
+        assert!(records(&zones, "new.hernan.rs").is_empty());
+        assert_eq!(zones.get("hernan.rs").unwrap().serial(), 4);
+
+        // A CNAME cannot share its name with other data.
+        let msg = message(vec![], vec![a("web.hernan.rs", [10, 0, 0, 5])]);
+        assert_eq!(update(&zones, &msg, &policy(), key), OpCode::no_error());
+        assert_eq!(records(&zones, "web.hernan.rs").len(), 1);
+        assert_eq!(
+            records(&zones, "web.hernan.rs")[0].domain().record,
+            Record::CNAME
+        );
+    }
+
+    #[test]
+    fn test_update_apex() {
+        let zones = zones();
+        let key = Some("admin.key");
+        let msg = message(vec![], vec![empty("hernan.rs", Record::ANY, Class::ANY)]);
+        assert_eq!(update(&zones, &msg, &policy(), key), OpCode::no_error());
+        assert_eq!(records(&zones, "hernan.rs").len(), 2);
+
+        let ns = record(
+            "hernan.rs",
+            Record::NS,
+            Class::NONE,
+            0,
+            Data::Name("ns.hernan.rs".into()),
+        );
+        let msg = message(vec![], vec![ns]);
+        assert_eq!(update(&zones, &msg, &policy(), key), OpCode::no_error());
+        assert_eq!(records(&zones, "hernan.rs").len(), 2);
+
+        let zone = zones.get("hernan.rs").unwrap();
+        let Data::Soa(soa) = zone.soa().data() else {
+            unreachable!()
+        };
+        let soa = Data::Soa(crate::message::data::Soa {
+            serial: 10,
+            ..soa.clone()
+        });
+        let msg = message(
+            vec![],
+            vec![record("hernan.rs", Record::SOA, Class::IN, 60, soa)],
+        );
+        assert_eq!(update(&zones, &msg, &policy(), key), OpCode::no_error());
+        assert_eq!(zones.get("hernan.rs").unwrap().serial(), 10);
+    }
+
+    #[test]
+    fn test_update_refused() {
+        let zones = zones();
+        let msg = message(vec![], vec![a("host.hernan.rs", [10, 0, 0, 9])]);
+        assert_eq!(update(&zones, &msg, &policy(), None), OpCode::refused());
+        assert_eq!(
+            update(&zones, &msg, &policy(), Some("admin.key")),
+            OpCode::refused()
+        );
+
+        let mx = record(
+            "host.hernan.rs",
+            Record::MX,
+            Class::IN,
+            60,
+            Data::Raw(vec![0, 1, 0]),
+        );
+        let msg = message(vec![], vec![mx]);
+        assert_eq!(
+            update(&zones, &msg, &policy(), Some("dhcp.key")),
+            OpCode::refused()
+        );
+
+        let msg = message(vec![], vec![a("host.example.com", [10, 0, 0, 9])]);
+        assert_eq!(
+            update(&zones, &msg, &policy(), Some("dhcp.key")),
+            OpCode::not_zone()
+        );
+
+        let mut msg = message(vec![], vec![]);
+        msg.set_questions(vec![Domain::new("example.com", Record::SOA)])
+            .unwrap();
+        assert_eq!(
+            update(&zones, &msg, &policy(), Some("dhcp.key")),
+            OpCode::not_auth()
+        );
+        assert_eq!(zones.get("hernan.rs").unwrap().serial(), 1);
+    }
+
+    #[test]
+    fn test_update_persisted() {
+        let file = std::env::temp_dir().join(format!("update-{}.zone", std::process::id()));
+        let _ = fs::remove_file(journal::path(&file));
+        fs::write(&file, ZONE).unwrap();
+        let zones = Zones::default();
+        zones.insert(Zone::load(&file).unwrap());
+
+        for ip in [[10, 0, 0, 9], [10, 0, 0, 10]] {
+            let msg = message(vec![], vec![a("host.hernan.rs", ip)]);
+            assert_eq!(
+                update(&zones, &msg, &policy(), Some("dhcp.key")),
+                OpCode::no_error()
+            );
+        }
+        let reloaded = Zone::load(&file).unwrap();
+        assert_eq!(reloaded.serial(), 3);
+        assert_eq!(reloaded.records_at("host.hernan.rs").count(), 2);
+        assert_eq!(reloaded.journal().since(1).unwrap().len(), 2);
+        fs::remove_file(journal::path(&file)).unwrap();
+        fs::remove_file(&file).unwrap();
+    }
+}